@@ -0,0 +1,100 @@
+use super::*;
+use std::{cmp, mem};
+
+pub const MAX_UDP_SIZE: usize = 65535;
+
+// How much we allocate at a time. Datagrams are received straight into a chunk and handed out as
+// slices of it, so a whole batch of them can land in one chunk.
+const CHUNK_SIZE: usize = 32 * MAX_UDP_SIZE;
+
+// Hands out buffers for received datagrams without giving each one its own 64 KiB allocation.
+// Datagrams are received into a chunk and carved off the front of it. Once a chunk is used up it's
+// retired, and put back into use once every datagram carved off it has been dropped, so in the
+// long run we only allocate as many chunks as there are datagrams being held onto at once.
+// Chunks are zero-initialised when they're allocated so we never need to expose uninitialised
+// memory to the socket. A datagram keeps its whole chunk from being reused, so anything that
+// holds onto one for longer than it takes to handle it should copy it out first.
+pub struct BufferPool {
+    chunk: BytesMut,
+    // Where the memory of the chunk we're carving starts.
+    base: usize,
+    // What's left of the chunks we've used up, along with where their memory starts.
+    retired: VecDeque<(Bytes, usize)>,
+}
+
+impl BufferPool {
+    pub fn new() -> BufferPool {
+        BufferPool {
+            chunk: BytesMut::new(),
+            base: 0,
+            retired: VecDeque::new(),
+        }
+    }
+
+    fn ensure(&mut self, len: usize) {
+        if self.chunk.len() >= len {
+            return;
+        }
+        let (chunk, base) = self.next_chunk();
+        let used = mem::replace(&mut self.chunk, chunk);
+        let used_base = mem::replace(&mut self.base, base);
+        if used_base != 0 {
+            self.retired.push_back((used.freeze(), used_base));
+        }
+    }
+
+    // Takes back the first retired chunk whose datagrams have all been dropped, or allocates a
+    // new one if they're all still in use.
+    fn next_chunk(&mut self) -> (BytesMut, usize) {
+        for _ in 0..self.retired.len() {
+            let (chunk, base) = unwrap!(self.retired.pop_front());
+            let mut chunk = match chunk.try_mut() {
+                Ok(chunk) => chunk,
+                Err(chunk) => {
+                    self.retired.push_back((chunk, base));
+                    continue;
+                },
+            };
+            // We hold the only handle left on the chunk, so reserving moves us back to the start
+            // of its memory rather than allocating.
+            chunk.clear();
+            chunk.reserve(CHUNK_SIZE);
+            if chunk.as_ptr() as usize != base {
+                continue;
+            }
+            // The chunk's memory was zeroed when it was allocated, and since then it's only been
+            // received into, so all of it is initialised.
+            unsafe {
+                chunk.set_len(CHUNK_SIZE);
+            }
+            return (chunk, base);
+        }
+        let chunk = BytesMut::from(vec![0u8; CHUNK_SIZE]);
+        let base = chunk.as_ptr() as usize;
+        (chunk, base)
+    }
+
+    // A buffer big enough for any datagram. Call `take` with however much of it gets filled.
+    pub fn recv_buf(&mut self) -> &mut [u8] {
+        self.ensure(MAX_UDP_SIZE);
+        &mut self.chunk[..MAX_UDP_SIZE]
+    }
+
+    pub fn take(&mut self, len: usize) -> BytesMut {
+        self.chunk.split_to(len)
+    }
+
+    // Room for up to `count` datagrams to be received at once, one after another in slots of
+    // `MAX_UDP_SIZE`. Call `take_slot` for each slot that gets filled, in order.
+    pub fn recv_slots(&mut self, count: usize) -> &mut [u8] {
+        self.ensure(MAX_UDP_SIZE);
+        let slots = cmp::min(count, self.chunk.len() / MAX_UDP_SIZE);
+        &mut self.chunk[..slots * MAX_UDP_SIZE]
+    }
+
+    pub fn take_slot(&mut self, len: usize) -> BytesMut {
+        let mut slot = self.chunk.split_to(MAX_UDP_SIZE);
+        slot.truncate(len);
+        slot
+    }
+}
@@ -0,0 +1,191 @@
+use super::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// The rate we start sending to a new destination at, before we've had any feedback.
+const INITIAL_RATE: BytePerSec = BytePerSec(64.0 * 1024.0);
+
+// We never throttle a destination below this, so that we can still find out when it recovers.
+const MIN_RATE: BytePerSec = BytePerSec(4.0 * 1024.0);
+
+// Each rate's worth of acknowledged data adds this much to the rate. This is the rate-based
+// equivalent of TCP growing its window by one segment per round trip.
+const ADDITIVE_INCREASE: BytePerSec = BytePerSec(1500.0);
+
+// Losses reported within this long of the last decrease are assumed to be from the same
+// congestion event and don't halve the rate again.
+const DECREASE_HOLDOFF: Duration = Duration::from_millis(200);
+
+// How long a bucket can go without sending before it fills up, ie. how bursty we allow traffic to
+// be.
+const BURST_TIME: Sec = Sec(0.1);
+
+// Forget about destinations we haven't sent to in this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// A token bucket. Sending uses up tokens, one per byte, which refill at `rate`.
+pub struct TokenBucket {
+    rate: BytePerSec,
+    tokens: Byte,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: BytePerSec, now: Instant) -> TokenBucket {
+        let mut bucket = TokenBucket {
+            rate,
+            tokens: Byte(0.0),
+            updated: now,
+        };
+        bucket.tokens = bucket.capacity();
+        bucket
+    }
+
+    // Always enough for the biggest datagram, otherwise it could never be sent.
+    fn capacity(&self) -> Byte {
+        let capacity = self.rate * BURST_TIME;
+        let min_capacity = Byte::from(MAX_UDP_SIZE);
+        if capacity > min_capacity { capacity } else { min_capacity }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.updated {
+            self.tokens += self.rate * Sec::from(now.duration_since(self.updated));
+            self.updated = now;
+        }
+        let capacity = self.capacity();
+        if self.tokens > capacity {
+            self.tokens = capacity;
+        }
+    }
+
+    pub fn can_send(&mut self, size: Byte, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= size
+    }
+
+    pub fn consume(&mut self, size: Byte) {
+        self.tokens -= size;
+    }
+
+    pub fn refund(&mut self, size: Byte) {
+        self.tokens += size;
+        let capacity = self.capacity();
+        if self.tokens > capacity {
+            self.tokens = capacity;
+        }
+    }
+
+    pub fn rate(&self) -> BytePerSec {
+        self.rate
+    }
+}
+
+// A cap on the total upload rate. Clones share the same budget, so one can be handed to several
+// sockets.
+#[derive(Clone)]
+pub struct UploadBudget {
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+impl UploadBudget {
+    pub fn new(rate: BytePerSec) -> UploadBudget {
+        UploadBudget {
+            bucket: Arc::new(Mutex::new(TokenBucket::new(rate, Instant::now()))),
+        }
+    }
+}
+
+struct Destination {
+    bucket: TokenBucket,
+    last_decrease: Option<Instant>,
+    last_used: Instant,
+}
+
+// Decides whether a packet can go out now. Each destination gets its own AIMD-controlled rate,
+// driven by the acks and losses reported through `SharedUdpSocket`, and everything comes out of
+// the global upload budget, if there is one.
+pub struct CongestionControl {
+    destinations: HashMap<SocketAddr, Destination>,
+    budget: Option<UploadBudget>,
+    last_expired: Instant,
+}
+
+impl CongestionControl {
+    pub fn new(budget: Option<UploadBudget>) -> CongestionControl {
+        CongestionControl {
+            destinations: HashMap::new(),
+            budget,
+            last_expired: Instant::now(),
+        }
+    }
+
+    fn destination(&mut self, dest: SocketAddr, now: Instant) -> &mut Destination {
+        self.destinations.entry(dest).or_insert_with(|| Destination {
+            bucket: TokenBucket::new(INITIAL_RATE, now),
+            last_decrease: None,
+            last_used: now,
+        })
+    }
+
+    pub fn can_send(&mut self, dest: SocketAddr, size: Byte, now: Instant) -> bool {
+        if !self.destination(dest, now).bucket.can_send(size, now) {
+            return false;
+        }
+        match self.budget {
+            Some(ref budget) => unwrap!(budget.bucket.lock()).can_send(size, now),
+            None => true,
+        }
+    }
+
+    pub fn consume(&mut self, dest: SocketAddr, size: Byte, now: Instant) {
+        {
+            let destination = self.destination(dest, now);
+            destination.bucket.consume(size);
+            destination.last_used = now;
+        }
+        if let Some(ref budget) = self.budget {
+            unwrap!(budget.bucket.lock()).consume(size);
+        }
+    }
+
+    // Gives back the tokens for a packet that we took out of the queue but didn't get to send.
+    pub fn refund(&mut self, dest: SocketAddr, size: Byte) {
+        if let Some(destination) = self.destinations.get_mut(&dest) {
+            destination.bucket.refund(size);
+        }
+        if let Some(ref budget) = self.budget {
+            unwrap!(budget.bucket.lock()).refund(size);
+        }
+    }
+
+    pub fn on_ack(&mut self, dest: SocketAddr, acked: Byte, now: Instant) {
+        let bucket = &mut self.destination(dest, now).bucket;
+        let rate = bucket.rate;
+        bucket.rate = rate + ADDITIVE_INCREASE * (acked / (rate * Sec(1.0)));
+    }
+
+    pub fn on_loss(&mut self, dest: SocketAddr, now: Instant) {
+        let destination = self.destination(dest, now);
+        if let Some(last_decrease) = destination.last_decrease {
+            if now < last_decrease + DECREASE_HOLDOFF {
+                return;
+            }
+        }
+        destination.last_decrease = Some(now);
+        let rate = destination.bucket.rate * 0.5;
+        destination.bucket.rate = if rate > MIN_RATE { rate } else { MIN_RATE };
+    }
+
+    pub fn rate(&self, dest: &SocketAddr) -> Option<BytePerSec> {
+        self.destinations.get(dest).map(|destination| destination.bucket.rate())
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        if now < self.last_expired + IDLE_TIMEOUT {
+            return;
+        }
+        self.last_expired = now;
+        self.destinations.retain(|_, destination| now < destination.last_used + IDLE_TIMEOUT);
+    }
+}
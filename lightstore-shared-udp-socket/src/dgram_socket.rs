@@ -0,0 +1,111 @@
+use super::*;
+#[cfg(all(feature = "mmsg", target_os = "linux"))]
+use std::os::unix::io::AsRawFd;
+
+// The parts of a UDP socket that `SharedUdpSocketDriver` needs. This lets the tests swap in a mock
+// socket to inject errors.
+pub trait DgramSocket: Send + 'static {
+    fn poll_send_to(&mut self, buf: &[u8], dest: &SocketAddr) -> io::Result<Async<usize>>;
+    fn poll_recv_from(&mut self, buf: &mut [u8]) -> io::Result<Async<(usize, SocketAddr)>>;
+
+    // How many datagrams the batch methods below can move at once.
+    fn batch_size(&self) -> usize {
+        1
+    }
+
+    // Sends as many of `packets` as possible, returning how many got sent.
+    fn poll_send_batch(&mut self, packets: &[(&[u8], &SocketAddr)]) -> io::Result<Async<usize>> {
+        let (data, dest) = packets[0];
+        let _n = try_ready!(self.poll_send_to(data, dest));
+        Ok(Async::Ready(1))
+    }
+
+    // Receives up to one datagram into each of `bufs`, returning the length and sender of each.
+    fn poll_recv_batch(
+        &mut self,
+        bufs: &mut [&mut [u8]],
+    ) -> io::Result<Async<Vec<(usize, SocketAddr)>>> {
+        let received = try_ready!(self.poll_recv_from(&mut bufs[0][..]));
+        Ok(Async::Ready(vec![received]))
+    }
+}
+
+impl DgramSocket for UdpSocket {
+    fn poll_send_to(&mut self, buf: &[u8], dest: &SocketAddr) -> io::Result<Async<usize>> {
+        UdpSocket::poll_send_to(self, buf, dest)
+    }
+
+    fn poll_recv_from(&mut self, buf: &mut [u8]) -> io::Result<Async<(usize, SocketAddr)>> {
+        UdpSocket::poll_recv_from(self, buf)
+    }
+
+    #[cfg(all(feature = "mmsg", target_os = "linux"))]
+    fn batch_size(&self) -> usize {
+        mmsg::BATCH_SIZE
+    }
+
+    #[cfg(all(feature = "mmsg", target_os = "linux"))]
+    fn poll_send_batch(&mut self, packets: &[(&[u8], &SocketAddr)]) -> io::Result<Async<usize>> {
+        let _ready = try_ready!(self.poll_write_ready());
+        match mmsg::sendmmsg(self.as_raw_fd(), packets) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.clear_write_ready()?;
+                Ok(Async::NotReady)
+            },
+            res => res.map(Async::Ready),
+        }
+    }
+
+    #[cfg(all(feature = "mmsg", target_os = "linux"))]
+    fn poll_recv_batch(
+        &mut self,
+        bufs: &mut [&mut [u8]],
+    ) -> io::Result<Async<Vec<(usize, SocketAddr)>>> {
+        let _ready = try_ready!(self.poll_read_ready(mio::Ready::readable()));
+        match mmsg::recvmmsg(self.as_raw_fd(), bufs) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.clear_read_ready(mio::Ready::readable())?;
+                Ok(Async::NotReady)
+            },
+            res => res.map(Async::Ready),
+        }
+    }
+}
+
+// What an error from the socket affects.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorScope {
+    // Only the packet that caused it. eg. it was too big.
+    Packet,
+    // Anything we send to that packet's destination. These are mostly ICMP errors reported back
+    // to us, and they say nothing about whether we can reach anyone else.
+    Destination,
+    // The socket itself is broken.
+    Socket,
+}
+
+pub fn error_scope(error: &io::Error) -> ErrorScope {
+    match error.kind() {
+        io::ErrorKind::ConnectionRefused |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::AddrNotAvailable |
+        io::ErrorKind::PermissionDenied => return ErrorScope::Destination,
+        io::ErrorKind::InvalidInput => return ErrorScope::Packet,
+        _ => (),
+    }
+    #[cfg(unix)]
+    {
+        if let Some(code) = error.raw_os_error() {
+            match code {
+                libc::EHOSTUNREACH | libc::ENETUNREACH | libc::EHOSTDOWN => {
+                    return ErrorScope::Destination;
+                },
+                // ENOBUFS means the kernel's send queue is full, which we treat like the packet
+                // getting lost on the network.
+                libc::EMSGSIZE | libc::ENOBUFS => return ErrorScope::Packet,
+                _ => (),
+            }
+        }
+    }
+    ErrorScope::Socket
+}
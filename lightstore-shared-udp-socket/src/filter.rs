@@ -0,0 +1,36 @@
+use super::*;
+
+// Picks out which incoming datagrams a subscription receives.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Filter {
+    Any,
+    // Datagrams sent from this address.
+    Source(SocketAddr),
+    // Datagrams that start with this connection ID, written as a big-endian u64.
+    ConnectionId(u64),
+    // Datagrams that start with these bytes.
+    Tag(Bytes),
+}
+
+impl Filter {
+    pub fn matches(&self, data: &[u8], source: &SocketAddr) -> bool {
+        match self {
+            Filter::Any => true,
+            Filter::Source(addr) => addr == source,
+            Filter::ConnectionId(id) => {
+                data.len() >= 8 && Cursor::new(&data[..8]).get_u64_be() == *id
+            },
+            Filter::Tag(tag) => data.starts_with(&tag[..]),
+        }
+    }
+
+    // When several subscriptions match the same datagram, the most specific one gets it.
+    pub fn specificity(&self) -> usize {
+        match self {
+            Filter::Any => 0,
+            Filter::Tag(tag) => 1 + tag.len(),
+            Filter::ConnectionId(..) => 1 + 8,
+            Filter::Source(..) => usize::max_value(),
+        }
+    }
+}
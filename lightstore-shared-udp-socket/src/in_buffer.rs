@@ -1,15 +1,25 @@
 use super::*;
 
 pub struct InBuffer {
-    buffer: BytesMut,
+    pool: BufferPool,
     result_txs: VecDeque<oneshot::Sender<io::Result<(BytesMut, SocketAddr)>>>,
+    // Kept sorted with the most specific filters first.
+    subscribers: Vec<Subscriber>,
+}
+
+pub struct Subscriber {
+    pub filter: Filter,
+    pub data_tx: UnboundedSender<(BytesMut, SocketAddr)>,
+    // Cancelled when the `Subscription` is dropped.
+    pub drop_tx: oneshot::Sender<()>,
 }
 
 impl InBuffer {
     pub fn new() -> InBuffer {
         InBuffer {
-            buffer: BytesMut::new(),
+            pool: BufferPool::new(),
             result_txs: VecDeque::new(),
+            subscribers: Vec::new(),
         }
     }
 
@@ -17,51 +27,114 @@ impl InBuffer {
         self.result_txs.push_back(result_tx);
     }
 
-    pub fn poll_recv(&mut self, socket: &mut UdpSocket) -> Async<()> {
-        const MAX_UDP_SIZE: usize = 65535;
+    pub fn add_subscriber(&mut self, subscriber: Subscriber) {
+        let specificity = subscriber.filter.specificity();
+        let index = {
+            self.subscribers
+            .iter()
+            .position(|other| other.filter.specificity() < specificity)
+            .unwrap_or(self.subscribers.len())
+        };
+        self.subscribers.insert(index, subscriber);
+    }
+
+    // Removes the receivers and subscribers that have gone away. Returns whether anyone's still
+    // waiting for data.
+    fn prune(&mut self) -> bool {
+        let mut i = 0;
+        while i < self.result_txs.len() {
+            match unwrap!(self.result_txs[i].poll_cancel()) {
+                Async::Ready(()) => {
+                    let _ = self.result_txs.swap_remove_back(i);
+                },
+                Async::NotReady => {
+                    i += 1;
+                },
+            }
+        }
+        self.subscribers.retain(|subscriber| {
+            match unwrap!(subscriber.drop_tx.poll_cancel()) {
+                Async::Ready(()) => false,
+                Async::NotReady => true,
+            }
+        });
+        !(self.result_txs.is_empty() && self.subscribers.is_empty())
+    }
+
+    // Gives a datagram to the most specific subscriber that wants it, or else to the next
+    // receiver in line. A subscription can be dropped after we last pruned, in which case its
+    // datagrams go to whoever's next. If no-one wants it, it's dropped.
+    pub fn dispatch(&mut self, data: BytesMut, addr: SocketAddr) {
+        let mut data = data;
+        for subscriber in &self.subscribers {
+            if subscriber.filter.matches(&data[..], &addr) {
+                match subscriber.data_tx.unbounded_send((data, addr)) {
+                    Ok(()) => return,
+                    Err(e) => data = e.into_inner().0,
+                }
+            }
+        }
+
+        while let Some(result_tx) = self.result_txs.pop_front() {
+            match result_tx.send(Ok((data, addr))) {
+                Ok(()) => return,
+                Err(res) => data = unwrap!(res).0,
+            }
+        }
+    }
+
+    fn recv_one<S: DgramSocket>(
+        &mut self,
+        socket: &mut S,
+    ) -> io::Result<Async<Vec<(BytesMut, SocketAddr)>>> {
+        let (n, addr) = try_ready!(socket.poll_recv_from(self.pool.recv_buf()));
+        Ok(Async::Ready(vec![(self.pool.take(n), addr)]))
+    }
+
+    fn recv_batch<S: DgramSocket>(
+        &mut self,
+        socket: &mut S,
+    ) -> io::Result<Async<Vec<(BytesMut, SocketAddr)>>> {
+        // Datagrams land straight in the pool and are handed out where they landed.
+        let batch_size = socket.batch_size();
+        let received = {
+            let slots = self.pool.recv_slots(batch_size);
+            let mut bufs: Vec<&mut [u8]> = slots.chunks_mut(MAX_UDP_SIZE).collect();
+            try_ready!(socket.poll_recv_batch(&mut bufs[..]))
+        };
+        let pool = &mut self.pool;
+        let dgrams = received.into_iter().map(|(n, addr)| (pool.take_slot(n), addr)).collect();
+        Ok(Async::Ready(dgrams))
+    }
+
+    // Hands out received packets to the queued receivers and subscribers. Errors which only
+    // concern one remote peer are skipped over, anything else is returned and means the socket is
+    // unusable.
+    pub fn poll_recv<S: DgramSocket>(&mut self, socket: &mut S) -> io::Result<Async<()>> {
         loop {
-            let result_tx = match self.result_txs.pop_front() {
-                Some(result_tx) => result_tx,
-                None => return Async::Ready(()),
-            };
-            self.buffer.reserve(MAX_UDP_SIZE);
-            let res = unsafe {
-                self.buffer.set_len(MAX_UDP_SIZE);
-                let res = socket.poll_recv_from(&mut self.buffer[..]);
-                let n = match res {
-                    Ok(Async::Ready((n, _addr))) => n,
-                    _ => 0,
-                };
-                self.buffer.set_len(n);
-                res
+            if !self.prune() {
+                return Ok(Async::Ready(()));
+            }
+
+            let res = if socket.batch_size() > 1 {
+                self.recv_batch(socket)
+            } else {
+                self.recv_one(socket)
             };
             match res {
-                Ok(Async::Ready((_n, addr))) => {
-                    let ret = self.buffer.take();
-                    let _ = result_tx.send(Ok((ret, addr)));
-                },
-                Ok(Async::NotReady) => {
-                    self.result_txs.push_front(result_tx);
-                    let mut i = 0;
-                    while i < self.result_txs.len() {
-                        match unwrap!(self.result_txs[i].poll_cancel()) {
-                            Async::Ready(()) => {
-                                let _ = self.result_txs.swap_remove_back(i);
-                            },
-                            Async::NotReady => {
-                                i += 1;
-                            },
-                        }
-                    }
-                    if !self.result_txs.is_empty() {
-                        return Async::NotReady;
+                Ok(Async::Ready(dgrams)) => {
+                    for (data, addr) in dgrams {
+                        self.dispatch(data, addr);
                     }
                 },
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
                 Err(e) => {
-                    let _ = result_tx.send(Err(e));
+                    if e.kind() == io::ErrorKind::Interrupted || error_scope(&e) != ErrorScope::Socket {
+                        continue;
+                    }
+                    return Err(e);
                 },
             }
-        } 
+        }
     }
 }
-
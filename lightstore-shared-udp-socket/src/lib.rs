@@ -1,69 +1,140 @@
 #![feature(never_type)]
 
 use unwrap::unwrap;
-use lightstore_units::{Btc, Sec, BtcPerSec};
+use lightstore_units::{Btc, Sec, BtcPerSec, BtcPerByte, Byte, BytePerSec};
 use std::io;
 use futures::sync::oneshot;
-use futures::{Future, Stream, Async};
+use futures::{Future, Stream, Async, try_ready};
 use future_utils::mpsc;
 use future_utils::mpsc::{UnboundedSender, UnboundedReceiver};
 use tokio::net::UdpSocket;
 use std::collections::VecDeque;
 use std::sync::Arc;
-use std::mem;
-use std::time::Instant;
-use bytes::{Bytes, BytesMut};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+use bytes::{Bytes, BytesMut, Buf};
+use std::io::Cursor;
 use std::net::SocketAddr;
-use canndrews_misc_ext_traits::{FutureExt, VecDequeExt};
+use canndrews_misc_ext_traits::FutureExt;
 use void::ResultVoidExt;
 
 pub use self::outgoing_packet::OutgoingPacket;
-use self::in_buffer::InBuffer;
+pub use self::dgram_socket::{DgramSocket, ErrorScope, error_scope};
+pub use self::filter::Filter;
+pub use self::congestion::UploadBudget;
+use self::congestion::CongestionControl;
+use self::in_buffer::{InBuffer, Subscriber};
+use self::out_queue::OutQueue;
+use self::buffer_pool::{BufferPool, MAX_UDP_SIZE};
 
 mod outgoing_packet;
+mod dgram_socket;
+mod filter;
 mod in_buffer;
+mod out_queue;
+mod buffer_pool;
+mod congestion;
+#[cfg(all(feature = "mmsg", target_os = "linux"))]
+mod mmsg;
 
 #[cfg(test)]
 mod test;
 
+// When every queued packet is held back by congestion control, check again after this long.
+const CONGESTION_RECHECK: Duration = Duration::from_millis(10);
+
 #[derive(Clone)]
 pub struct SharedUdpSocket {
     packet_tx: UnboundedSender<Operation>,
 }
 
-struct SharedUdpSocketDriver {
+#[derive(Clone)]
+pub struct SocketConfig {
+    // Packets whose utility falls below what this says they'd cost to send get dropped. It's
+    // asked again each time we send, so it can follow costs that change.
+    pub upload_cost: Arc<dyn Fn() -> BtcPerByte + Send + Sync>,
+    // Caps our total upload rate. Pass the same budget to several sockets to share it between
+    // them.
+    pub upload_budget: Option<UploadBudget>,
+    // Told the size of each packet once it's actually gone out. Packets that are dropped or fail
+    // to send aren't counted.
+    pub on_sent: Arc<dyn Fn(Byte) + Send + Sync>,
+}
+
+impl Default for SocketConfig {
+    fn default() -> SocketConfig {
+        SocketConfig {
+            upload_cost: Arc::new(|| BtcPerByte(0.0)),
+            upload_budget: None,
+            on_sent: Arc::new(|_| ()),
+        }
+    }
+}
+
+struct SharedUdpSocketDriver<S> {
     packet_rx: UnboundedReceiver<Operation>,
-    out_queue: VecDeque<Pending>,
-    socket: UdpSocket,
+    out_queue: OutQueue,
+    socket: S,
     in_buffer: InBuffer,
+    upload_cost: Arc<dyn Fn() -> BtcPerByte + Send + Sync>,
+    on_sent: Arc<dyn Fn(Byte) + Send + Sync>,
+    congestion: CongestionControl,
+    recheck: Option<Delay>,
 }
 
 pub struct SendDgram {
-    error_rx: oneshot::Receiver<Arc<io::Error>>,
+    result_rx: oneshot::Receiver<Result<SendOutcome, Arc<io::Error>>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SendOutcome {
+    Sent,
+    // The packet's utility decayed below the cost of sending it before it reached the front of
+    // the queue.
+    DroppedUnprofitable,
 }
 
 pub struct RecvDgram {
     result_rx: oneshot::Receiver<io::Result<(BytesMut, SocketAddr)>>,
 }
 
+// A stream of the incoming datagrams which match a `Filter`. The stream ends if the socket fails.
+// The datagrams share their memory with others received around the same time, so copy any
+// you mean to keep for a while.
+pub struct Subscription {
+    data_rx: UnboundedReceiver<(BytesMut, SocketAddr)>,
+    _drop_rx: oneshot::Receiver<()>,
+}
+
 struct Pending {
-    error_tx: oneshot::Sender<Arc<io::Error>>,
+    result_tx: oneshot::Sender<Result<SendOutcome, Arc<io::Error>>>,
     packet: OutgoingPacket,
 }
 
 enum Operation {
     SendDgram(Pending),
     RecvDgram(oneshot::Sender<io::Result<(BytesMut, SocketAddr)>>),
+    Subscribe(Subscriber),
+    Ack(SocketAddr, Byte),
+    Loss(SocketAddr),
 }
 
 impl SharedUdpSocket {
-    pub fn share(socket: UdpSocket) -> SharedUdpSocket {
+    pub fn share<S: DgramSocket>(socket: S) -> SharedUdpSocket {
+        SharedUdpSocket::share_with_config(socket, SocketConfig::default())
+    }
+
+    pub fn share_with_config<S: DgramSocket>(socket: S, config: SocketConfig) -> SharedUdpSocket {
         let (out_packet_tx, out_packet_rx) = mpsc::unbounded();
         let driver = SharedUdpSocketDriver {
             socket,
-            out_queue: VecDeque::new(),
+            out_queue: OutQueue::new(),
             packet_rx: out_packet_rx,
             in_buffer: InBuffer::new(),
+            upload_cost: config.upload_cost,
+            on_sent: config.on_sent,
+            congestion: CongestionControl::new(config.upload_budget),
+            recheck: None,
         };
         tokio::spawn(driver.infer_err());
         SharedUdpSocket {
@@ -75,27 +146,58 @@ impl SharedUdpSocket {
         &mut self,
         packet: OutgoingPacket,
     ) -> SendDgram {
+        // If the driver has shut down because the socket broke then dropping the operation here
+        // resolves the returned future with an error.
         let (send_dgram, pending) = SendDgram::new(packet);
-        unwrap!(self.packet_tx.unbounded_send(Operation::SendDgram(pending)));
+        let _ = self.packet_tx.unbounded_send(Operation::SendDgram(pending));
         send_dgram
     }
 
     pub fn recv_dgram(&mut self) -> RecvDgram {
         let (recv_dgram, result_tx) = RecvDgram::new();
-        unwrap!(self.packet_tx.unbounded_send(Operation::RecvDgram(result_tx)));
+        let _ = self.packet_tx.unbounded_send(Operation::RecvDgram(result_tx));
         recv_dgram
     }
+
+    // Tells congestion control that `bytes` we sent to `dest` got acknowledged, so it can speed up.
+    pub fn report_ack(&mut self, dest: SocketAddr, bytes: Byte) {
+        let _ = self.packet_tx.unbounded_send(Operation::Ack(dest, bytes));
+    }
+
+    // Tells congestion control that something we sent to `dest` got lost, so it should slow down.
+    pub fn report_loss(&mut self, dest: SocketAddr) {
+        let _ = self.packet_tx.unbounded_send(Operation::Loss(dest));
+    }
+
+    // Subscribes to the datagrams matching `filter`. These go to the subscription with the most
+    // specific matching filter rather than to `recv_dgram`, which only gets what's left over.
+    pub fn subscribe(&mut self, filter: Filter) -> Subscription {
+        let (data_tx, data_rx) = mpsc::unbounded();
+        let (drop_tx, drop_rx) = oneshot::channel();
+        let subscriber = Subscriber { filter, data_tx, drop_tx };
+        let _ = self.packet_tx.unbounded_send(Operation::Subscribe(subscriber));
+        Subscription {
+            data_rx,
+            _drop_rx: drop_rx,
+        }
+    }
 }
 
 impl SendDgram {
     fn new(packet: OutgoingPacket) -> (SendDgram, Pending) {
-        let (error_tx, error_rx) = oneshot::channel();
-        let send_dgram = SendDgram { error_rx };
-        let pending = Pending { error_tx, packet };
+        let (result_tx, result_rx) = oneshot::channel();
+        let send_dgram = SendDgram { result_rx };
+        let pending = Pending { result_tx, packet };
         (send_dgram, pending)
     }
 }
 
+impl Pending {
+    fn resolve(self, result: Result<SendOutcome, Arc<io::Error>>) {
+        let _ = self.result_tx.send(result);
+    }
+}
+
 impl RecvDgram {
     fn new() -> (RecvDgram, oneshot::Sender<io::Result<(BytesMut, SocketAddr)>>) {
         let (result_tx, result_rx) = oneshot::channel();
@@ -104,21 +206,48 @@ impl RecvDgram {
     }
 }
 
-impl Future for SharedUdpSocketDriver {
+impl<S: DgramSocket> SharedUdpSocketDriver<S> {
+    // Puts back a packet that we took out of the queue but didn't send.
+    fn requeue(&mut self, pending: Pending, now: Instant) {
+        self.congestion.refund(pending.packet.dest, pending.packet.size());
+        self.out_queue.push(pending, now);
+    }
+
+    // Something's wrong with the socket itself. Fail everything that's queued and stop.
+    fn shut_down(&mut self, error: io::Error) -> Result<Async<()>, !> {
+        let error = Arc::new(error);
+        for pending in self.out_queue.drain() {
+            pending.resolve(Err(error.clone()));
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<S: DgramSocket> Future for SharedUdpSocketDriver<S> {
     type Item = ();
     type Error = !;
 
     fn poll(&mut self) -> Result<Async<()>, !> {
+        let now = Instant::now();
         let shutting_down = loop {
             match self.packet_rx.poll().void_unwrap() {
                 Async::Ready(Some(operation)) => {
                     match operation {
                         Operation::SendDgram(pending) => {
-                            self.out_queue.push_back(pending);
+                            self.out_queue.push(pending, now);
                         },
                         Operation::RecvDgram(result_tx) => {
                             self.in_buffer.queue_receiver(result_tx);
                         },
+                        Operation::Subscribe(subscriber) => {
+                            self.in_buffer.add_subscriber(subscriber);
+                        },
+                        Operation::Ack(dest, bytes) => {
+                            self.congestion.on_ack(dest, bytes, now);
+                        },
+                        Operation::Loss(dest) => {
+                            self.congestion.on_loss(dest, now);
+                        },
                     }
                 },
                 Async::Ready(None) => break true,
@@ -126,42 +255,89 @@ impl Future for SharedUdpSocketDriver {
             }
         };
 
+        let upload_cost = (self.upload_cost)();
+        let batch_size = self.socket.batch_size();
+        self.congestion.expire(now);
         let out_queue_empty = loop {
-            let error_opt = match self.out_queue.front() {
-                Some(pending) => {
-                    let packet = &pending.packet;
-                    match self.socket.poll_send_to(packet.as_bytes(), packet.dest()) {
-                        Ok(Async::Ready(_n)) => None,
-                        Ok(Async::NotReady) => break false,
-                        Err(e) => Some(e),
+            let mut batch = Vec::with_capacity(batch_size);
+            while batch.len() < batch_size {
+                let congestion = &mut self.congestion;
+                let can_send = |packet: &OutgoingPacket| {
+                    congestion.can_send(packet.dest, packet.size(), now)
+                };
+                if self.out_queue.front(now, upload_cost, can_send).is_none() {
+                    break;
+                }
+                let pending = unwrap!(self.out_queue.pop());
+                self.congestion.consume(pending.packet.dest, pending.packet.size(), now);
+                batch.push(pending);
+            }
+            if batch.is_empty() {
+                if self.out_queue.is_empty() {
+                    break true;
+                }
+                // Everything left is waiting on congestion control.
+                let mut recheck = Delay::new(now + CONGESTION_RECHECK);
+                let _ = recheck.poll();
+                self.recheck = Some(recheck);
+                break false;
+            }
+
+            let res = {
+                let packets: Vec<(&[u8], &SocketAddr)> = {
+                    batch
+                    .iter()
+                    .map(|pending| (pending.packet.as_bytes(), pending.packet.dest()))
+                    .collect()
+                };
+                self.socket.poll_send_batch(&packets)
+            };
+            let mut batch = batch.into_iter();
+            let error_opt = match res {
+                Ok(Async::Ready(n)) => {
+                    for pending in batch.by_ref().take(n) {
+                        (self.on_sent)(pending.packet.size());
+                        pending.resolve(Ok(SendOutcome::Sent));
                     }
+                    None
                 },
-                None => break true,
-            };
-            match error_opt {
-                Some(e) => {
-                    let error = Arc::new(e);
-                    let pendings = mem::replace(&mut self.out_queue, VecDeque::new());
-                    for pending in pendings {
-                        let _ = pending.error_tx.send(error.clone());
+                Ok(Async::NotReady) => {
+                    for pending in batch {
+                        self.requeue(pending, now);
                     }
+                    break false;
                 },
-                None => {
-                    let _ = self.out_queue.pop_front();
+                Err(e) => Some(e),
+            };
+
+            // Whatever didn't get sent goes back in the queue, except the packet that caused an
+            // error.
+            match error_opt {
+                Some(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Some(e) => match error_scope(&e) {
+                    ErrorScope::Packet | ErrorScope::Destination => {
+                        unwrap!(batch.next()).resolve(Err(Arc::new(e)));
+                    },
+                    ErrorScope::Socket => {
+                        for pending in batch {
+                            self.out_queue.push(pending, now);
+                        }
+                        return self.shut_down(e);
+                    },
                 },
+                None => (),
+            }
+            for pending in batch {
+                self.requeue(pending, now);
             }
         };
 
         let in_queue_empty = match self.in_buffer.poll_recv(&mut self.socket) {
-            Async::Ready(()) => true,
-            Async::NotReady => false,
+            Ok(Async::Ready(())) => true,
+            Ok(Async::NotReady) => false,
+            Err(e) => return self.shut_down(e),
         };
 
-        let now = Instant::now();
-        self.out_queue.insertion_sort_by(|pending0, pending1| {
-            unwrap!(pending0.packet.utility_decay_at(now).partial_cmp(&pending1.packet.utility_decay_at(now)))
-        });
-
         if shutting_down && out_queue_empty && in_queue_empty {
             Ok(Async::Ready(()))
         } else {
@@ -171,14 +347,17 @@ impl Future for SharedUdpSocketDriver {
 }
 
 impl Future for SendDgram {
-    type Item = ();
+    type Item = SendOutcome;
     type Error = Arc<io::Error>;
 
-    fn poll(&mut self) -> Result<Async<()>, Arc<io::Error>> {
-        match self.error_rx.poll() {
-            Ok(Async::Ready(error)) => Err(error),
+    fn poll(&mut self) -> Result<Async<SendOutcome>, Arc<io::Error>> {
+        match self.result_rx.poll() {
+            Ok(Async::Ready(Ok(outcome))) => Ok(Async::Ready(outcome)),
+            Ok(Async::Ready(Err(e))) => Err(e),
             Ok(Async::NotReady) => Ok(Async::NotReady),
-            Err(oneshot::Canceled) => Ok(Async::Ready(())),
+            Err(oneshot::Canceled) => {
+                Err(Arc::new(io::Error::new(io::ErrorKind::BrokenPipe, "socket driver shut down")))
+            },
         }
     }
 }
@@ -197,3 +376,11 @@ impl Future for RecvDgram {
     }
 }
 
+impl Stream for Subscription {
+    type Item = (BytesMut, SocketAddr);
+    type Error = !;
+
+    fn poll(&mut self) -> Result<Async<Option<(BytesMut, SocketAddr)>>, !> {
+        Ok(self.data_rx.poll().void_unwrap())
+    }
+}
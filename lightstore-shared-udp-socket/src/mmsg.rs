@@ -0,0 +1,148 @@
+// Thin wrappers around Linux's `recvmmsg` and `sendmmsg`, which move several datagrams per
+// syscall. Both are non-blocking and report `WouldBlock` like the ordinary socket calls.
+
+use super::*;
+use std::{mem, ptr};
+use std::os::unix::io::RawFd;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+// How many datagrams we try to move per syscall.
+pub const BATCH_SIZE: usize = 32;
+
+pub fn recvmmsg(fd: RawFd, bufs: &mut [&mut [u8]]) -> io::Result<Vec<(usize, SocketAddr)>> {
+    let mut iovecs: Vec<libc::iovec> = {
+        bufs
+        .iter_mut()
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect()
+    };
+    let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; bufs.len()];
+    let mut msgs: Vec<libc::mmsghdr> = {
+        iovecs
+        .iter_mut()
+        .zip(addrs.iter_mut())
+        .map(|(iovec, addr)| {
+            let mut msg_hdr: libc::msghdr = unsafe { mem::zeroed() };
+            msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+            msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg_hdr.msg_iov = iovec;
+            msg_hdr.msg_iovlen = 1;
+            libc::mmsghdr { msg_hdr, msg_len: 0 }
+        })
+        .collect()
+    };
+
+    let ret = unsafe {
+        libc::recvmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            msgs.len() as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            ptr::null_mut(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut received = Vec::with_capacity(ret as usize);
+    for (msg, addr) in msgs.iter().zip(addrs.iter()).take(ret as usize) {
+        received.push((msg.msg_len as usize, from_sockaddr(addr)?));
+    }
+    Ok(received)
+}
+
+// Returns how many of the packets got sent. If that's fewer than all of them then the next one
+// will fail if we try again, and the error will tell us why.
+pub fn sendmmsg(fd: RawFd, packets: &[(&[u8], &SocketAddr)]) -> io::Result<usize> {
+    let mut iovecs: Vec<libc::iovec> = {
+        packets
+        .iter()
+        .map(|(data, _)| libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        })
+        .collect()
+    };
+    let mut addrs: Vec<(libc::sockaddr_storage, libc::socklen_t)> = {
+        packets
+        .iter()
+        .map(|(_, dest)| to_sockaddr(dest))
+        .collect()
+    };
+    let mut msgs: Vec<libc::mmsghdr> = {
+        iovecs
+        .iter_mut()
+        .zip(addrs.iter_mut())
+        .map(|(iovec, (addr, addr_len))| {
+            let mut msg_hdr: libc::msghdr = unsafe { mem::zeroed() };
+            msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+            msg_hdr.msg_namelen = *addr_len;
+            msg_hdr.msg_iov = iovec;
+            msg_hdr.msg_iovlen = 1;
+            libc::mmsghdr { msg_hdr, msg_len: 0 }
+        })
+        .collect()
+    };
+
+    let ret = unsafe {
+        libc::sendmmsg(fd, msgs.as_mut_ptr(), msgs.len() as libc::c_uint, libc::MSG_DONTWAIT)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+fn from_sockaddr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe {
+                &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in)
+            };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            let port = u16::from_be(addr.sin_port);
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+        },
+        libc::AF_INET6 => {
+            let addr = unsafe {
+                &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6)
+            };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            let port = u16::from_be(addr.sin6_port);
+            let addr = SocketAddrV6::new(ip, port, addr.sin6_flowinfo, addr.sin6_scope_id);
+            Ok(SocketAddr::V6(addr))
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unknown address family")),
+    }
+}
+
+fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe {
+                &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in)
+            };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        },
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe {
+                &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6)
+            };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        },
+    };
+    (storage, len as libc::socklen_t)
+}
@@ -0,0 +1,123 @@
+use super::*;
+use std::collections::BinaryHeap;
+use std::cmp::Ordering;
+
+// Packets waiting to be sent, ordered by their current (decayed) utility scaled by their
+// priority.
+//
+// Packets decay at different rates so their relative order changes over time, which means we
+// can't just key the heap on utility once and forget about it. Instead we rely on the fact that
+// utility only ever decreases: a key calculated at some earlier time is an upper bound on the
+// packet's utility now. When we look at the top of the heap we recalculate its utility, and if
+// it's fallen below the key of the next packet we push it back down and try again.
+pub(crate) struct OutQueue {
+    heap: BinaryHeap<Queued>,
+    // The packet returned by the last call to `front`, with its key up to date.
+    front: Option<Queued>,
+}
+
+struct Queued {
+    // An upper bound on the packet's utility, scaled by its priority.
+    key: Btc,
+    pending: Pending,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Queued) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Queued) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Keys are never NaN, `OutQueue` drops packets whose keys would be.
+impl Ord for Queued {
+    fn cmp(&self, other: &Queued) -> Ordering {
+        unwrap!(self.key.partial_cmp(&other.key))
+    }
+}
+
+impl OutQueue {
+    pub(crate) fn new() -> OutQueue {
+        OutQueue {
+            heap: BinaryHeap::new(),
+            front: None,
+        }
+    }
+
+    pub(crate) fn push(&mut self, pending: Pending, now: Instant) {
+        let key = pending.packet.utility_at(now) * pending.packet.priority;
+        // A NaN utility isn't worth anything, and there'd be no ordering it against the others.
+        if key.val().is_nan() {
+            pending.resolve(Ok(SendOutcome::DroppedUnprofitable));
+            return;
+        }
+        self.heap.push(Queued { key, pending });
+    }
+
+    // Returns the packet with the highest utility at time `now`, as scaled by its priority, out
+    // of those that `can_send` allows, dropping any packets along the way that aren't worth what
+    // it would cost us to send them.
+    pub(crate) fn front<F>(
+        &mut self,
+        now: Instant,
+        upload_cost: BtcPerByte,
+        mut can_send: F,
+    ) -> Option<&Pending>
+    where
+        F: FnMut(&OutgoingPacket) -> bool,
+    {
+        if let Some(queued) = self.front.take() {
+            self.heap.push(queued);
+        }
+        let mut held_back = Vec::new();
+        while let Some(queued) = self.heap.pop() {
+            let utility = queued.pending.packet.utility_at(now);
+            let key = utility * queued.pending.packet.priority;
+            if key.val().is_nan() || utility < upload_cost * queued.pending.packet.size() {
+                queued.pending.resolve(Ok(SendOutcome::DroppedUnprofitable));
+                continue;
+            }
+
+            let is_best = match self.heap.peek() {
+                Some(next) => key >= next.key,
+                None => true,
+            };
+            let queued = Queued { key, pending: queued.pending };
+            if !is_best {
+                self.heap.push(queued);
+            } else if can_send(&queued.pending.packet) {
+                self.front = Some(queued);
+                break;
+            } else {
+                held_back.push(queued);
+            }
+        }
+        self.heap.extend(held_back);
+        self.front.as_ref().map(|queued| &queued.pending)
+    }
+
+    // Removes the packet returned by the last call to `front`.
+    pub(crate) fn pop(&mut self) -> Option<Pending> {
+        self.front.take().map(|queued| queued.pending)
+    }
+
+    pub(crate) fn drain(&mut self) -> Vec<Pending> {
+        self.front
+        .take()
+        .into_iter()
+        .chain(self.heap.drain())
+        .map(|queued| queued.pending)
+        .collect()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.front.is_none() && self.heap.is_empty()
+    }
+}
@@ -6,6 +6,9 @@ pub struct OutgoingPacket {
     pub utility: Btc,
     pub utility_decay: Sec,
     pub utility_time: Instant,
+    // How far to favour this packet over others when deciding what to send first. It doesn't
+    // change what the packet's worth, so it has no say in whether it's worth sending at all.
+    pub priority: f64,
 }
 
 impl OutgoingPacket {
@@ -21,9 +24,15 @@ impl OutgoingPacket {
             utility,
             utility_decay,
             utility_time: Instant::now(),
+            priority: 1.0,
         }
     }
 
+    pub fn utility_at(&self, at: Instant) -> Btc {
+        let time = Sec::from(at.duration_since(self.utility_time));
+        self.utility * (- time / self.utility_decay).exp()
+    }
+
     pub fn utility_decay_at(&self, at: Instant) -> BtcPerSec {
         let time = Sec::from(at.duration_since(self.utility_time));
         self.utility * (-1.0 / self.utility_decay) * (- time / self.utility_decay).exp()
@@ -33,6 +42,10 @@ impl OutgoingPacket {
         &self.data[..]
     }
 
+    pub fn size(&self) -> Byte {
+        Byte::from(self.data.len())
+    }
+
     pub fn dest(&self) -> &SocketAddr {
         &self.dest
     }
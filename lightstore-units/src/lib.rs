@@ -299,6 +299,9 @@ mul!(BtcPerByte * Byte -> Btc);
 dim!(BtcPerSec);
 mul!(Btc * PerSec -> BtcPerSec);
 
+dim!(BytePerSec);
+mul!(BytePerSec * Sec -> Byte);
+
 dim!(ByteSec);
 dim!(SecPerByte);
 mul!(Byte * Sec -> ByteSec);
@@ -306,6 +309,7 @@ mul!(SecPerByte * Byte -> Sec);
 
 dim!(BtcPerByteSec);
 mul!(BtcPerByteSec * ByteSec -> Btc);
+mul!(BtcPerByteSec * Sec -> BtcPerByte);
 
 impl From<Duration> for Sec {
     fn from(duration: Duration) -> Sec {
@@ -313,6 +317,17 @@ impl From<Duration> for Sec {
     }
 }
 
+// Negative times are clamped to zero since a `Duration` can't represent them.
+impl From<Sec> for Duration {
+    fn from(sec: Sec) -> Duration {
+        if sec.val() <= 0.0 {
+            return Duration::from_secs(0);
+        }
+        let secs = sec.val().trunc();
+        Duration::new(secs as u64, ((sec.val() - secs) * 1e9) as u32)
+    }
+}
+
 impl From<usize> for Byte {
     fn from(size: usize) -> Byte {
         Byte(size as f64)
@@ -3,20 +3,21 @@
 use std::str;
 use future_utils::{FutureExt, StreamExt, BoxSendFuture, BoxSendStream};
 use git_remote_helper::{Ref, Object, PushObject};
-use futures::Future;
+use futures::{future, Future};
 #[allow(unused)]
 use unwrap::*;
 use std::io;
-use lightstore::crypto::PublicSignKey;
-use lightstore_units::*;
+use lightstore::crypto::{PublicSignKey, SignKeypair};
+use lightstore::daemon::{GetMutableError, DEFAULT_AVAILABILITY};
+use lightstore::git::RepositoryExt;
 use futures::stream;
 use std::path::Path;
-use canndrews_misc_ext_traits::FutureExt as _;
+use bytes::Bytes;
 
 struct App {
     _remote: String,
     key: PublicSignKey,
-    _repo: git2::Repository,
+    repo: git2::Repository,
     daemon: lightstore::Daemon,
 }
 
@@ -36,7 +37,7 @@ impl git_remote_helper::RemoteHelper for App {
             App {
                 _remote: remote,
                 key: key,
-                _repo: repo,
+                repo,
                 daemon,
             }
         })
@@ -49,12 +50,14 @@ impl git_remote_helper::List for App {
 
     fn list(&mut self) -> BoxSendStream<Ref, io::Error> {
         self.daemon
-        .get_mutable(self.key, Btc(0.0), Sec(1.0), 1.0)
-        .infer_err()
-        .map(|bytes| {
-            let refs = parse_refs(&bytes);
-            stream::iter_ok(refs)
+        .get_mutable(self.key, None)
+        .then(|res| match res {
+            Ok(record) => Ok(parse_refs(&record.data)),
+            // Nothing's been pushed yet.
+            Err(GetMutableError::NotFound) => Ok(Vec::new()),
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
         })
+        .map(stream::iter_ok)
         .flatten_stream()
         .into_send_boxed()
     }
@@ -66,11 +69,68 @@ impl git_remote_helper::ListForPush for App {
     }
 }
 
+impl App {
+    // The key this remote's refs are signed with, which `lightstore create` left in the repo.
+    fn keypair(&self) -> io::Result<SignKeypair> {
+        let keys = self.repo.get_all_lightstore_keys().map_err(other_error)?;
+        match keys.into_iter().find(|keypair| keypair.public == self.key) {
+            Some(keypair) => Ok(keypair),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no key for this remote")),
+        }
+    }
+}
+
 impl git_remote_helper::Push for App {
     type Fut = BoxSendFuture<(), io::Error>;
 
-    fn push(&self, _objects: &[PushObject]) -> BoxSendFuture<(), io::Error> {
-        panic!("push unimplemented");
+    // Packs up the commits the remote doesn't have yet and uploads the pack. Once it's up, we
+    // publish a new version of the remote's refs with where to find it.
+    fn push(&self, objects: &[PushObject]) -> BoxSendFuture<(), io::Error> {
+        let keypair = match self.keypair() {
+            Ok(keypair) => keypair,
+            Err(e) => return future::err(e).into_send_boxed(),
+        };
+        let updates: Vec<(String, String)> = {
+            objects
+            .iter()
+            .map(|object| (object.src.clone(), object.dst.clone()))
+            .collect()
+        };
+        let repo_path = self.repo.path().to_owned();
+        let daemon = self.daemon.clone();
+        let put_daemon = self.daemon.clone();
+
+        self.daemon
+        .get_mutable(self.key, None)
+        .then(|res| match res {
+            Ok(record) => Ok((record.version + 1, record.data)),
+            // Nothing's been pushed yet.
+            Err(GetMutableError::NotFound) => Ok((0, Bytes::new())),
+            Err(e) => Err(other_error(e)),
+        })
+        .and_then(move |(version, data)| {
+            let mut refs = parse_refs(&data);
+            let packs = parse_packs(&data);
+            let repo = git2::Repository::open(&repo_path).map_err(other_error)?;
+            let pack = pack_updates(&repo, &mut refs, &updates).map_err(other_error)?;
+            Ok((version, refs, packs, pack))
+        })
+        .and_then(move |(version, refs, mut packs, pack)| {
+            daemon
+            .upload(Bytes::from(pack), DEFAULT_AVAILABILITY, None)
+            .map_err(other_error)
+            .map(move |uploaded| {
+                packs.push(uploaded.manifest.to_string());
+                (version, format_record(&refs, &packs))
+            })
+        })
+        .and_then(move |(version, data)| {
+            put_daemon
+            .put_mutable(&keypair, version, data)
+            .map_err(other_error)
+            .map(|_| ())
+        })
+        .into_send_boxed()
     }
 }
 
@@ -78,10 +138,83 @@ fn main() {
     git_remote_helper::run::<App>();
 }
 
+fn other_error<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+// Points the refs named by `updates` at what they're being pushed from, and packs up the commits
+// that takes which the remote doesn't have yet. An empty source deletes the ref.
+fn pack_updates(
+    repo: &git2::Repository,
+    refs: &mut Vec<Ref>,
+    updates: &[(String, String)],
+) -> Result<Vec<u8>, git2::Error> {
+    let mut walk = repo.revwalk()?;
+    for remote_ref in refs.iter() {
+        if let Object::Hash(hash) = remote_ref.object {
+            // We might not have everything the remote has.
+            if let Ok(oid) = git2::Oid::from_bytes(&hash) {
+                let _ = walk.hide(oid);
+            }
+        }
+    }
+    for (src, dst) in updates {
+        refs.retain(|remote_ref| remote_ref.name != *dst);
+        if src.is_empty() {
+            continue;
+        }
+        let oid = repo.revparse_single(src)?.peel(git2::ObjectType::Commit)?.id();
+        walk.push(oid)?;
+        let mut hash = [0u8; 20];
+        hash.copy_from_slice(oid.as_bytes());
+        refs.push(Ref { object: Object::Hash(hash), name: dst.clone(), unchanged: false });
+    }
+
+    let mut builder = repo.packbuilder()?;
+    for oid in walk {
+        builder.insert_commit(oid?)?;
+    }
+    let mut buf = git2::Buf::new();
+    builder.write_buf(&mut buf)?;
+    Ok(buf.to_vec())
+}
+
+// A remote's mutable data is its refs, one a line, followed by a line for each pack pushed to it
+// giving the manifest it was uploaded with.
+fn format_record(refs: &[Ref], packs: &[String]) -> Bytes {
+    let mut text = String::new();
+    for remote_ref in refs {
+        match remote_ref.object {
+            Object::Hash(ref hash) => text.push_str(&base16::encode_lower(&hash[..])),
+            Object::Link(ref name) => {
+                text.push('@');
+                text.push_str(name);
+            },
+        }
+        text.push(' ');
+        text.push_str(&remote_ref.name);
+        text.push('\n');
+    }
+    for pack in packs {
+        text.push_str("pack ");
+        text.push_str(pack);
+        text.push('\n');
+    }
+    Bytes::from(text)
+}
+
+fn parse_packs(bytes: &[u8]) -> Vec<String> {
+    let text = unwrap!(str::from_utf8(bytes));
+    text.lines().filter(|line| line.starts_with("pack ")).map(|line| line[5..].to_owned()).collect()
+}
+
 pub fn parse_refs(bytes: &[u8]) -> Vec<Ref> {
     let text = unwrap!(str::from_utf8(bytes));
     let mut ret = Vec::new();
     for line in text.lines() {
+        if line.starts_with("pack ") {
+            continue;
+        }
         let mut split = line.split_whitespace();
         let object = unwrap!(split.next());
         let object = if object.starts_with('@') {
@@ -4,7 +4,9 @@ use unwrap::*;
 use clap::{Arg, App, SubCommand, AppSettings};
 use git2::Repository;
 use lightstore::git::RepositoryExt;
-use lightstore::daemon::Daemon;
+use lightstore::daemon::{Daemon, Reputations};
+use lightstore::crypto::as_base32;
+use lightstore::Config;
 use futures::future;
 
 fn main() {
@@ -31,6 +33,10 @@ fn main() {
             SubCommand::with_name("daemon")
             .about("Start the lightstore daemon")
         })
+        .subcommand({
+            SubCommand::with_name("reputation")
+            .about("Show the reputations of the peers the daemon has dealt with")
+        })
         .get_matches()
     };
 
@@ -59,10 +65,45 @@ fn main() {
             }
         },
         "daemon" => {
-            let (_daemon, addr) = unwrap!(Daemon::start());
-            println!("Daemon running at {}", addr);
+            let (daemon, addrs) = unwrap!(Daemon::start());
+            println!("Daemon key {}", daemon.node_key());
+            for addr in addrs {
+                println!("Daemon running at {}", addr);
+            }
             tokio::run(future::empty());
         },
+        "reputation" => {
+            let config = unwrap!(Config::load());
+            let path = match config.reputation_path() {
+                Some(path) => path,
+                None => {
+                    println!("no data directory configured, set lightstore.dataDir");
+                    return;
+                },
+            };
+            if !path.exists() {
+                println!("no reputations recorded yet");
+                return;
+            }
+            let mut peers: Vec<_> = unwrap!(Reputations::load(&path)).into_iter().collect();
+            peers.sort_by(|(_, a), (_, b)| unwrap!(b.score().partial_cmp(&a.score())));
+            println!(
+                "{:52} {:>5} {:>10} {:>10} {:>7} {:>6} {:>8}",
+                "peer", "score", "debt limit", "paid", "on time", "uptime", "accuracy",
+            );
+            for (peer, reputation) in peers {
+                println!(
+                    "{:52} {:>5.3} {:>10.3e} {:>10.3e} {:>7.3} {:>6.3} {:>8.3}",
+                    as_base32(&peer.as_bytes()),
+                    reputation.score(),
+                    reputation.debt_limit().val(),
+                    reputation.paid.val(),
+                    reputation.on_time,
+                    reputation.uptime,
+                    reputation.accuracy,
+                );
+            }
+        },
         _ => unreachable!(),
     }
 }
@@ -0,0 +1,245 @@
+use super::*;
+
+// User settings, read from git config. eg.
+//
+//     git config --global lightstore.uploadBudget 1000000
+//     git config --global lightstore.lookupBudget 0.000001
+//     git config --global lightstore.uploadCap 200g
+//     git config --global lightstore.uploadPeriod 2592000
+//
+pub struct Config {
+    // The most we'll upload, in bytes per second, across all peers.
+    pub upload_budget: Option<BytePerSec>,
+    // The most we'll pay for one lookup, in bitcoin.
+    pub lookup_budget: Option<Btc>,
+    // How long we're happy to wait for a lookup, in seconds.
+    pub target_latency: Option<Sec>,
+    // What the user's internet plan costs a month, in bitcoin, and how many bytes it allows each
+    // way.
+    pub plan_price: Option<Btc>,
+    pub upload_cap: Option<Byte>,
+    pub download_cap: Option<Byte>,
+    // What we credit a peer for each hash of proof-of-work they pay us with, in bitcoin.
+    pub btc_per_hash: Option<Btc>,
+    // Where we keep what we know about peers between runs. Defaults to ~/.lightstore.
+    pub data_dir: Option<PathBuf>,
+    // The most we'll pay, in bitcoin per byte, to have an upload hosted, how long to have it
+    // hosted for, in seconds, and how many times the cheapest price that gets it the availability
+    // we're after we'll pay to make it more available still.
+    pub max_upload_price: Option<BtcPerByte>,
+    pub upload_period: Option<Sec>,
+    pub upload_price_factor: Option<f64>,
+}
+
+#[derive(Debug, Fail)]
+pub enum ConfigError {
+    #[fail(display = "error opening git config: {}", _0)]
+    Open(git2::Error),
+    #[fail(display = "invalid value for {}: {}", key, error)]
+    InvalidValue {
+        key: &'static str,
+        error: git2::Error,
+    },
+}
+
+impl Config {
+    pub fn load() -> Result<Config, ConfigError> {
+        let git_config = git2::Config::open_default().map_err(ConfigError::Open)?;
+        Config::from_git_config(&git_config)
+    }
+
+    pub fn from_git_config(git_config: &git2::Config) -> Result<Config, ConfigError> {
+        let upload_budget = {
+            get_i64(git_config, "lightstore.uploadBudget")?
+            .map(|budget| BytePerSec(budget as f64))
+        };
+        let lookup_budget = get_f64(git_config, "lightstore.lookupBudget")?.map(Btc);
+        let target_latency = get_f64(git_config, "lightstore.targetLatency")?.map(Sec);
+        let plan_price = get_f64(git_config, "lightstore.planPrice")?.map(Btc);
+        let upload_cap = {
+            get_i64(git_config, "lightstore.uploadCap")?
+            .map(|cap| Byte(cap as f64))
+        };
+        let download_cap = {
+            get_i64(git_config, "lightstore.downloadCap")?
+            .map(|cap| Byte(cap as f64))
+        };
+        let btc_per_hash = get_f64(git_config, "lightstore.btcPerHash")?.map(Btc);
+        let data_dir = get_path(git_config, "lightstore.dataDir")?;
+        let max_upload_price = get_f64(git_config, "lightstore.maxUploadPrice")?.map(BtcPerByte);
+        let upload_period = get_f64(git_config, "lightstore.uploadPeriod")?.map(Sec);
+        let upload_price_factor = get_f64(git_config, "lightstore.uploadPriceFactor")?;
+        Ok(Config {
+            upload_budget,
+            lookup_budget,
+            target_latency,
+            plan_price,
+            upload_cap,
+            download_cap,
+            btc_per_hash,
+            data_dir,
+            max_upload_price,
+            upload_period,
+            upload_price_factor,
+        })
+    }
+
+    // Where we keep things between runs, if we've anywhere to keep them.
+    fn data_dir(&self) -> Option<PathBuf> {
+        match self.data_dir {
+            Some(ref data_dir) => Some(data_dir.clone()),
+            None => Some(PathBuf::from(std::env::var_os("HOME")?).join(".lightstore")),
+        }
+    }
+
+    // Where peers' reputations are saved, if we've anywhere to save them.
+    pub fn reputation_path(&self) -> Option<PathBuf> {
+        Some(self.data_dir()?.join("reputation"))
+    }
+
+    // Where the key peers know us by is kept.
+    pub fn node_key_path(&self) -> Option<PathBuf> {
+        Some(self.data_dir()?.join("node_key"))
+    }
+
+    // Where we write down the proof-of-work tokens we've been paid with, so that they can't be
+    // spent on us twice.
+    pub fn spent_pow_path(&self) -> Option<PathBuf> {
+        Some(self.data_dir()?.join("spent_pow"))
+    }
+
+    // Where the contracts we've taken out and the ones we're hosting are saved.
+    pub fn contracts_path(&self) -> Option<PathBuf> {
+        Some(self.data_dir()?.join("contracts"))
+    }
+
+    // Where we keep the content we've been paid to host, a file for each root.
+    pub fn hosted_dir(&self) -> Option<PathBuf> {
+        Some(self.data_dir()?.join("hosted"))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            upload_budget: None,
+            lookup_budget: None,
+            target_latency: None,
+            plan_price: None,
+            upload_cap: None,
+            download_cap: None,
+            btc_per_hash: None,
+            data_dir: None,
+            max_upload_price: None,
+            upload_period: None,
+            upload_price_factor: None,
+        }
+    }
+}
+
+fn get_i64(git_config: &git2::Config, key: &'static str) -> Result<Option<i64>, ConfigError> {
+    match git_config.get_i64(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(ref e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(error) => Err(ConfigError::InvalidValue { key, error }),
+    }
+}
+
+fn get_path(git_config: &git2::Config, key: &'static str) -> Result<Option<PathBuf>, ConfigError> {
+    match git_config.get_path(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(ref e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(error) => Err(ConfigError::InvalidValue { key, error }),
+    }
+}
+
+// Git only knows about integers, so amounts of bitcoin and fractions of a second are stored as
+// strings.
+fn get_f64(git_config: &git2::Config, key: &'static str) -> Result<Option<f64>, ConfigError> {
+    let value = match git_config.get_string(key) {
+        Ok(value) => value,
+        Err(ref e) if e.code() == git2::ErrorCode::NotFound => return Ok(None),
+        Err(error) => return Err(ConfigError::InvalidValue { key, error }),
+    };
+    match value.trim().parse::<f64>() {
+        Ok(value) if value.is_finite() && value > 0.0 => Ok(Some(value)),
+        _ => {
+            let error = git2::Error::from_str("expected a positive number");
+            Err(ConfigError::InvalidValue { key, error })
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    fn config_from_str(contents: &str) -> Result<Config, ConfigError> {
+        let dir = unwrap!(TempDir::new("lightstore-config"));
+        let path = dir.path().join("config");
+        let mut file = unwrap!(File::create(&path));
+        unwrap!(file.write_all(contents.as_bytes()));
+        let git_config = unwrap!(git2::Config::open(&path));
+        Config::from_git_config(&git_config)
+    }
+
+    #[test]
+    fn read_upload_budget() {
+        let config = unwrap!(config_from_str("[lightstore]\n\tuploadBudget = 100k\n"));
+        assert_eq!(config.upload_budget, Some(BytePerSec(102400.0)));
+
+        let config = unwrap!(config_from_str(""));
+        assert_eq!(config.upload_budget, None);
+
+        assert!(config_from_str("[lightstore]\n\tuploadBudget = lots\n").is_err());
+    }
+
+    #[test]
+    fn read_lookup_pricing() {
+        let contents = "[lightstore]\n\tlookupBudget = 0.00001\n\ttargetLatency = 0.5\n";
+        let config = unwrap!(config_from_str(contents));
+        assert_eq!(config.lookup_budget, Some(Btc(0.00001)));
+        assert_eq!(config.target_latency, Some(Sec(0.5)));
+
+        assert!(config_from_str("[lightstore]\n\tlookupBudget = -1\n").is_err());
+    }
+
+    #[test]
+    fn read_plan() {
+        let contents = "[lightstore]\n\tplanPrice = 0.002\n\tuploadCap = 100g\n";
+        let config = unwrap!(config_from_str(contents));
+        assert_eq!(config.plan_price, Some(Btc(0.002)));
+        assert_eq!(config.upload_cap, Some(Byte(100.0 * 1024.0 * 1024.0 * 1024.0)));
+        assert_eq!(config.download_cap, None);
+    }
+
+    #[test]
+    fn read_btc_per_hash() {
+        let config = unwrap!(config_from_str("[lightstore]\n\tbtcPerHash = 1e-15\n"));
+        assert_eq!(config.btc_per_hash, Some(Btc(1e-15)));
+    }
+
+    #[test]
+    fn read_data_dir() {
+        let config = unwrap!(config_from_str("[lightstore]\n\tdataDir = /var/lib/lightstore\n"));
+        assert_eq!(config.reputation_path(), Some(PathBuf::from("/var/lib/lightstore/reputation")));
+        assert_eq!(config.node_key_path(), Some(PathBuf::from("/var/lib/lightstore/node_key")));
+        assert_eq!(config.hosted_dir(), Some(PathBuf::from("/var/lib/lightstore/hosted")));
+    }
+
+    #[test]
+    fn read_upload_settings() {
+        let contents = {
+            "[lightstore]\n\tmaxUploadPrice = 1e-11\n\tuploadPeriod = 86400\n\
+             \tuploadPriceFactor = 1.5\n"
+        };
+        let config = unwrap!(config_from_str(contents));
+        assert_eq!(config.max_upload_price, Some(BtcPerByte(1e-11)));
+        assert_eq!(config.upload_period, Some(Sec(86400.0)));
+        assert_eq!(config.upload_price_factor, Some(1.5));
+
+        assert!(config_from_str("[lightstore]\n\tuploadPeriod = forever\n").is_err());
+    }
+}
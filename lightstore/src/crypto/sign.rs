@@ -18,6 +18,11 @@ pub struct SignKeypair {
     pub secret: SecretSignKey,
 }
 
+#[derive(Clone, Copy)]
+pub struct Signature {
+    bytes: [u8; 64],
+}
+
 impl PublicSignKey {
     pub fn to_xor_addr(&self) -> XorAddr {
         XorAddr::from_bytes(self.as_bytes())
@@ -31,6 +36,18 @@ impl PublicSignKey {
         PublicSignKey { bytes }
     }
 
+    pub fn verify(&self, msg: &[u8], signature: &Signature) -> bool {
+        let public = match ed25519_dalek::PublicKey::from_bytes(&self.bytes[..]) {
+            Ok(public) => public,
+            Err(..) => return false,
+        };
+        let signature = match ed25519_dalek::Signature::from_bytes(&signature.bytes[..]) {
+            Ok(signature) => signature,
+            Err(..) => return false,
+        };
+        public.verify::<Sha512>(msg, &signature).is_ok()
+    }
+
     pub fn to_url(&self) -> String {
         format!("lsd://{}/", self)
     }
@@ -119,6 +136,40 @@ impl SignKeypair {
         let secret = SecretSignKey::from_bytes(&mut keypair.secret.to_bytes());
         Ok(SignKeypair { public, secret })
     }
+
+    pub fn sign(&self, msg: &[u8]) -> Signature {
+        let keypair = ed25519_dalek::Keypair {
+            secret: unwrap!(ed25519_dalek::SecretKey::from_bytes(&self.secret.bytes[..])),
+            public: unwrap!(ed25519_dalek::PublicKey::from_bytes(&self.public.bytes[..])),
+        };
+        let signature = keypair.sign::<Sha512>(msg);
+        Signature { bytes: signature.to_bytes() }
+    }
+}
+
+impl Signature {
+    pub fn from_bytes(bytes: [u8; 64]) -> Signature {
+        Signature { bytes }
+    }
+
+    pub fn as_bytes(&self) -> [u8; 64] {
+        self.bytes
+    }
+}
+
+impl PartialEq for Signature {
+    fn eq(&self, other: &Signature) -> bool {
+        self.bytes[..] == other.bytes[..]
+    }
+}
+
+impl Eq for Signature {}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let s = base32::as_base32(&self.bytes[..]);
+        fmt.debug_tuple("Signature").field(&s).finish()
+    }
 }
 
 impl fmt::Debug for SecretSignKey {
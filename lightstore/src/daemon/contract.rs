@@ -0,0 +1,904 @@
+use super::*;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+
+const SIGNATURE_CONTEXT: &[u8] = b"lightstore-contract-receipt";
+
+// The longest we'll sign up to keep anything for, and the most installments it can be paid in.
+pub const MAX_CONTRACT_DURATION: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+pub const MAX_INSTALLMENTS: u32 = 1000;
+
+// How far a receipt's start time can be from our clock before we decide the host's lying.
+const MAX_CLOCK_SKEW: u64 = 5 * 60;
+
+// How late a client can be with an installment before we stop hosting its content.
+const INSTALLMENT_GRACE: u64 = 60 * 60;
+
+// How often we check for installments that are due or overdue.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// How often we check that a host still has our content, how soon we check again after it fails
+// to show us, and how many failures in a row we put up with before giving up on it.
+const CHALLENGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const CHALLENGE_RETRY: Duration = Duration::from_secs(10 * 60);
+const MAX_CHALLENGE_FAILURES: u32 = 3;
+
+// How long a host has to answer a challenge. A host that has the content can read a leaf off and
+// send it in well under this, one that has to fetch it from somewhere first probably can't.
+pub const CHALLENGE_DEADLINE: Duration = Duration::from_secs(10);
+
+// What a client's asking a host to do: keep the content with root `root` for `duration` seconds,
+// for `price` paid in `installments` equal parts as the contract runs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContractTerms {
+    // Picked by the client, so that it can tell its contracts with a host apart.
+    pub contract_id: u64,
+    pub root: MerkleHash,
+    pub size: u64,
+    pub duration: u64,
+    pub price: Btc,
+    pub installments: u32,
+}
+
+impl ContractTerms {
+    pub fn validate(&self) -> Result<(), ContractError> {
+        if self.duration == 0 || self.duration > MAX_CONTRACT_DURATION.as_secs() {
+            return Err(ContractError::InvalidDuration);
+        }
+        if self.installments == 0 || self.installments > MAX_INSTALLMENTS {
+            return Err(ContractError::InvalidInstallments);
+        }
+        if self.price.val().is_nan() || self.price < Btc(0.0) || self.price.val().is_infinite() {
+            return Err(ContractError::InvalidPrice);
+        }
+        Ok(())
+    }
+
+    pub fn installment(&self) -> Btc {
+        self.price / f64::from(self.installments)
+    }
+
+    // When installment `index` falls due, for a contract which started at `starts`. Each one pays
+    // for the stretch of the contract that's just gone by.
+    pub fn due(&self, starts: u64, index: u32) -> u64 {
+        starts + self.duration * (u64::from(index) + 1) / u64::from(self.installments)
+    }
+
+    // What fetching the content and keeping it for the whole term costs a host.
+    pub fn hosting_cost(&self, costs: &Costs) -> Btc {
+        let size = Byte(self.size as f64);
+        let duration = Sec(self.duration as f64);
+        costs.download * size + costs.memory * (size * duration)
+    }
+}
+
+// A host's signed promise to keep to some terms, from `starts`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Receipt {
+    pub client: XorAddr,
+    pub host: XorAddr,
+    pub terms: ContractTerms,
+    pub starts: u64,
+    pub signature: Signature,
+}
+
+impl Receipt {
+    pub fn new(
+        keypair: &SignKeypair,
+        client: XorAddr,
+        terms: ContractTerms,
+        starts: u64,
+    ) -> Receipt {
+        let host = keypair.public.to_xor_addr();
+        let signature = keypair.sign(&signed_bytes(&client, &host, &terms, starts));
+        Receipt { client, host, terms, starts, signature }
+    }
+
+    pub fn verify(&self) -> Result<(), ContractError> {
+        self.terms.validate()?;
+        let key = PublicSignKey::from_bytes(self.host.as_bytes());
+        let signed = signed_bytes(&self.client, &self.host, &self.terms, self.starts);
+        if !key.verify(&signed, &self.signature) {
+            return Err(ContractError::BadSignature);
+        }
+        Ok(())
+    }
+
+    pub fn expires(&self) -> u64 {
+        self.starts + self.terms.duration
+    }
+}
+
+fn signed_bytes(client: &XorAddr, host: &XorAddr, terms: &ContractTerms, starts: u64) -> Bytes {
+    let mut bytes = BytesMut::with_capacity(SIGNATURE_CONTEXT.len() + 2 * 32 + 32 + 5 * 8 + 4);
+    bytes.put_slice(SIGNATURE_CONTEXT);
+    bytes.put_slice(&client.as_bytes());
+    bytes.put_slice(&host.as_bytes());
+    bytes.put_u64_be(terms.contract_id);
+    bytes.put_slice(&terms.root.as_bytes());
+    bytes.put_u64_be(terms.size);
+    bytes.put_u64_be(terms.duration);
+    bytes.put_f64_be(terms.price.val());
+    bytes.put_u32_be(terms.installments);
+    bytes.put_u64_be(starts);
+    bytes.freeze()
+}
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum ContractError {
+    #[fail(display = "contract duration out of range")]
+    InvalidDuration,
+    #[fail(display = "number of installments out of range")]
+    InvalidInstallments,
+    #[fail(display = "invalid price")]
+    InvalidPrice,
+    #[fail(display = "receipt has a bad signature")]
+    BadSignature,
+}
+
+#[derive(Debug, Fail)]
+pub enum StoreError {
+    #[fail(display = "invalid contract terms: {}", _0)]
+    InvalidTerms(ContractError),
+    #[fail(display = "the host didn't accept the contract")]
+    Refused,
+    #[fail(display = "the host's receipt doesn't match what we asked for")]
+    BadReceipt,
+    #[fail(display = "the daemon shut down")]
+    Shutdown,
+}
+
+// Resolves to the host's receipt once it's agreed to keep our content.
+pub struct StoreWith {
+    result_rx: oneshot::Receiver<Result<Receipt, StoreError>>,
+}
+
+impl StoreWith {
+    pub fn new(result_rx: oneshot::Receiver<Result<Receipt, StoreError>>) -> StoreWith {
+        StoreWith { result_rx }
+    }
+}
+
+impl Future for StoreWith {
+    type Item = Receipt;
+    type Error = StoreError;
+
+    fn poll(&mut self) -> Result<Async<Receipt>, StoreError> {
+        match self.result_rx.poll() {
+            Ok(Async::Ready(res)) => res.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(oneshot::Canceled) => Err(StoreError::Shutdown),
+        }
+    }
+}
+
+// Checks the receipt a host sent back for the terms we proposed.
+pub fn check_receipt(
+    receipt: &Receipt,
+    host: XorAddr,
+    our_addr: XorAddr,
+    terms: &ContractTerms,
+    now: u64,
+) -> Result<(), StoreError> {
+    let skew = cmp::max(receipt.starts, now) - cmp::min(receipt.starts, now);
+    if receipt.host != host || receipt.client != our_addr || receipt.terms != *terms {
+        return Err(StoreError::BadReceipt);
+    }
+    if skew > MAX_CLOCK_SKEW || receipt.verify().is_err() {
+        return Err(StoreError::BadReceipt);
+    }
+    Ok(())
+}
+
+struct Contract {
+    receipt: Receipt,
+    // How many installments have fallen due so far, and what's been paid for them.
+    paid: u32,
+    total: Btc,
+    // For contracts we've taken out, how many challenges in a row the host's failed and when we
+    // next challenge it.
+    failures: u32,
+    next_challenge: Instant,
+}
+
+impl Contract {
+    fn new(receipt: Receipt, now: Instant) -> Contract {
+        Contract {
+            receipt,
+            paid: 0,
+            total: Btc(0.0),
+            failures: 0,
+            // Give the host time to fetch the content first.
+            next_challenge: now + CHALLENGE_INTERVAL,
+        }
+    }
+}
+
+// An installment we owe a host, and what we'll have paid on the contract with it.
+pub struct Installment {
+    pub host: XorAddr,
+    pub contract_id: u64,
+    pub index: u32,
+    pub amount: Btc,
+    pub total: Btc,
+}
+
+// A leaf we're asking a host to show us it still has.
+#[derive(Clone, Debug)]
+pub struct Challenge {
+    pub host: XorAddr,
+    pub contract_id: u64,
+    pub root: MerkleHash,
+    pub size: u64,
+    pub index: u64,
+}
+
+impl Challenge {
+    // Whether the host's reply proves it has the leaf. All we need for this is the root.
+    pub fn check(&self, index: u64, proof: &MerkleProof, data: &[u8]) -> bool {
+        if index != self.index {
+            return false;
+        }
+        let mut verifier = LeafVerifier::new(self.root, self.size, index);
+        verifier.verify(index, data, proof).is_ok()
+    }
+}
+
+// What's come up since we last polled our contracts.
+pub struct ContractEvents {
+    pub installments: Vec<Installment>,
+    pub challenges: Vec<Challenge>,
+    // The content we no longer need to host.
+    pub expired: Vec<MerkleHash>,
+}
+
+type ContractMap = HashMap<(XorAddr, u64), Contract>;
+
+// The contracts we've taken out with hosts, and the ones we're hosting for clients. They're
+// worth money to both sides, so we save them at `path` whenever they change.
+pub struct Contracts {
+    // Keyed by host or client, and the contract id.
+    taken: ContractMap,
+    hosting: ContractMap,
+    path: Option<PathBuf>,
+    next_check: Instant,
+}
+
+impl Contracts {
+    pub fn open(path: Option<PathBuf>, now: Instant) -> Contracts {
+        let (taken, hosting) = match path {
+            Some(ref path) => match load_contracts(path, now) {
+                Ok(contracts) => contracts,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    (HashMap::new(), HashMap::new())
+                },
+                Err(e) => {
+                    debug!("couldn't read contracts at {:?}: {}", path, e);
+                    (HashMap::new(), HashMap::new())
+                },
+            },
+            None => (HashMap::new(), HashMap::new()),
+        };
+        Contracts {
+            taken,
+            hosting,
+            path,
+            next_check: now,
+        }
+    }
+
+    pub fn add_taken(&mut self, receipt: Receipt, now: Instant) {
+        let key = (receipt.host, receipt.terms.contract_id);
+        let _ = self.taken.insert(key, Contract::new(receipt, now));
+        self.save();
+    }
+
+    pub fn add_hosting(&mut self, receipt: Receipt, now: Instant) {
+        let key = (receipt.client, receipt.terms.contract_id);
+        let _ = self.hosting.insert(key, Contract::new(receipt, now));
+        self.save();
+    }
+
+    // The receipt we gave `client` for a contract, if we're hosting it.
+    pub fn hosting_receipt(&self, client: &XorAddr, contract_id: u64) -> Option<&Receipt> {
+        self.hosting.get(&(*client, contract_id)).map(|contract| &contract.receipt)
+    }
+
+    // The roots of all the content we're hosting for clients.
+    pub fn hosted_roots(&self) -> HashSet<MerkleHash> {
+        self.hosting.values().map(|contract| contract.receipt.terms.root).collect()
+    }
+
+    // Stops hosting a contract, returning its root if no other contract needs the content.
+    pub fn drop_hosting(&mut self, client: &XorAddr, contract_id: u64) -> Option<MerkleHash> {
+        let root = self.remove_hosting(client, contract_id);
+        self.save();
+        root
+    }
+
+    // Stops hosting every contract for the content with root `root`.
+    pub fn drop_root(&mut self, root: &MerkleHash) {
+        self.hosting.retain(|_, contract| contract.receipt.terms.root != *root);
+        self.save();
+    }
+
+    fn remove_hosting(&mut self, client: &XorAddr, contract_id: u64) -> Option<MerkleHash> {
+        let contract = self.hosting.remove(&(*client, contract_id))?;
+        let root = contract.receipt.terms.root;
+        if self.hosting.values().any(|contract| contract.receipt.terms.root == root) {
+            return None;
+        }
+        Some(root)
+    }
+
+    // Whether we've got `host` keeping the content with root `root` for us, in which case it can
+    // fetch it from us for free.
+    pub fn is_host_of(&self, host: &XorAddr, root: &MerkleHash) -> bool {
+        self.taken.values().any(|contract| {
+            contract.receipt.host == *host && contract.receipt.terms.root == *root
+        })
+    }
+
+    // Records that `client` has paid installments up to `index`, `total` in all, returning what
+    // that adds to what it's paid before. Payments can get lost on the way, so a later one covers
+    // any before it.
+    pub fn on_payment(
+        &mut self,
+        client: &XorAddr,
+        contract_id: u64,
+        index: u32,
+        total: Btc,
+    ) -> Btc {
+        let paid = {
+            let contract = match self.hosting.get_mut(&(*client, contract_id)) {
+                Some(contract) => contract,
+                None => return Btc(0.0),
+            };
+            let terms = &contract.receipt.terms;
+            if index < contract.paid || index >= terms.installments {
+                return Btc(0.0);
+            }
+            contract.paid = index + 1;
+            let owed = terms.installment() * f64::from(index + 1);
+            let total = match total {
+                total if total.val().is_nan() => Btc(0.0),
+                total if total < owed => total,
+                _ => owed,
+            };
+            if total <= contract.total {
+                Btc(0.0)
+            } else {
+                let paid = total - contract.total;
+                contract.total = total;
+                paid
+            }
+        };
+        self.save();
+        paid
+    }
+
+    // Records whether `host` answered a challenge in time with a leaf that checked out. Returns
+    // `true` if it's failed so many times that we've given up on the contract.
+    pub fn challenged(
+        &mut self,
+        host: &XorAddr,
+        contract_id: u64,
+        passed: bool,
+        now: Instant,
+    ) -> bool {
+        let contract = match self.taken.get_mut(&(*host, contract_id)) {
+            Some(contract) => contract,
+            None => return false,
+        };
+        if passed {
+            contract.failures = 0;
+            return false;
+        }
+        contract.failures += 1;
+        contract.next_challenge = now + CHALLENGE_RETRY;
+        if contract.failures < MAX_CHALLENGE_FAILURES {
+            return false;
+        }
+        let _ = self.taken.remove(&(*host, contract_id));
+        self.save();
+        true
+    }
+
+    // Returns the installments that have fallen due, the hosts due a challenge, and the roots of
+    // the content we no longer need to host because their contracts are over or the client's
+    // stopped paying. Installments that fall due while a host is failing its challenges are held
+    // back until it passes one, and never paid if it doesn't.
+    pub fn poll(&mut self, now: Instant, unix_now: u64) -> ContractEvents {
+        let mut events = ContractEvents {
+            installments: Vec::new(),
+            challenges: Vec::new(),
+            expired: Vec::new(),
+        };
+        if now < self.next_check {
+            return events;
+        }
+        self.next_check = now + CHECK_INTERVAL;
+
+        let mut changed = false;
+        let (installments, challenges) = (&mut events.installments, &mut events.challenges);
+        self.taken.retain(|_, contract| {
+            let terms = &contract.receipt.terms;
+            let (host, starts) = (contract.receipt.host, contract.receipt.starts);
+            while contract.paid < terms.installments && contract.failures == 0 {
+                if terms.due(starts, contract.paid) > unix_now {
+                    break;
+                }
+                contract.total += terms.installment();
+                installments.push(Installment {
+                    host,
+                    contract_id: terms.contract_id,
+                    index: contract.paid,
+                    amount: terms.installment(),
+                    total: contract.total,
+                });
+                contract.paid += 1;
+                changed = true;
+            }
+            if contract.next_challenge <= now {
+                contract.next_challenge = now + CHALLENGE_INTERVAL;
+                challenges.push(Challenge {
+                    host,
+                    contract_id: terms.contract_id,
+                    root: terms.root,
+                    size: terms.size,
+                    index: rand::random::<u64>() % leaf_count(terms.size),
+                });
+            }
+            if contract.paid < terms.installments {
+                return true;
+            }
+            changed = true;
+            false
+        });
+
+        let finished: Vec<(XorAddr, u64)> = {
+            self.hosting
+            .iter()
+            .filter(|(_, contract)| {
+                let receipt = &contract.receipt;
+                if receipt.expires() <= unix_now {
+                    return true;
+                }
+                let next_due = receipt.terms.due(receipt.starts, contract.paid);
+                next_due + INSTALLMENT_GRACE < unix_now
+            })
+            .map(|(key, _)| *key)
+            .collect()
+        };
+        for (client, contract_id) in finished {
+            changed = true;
+            if let Some(root) = self.remove_hosting(&client, contract_id) {
+                events.expired.push(root);
+            }
+        }
+        if changed {
+            self.save();
+        }
+        events
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.taken.is_empty() && self.hosting.is_empty() {
+            return None;
+        }
+        Some(self.next_check)
+    }
+
+    fn save(&self) {
+        if let Err(e) = self.write() {
+            debug!("error saving contracts: {}", e);
+        }
+    }
+
+    // Writes the contracts to a temporary file first, so that dying part way through doesn't
+    // lose the lot.
+    fn write(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            for contract in self.taken.values() {
+                write_contract(&mut file, "taken", contract)?;
+            }
+            for contract in self.hosting.values() {
+                write_contract(&mut file, "hosting", contract)?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)
+    }
+}
+
+// Each line is whether we took the contract out or are hosting it, the receipt, and how many
+// installments have fallen due and what's been paid for them.
+fn write_contract(file: &mut File, kind: &str, contract: &Contract) -> io::Result<()> {
+    let receipt = &contract.receipt;
+    let terms = &receipt.terms;
+    writeln!(
+        file,
+        "{} {} {} {} {} {} {} {:e} {} {} {} {} {:e}",
+        kind,
+        as_base32(&receipt.client.as_bytes()),
+        as_base32(&receipt.host.as_bytes()),
+        terms.contract_id,
+        as_base32(&terms.root.as_bytes()),
+        terms.size,
+        terms.duration,
+        terms.price.val(),
+        terms.installments,
+        receipt.starts,
+        as_base32(&receipt.signature.as_bytes()),
+        contract.paid,
+        contract.total.val(),
+    )
+}
+
+// Skips lines it can't make sense of, and receipts which don't check out, rather than forgetting
+// everything else in the file.
+fn load_contracts(path: &Path, now: Instant) -> io::Result<(ContractMap, ContractMap)> {
+    let file = File::open(path)?;
+    let (mut taken, mut hosting) = (HashMap::new(), HashMap::new());
+    for line in BufReader::new(file).lines() {
+        let (is_taken, contract) = match parse_contract(&line?, now) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        let (client, host) = (contract.receipt.client, contract.receipt.host);
+        let contract_id = contract.receipt.terms.contract_id;
+        if is_taken {
+            let _ = taken.insert((host, contract_id), contract);
+        } else {
+            let _ = hosting.insert((client, contract_id), contract);
+        }
+    }
+    Ok((taken, hosting))
+}
+
+fn parse_contract(line: &str, now: Instant) -> Option<(bool, Contract)> {
+    let mut fields = line.split_whitespace();
+    let is_taken = match fields.next()? {
+        "taken" => true,
+        "hosting" => false,
+        _ => return None,
+    };
+    let mut client = [0u8; 32];
+    from_base32(fields.next()?, &mut client).ok()?;
+    let mut host = [0u8; 32];
+    from_base32(fields.next()?, &mut host).ok()?;
+    let contract_id = fields.next()?.parse().ok()?;
+    let mut root = [0u8; 32];
+    from_base32(fields.next()?, &mut root).ok()?;
+    let size = fields.next()?.parse().ok()?;
+    let duration = fields.next()?.parse().ok()?;
+    let price = Btc(fields.next()?.parse().ok()?);
+    let installments = fields.next()?.parse().ok()?;
+    let starts = fields.next()?.parse().ok()?;
+    let mut signature = [0u8; 64];
+    from_base32(fields.next()?, &mut signature).ok()?;
+    let paid = fields.next()?.parse().ok()?;
+    let total = Btc(fields.next()?.parse().ok()?);
+
+    let terms = ContractTerms {
+        contract_id,
+        root: MerkleHash::from_bytes(root),
+        size,
+        duration,
+        price,
+        installments,
+    };
+    let receipt = Receipt {
+        client: XorAddr::from_bytes(client),
+        host: XorAddr::from_bytes(host),
+        terms,
+        starts,
+        signature: Signature::from_bytes(signature),
+    };
+    receipt.verify().ok()?;
+    let mut contract = Contract::new(receipt, now);
+    contract.paid = paid;
+    contract.total = total;
+    Some((is_taken, contract))
+}
+
+// Where we keep the content we're hosting for clients, so that it's still there after a restart.
+pub struct ContentStore {
+    dir: Option<PathBuf>,
+}
+
+impl ContentStore {
+    pub fn new(dir: Option<PathBuf>) -> ContentStore {
+        ContentStore { dir }
+    }
+
+    // Starts saving the content with root `root` as it arrives, so that we never have to hold all
+    // of it at once before it's on disk.
+    pub fn writer(&self, root: MerkleHash) -> io::Result<ContentWriter> {
+        let saving = match self.dir {
+            Some(ref dir) => {
+                fs::create_dir_all(dir)?;
+                let path = dir.join(as_base32(&root.as_bytes()));
+                let tmp_path = path.with_extension("tmp");
+                let file = File::create(&tmp_path)?;
+                Some(Saving { file, tmp_path, path })
+            },
+            None => None,
+        };
+        Ok(ContentWriter {
+            root,
+            saving,
+            data: BytesMut::new(),
+            error: None,
+        })
+    }
+
+    // Reads back the content with root `root`, if we've got it and it's all there.
+    pub fn load(&self, root: &MerkleHash) -> Option<Arc<MerkleTree>> {
+        let path = self.dir.as_ref()?.join(as_base32(&root.as_bytes()));
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                debug!("couldn't read hosted content at {:?}: {}", path, e);
+                return None;
+            },
+        };
+        let tree = MerkleTree::new(Bytes::from(data));
+        if tree.root() != *root {
+            debug!("hosted content at {:?} doesn't match its root", path);
+            return None;
+        }
+        Some(Arc::new(tree))
+    }
+
+    pub fn remove(&self, root: &MerkleHash) {
+        let path = match self.dir {
+            Some(ref dir) => dir.join(as_base32(&root.as_bytes())),
+            None => return,
+        };
+        match fs::remove_file(&path) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => debug!("couldn't remove hosted content at {:?}: {}", path, e),
+        }
+    }
+}
+
+// Content on its way into the store. It's written to a temporary file as it arrives and only put
+// where `ContentStore::load` will find it once it's all checked out against its root.
+pub struct ContentWriter {
+    root: MerkleHash,
+    // Where it's going, or `None` if we've nowhere to save it and are keeping it in `data`.
+    saving: Option<Saving>,
+    data: BytesMut,
+    // The first error we hit writing it out. Anything that arrives after that is thrown away.
+    error: Option<io::Error>,
+}
+
+struct Saving {
+    file: File,
+    tmp_path: PathBuf,
+    path: PathBuf,
+}
+
+impl ContentWriter {
+    pub fn write(&mut self, data: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        match self.saving {
+            Some(ref mut saving) => {
+                if let Err(e) = saving.file.write_all(data) {
+                    self.error = Some(e);
+                }
+            },
+            None => self.data.extend_from_slice(data),
+        }
+    }
+
+    // Checks what's arrived against the root and puts it in place, returning the tree to host it
+    // from.
+    pub fn finish(mut self) -> io::Result<Arc<MerkleTree>> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        let tree = match self.saving {
+            Some(ref saving) => {
+                saving.file.sync_all()?;
+                MerkleTree::new(Bytes::from(fs::read(&saving.tmp_path)?))
+            },
+            None => MerkleTree::new(self.data.take().freeze()),
+        };
+        if tree.root() != self.root {
+            let e = io::Error::new(io::ErrorKind::InvalidData, "content doesn't match its root");
+            return Err(e);
+        }
+        if let Some(ref saving) = self.saving {
+            fs::rename(&saving.tmp_path, &saving.path)?;
+        }
+        self.saving = None;
+        Ok(Arc::new(tree))
+    }
+}
+
+// Whatever arrived of content that never made it into place is no use to anyone.
+impl Drop for ContentWriter {
+    fn drop(&mut self) {
+        if let Some(ref saving) = self.saving {
+            let _ = fs::remove_file(&saving.tmp_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn terms() -> ContractTerms {
+        ContractTerms {
+            contract_id: 7,
+            root: MerkleHash::from_bytes([3; 32]),
+            size: 1000,
+            duration: 100_000,
+            price: Btc(4e-6),
+            installments: 4,
+        }
+    }
+
+    #[test]
+    fn receipts_are_signed_by_the_host() {
+        let keypair = unwrap!(SignKeypair::new());
+        let host = keypair.public.to_xor_addr();
+        let client = XorAddr::from_bytes([1; 32]);
+        let receipt = Receipt::new(&keypair, client, terms(), 1_000_000);
+        unwrap!(check_receipt(&receipt, host, client, &terms(), 1_000_010));
+
+        let mut tampered = receipt.clone();
+        tampered.terms.duration *= 2;
+        assert_eq!(tampered.verify(), Err(ContractError::BadSignature));
+        assert!(check_receipt(&receipt, host, client, &tampered.terms, 1_000_010).is_err());
+        assert!(check_receipt(&receipt, client, client, &terms(), 1_000_010).is_err());
+        assert!(check_receipt(&receipt, host, client, &terms(), 2_000_000).is_err());
+    }
+
+    #[test]
+    fn installments_fall_due_as_the_contract_runs() {
+        let keypair = unwrap!(SignKeypair::new());
+        let client = XorAddr::from_bytes([1; 32]);
+        let receipt = Receipt::new(&keypair, client, terms(), 1_000_000);
+        let now = Instant::now();
+
+        let mut ours = Contracts::open(None, now);
+        ours.add_taken(receipt.clone(), now);
+        assert!(ours.is_host_of(&receipt.host, &terms().root));
+        let due = ours.poll(now, 1_050_000).installments;
+        assert_eq!(due.iter().map(|due| due.index).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(due[0].amount, Btc(1e-6));
+        assert_eq!(due[1].total, Btc(2e-6));
+        let due = ours.poll(now + CHECK_INTERVAL, 1_100_000).installments;
+        assert_eq!(due.len(), 2);
+        assert!(ours.next_deadline().is_none());
+
+        let mut theirs = Contracts::open(None, now);
+        theirs.add_hosting(receipt, now);
+        assert_eq!(theirs.on_payment(&client, 7, 1, Btc(2e-6)), Btc(2e-6));
+        assert_eq!(theirs.on_payment(&client, 7, 0, Btc(1e-6)), Btc(0.0));
+        assert!(theirs.poll(now, 1_050_000).expired.is_empty());
+        // They've stopped paying.
+        let expired = theirs.poll(now + CHECK_INTERVAL, 1_075_000 + INSTALLMENT_GRACE + 1).expired;
+        assert_eq!(expired, vec![terms().root]);
+    }
+
+    #[test]
+    fn hosts_that_fail_challenges_stop_being_paid() {
+        let keypair = unwrap!(SignKeypair::new());
+        let client = XorAddr::from_bytes([1; 32]);
+        let content: Vec<u8> = (0..10 * LEAF_SIZE).map(|i| (i * 7 + i / LEAF_SIZE) as u8).collect();
+        let tree = MerkleTree::new(Bytes::from(content));
+        let terms = ContractTerms { root: tree.root(), size: tree.size(), ..terms() };
+        let receipt = Receipt::new(&keypair, client, terms, 1_000_000);
+        let host = receipt.host;
+        let now = Instant::now();
+        let mut contracts = Contracts::open(None, now);
+        contracts.add_taken(receipt, now);
+
+        let later = now + CHALLENGE_INTERVAL;
+        let challenges = contracts.poll(later, 1_000_000).challenges;
+        assert_eq!(challenges.len(), 1);
+        let challenge = &challenges[0];
+        let index = challenge.index;
+        assert!(challenge.check(index, &tree.proof(index, index), &tree.leaf(index)));
+        let other = (index + 1) % tree.leaf_count();
+        assert!(!challenge.check(other, &tree.proof(other, other), &tree.leaf(other)));
+        assert!(!challenge.check(index, &tree.proof(other, other), &tree.leaf(other)));
+
+        assert!(!contracts.challenged(&host, 7, false, later));
+        let events = contracts.poll(later + CHALLENGE_RETRY, 1_025_000);
+        assert!(events.installments.is_empty());
+        assert_eq!(events.challenges.len(), 1);
+
+        // The installment it missed is paid once it shows us the content again.
+        assert!(!contracts.challenged(&host, 7, true, later));
+        let events = contracts.poll(later + CHALLENGE_RETRY + CHECK_INTERVAL, 1_025_000);
+        assert_eq!(events.installments.iter().map(|due| due.index).collect::<Vec<_>>(), vec![0]);
+
+        assert!(!contracts.challenged(&host, 7, false, later));
+        assert!(!contracts.challenged(&host, 7, false, later));
+        assert!(contracts.challenged(&host, 7, false, later));
+        assert!(!contracts.is_host_of(&host, &tree.root()));
+    }
+
+    #[test]
+    fn contracts_and_content_survive_restarts() {
+        let dir = unwrap!(TempDir::new("lightstore-contracts"));
+        let path = dir.path().join("contracts");
+        let keypair = unwrap!(SignKeypair::new());
+        let client = XorAddr::from_bytes([1; 32]);
+        let tree = MerkleTree::new(Bytes::from(vec![5u8; 3 * LEAF_SIZE]));
+        let terms = ContractTerms { root: tree.root(), size: tree.size(), ..terms() };
+        let receipt = Receipt::new(&keypair, client, terms, 1_000_000);
+        let now = Instant::now();
+
+        let mut contracts = Contracts::open(Some(path.clone()), now);
+        contracts.add_hosting(receipt.clone(), now);
+        assert_eq!(contracts.on_payment(&client, 7, 0, Btc(1e-6)), Btc(1e-6));
+        let content = ContentStore::new(Some(dir.path().join("hosted")));
+        let mut writer = unwrap!(content.writer(tree.root()));
+        for leaf in tree.data().chunks(LEAF_SIZE) {
+            writer.write(leaf);
+        }
+        assert_eq!(unwrap!(writer.finish()).root(), tree.root());
+
+        let mut loaded = Contracts::open(Some(path.clone()), now);
+        assert_eq!(loaded.hosting_receipt(&client, 7), Some(&receipt));
+        assert_eq!(loaded.hosted_roots().into_iter().collect::<Vec<_>>(), vec![tree.root()]);
+        // What's been paid carries over too.
+        assert_eq!(loaded.on_payment(&client, 7, 0, Btc(1e-6)), Btc(0.0));
+        assert_eq!(unwrap!(content.load(&tree.root())).data(), tree.data());
+
+        assert_eq!(loaded.drop_hosting(&client, 7), Some(tree.root()));
+        content.remove(&tree.root());
+        assert!(Contracts::open(Some(path), now).hosting_receipt(&client, 7).is_none());
+        assert!(content.load(&tree.root()).is_none());
+    }
+    #[test]
+    fn content_only_lands_once_it_checks_out() {
+        let dir = unwrap!(TempDir::new("lightstore-content"));
+        let content = ContentStore::new(Some(dir.path().join("hosted")));
+        let tree = MerkleTree::new(Bytes::from(vec![9u8; 2 * LEAF_SIZE + 7]));
+
+        // Nothing's there until it's all arrived.
+        let mut writer = unwrap!(content.writer(tree.root()));
+        writer.write(&tree.data()[..LEAF_SIZE]);
+        assert!(content.load(&tree.root()).is_none());
+        writer.write(&tree.data()[LEAF_SIZE..]);
+        unwrap!(writer.finish());
+        assert_eq!(unwrap!(content.load(&tree.root())).data(), tree.data());
+
+        // Content that doesn't match its root is thrown away, as is any that never finishes.
+        let other = MerkleTree::new(Bytes::from(vec![1u8; LEAF_SIZE]));
+        let mut writer = unwrap!(content.writer(other.root()));
+        writer.write(&tree.data()[..LEAF_SIZE]);
+        assert!(writer.finish().is_err());
+        let mut writer = unwrap!(content.writer(other.root()));
+        writer.write(other.data());
+        drop(writer);
+        assert!(content.load(&other.root()).is_none());
+        assert_eq!(unwrap!(fs::read_dir(dir.path().join("hosted"))).count(), 1);
+
+        // Without anywhere to save it, it's kept in memory.
+        let mut writer = unwrap!(ContentStore::new(None).writer(other.root()));
+        writer.write(other.data());
+        assert_eq!(unwrap!(writer.finish()).data(), other.data());
+    }
+}
@@ -1,100 +1,2443 @@
 use super::*;
 //use crate::daemon::ai;
 use futures::sync::oneshot;
+use futures::stream::FuturesUnordered;
 use std::io;
+use std::thread;
 
 #[cfg(test)]
 use test;
 
+#[derive(Clone)]
 pub struct Daemon {
     user_command_tx: UnboundedSender<UserCommand>,
+    node_key: PublicSignKey,
+}
+
+// How many peers we ask for our reflexive address each time we refresh it.
+const ADDRESS_QUERY_PEERS: usize = 4;
+
+// What we offer a peer for telling us our address. The reply is a couple of dozen bytes, so this
+// covers their upload costs many times over.
+const ADDRESS_QUERY_REWARD: Btc = Btc(1e-10);
+const ADDRESS_QUERY_REWARD_DECAY: Sec = Sec(2.0);
+
+// How long an address we've been told about stays worth trying without hearing from it.
+const KNOWN_ADDR_LIFETIME: Sec = Sec(24.0 * 60.0 * 60.0);
+
+// How long the replies we send stay worth sending.
+const REPLY_UTILITY_DECAY: Sec = Sec(5.0);
+
+// Probes are tiny and only useful for the few seconds a hole punch takes.
+const PUNCH_PROBE_UTILITY: Btc = Btc(1e-10);
+const PUNCH_PROBE_UTILITY_DECAY: Sec = Sec(1.0);
+
+// How many times over what we charge for downloads has to change before we tell our peers again.
+const DOWNLOAD_FEE_CHANGE: f64 = 1.5;
+
+// How much we lose faith in a domain address each time it fails to resolve, and the point at
+// which we give up on it entirely.
+const DNS_FAILURE_PENALTY: f64 = 0.5;
+const MIN_DOMAIN_PROBABILITY: f64 = 0.01;
+
+// What it's worth to us to have a peer store or look up a provider record.
+const PROVIDER_UTILITY: Btc = Btc(1e-9);
+const PROVIDER_UTILITY_DECAY: Sec = Sec(5.0);
+
+// Stream control messages are tiny, but a stream that stalls for want of one costs the sender
+// a window's worth of payment.
+const STREAM_CONTROL_UTILITY: Btc = Btc(1e-9);
+const STREAM_CONTROL_UTILITY_DECAY: Sec = Sec(2.0);
+
+// A proof-of-work payment stays worth sending for as long as the token is good, but we'd rather
+// it didn't sit in the queue for long. We offer a little for the peer to acknowledge it, so that
+// it's worth their while.
+const POW_ACK_UTILITY: Btc = Btc(1e-9);
+const POW_PAYMENT_UTILITY_DECAY: Sec = Sec(60.0);
+
+// What it's worth to us to have a host answer a contract proposal, and to have an installment
+// reach the host while it's still due.
+const CONTRACT_REPLY_UTILITY: Btc = Btc(1e-9);
+const CONTRACT_REPLY_UTILITY_DECAY: Sec = Sec(5.0);
+const CONTRACT_PAYMENT_UTILITY_DECAY: Sec = Sec(60.0);
+
+// How often hosts of our uploads get paid, so that one which loses our content doesn't get paid
+// for long.
+const UPLOAD_INSTALLMENT_PERIOD: Sec = Sec(24.0 * 60.0 * 60.0);
+
+// Resolves to who we asked and what they said.
+type AddressQuery = BoxSendFuture<(XorAddr, Result<Msg, RequestError>), Void>;
+
+// Resolves to who we asked to open which stream, and what they said.
+type StreamOpening = BoxSendFuture<(XorAddr, u64, Result<Msg, RequestError>), Void>;
+
+// Resolves to which swarm looked up the content's providers, and who they are.
+type SwarmLookup = BoxSendFuture<(u64, Vec<ProviderRecord>), Void>;
+
+// Resolves to which swarm asked, who it asked, and what they said.
+type SwarmQuery = BoxSendFuture<(u64, XorAddr, Result<Msg, RequestError>), Void>;
+
+// Resolves once a peer has stored our record or we've given up asking. It's paid for like any
+// other request, so there's nothing left to do with the reply.
+type ProviderAdding = BoxSendFuture<(), Void>;
+
+// Resolves to which lookup asked, and what they said.
+type ProviderReply = BoxSendFuture<(u64, Result<Msg, RequestError>), Void>;
+
+// Resolves to which mutable data lookup asked, and what they said.
+type MutableReply = BoxSendFuture<(u64, Result<Msg, RequestError>), Void>;
+
+// Resolves once the peers we've asked to store some mutable data have all answered.
+type MutablePutting = BoxSendFuture<(), Void>;
+
+// Resolves to the request we forwarded, and what the peer we forwarded it to said.
+type Forwarding = BoxSendFuture<(IncomingRequest, Result<Msg, RequestError>), Void>;
+
+// Resolves to the payment we've been minting a token for, and the token if we found it in time.
+type Minting = BoxSendFuture<(PendingPowPayment, Option<PowToken>), Void>;
+
+// Resolves to a payment we've sent, the token we paid with, and what the peer said.
+type PowReply = BoxSendFuture<(PendingPowPayment, PowToken, Result<Msg, RequestError>), Void>;
+
+// Resolves to the contract we proposed, and what the host said.
+type ContractProposal = BoxSendFuture<(ProposedContract, Result<Msg, RequestError>), Void>;
+
+// Resolves to which client's contract we were fetching content for, and the content.
+type ContractFetch = BoxSendFuture<(XorAddr, u64, Result<ContentWriter, DownloadError>), Void>;
+
+// Resolves to a challenge we sent a host, when we sent it, and what the host said.
+type ChallengeReply = BoxSendFuture<(Challenge, Instant, Result<Msg, RequestError>), Void>;
+
+// Resolves to an upload, the hosts we've planned to put its shards with, and the shards, once
+// they've been coded.
+type ShardEncoding = BoxSendFuture<(UploadJob, Vec<HostCandidate>, Vec<Arc<MerkleTree>>), Void>;
+
+// Resolves once the hosts we've asked to keep an upload have all answered, to the upload and
+// which of them agreed.
+type PendingUpload = BoxSendFuture<(UploadJob, Vec<Option<ShardStored>>), Void>;
+
+// A request someone's sent us, when it arrived and where to send the reply.
+#[derive(Clone)]
+struct IncomingRequest {
+    peer: XorAddr,
+    request_id: u64,
+    offer: Offer,
+    addr: Option<SocketAddr>,
+    received: Instant,
+}
+
+// A proof-of-work payment we're making, and where to say how it went.
+struct PendingPowPayment {
+    peer: XorAddr,
+    result_tx: oneshot::Sender<Result<Btc, PayWithWorkError>>,
+}
+
+// A contract we've proposed to a host, and where to send its receipt.
+struct ProposedContract {
+    host: XorAddr,
+    terms: ContractTerms,
+    result_tx: oneshot::Sender<Result<Receipt, StoreError>>,
 }
 
 pub struct Driver {
     peer_txs: HashMap<XorAddr, PeerTx>,
+    peer_infos: HashMap<XorAddr, Arc<PeerInfo>>,
+    peer_addrs: HashMap<SocketAddr, XorAddr>,
     user_command_rx: UnboundedReceiver<UserCommand>,
-    socket: SharedUdpSocket,
+    sockets: Sockets,
+    msg_rxs: Vec<MsgRx>,
+    ledger: Ledger,
+    reputations: Reputations,
+    reflexive_addrs: ReflexiveAddrs,
+    address_queries: FuturesUnordered<AddressQuery>,
+    transactions: Transactions,
+    fragmenter: Fragmenter,
+    reassembly: Reassembly,
+    retransmit_timer: Option<Delay>,
+    hosted: HashMap<MerkleHash, Arc<MerkleTree>>,
+    stream_sender: StreamSender,
+    stream_receiver: StreamReceiver,
+    stream_opens: FuturesUnordered<StreamOpening>,
+    swarms: HashMap<u64, Swarm>,
+    swarm_lookups: FuturesUnordered<SwarmLookup>,
+    swarm_queries: FuturesUnordered<SwarmQuery>,
+    node_keypair: SignKeypair,
+    provider_store: ProviderStore,
+    announcements: Announcements,
+    provider_adds: FuturesUnordered<ProviderAdding>,
+    provider_lookups: HashMap<u64, PendingLookup>,
+    provider_replies: FuturesUnordered<ProviderReply>,
+    mutable_store: MutableStore,
+    bid_strategy: BidStrategy,
+    mutable_lookups: HashMap<u64, PendingGetMutable>,
+    mutable_replies: FuturesUnordered<MutableReply>,
+    mutable_puts: FuturesUnordered<MutablePutting>,
+    forwards: FuturesUnordered<Forwarding>,
+    forwarding: HashSet<(XorAddr, u64)>,
+    costs: ResourceCosts,
+    resource_monitor: resource_costs::ResourceMonitor,
+    btc_per_hash: Btc,
+    pow_verifier: PowVerifier,
+    pow_minter: PowMinter,
+    mintings: FuturesUnordered<Minting>,
+    pow_payments: FuturesUnordered<PowReply>,
+    contracts: Contracts,
+    content_store: ContentStore,
+    // Content we're only hosting until the hosts we've proposed contracts to have fetched it, and
+    // which of them have yet to.
+    awaiting_fetch: HashMap<MerkleHash, HashSet<XorAddr>>,
+    contract_proposals: FuturesUnordered<ContractProposal>,
+    contract_fetches: FuturesUnordered<ContractFetch>,
+    challenges: FuturesUnordered<ChallengeReply>,
+    upload_planner: UploadPlanner,
+    shard_encodings: FuturesUnordered<ShardEncoding>,
+    uploads: FuturesUnordered<PendingUpload>,
+    hole_punches: HashMap<u64, HolePunch>,
+    introductions: Introductions,
+    dns_cache: DnsCache,
+    our_info: PeerInfo,
+    peer_event_tx: UnboundedSender<PeerEvent>,
+    peer_event_rx: UnboundedReceiver<PeerEvent>,
+}
+
+enum UserCommand {
+    AddPeer {
+        peer: XorAddr,
+        addrs: Vec<SocketAddr>,
+    },
+    Host {
+        tree: Arc<MerkleTree>,
+    },
+    Unhost {
+        root: MerkleHash,
+    },
+    FindProviders {
+        root: MerkleHash,
+        result_tx: oneshot::Sender<Vec<ProviderRecord>>,
+    },
+    Download {
+        peer: XorAddr,
+        root: MerkleHash,
+        size: u64,
+        start: u64,
+        end: u64,
+        price: BtcPerByte,
+        data_tx: UnboundedSender<Result<Bytes, DownloadError>>,
+    },
+    SwarmDownload {
+        root: MerkleHash,
+        size: u64,
+        max_price: BtcPerByte,
+        data_tx: UnboundedSender<Result<Bytes, DownloadError>>,
+    },
+    PayWithWork {
+        peer: XorAddr,
+        amount: Btc,
+        result_tx: oneshot::Sender<Result<Btc, PayWithWorkError>>,
+    },
+    StoreWith {
+        host: XorAddr,
+        tree: Arc<MerkleTree>,
+        duration: Duration,
+        price: Btc,
+        installments: u32,
+        result_tx: oneshot::Sender<Result<Receipt, StoreError>>,
+    },
+    Upload {
+        tree: Arc<MerkleTree>,
+        availability: f64,
+        period: Option<Duration>,
+        result_tx: oneshot::Sender<Result<Uploaded, UploadError>>,
+    },
+    GetMutable {
+        id: PublicSignKey,
+        price: Option<Btc>,
+        result_tx: oneshot::Sender<Result<MutableRecord, GetMutableError>>,
+    },
+    PutMutable {
+        record: MutableRecord,
+        result_tx: oneshot::Sender<Result<usize, PutMutableError>>,
+    },
 }
 
-enum UserCommand {
-/*
-    GetMutable {
-        id: PublicSignKey,
-        params: GetMutableParams,
-        result_tx: oneshot::Sender<Vec<u8>>,
-    },
-*/
-}
+impl Daemon {
+    pub fn start() -> Result<(Daemon, Vec<SocketAddr>), DaemonStartError> {
+        let config = Config::load().map_err(DaemonStartError::Config)?;
+        Daemon::start_with_config(&config)
+    }
+
+    pub fn start_with_config(
+        config: &Config,
+    ) -> Result<(Daemon, Vec<SocketAddr>), DaemonStartError> {
+        let (driver, local_addrs, user_command_tx) = Driver::new(config)?;
+        let daemon = Daemon {
+            user_command_tx,
+            node_key: driver.node_keypair.public,
+        };
+        tokio::spawn(driver.infallible());
+        Ok((daemon, local_addrs))
+    }
+
+    // The key we sign our receipts and records with. Peers know us by its address, and it stays
+    // the same from one run to the next.
+    pub fn node_key(&self) -> PublicSignKey {
+        self.node_key
+    }
+
+    // Tells us where to find the peer with node key `key`.
+    pub fn add_peer(&self, key: PublicSignKey, addrs: Vec<SocketAddr>) {
+        let peer = key.to_xor_addr();
+        unwrap!(self.user_command_tx.unbounded_send(UserCommand::AddPeer { peer, addrs }));
+    }
+
+    pub fn add_repo(&self, path: &Path) -> Result<git2::Repository, git2::Error> {
+        git2::Repository::open(path)
+    }
+
+    // Makes `data` available for peers to download, returning the root they can ask for it by.
+    pub fn host(&self, data: Bytes) -> MerkleHash {
+        let tree = Arc::new(MerkleTree::new(data));
+        let root = tree.root();
+        unwrap!(self.user_command_tx.unbounded_send(UserCommand::Host { tree }));
+        root
+    }
+
+    // Stops hosting the content with root `root`, and withdraws our provider records for it.
+    pub fn unhost(&self, root: MerkleHash) {
+        unwrap!(self.user_command_tx.unbounded_send(UserCommand::Unhost { root }));
+    }
+
+    // Finds the peers which say they're hosting the content with root `root`.
+    pub fn find_providers(&self, root: MerkleHash) -> FindProviders {
+        let (result_tx, result_rx) = oneshot::channel();
+        let command = UserCommand::FindProviders { root, result_tx };
+        unwrap!(self.user_command_tx.unbounded_send(command));
+        FindProviders::new(result_rx)
+    }
+
+    // Downloads the content with root `root` from `peer`, starting from leaf `start`, paying
+    // `price` per byte for each leaf that checks out.
+    pub fn download(
+        &self,
+        peer: XorAddr,
+        root: MerkleHash,
+        size: u64,
+        start: u64,
+        price: BtcPerByte,
+    ) -> Download {
+        let end = leaf_count(size);
+        let (data_tx, download) = download_channel(start, end);
+        let command = UserCommand::Download { peer, root, size, start, end, price, data_tx };
+        unwrap!(self.user_command_tx.unbounded_send(command));
+        download
+    }
+
+    // Downloads the content with root `root` from every peer that has it at once, from those
+    // which charge no more than `max_price` per byte.
+    pub fn swarm_download(&self, root: MerkleHash, size: u64, max_price: BtcPerByte) -> Download {
+        let (data_tx, download) = download_channel(0, leaf_count(size));
+        let command = UserCommand::SwarmDownload { root, size, max_price, data_tx };
+        unwrap!(self.user_command_tx.unbounded_send(command));
+        download
+    }
+
+    // Pays `peer` `amount` by doing proof-of-work for them instead of with bitcoin. Resolves to
+    // what the token we paid with was worth, which may be a bit more than `amount`, once the peer
+    // has taken it. Amounts too big to mint in one token are refused.
+    pub fn pay_with_work(&self, peer: XorAddr, amount: Btc) -> PayWithWork {
+        let (result_tx, result_rx) = oneshot::channel();
+        let command = UserCommand::PayWithWork { peer, amount, result_tx };
+        unwrap!(self.user_command_tx.unbounded_send(command));
+        PayWithWork::new(result_rx)
+    }
+
+    // Pays `host` `price` in all to keep `data` for `duration`, in `installments` equal parts as
+    // the contract runs, so that it stays available while we're not. Resolves to the host's
+    // signed receipt once it's agreed. We serve the content ourselves until the host has it.
+    pub fn store_with(
+        &self,
+        host: XorAddr,
+        data: Bytes,
+        duration: Duration,
+        price: Btc,
+        installments: u32,
+    ) -> StoreWith {
+        let tree = Arc::new(MerkleTree::new(data));
+        let (result_tx, result_rx) = oneshot::channel();
+        let command = UserCommand::StoreWith {
+            host, tree, duration, price, installments, result_tx,
+        };
+        unwrap!(self.user_command_tx.unbounded_send(command));
+        StoreWith::new(result_rx)
+    }
+
+    // Takes out contracts with enough hosts that `data` is still available after `period` with a
+    // chance of at least `availability`, for as little as we can, going by the upload settings in
+    // the user's config. If `period` is `None` we use the upload period from the config. Should
+    // some of the hosts refuse, we ask others in their place.
+    pub fn upload(&self, data: Bytes, availability: f64, period: Option<Duration>) -> Upload {
+        let tree = Arc::new(MerkleTree::new(data));
+        let (result_tx, result_rx) = oneshot::channel();
+        let command = UserCommand::Upload { tree, availability, period, result_tx };
+        unwrap!(self.user_command_tx.unbounded_send(command));
+        Upload::new(result_rx)
+    }
+
+    // Gets back an upload from the hosts of its shards, paying `price` per byte, rebuilding it
+    // from whichever shards arrive first.
+    pub fn rebuild(&self, manifest: ShardManifest, price: BtcPerByte) -> Rebuild {
+        let shard_size = manifest.shard_size();
+        let downloads = {
+            manifest.shards
+            .iter()
+            .map(|shard| {
+                (shard.index, self.download(shard.host, shard.root, shard_size, 0, price))
+            })
+            .collect()
+        };
+        Rebuild::new(manifest, downloads)
+    }
+
+    // Looks up the latest version of the mutable data `id`, offering `price` for it. If that's
+    // `None` we pick a price from what peers tend to charge and raise it until someone answers or
+    // we hit the budget in the user's config.
+    pub fn get_mutable(&self, id: PublicSignKey, price: Option<Btc>) -> GetMutable {
+        let (result_tx, result_rx) = oneshot::channel();
+        let command = UserCommand::GetMutable { id, price, result_tx };
+        unwrap!(self.user_command_tx.unbounded_send(command));
+        GetMutable::new(result_rx)
+    }
+
+    // Signs `data` as version `version` of the mutable data belonging to `keypair`, and has the
+    // peers closest to it hold onto it. Peers only take versions newer than what they've got.
+    pub fn put_mutable(&self, keypair: &SignKeypair, version: u64, data: Bytes) -> PutMutable {
+        let expires = unix_time() + MUTABLE_RECORD_LIFETIME.as_secs();
+        let record = MutableRecord::new(keypair, version, data, expires);
+        let (result_tx, result_rx) = oneshot::channel();
+        let command = UserCommand::PutMutable { record, result_tx };
+        unwrap!(self.user_command_tx.unbounded_send(command));
+        PutMutable::new(result_rx)
+    }
+}
+
+impl Driver {
+    fn new(
+        config: &Config,
+    ) -> Result<(Driver, Vec<SocketAddr>, UnboundedSender<UserCommand>), DaemonStartError> {
+        let (user_command_tx, user_command_rx) = mpsc::unbounded();
+        let (peer_event_tx, peer_event_rx) = mpsc::unbounded();
+        let costs = ResourceCosts::new();
+        let (sockets, local_addrs) = {
+            Sockets::bind(config, costs.clone())
+            .map_err(DaemonStartError::Bind)?
+        };
+        let msg_rxs = {
+            sockets
+            .all()
+            .into_iter()
+            .map(|socket| MsgRx::new(socket, costs.clone()))
+            .collect()
+        };
+        let node_keypair = {
+            load_node_key(config.node_key_path().as_ref().map(PathBuf::as_path))
+            .map_err(DaemonStartError::NodeKey)?
+        };
+        let our_info = PeerInfo {
+            addrs: Vec::new(),
+            exp_download_fee: costs.upload().log(),
+            var_download_fee: 0.0,
+            relay_fee: None,
+        };
+        let btc_per_hash = config.btc_per_hash.unwrap_or(DEFAULT_BTC_PER_HASH);
+        let pow_verifier = PowVerifier::open(
+            node_keypair.public.to_xor_addr(),
+            btc_per_hash,
+            config.spent_pow_path(),
+            unix_time(),
+        );
+        let mut driver = Driver {
+            peer_txs: HashMap::new(),
+            peer_infos: HashMap::new(),
+            peer_addrs: HashMap::new(),
+            user_command_rx,
+            sockets,
+            msg_rxs,
+            ledger: Ledger::new(),
+            reputations: Reputations::open(config.reputation_path(), Instant::now()),
+            reflexive_addrs: ReflexiveAddrs::new(local_addrs.clone()),
+            address_queries: FuturesUnordered::new(),
+            transactions: Transactions::new(),
+            fragmenter: Fragmenter::new(),
+            reassembly: Reassembly::new(),
+            retransmit_timer: None,
+            hosted: HashMap::new(),
+            stream_sender: StreamSender::new(),
+            stream_receiver: StreamReceiver::new(),
+            stream_opens: FuturesUnordered::new(),
+            swarms: HashMap::new(),
+            swarm_lookups: FuturesUnordered::new(),
+            swarm_queries: FuturesUnordered::new(),
+            node_keypair,
+            provider_store: ProviderStore::new(),
+            announcements: Announcements::new(),
+            provider_adds: FuturesUnordered::new(),
+            provider_lookups: HashMap::new(),
+            provider_replies: FuturesUnordered::new(),
+            mutable_store: MutableStore::new(),
+            bid_strategy: BidStrategy::new(config),
+            mutable_lookups: HashMap::new(),
+            mutable_replies: FuturesUnordered::new(),
+            mutable_puts: FuturesUnordered::new(),
+            forwards: FuturesUnordered::new(),
+            forwarding: HashSet::new(),
+            resource_monitor: {
+                resource_costs::ResourceMonitor::new(config, costs.clone(), Instant::now())
+            },
+            costs,
+            btc_per_hash,
+            pow_verifier,
+            pow_minter: PowMinter::new(),
+            mintings: FuturesUnordered::new(),
+            pow_payments: FuturesUnordered::new(),
+            contracts: Contracts::open(config.contracts_path(), Instant::now()),
+            content_store: ContentStore::new(config.hosted_dir()),
+            awaiting_fetch: HashMap::new(),
+            contract_proposals: FuturesUnordered::new(),
+            contract_fetches: FuturesUnordered::new(),
+            challenges: FuturesUnordered::new(),
+            upload_planner: UploadPlanner::new(config),
+            shard_encodings: FuturesUnordered::new(),
+            uploads: FuturesUnordered::new(),
+            hole_punches: HashMap::new(),
+            introductions: Introductions::new(),
+            dns_cache: DnsCache::new(),
+            our_info,
+            peer_event_tx,
+            peer_event_rx,
+        };
+        driver.load_hosted();
+        Ok((driver, local_addrs, user_command_tx))
+    }
+
+    fn add_peer(&mut self, xor_addr: XorAddr, peer_info: Arc<PeerInfo>) {
+        let peer_info = self.resolve_domains(xor_addr, peer_info);
+        for addr in &peer_info.addrs {
+            if let AddressKind::Resolved(socket_addr) = addr.kind() {
+                let _ = self.peer_addrs.insert(*socket_addr, xor_addr);
+            }
+        }
+        let new_peer = match self.peer_txs.get(&xor_addr) {
+            Some(peer_tx) => {
+                peer_tx.update_info(peer_info.clone());
+                false
+            },
+            None => {
+                let peer_tx = PeerTx::from_peer_info(
+                    self.sockets.clone(),
+                    xor_addr,
+                    peer_info.clone(),
+                    self.reputations.get(&xor_addr).priority(),
+                    self.node_keypair.clone(),
+                    self.peer_event_tx.clone(),
+                );
+                let _ = self.peer_txs.insert(xor_addr, peer_tx);
+                true
+            },
+        };
+        let _ = self.peer_infos.insert(xor_addr, peer_info);
+        // Let them know what we charge, so they know what to offer us.
+        if new_peer {
+            let btc_per_byte = self.our_info.exp_download_fee.exp();
+            let msg = Msg::SenderDownloadFee { btc_per_byte };
+            self.send_msg(&xor_addr, msg, PUNCH_PROBE_UTILITY, PUNCH_PROBE_UTILITY_DECAY);
+        }
+    }
+
+    // Adds the resolved addresses for any of the peer's domain addresses we have cached, and
+    // starts looking up the rest. Addresses we resolved before are replaced, so a refreshed
+    // lookup gives them a fresh TTL.
+    fn resolve_domains(&mut self, xor_addr: XorAddr, peer_info: Arc<PeerInfo>) -> Arc<PeerInfo> {
+        let now = Instant::now();
+        let mut resolved = Vec::new();
+        for addr in &peer_info.addrs {
+            let domain = match addr.kind() {
+                AddressKind::Domain(domain) => domain,
+                _ => continue,
+            };
+            match self.dns_cache.get(domain, now) {
+                Some((socket_addrs, ttl)) => {
+                    for socket_addr in socket_addrs {
+                        let kind = AddressKind::Resolved(*socket_addr);
+                        resolved.push(Address::new(kind, addr.probability_at(now), ttl));
+                    }
+                },
+                None => self.dns_cache.start_lookup(domain.clone(), xor_addr),
+            }
+        }
+
+        if resolved.is_empty() {
+            return peer_info;
+        }
+        let mut peer_info = (*peer_info).clone();
+        peer_info.addrs.retain(|known| !resolved.iter().any(|addr| addr.kind() == known.kind()));
+        peer_info.addrs.extend(resolved);
+        Arc::new(peer_info)
+    }
+
+    fn poll_dns_lookups(&mut self) {
+        let now = Instant::now();
+        for result in self.dns_cache.poll_lookups() {
+            for xor_addr in result.peers {
+                let mut peer_info = match self.peer_infos.get(&xor_addr) {
+                    Some(peer_info) => (**peer_info).clone(),
+                    None => continue,
+                };
+                if !result.succeeded {
+                    let domain = AddressKind::Domain(result.domain.clone());
+                    peer_info.scale_addr_probability(&domain, DNS_FAILURE_PENALTY, now);
+                    peer_info.addrs.retain(|addr| match addr.kind() {
+                        AddressKind::Domain(..) => addr.probability_at(now) >= MIN_DOMAIN_PROBABILITY,
+                        _ => true,
+                    });
+                    // Stop keeping the records fresh for a peer which has given up on them.
+                    if !peer_info.has_addr(&domain) {
+                        self.dns_cache.remove_peer(&result.domain, &xor_addr);
+                    }
+                }
+                self.add_peer(xor_addr, Arc::new(peer_info));
+            }
+        }
+    }
+
+    // One of the peer drivers couldn't send to `route` because the network told it the address is
+    // unreachable.
+    fn handle_unreachable(&mut self, xor_addr: XorAddr, route: AddressKind) {
+        let mut peer_info = match self.peer_infos.get(&xor_addr) {
+            Some(peer_info) => (**peer_info).clone(),
+            None => return,
+        };
+        peer_info.scale_addr_probability(&route, UNREACHABLE_PENALTY, Instant::now());
+        self.add_peer(xor_addr, Arc::new(peer_info));
+        if let AddressKind::Resolved(..) = route {
+            self.request_introduction(xor_addr, Instant::now());
+        }
+    }
+
+    // We owe `relay` its fee for passing on `len` bytes for us. It charges us on its side.
+    fn pay_relay(&mut self, relay: XorAddr, len: usize) {
+        let relay_fee = match self.peer_infos.get(&relay) {
+            Some(peer_info) => peer_info.relay_fee,
+            None => None,
+        };
+        if let Some(relay_fee) = relay_fee {
+            self.ledger.debit(relay, relay_fee * Byte::from(len));
+        }
+    }
+
+    fn add_peer_addr(&mut self, xor_addr: XorAddr, addr: Address) {
+        let mut peer_info = match self.peer_infos.get(&xor_addr) {
+            Some(peer_info) => (**peer_info).clone(),
+            None => (*PeerInfo::new()).clone(),
+        };
+        peer_info.addrs.retain(|known| known.kind() != addr.kind());
+        peer_info.addrs.push(addr);
+        self.add_peer(xor_addr, Arc::new(peer_info));
+    }
+
+    // Sends a message straight to an address, rather than through a peer's queue. Used for
+    // replies and for talking to addresses we don't know the owner of yet.
+    fn send_to(&mut self, msg: Msg, addr: SocketAddr, utility: Btc, utility_decay: Sec) {
+        let mut data = BytesMut::new();
+        msg.write(&mut data);
+        let packet = OutgoingPacket::new(data.freeze(), addr, utility, utility_decay);
+        let _ = self.sockets.send_dgram(packet);
+    }
+
+    fn send_msg(&mut self, peer: &XorAddr, msg: Msg, utility: Btc, utility_decay: Sec) {
+        let outgoing_msg = OutgoingMsg {
+            msg,
+            utility,
+            utility_time: Instant::now(),
+            utility_decay,
+        };
+        if let Err(e) = self.send_outgoing(*peer, outgoing_msg) {
+            debug!("not sending to {:?}: {}", peer, e);
+        }
+    }
+
+    // Queues a message with the peer's driver, splitting it into fragments first if it's too big
+    // for the path.
+    fn send_outgoing(
+        &mut self,
+        peer: XorAddr,
+        outgoing_msg: OutgoingMsg,
+    ) -> Result<(), MsgTooBig> {
+        if !self.peer_txs.contains_key(&peer) {
+            return Ok(());
+        }
+
+        let data = outgoing_msg.msg.to_bytes();
+        let mtu = self.msg_mtu(&peer);
+        if data.len() <= mtu {
+            let _ = self.peer_txs[&peer].send_message(outgoing_msg);
+            return Ok(());
+        }
+
+        let fragments = {
+            let rto = self.transactions.rto(&peer);
+            let utility = outgoing_msg.utility_decay_at(Instant::now());
+            let utility_decay = outgoing_msg.utility_decay;
+            self.fragmenter.split(peer, data, mtu, utility, utility_decay, rto, Instant::now())?
+        };
+        for fragment in fragments {
+            let _ = self.peer_txs[&peer].send_message(fragment);
+        }
+        Ok(())
+    }
+
+    // The biggest message we can send `peer` in one datagram. If we're reaching it through a
+    // relay, the message gets wrapped up in a `Msg::Relay` on the way, which has to fit too.
+    fn msg_mtu(&self, peer: &XorAddr) -> usize {
+        let mtu = self.fragmenter.mtu(peer);
+        let peer_info = match self.peer_infos.get(peer) {
+            Some(peer_info) => peer_info,
+            None => return mtu,
+        };
+        let sockets = &self.sockets;
+        match peer_info.best_route(Instant::now(), |addr| sockets.can_send_to(addr)) {
+            Some(AddressKind::Relayed { .. }) => mtu - RELAY_OVERHEAD,
+            _ => mtu,
+        }
+    }
+
+    // Sends a request, failing it straight away if it's too big to ever get there.
+    fn send_request(&mut self, peer: XorAddr, outgoing_msg: OutgoingMsg) {
+        let request_id = match outgoing_msg.msg {
+            Msg::Request { request_id, .. } => request_id,
+            _ => unreachable!(),
+        };
+        if let Err(e) = self.send_outgoing(peer, outgoing_msg) {
+            self.transactions.fail(request_id, RequestError::TooBig(e));
+        }
+    }
+
+    // Sends a reply straight back to where the message we're replying to came from if we can, or
+    // through the peer's driver if it needs splitting up or came through a relay.
+    fn reply(
+        &mut self,
+        peer: XorAddr,
+        msg: Msg,
+        addr: Option<SocketAddr>,
+        utility: Btc,
+        utility_decay: Sec,
+    ) {
+        match addr {
+            Some(addr) if msg.to_bytes().len() <= MAX_MSG_LEN => {
+                self.send_to(msg, addr, utility, utility_decay);
+            },
+            _ => self.send_msg(&peer, msg, utility, utility_decay),
+        }
+    }
+
+    // Sends a request to `peer`, offering to pay `offer` for the reply. It's retransmitted until
+    // we get a reply or it stops being worth the upload costs.
+    fn request(&mut self, peer: XorAddr, msg: Msg, offer: Offer, utility_decay: Sec) -> Response {
+        let (outgoing_msg, response) = {
+            self.transactions.start(peer, &msg, offer, utility_decay, Instant::now())
+        };
+        self.send_request(peer, outgoing_msg);
+        response
+    }
+
+    // Works out what's most profitable to do about a request, given that we could answer it with
+    // `reply` at `cost` on top of uploading it, and does it. Requests about a key can also be
+    // answered with closer peers, or passed on to one as `forward`. Returns whether we answered
+    // with `reply`, so that the caller can follow through.
+    fn respond(
+        &mut self,
+        request: &IncomingRequest,
+        reply: Msg,
+        cost: Btc,
+        key: Option<XorAddr>,
+        forward: Option<Msg>,
+    ) -> bool {
+        let peers: Vec<(XorAddr, Sec)> = match key {
+            Some(..) => {
+                self.peer_txs
+                .keys()
+                .filter(|peer| **peer != request.peer)
+                .map(|peer| (*peer, Sec::from(self.transactions.rto(peer))))
+                .collect()
+            },
+            None => Vec::new(),
+        };
+        let decision = decide(&RequestContext {
+            offer: &request.offer,
+            our_addr: self.node_keypair.public.to_xor_addr(),
+            key,
+            answer: Some((&reply, cost)),
+            forward: forward.as_ref(),
+            peers,
+            costs: self.costs.get(),
+        });
+        debug!(
+            "request {} from {:?}: {}, expecting {:e} ({})",
+            request.request_id,
+            request.peer,
+            decision.action.name(),
+            decision.expected_profit.val(),
+            decision.reason
+        );
+
+        match decision.action {
+            Action::Answer => {
+                self.send_answer(request, reply);
+                true
+            },
+            Action::Forward { peer, offer } => {
+                self.forward(request, peer, offer, unwrap!(forward));
+                false
+            },
+            Action::Contacts(peers) => {
+                let reply = Msg::Contacts { key: unwrap!(key), peers };
+                self.send_answer(request, reply);
+                false
+            },
+            Action::Ignore => false,
+        }
+    }
+
+    // Replies to a request, charging the peer what their offer says it's worth unless this is a
+    // retransmission we've already charged for. The reply says how long we held the request so
+    // that the peer values it the same as we do.
+    fn send_answer(&mut self, request: &IncomingRequest, reply: Msg) {
+        let IncomingRequest { peer, request_id, ref offer, addr, received } = *request;
+        let payload = reply.to_bytes();
+        if payload.len() > MAX_PAYLOAD_LEN {
+            debug!("not answering request {} from {:?}: reply too big", request_id, peer);
+            return;
+        }
+        let now = Instant::now();
+        let held = Sec::from(now.duration_since(received));
+        let (delay, first) = self.transactions.answer(peer, request_id, held, now);
+        let payout = offer.payout(&ReplyTraits::of(&reply, delay));
+        if first {
+            self.ledger.credit(peer, payout);
+        }
+        let response = Msg::Response { request_id, delay, payload };
+        self.reply(peer, response, addr, payout, REPLY_UTILITY_DECAY);
+    }
+
+    // Passes a request on to `next`, unless we're already waiting on them for it.
+    fn forward(&mut self, request: &IncomingRequest, next: XorAddr, offer: Offer, msg: Msg) {
+        if !self.forwarding.insert((request.peer, request.request_id)) {
+            return;
+        }
+        let request = request.clone();
+        let forwarded = {
+            self.request(next, msg, offer, REPLY_UTILITY_DECAY)
+            .then(move |res| Ok((request, res)))
+            .into_send_boxed()
+        };
+        self.forwards.push(forwarded);
+    }
+
+    fn poll_forwards(&mut self) {
+        loop {
+            let (request, res) = match self.forwards.poll().void_unwrap() {
+                Async::Ready(Some(forwarded)) => forwarded,
+                Async::Ready(None) | Async::NotReady => break,
+            };
+            let _ = self.forwarding.remove(&(request.peer, request.request_id));
+            // We've paid for the reply, now it's ours to sell on.
+            if let Ok(reply) = res {
+                self.send_answer(&request, reply);
+            }
+        }
+    }
+
+    fn handle_request(&mut self, request: IncomingRequest, msg: Msg) {
+        match msg {
+            Msg::SenderGetAddress => {
+                // The relay's address is no use to anyone.
+                let addr = match request.addr {
+                    Some(addr) => addr,
+                    None => return,
+                };
+                let reply = Msg::SenderAddress { addr };
+                let _ = self.respond(&request, reply, Btc(0.0), None, None);
+            },
+            Msg::StreamOpen { stream_id, root, size, start, end, price } => {
+                let tree = match self.hosted.get(&root) {
+                    Some(tree) if tree.size() == size => tree.clone(),
+                    _ => return,
+                };
+                if start >= end || end > tree.leaf_count() {
+                    return;
+                }
+                // We're not going to upload at a loss, unless it's to a host we've paid to keep the
+                // content.
+                let contracted = self.contracts.is_host_of(&request.peer, &root);
+                if price < self.costs.upload() && !contracted {
+                    return;
+                }
+                if !self.stream_sender.has_room_for(&request.peer, stream_id) {
+                    return;
+                }
+                let reply = Msg::StreamOpened { stream_id };
+                if self.respond(&request, reply, Btc(0.0), None, None) {
+                    let (peer, now) = (request.peer, Instant::now());
+                    self.stream_sender.open(peer, stream_id, tree, start, end, price, now);
+                }
+            },
+            Msg::ProviderAdd { record } => {
+                let now = unix_time();
+                if record.verify(now).is_err() || !self.provider_store.has_room_for(&record) {
+                    return;
+                }
+                let reply = Msg::ProviderAdded { root: record.root };
+                let cost = record.storage_cost(now, self.costs.memory());
+                let key = record.root.to_xor_addr();
+                if self.respond(&request, reply, cost, Some(key), None) {
+                    // If it's stale we've already got it, they must have missed our reply.
+                    let _ = self.provider_store.add(record, now);
+                }
+            },
+            Msg::ContractPropose { terms } => {
+                // If we've already agreed they must have missed our receipt.
+                let existing = self.contracts.hosting_receipt(&request.peer, terms.contract_id);
+                if let Some(receipt) = existing.cloned() {
+                    let reply = Msg::ContractAccepted { receipt };
+                    let _ = self.respond(&request, reply, Btc(0.0), None, None);
+                    return;
+                }
+                // The contract price pays for the hosting, the offer just for our reply.
+                let hosting_cost = terms.hosting_cost(&self.costs.get());
+                if terms.validate().is_err() || terms.price < hosting_cost {
+                    return;
+                }
+                let receipt = {
+                    Receipt::new(&self.node_keypair, request.peer, terms.clone(), unix_time())
+                };
+                let reply = Msg::ContractAccepted { receipt: receipt.clone() };
+                if self.respond(&request, reply, Btc(0.0), None, None) {
+                    self.contracts.add_hosting(receipt, Instant::now());
+                    self.fetch_contract_content(request.peer, terms);
+                }
+            },
+            Msg::ContractChallenge { contract_id, index } => {
+                let root = match self.contracts.hosting_receipt(&request.peer, contract_id) {
+                    Some(receipt) => receipt.terms.root,
+                    None => return,
+                };
+                let tree = match self.hosted.get(&root) {
+                    Some(tree) if index < tree.leaf_count() => tree.clone(),
+                    _ => return,
+                };
+                let proof = tree.proof(index, index);
+                let data = tree.leaf(index);
+                let reply = Msg::ContractProof { contract_id, index, proof, data };
+                let _ = self.respond(&request, reply, Btc(0.0), None, None);
+            },
+            Msg::ProviderQuery { root } => {
+                let records = self.provider_store.lookup(&root, unix_time());
+                let reply = Msg::Providers { root, records };
+                let forward = Msg::ProviderQuery { root };
+                let key = root.to_xor_addr();
+                let _ = self.respond(&request, reply, Btc(0.0), Some(key), Some(forward));
+            },
+            Msg::MutablePut { record } => {
+                let now = unix_time();
+                if record.verify(now).is_err() || !self.mutable_store.has_room_for(&record) {
+                    return;
+                }
+                let reply = Msg::MutableStored { id: record.id, version: record.version };
+                let cost = record.storage_cost(now, self.costs.memory());
+                let key = record.id.to_xor_addr();
+                if self.respond(&request, reply, cost, Some(key), None) {
+                    // If it's stale we've already got it, they must have missed our reply.
+                    let _ = self.mutable_store.add(record, now);
+                }
+            },
+            Msg::MutableQuery { id } => {
+                let record = self.mutable_store.get(&id, unix_time());
+                let reply = Msg::Mutable { id, record };
+                let forward = Msg::MutableQuery { id };
+                let key = id.to_xor_addr();
+                let _ = self.respond(&request, reply, Btc(0.0), Some(key), Some(forward));
+            },
+            Msg::PowPayment { token } => {
+                let peer = request.peer;
+                match self.pow_verifier.redeem(peer, &token, unix_time()) {
+                    Ok(paid) => {
+                        let debt_limit = self.reputations.get(&peer).debt_limit();
+                        let on_time = self.ledger.balance(&peer) <= debt_limit;
+                        self.ledger.debit(peer, paid);
+                        self.reputations.record_payment(peer, paid, on_time);
+                        // Once a peer's squared up, we let go of whatever small change it still
+                        // owes.
+                        let forgiveness = self.reputations.get(&peer).forgiveness();
+                        let _ = self.ledger.forgive(peer, forgiveness);
+                        self.update_priority(peer);
+                    },
+                    // They've already paid us with it, they must have missed our reply.
+                    Err(PowError::Replayed) => (),
+                    Err(e) => {
+                        debug!("rejected proof-of-work from {:?}: {}", peer, e);
+                        return;
+                    },
+                }
+                let reply = Msg::PowAccepted { nonce: token.nonce };
+                let _ = self.respond(&request, reply, Btc(0.0), None, None);
+            },
+            Msg::StreamQuery { root, size } => {
+                let hosted = match self.hosted.get(&root) {
+                    Some(tree) => tree.size() == size,
+                    None => false,
+                };
+                // The swarm asking opens its stream at this price, so it'd better be enough.
+                let reply = Msg::StreamHave { root, hosted, price: self.costs.upload() };
+                let _ = self.respond(&request, reply, Btc(0.0), None, None);
+            },
+            // Not something we answer.
+            _ => (),
+        }
+    }
+
+    fn open_stream(&mut self, request: StreamRequest) {
+        let StreamRequest { peer, stream_id, msg } = request;
+        let offer = Offer::fixed(STREAM_CONTROL_UTILITY);
+        let opening = {
+            self.request(peer, msg, offer, STREAM_CONTROL_UTILITY_DECAY)
+            .then(move |res| Ok((peer, stream_id, res)))
+            .into_send_boxed()
+        };
+        self.stream_opens.push(opening);
+    }
+
+    // The peers we know of whose addresses are closest to `target`.
+    fn closest_peers(&self, target: XorAddr, count: usize) -> Vec<XorAddr> {
+        let mut peers: Vec<XorAddr> = self.peer_txs.keys().cloned().collect();
+        peers.sort_by_key(|peer| *peer ^ target);
+        peers.truncate(count);
+        peers
+    }
+
+    // Tells the peers closest to some content that we're hosting it at `addrs`, or that we've
+    // stopped if there aren't any.
+    fn announce(&mut self, root: MerkleHash, addrs: Vec<SocketAddr>) {
+        let now = unix_time();
+        let expires = now + PROVIDER_RECORD_LIFETIME.as_secs();
+        let record = ProviderRecord::new(&self.node_keypair, root, addrs, expires);
+        // Pay for the storage, and a bit more to make it worth their while.
+        let offer = Offer::fixed(record.storage_cost(now, self.costs.memory()) + PROVIDER_UTILITY);
+        for peer in self.closest_peers(root.to_xor_addr(), PROVIDER_REPLICATION) {
+            let msg = Msg::ProviderAdd { record: record.clone() };
+            let adding = {
+                self.request(peer, msg, offer.clone(), PROVIDER_UTILITY_DECAY)
+                .then(|_| Ok(()))
+                .into_send_boxed()
+            };
+            self.provider_adds.push(adding);
+        }
+    }
+
+    // Announces whatever content is due for it. We can't tell anyone where to find us until we
+    // know our public addresses, so until then we wait for the next refresh.
+    fn refresh_announcements(&mut self, now: Instant) {
+        let addrs: Vec<SocketAddr> = {
+            self.our_info.addrs
+            .iter()
+            .filter_map(|addr| match addr.kind() {
+                AddressKind::Resolved(socket_addr) => Some(*socket_addr),
+                _ => None,
+            })
+            .take(MAX_PROVIDER_ADDRS)
+            .collect()
+        };
+        for root in self.announcements.due(now) {
+            if !addrs.is_empty() && self.hosted.contains_key(&root) {
+                self.announce(root, addrs.clone());
+            }
+        }
+    }
+
+    fn poll_provider_adds(&mut self) {
+        loop {
+            match self.provider_adds.poll().void_unwrap() {
+                Async::Ready(Some(())) => (),
+                Async::Ready(None) | Async::NotReady => break,
+            }
+        }
+    }
+
+    fn find_providers(
+        &mut self,
+        root: MerkleHash,
+        result_tx: oneshot::Sender<Vec<ProviderRecord>>,
+    ) {
+        let local = self.provider_store.lookup(&root, unix_time());
+        let peers = self.closest_peers(root.to_xor_addr(), PROVIDER_REPLICATION);
+        let lookup = PendingLookup::new(root, local, &peers, result_tx);
+        if peers.is_empty() {
+            lookup.finish();
+            return;
+        }
+
+        let lookup_id = rand::random();
+        for peer in peers {
+            self.query_providers(lookup_id, root, peer);
+        }
+        let _ = self.provider_lookups.insert(lookup_id, lookup);
+    }
+
+    fn query_providers(&mut self, lookup_id: u64, root: MerkleHash, peer: XorAddr) {
+        // Pay in full for providers, but only a little for being told there aren't any or who to
+        // ask instead.
+        let reward = Reward::TimeDecay {
+            decay: PROVIDER_UTILITY_DECAY,
+            reward: Box::new(Reward::IfData {
+                then: Box::new(Reward::Fixed(PROVIDER_UTILITY)),
+                otherwise: Box::new(Reward::Fixed(PROVIDER_UTILITY * 0.1)),
+            }),
+        };
+        let offer = unwrap!(Offer::new(reward));
+        let msg = Msg::ProviderQuery { root };
+        let reply = {
+            self.request(peer, msg, offer, PROVIDER_UTILITY_DECAY)
+            .then(move |res| Ok((lookup_id, res)))
+            .into_send_boxed()
+        };
+        self.provider_replies.push(reply);
+    }
+
+    fn poll_provider_replies(&mut self) {
+        loop {
+            let (lookup_id, res) = match self.provider_replies.poll().void_unwrap() {
+                Async::Ready(Some(reply)) => reply,
+                Async::Ready(None) | Async::NotReady => break,
+            };
+            let (root, to_ask, finished) = {
+                let lookup = match self.provider_lookups.get_mut(&lookup_id) {
+                    Some(lookup) => lookup,
+                    None => continue,
+                };
+                let root = lookup.root();
+                let (records, contacts) = match res {
+                    Ok(Msg::Providers { records, .. }) => (records, Vec::new()),
+                    Ok(Msg::Contacts { key, peers }) if key == root.to_xor_addr() => {
+                        (Vec::new(), peers)
+                    },
+                    _ => (Vec::new(), Vec::new()),
+                };
+                let to_ask = lookup.follow(contacts);
+                (root, to_ask, lookup.add(records, unix_time()))
+            };
+            for peer in to_ask {
+                self.query_providers(lookup_id, root, peer);
+            }
+            if finished {
+                unwrap!(self.provider_lookups.remove(&lookup_id)).finish();
+            }
+        }
+    }
+
+    fn get_mutable(
+        &mut self,
+        id: PublicSignKey,
+        price: Option<Btc>,
+        result_tx: oneshot::Sender<Result<MutableRecord, GetMutableError>>,
+    ) {
+        let bid = {
+            let peer_infos = self.peer_infos.values().map(|peer_info| &**peer_info);
+            self.bid_strategy.first_bid(price, peer_infos, self.costs.upload())
+        };
+        let local = self.mutable_store.get(&id, unix_time());
+        let lookup = PendingGetMutable::new(id, local, bid, price.is_some(), result_tx);
+        self.start_mutable_round(rand::random(), lookup);
+    }
+
+    // Asks the peers closest to some mutable data for it, offering what the lookup's bidding.
+    fn start_mutable_round(&mut self, lookup_id: u64, mut lookup: PendingGetMutable) {
+        let (id, bid) = (lookup.id(), *lookup.bid());
+        let peers = self.closest_peers(id.to_xor_addr(), PROVIDER_REPLICATION);
+        if !lookup.start_round(&peers) {
+            lookup.finish();
+            return;
+        }
+        for peer in peers {
+            self.query_mutable(lookup_id, id, bid, peer);
+        }
+        let _ = self.mutable_lookups.insert(lookup_id, lookup);
+    }
+
+    fn query_mutable(&mut self, lookup_id: u64, id: PublicSignKey, bid: Bid, peer: XorAddr) {
+        let msg = Msg::MutableQuery { id };
+        let reply = {
+            self.request(peer, msg, bid.offer(), bid.decay)
+            .then(move |res| Ok((lookup_id, res)))
+            .into_send_boxed()
+        };
+        self.mutable_replies.push(reply);
+    }
+
+    fn poll_mutable_replies(&mut self) {
+        loop {
+            let (lookup_id, res) = match self.mutable_replies.poll().void_unwrap() {
+                Async::Ready(Some(reply)) => reply,
+                Async::Ready(None) | Async::NotReady => break,
+            };
+            let (id, bid, to_ask, finished) = {
+                let lookup = match self.mutable_lookups.get_mut(&lookup_id) {
+                    Some(lookup) => lookup,
+                    None => continue,
+                };
+                let id = lookup.id();
+                let (record, contacts) = match res {
+                    Ok(Msg::Mutable { record, .. }) => (record, Vec::new()),
+                    Ok(Msg::Contacts { key, peers }) if key == id.to_xor_addr() => (None, peers),
+                    _ => (None, Vec::new()),
+                };
+                let to_ask = lookup.follow(contacts);
+                (id, *lookup.bid(), to_ask, lookup.add(record, unix_time()))
+            };
+            for peer in to_ask {
+                self.query_mutable(lookup_id, id, bid, peer);
+            }
+            if finished {
+                let lookup = unwrap!(self.mutable_lookups.remove(&lookup_id));
+                if let Some(lookup) = lookup.next_round(&self.bid_strategy) {
+                    self.start_mutable_round(lookup_id, lookup);
+                }
+            }
+        }
+    }
+
+    // Keeps a new version of some mutable data ourselves, and pays the peers closest to it to
+    // keep it too.
+    fn put_mutable(
+        &mut self,
+        record: MutableRecord,
+        result_tx: oneshot::Sender<Result<usize, PutMutableError>>,
+    ) {
+        let now = unix_time();
+        if let Err(e) = record.verify(now) {
+            let _ = result_tx.send(Err(PutMutableError::InvalidRecord(e)));
+            return;
+        }
+        let _ = self.mutable_store.add(record.clone(), now);
+
+        // Pay for the storage, and a bit more to make it worth their while.
+        let offer = Offer::fixed(record.storage_cost(now, self.costs.memory()) + PROVIDER_UTILITY);
+        let mut puts = Vec::new();
+        for peer in self.closest_peers(record.id.to_xor_addr(), PROVIDER_REPLICATION) {
+            let msg = Msg::MutablePut { record: record.clone() };
+            let put = {
+                self.request(peer, msg, offer.clone(), PROVIDER_UTILITY_DECAY)
+                .then(|res| Ok::<_, Void>(res))
+            };
+            puts.push(put);
+        }
+        let putting = {
+            future::join_all(puts)
+            .map(move |replies| {
+                let stored = {
+                    replies
+                    .into_iter()
+                    .filter(|reply| match reply {
+                        Ok(Msg::MutableStored { .. }) => true,
+                        _ => false,
+                    })
+                    .count()
+                };
+                let res = if stored > 0 { Ok(stored) } else { Err(PutMutableError::NotStored) };
+                let _ = result_tx.send(res);
+            })
+            .into_send_boxed()
+        };
+        self.mutable_puts.push(putting);
+    }
+
+    fn poll_mutable_puts(&mut self) {
+        loop {
+            match self.mutable_puts.poll().void_unwrap() {
+                Async::Ready(Some(())) => (),
+                Async::Ready(None) | Async::NotReady => break,
+            }
+        }
+    }
+
+    // Looks up who provides the content a new swarm is after.
+    fn start_swarm(&mut self, swarm: Swarm) {
+        let swarm_id = rand::random();
+        let (result_tx, result_rx) = oneshot::channel();
+        self.find_providers(swarm.root(), result_tx);
+        let lookup = {
+            result_rx
+            .then(move |res| Ok((swarm_id, res.unwrap_or_default())))
+            .into_send_boxed()
+        };
+        self.swarm_lookups.push(lookup);
+        let _ = self.swarms.insert(swarm_id, swarm);
+    }
+
+    // Asks the providers each swarm's found whether they still have the content, and what
+    // they'll charge.
+    fn poll_swarm_lookups(&mut self) {
+        let our_addr = self.node_keypair.public.to_xor_addr();
+        loop {
+            let (swarm_id, records) = match self.swarm_lookups.poll().void_unwrap() {
+                Async::Ready(Some(found)) => found,
+                Async::Ready(None) | Async::NotReady => break,
+            };
+            let (root, size) = match self.swarms.get(&swarm_id) {
+                Some(swarm) => (swarm.root(), swarm.size()),
+                None => continue,
+            };
+            let mut providers = Vec::new();
+            for record in records {
+                if record.provider == our_addr {
+                    continue;
+                }
+                for addr in record.addrs {
+                    let kind = AddressKind::Resolved(normalize_addr(addr));
+                    let addr = Address::new(kind, 1.0, KNOWN_ADDR_LIFETIME);
+                    self.add_peer_addr(record.provider, addr);
+                }
+                providers.push(record.provider);
+            }
+            unwrap!(self.swarms.get_mut(&swarm_id)).querying(providers.len());
+
+            let reward = Reward::IfData {
+                then: Box::new(Reward::Fixed(STREAM_CONTROL_UTILITY)),
+                otherwise: Box::new(Reward::Fixed(STREAM_CONTROL_UTILITY * 0.1)),
+            };
+            let offer = unwrap!(Offer::new(reward));
+            for peer in providers {
+                let msg = Msg::StreamQuery { root, size };
+                let query = {
+                    self.request(peer, msg, offer.clone(), STREAM_CONTROL_UTILITY_DECAY)
+                    .then(move |res| Ok((swarm_id, peer, res)))
+                    .into_send_boxed()
+                };
+                self.swarm_queries.push(query);
+            }
+        }
+    }
+
+    fn poll_swarm_queries(&mut self) {
+        loop {
+            let (swarm_id, peer, res) = match self.swarm_queries.poll().void_unwrap() {
+                Async::Ready(Some(answer)) => answer,
+                Async::Ready(None) | Async::NotReady => break,
+            };
+            let swarm = match self.swarms.get_mut(&swarm_id) {
+                Some(swarm) => swarm,
+                None => continue,
+            };
+            // What they've said they want per byte.
+            let price = match res {
+                Ok(Msg::StreamHave { root, hosted: true, price }) if root == swarm.root() => {
+                    Some(price)
+                },
+                _ => None,
+            };
+            swarm.query_answered(peer, price);
+        }
+    }
+
+    // Collects the leaves each swarm's been sent and opens streams for its idle peers, until
+    // there's nothing more to do.
+    fn poll_swarms(&mut self, now: Instant) {
+        let download_cost = self.costs.download();
+        let swarm_ids: Vec<u64> = self.swarms.keys().cloned().collect();
+        for swarm_id in swarm_ids {
+            loop {
+                let (assignments, root, size) = {
+                    let swarm = unwrap!(self.swarms.get_mut(&swarm_id));
+                    if swarm.poll(now).is_ready() {
+                        let _ = self.swarms.remove(&swarm_id);
+                        break;
+                    }
+                    (swarm.assign(download_cost), swarm.root(), swarm.size())
+                };
+                if assignments.is_empty() {
+                    if self.swarms[&swarm_id].is_stuck() {
+                        unwrap!(self.swarms.remove(&swarm_id)).fail();
+                    }
+                    break;
+                }
+                for assignment in assignments {
+                    let RangeAssignment { peer, start, end, price } = assignment;
+                    let (data_tx, download) = download_channel(start, end);
+                    let request = {
+                        self.stream_receiver
+                        .open(peer, root, size, start, end, price, data_tx, now)
+                    };
+                    self.open_stream(request);
+                    let swarm = unwrap!(self.swarms.get_mut(&swarm_id));
+                    swarm.range_opened(assignment, download, now);
+                }
+            }
+        }
+    }
+
+    fn poll_stream_opens(&mut self) {
+        loop {
+            match self.stream_opens.poll().void_unwrap() {
+                Async::Ready(Some((peer, stream_id, res))) => {
+                    if res.is_err() {
+                        self.stream_receiver.open_failed(peer, stream_id);
+                    }
+                },
+                Async::Ready(None) | Async::NotReady => break,
+            }
+        }
+    }
+
+    fn handle_address_reply(&mut self, peer: XorAddr, res: Result<Msg, RequestError>) {
+        let reported = match res {
+            Ok(Msg::SenderAddress { addr }) => addr,
+            _ => return,
+        };
+        self.reflexive_addrs.add_report(peer, reported);
+        let peer_addr = match self.peer_infos.get(&peer) {
+            Some(peer_info) => peer_info.best_addr(Instant::now()),
+            None => None,
+        };
+        if let Some(local_ip) = peer_addr.and_then(|peer_addr| local_ip_for(&peer_addr)) {
+            self.reflexive_addrs.add_local_ip(local_ip);
+        }
+        let had_addrs = !self.our_info.addrs.is_empty();
+        self.our_info.addrs = self.reflexive_addrs.public_addrs();
+        // Now we can tell people where to find the content we've been sitting on.
+        if !had_addrs && !self.our_info.addrs.is_empty() {
+            self.announcements.refresh_all(Instant::now());
+        }
+        self.update_relay_fee();
+    }
+
+    // Has the minter find a token worth at least `amount` to `peer`, to pay them with once it's
+    // found.
+    fn pay_with_work(
+        &mut self,
+        peer: XorAddr,
+        amount: Btc,
+        result_tx: oneshot::Sender<Result<Btc, PayWithWorkError>>,
+    ) {
+        let difficulty = match pow_difficulty_for(amount, self.btc_per_hash) {
+            Some(difficulty) => difficulty,
+            None => {
+                let _ = result_tx.send(Err(PayWithWorkError::TooMuch));
+                return;
+            },
+        };
+        // Leave the peer some leeway in case their clock's ahead of ours.
+        let expires = unix_time() + POW_TOKEN_LIFETIME.as_secs() / 2;
+        // The token's bound to both our node keys, which is how the peer knows it's from us.
+        let creditor = peer;
+        let debtor = self.node_keypair.public.to_xor_addr();
+        let token_rx = match self.pow_minter.mint(creditor, debtor, difficulty, expires) {
+            Some(token_rx) => token_rx,
+            None => {
+                let _ = result_tx.send(Err(PayWithWorkError::Busy));
+                return;
+            },
+        };
+        let payment = PendingPowPayment { peer, result_tx };
+        let minting = {
+            token_rx
+            .then(move |res| Ok((payment, res.unwrap_or(None))))
+            .into_send_boxed()
+        };
+        self.mintings.push(minting);
+    }
+
+    // Sends the tokens we've found. They're resent until the peer acknowledges them.
+    fn poll_mintings(&mut self) {
+        loop {
+            let (payment, token) = match self.mintings.poll().void_unwrap() {
+                Async::Ready(Some(minting)) => minting,
+                Async::Ready(None) | Async::NotReady => break,
+            };
+            let token = match token {
+                Some(token) => token,
+                None => {
+                    let _ = payment.result_tx.send(Err(PayWithWorkError::Expired));
+                    continue;
+                },
+            };
+            let msg = Msg::PowPayment { token };
+            let offer = Offer::fixed(POW_ACK_UTILITY);
+            let reply = {
+                self.request(payment.peer, msg, offer, POW_PAYMENT_UTILITY_DECAY)
+                .then(move |res| Ok((payment, token, res)))
+                .into_send_boxed()
+            };
+            self.pow_payments.push(reply);
+        }
+    }
+
+    // Counts a payment as made once the peer's acknowledged the token.
+    fn poll_pow_payments(&mut self) {
+        loop {
+            let (payment, token, res) = match self.pow_payments.poll().void_unwrap() {
+                Async::Ready(Some(reply)) => reply,
+                Async::Ready(None) | Async::NotReady => break,
+            };
+            match res {
+                Ok(Msg::PowAccepted { nonce }) if nonce == token.nonce => {
+                    let paid = self.btc_per_hash * token.expected_hashes();
+                    self.ledger.credit(payment.peer, paid);
+                    let _ = payment.result_tx.send(Ok(paid));
+                },
+                _ => {
+                    let _ = payment.result_tx.send(Err(PayWithWorkError::Refused));
+                },
+            }
+        }
+    }
+
+    fn propose_contract(
+        &mut self,
+        host: XorAddr,
+        tree: Arc<MerkleTree>,
+        duration: Duration,
+        price: Btc,
+        installments: u32,
+        result_tx: oneshot::Sender<Result<Receipt, StoreError>>,
+    ) {
+        let terms = ContractTerms {
+            contract_id: rand::random(),
+            root: tree.root(),
+            size: tree.size(),
+            duration: duration.as_secs(),
+            price,
+            installments,
+        };
+        if let Err(e) = terms.validate() {
+            let _ = result_tx.send(Err(StoreError::InvalidTerms(e)));
+            return;
+        }
+        // The host fetches the content from us, so we serve it until it has, unless we were
+        // hosting it anyway.
+        let root = tree.root();
+        if self.awaiting_fetch.contains_key(&root) || !self.hosted.contains_key(&root) {
+            let mut hosts = self.awaiting_fetch.remove(&root).unwrap_or_default();
+            let _ = hosts.insert(host);
+            let _ = self.awaiting_fetch.insert(root, hosts);
+            let _ = self.hosted.insert(root, tree);
+        }
+        let msg = Msg::ContractPropose { terms: terms.clone() };
+        let offer = Offer::fixed(CONTRACT_REPLY_UTILITY);
+        let proposed = ProposedContract { host, terms, result_tx };
+        let proposal = {
+            self.request(host, msg, offer, CONTRACT_REPLY_UTILITY_DECAY)
+            .then(move |res| Ok((proposed, res)))
+            .into_send_boxed()
+        };
+        self.contract_proposals.push(proposal);
+    }
+
+    // Plans an upload across the peers we know and proposes contracts to the hosts in the plan.
+    fn upload(
+        &mut self,
+        tree: Arc<MerkleTree>,
+        availability: f64,
+        period: Option<Duration>,
+        result_tx: oneshot::Sender<Result<Uploaded, UploadError>>,
+    ) {
+        let period = match period {
+            Some(period) => Sec::from(period),
+            None => self.upload_planner.default_period(),
+        };
+        let candidates = self.host_candidates(period);
+        let plan = {
+            self.upload_planner
+            .plan(tree.size(), period, &candidates, availability, MAX_DATA_SHARDS)
+        };
+        let plan = match plan {
+            Ok(plan) => plan,
+            Err(e) => {
+                let _ = result_tx.send(Err(UploadError::Plan(e)));
+                return;
+            },
+        };
+
+        let job = UploadJob {
+            root: tree.root(),
+            size: tree.size(),
+            needed: plan.needed,
+            period,
+            target: availability,
+            limit: plan.limit,
+            shards: Vec::new(),
+            stored: Vec::new(),
+            asked: HashSet::new(),
+            result_tx,
+        };
+        // The shards are coded on a thread of their own, so that a big upload doesn't hold up
+        // everything else.
+        let (shards_tx, shards_rx) = oneshot::channel();
+        let (needed, shard_count) = (plan.needed, plan.hosts.len());
+        let _ = thread::spawn(move || {
+            let _ = shards_tx.send(encode_shards(tree, needed, shard_count));
+        });
+        let hosts = plan.hosts;
+        let encoding = {
+            shards_rx
+            .then(move |res| Ok((job, hosts, res.unwrap_or_default())))
+            .into_send_boxed()
+        };
+        self.shard_encodings.push(encoding);
+    }
 
-pub struct GetMutable;
-impl Future for GetMutable {
-    type Item = Bytes;
-    type Error = !;
-    fn poll(&mut self) -> Result<Async<Bytes>, !> {
-        unimplemented!()
+    // Offers the shards of uploads which have finished coding to the hosts we planned them for.
+    fn poll_shard_encodings(&mut self) {
+        loop {
+            let (mut job, hosts, shards) = match self.shard_encodings.poll().void_unwrap() {
+                Async::Ready(Some(encoded)) => encoded,
+                Async::Ready(None) | Async::NotReady => break,
+            };
+            // The coding thread only hangs up without sending if it panicked.
+            if shards.len() != hosts.len() {
+                job.finish();
+                continue;
+            }
+            job.shards = shards;
+            let placements = hosts.into_iter().enumerate().collect();
+            self.place_shards(job, placements);
+        }
     }
-}
 
-impl Daemon {
-    pub fn start() -> Result<(Daemon, SocketAddr), DaemonStartError> {
-        unimplemented!()
-        /*
-        let (driver, addr, user_command_tx) = Driver::new()?;
-        let daemon = Daemon {
-            user_command_tx,
+    // What the peers we know are likely to want to host each byte for `period`, and how likely
+    // they are to still have it at the end.
+    fn host_candidates(&self, period: Sec) -> Vec<HostCandidate> {
+        let costs = self.costs.get();
+        self.peer_infos
+        .iter()
+        .map(|(peer, info)| HostCandidate {
+            host: *peer,
+            price: hosting_price(info, period, &costs),
+            reliability: host_reliability(&self.reputations.get(peer), period),
+        })
+        .collect()
+    }
+
+    // Proposes contracts to keep the shards of an upload to the hosts we've picked for them.
+    fn place_shards(&mut self, mut job: UploadJob, placements: Vec<(usize, HostCandidate)>) {
+        let installments = (job.period / UPLOAD_INSTALLMENT_PERIOD) as u32;
+        let installments = cmp::min(cmp::max(installments, 1), MAX_INSTALLMENTS);
+        let duration = Duration::from(job.period);
+        let shard_bytes = Byte(shard_size(job.size, job.needed) as f64);
+        let mut stores = Vec::new();
+        for (index, candidate) in placements {
+            let (store_tx, store_rx) = oneshot::channel();
+            let host = candidate.host;
+            let _ = job.asked.insert(host);
+            let price = candidate.price * shard_bytes;
+            let shard = job.shards[index].clone();
+            self.propose_contract(host, shard, duration, price, installments, store_tx);
+            let reliability = candidate.reliability;
+            let store = {
+                StoreWith::new(store_rx)
+                .then(move |res| {
+                    let stored = res.ok().map(|receipt| {
+                        ShardStored { index, receipt, reliability }
+                    });
+                    Ok::<_, Void>(stored)
+                })
+            };
+            stores.push(store);
+        }
+        let uploading = {
+            future::join_all(stores)
+            .map(move |stored| (job, stored))
+            .into_send_boxed()
         };
-        tokio::spawn(driver.infallible());
-        Ok((daemon, addr))
-        */
+        self.uploads.push(uploading);
     }
 
-    pub fn add_repo(&self, path: &Path) -> Result<git2::Repository, git2::Error> {
-        git2::Repository::open(path)
+    // Sees how the hosts we asked to keep an upload answered. If too few agreed to meet its
+    // target, we offer the shards they refused to the next best hosts we haven't asked yet, for
+    // what's left of its budget, until it's met or there's nobody left to ask.
+    fn poll_uploads(&mut self) {
+        loop {
+            let (mut job, stored) = match self.uploads.poll().void_unwrap() {
+                Async::Ready(Some(answered)) => answered,
+                Async::Ready(None) | Async::NotReady => break,
+            };
+            job.stored.extend(stored.into_iter().flatten());
+            if job.availability() >= job.target {
+                job.finish();
+                continue;
+            }
+            let placements = {
+                let shard_bytes = Byte(shard_size(job.size, job.needed) as f64);
+                let mut left = job.limit - job.spent();
+                let asked = &job.asked;
+                let mut candidates = {
+                    rank_hosts(&self.host_candidates(job.period))
+                    .into_iter()
+                    .filter(|candidate| !asked.contains(&candidate.host))
+                };
+                let mut placements = Vec::new();
+                for index in job.missing() {
+                    match candidates.find(|candidate| candidate.price * shard_bytes <= left) {
+                        Some(candidate) => {
+                            left = left - candidate.price * shard_bytes;
+                            placements.push((index, candidate));
+                        },
+                        None => break,
+                    }
+                }
+                placements
+            };
+            if placements.is_empty() {
+                job.finish();
+                continue;
+            }
+            self.place_shards(job, placements);
+        }
     }
 
-    pub fn get_mutable(
-        &self,
-        _id: PublicSignKey,
-        _price: Btc,
-        _price_decay_over_time: Sec,
-        _price_decay_over_versions: f64,
-    ) -> GetMutable {
-        unimplemented!()
-    /*
-        let params = GetMutableParams {
-            price,
-            price_decay_over_time,
-            price_decay_over_versions,
+    fn poll_contract_proposals(&mut self) {
+        loop {
+            let (proposed, res) = match self.contract_proposals.poll().void_unwrap() {
+                Async::Ready(Some(proposal)) => proposal,
+                Async::Ready(None) | Async::NotReady => break,
+            };
+            let ProposedContract { host, terms, result_tx } = proposed;
+            let receipt = match res {
+                Ok(Msg::ContractAccepted { receipt }) => receipt,
+                _ => {
+                    self.done_serving(&host, &terms.root);
+                    let _ = result_tx.send(Err(StoreError::Refused));
+                    continue;
+                },
+            };
+            let our_addr = self.node_keypair.public.to_xor_addr();
+            if let Err(e) = check_receipt(&receipt, host, our_addr, &terms, unix_time()) {
+                self.done_serving(&host, &terms.root);
+                let _ = result_tx.send(Err(e));
+                continue;
+            }
+            self.contracts.add_taken(receipt.clone(), Instant::now());
+            let _ = result_tx.send(Ok(receipt));
+        }
+    }
+
+    // Fetches the content of a contract we've just agreed to from the client. They've priced the
+    // upload into the contract, so they don't charge us for it.
+    fn fetch_contract_content(&mut self, client: XorAddr, terms: ContractTerms) {
+        let contract_id = terms.contract_id;
+        let writer = match self.content_store.writer(terms.root) {
+            Ok(writer) => writer,
+            Err(e) => {
+                debug!("couldn't save the content of contract {}: {}", contract_id, e);
+                self.drop_contract(client, contract_id);
+                return;
+            },
         };
-        let (result_tx, result_rx) = oneshot::channel();
-        let command = UserCommand::GetMutable {
-            id,
-            params,
-            result_tx,
+        let end = leaf_count(terms.size);
+        let (data_tx, download) = download_channel(0, end);
+        let request = {
+            self.stream_receiver
+            .open(client, terms.root, terms.size, 0, end, BtcPerByte(0.0), data_tx, Instant::now())
         };
-        unwrap!(self.user_command_tx.unbounded_send(command));
-        GetMutable {
-            result_rx,
+        self.open_stream(request);
+        // Leaves go to disk as they arrive, so a big contract doesn't have to fit in memory.
+        let fetch = {
+            download
+            .fold(writer, |mut writer, data| {
+                writer.write(&data);
+                Ok::<_, DownloadError>(writer)
+            })
+            .then(move |res| Ok((client, contract_id, res)))
+            .into_send_boxed()
+        };
+        self.contract_fetches.push(fetch);
+    }
+
+    fn poll_contract_fetches(&mut self) {
+        loop {
+            let (client, contract_id, res) = match self.contract_fetches.poll().void_unwrap() {
+                Async::Ready(Some(fetched)) => fetched,
+                Async::Ready(None) | Async::NotReady => break,
+            };
+            if self.contracts.hosting_receipt(&client, contract_id).is_none() {
+                continue;
+            }
+            // The stream checks every leaf against the root, and the writer checks the lot again
+            // before putting it in place.
+            let tree = match res.map(ContentWriter::finish) {
+                Ok(Ok(tree)) => tree,
+                Ok(Err(e)) => {
+                    debug!("couldn't save the content of contract {}: {}", contract_id, e);
+                    self.drop_contract(client, contract_id);
+                    continue;
+                },
+                Err(..) => {
+                    self.drop_contract(client, contract_id);
+                    continue;
+                },
+            };
+            // Now we let everyone know where to find it while the client's away.
+            let root = tree.root();
+            self.announcements.add(root, Instant::now());
+            let _ = self.hosted.insert(root, tree);
         }
-    */
     }
-}
 
-impl Driver {
-    fn new(bind_addr: &SocketAddr) -> Result<(Driver, UnboundedSender<UserCommand>), DaemonStartError> {
-        let (user_command_tx, user_command_rx) = mpsc::unbounded();
-        let socket = {
-            UdpSocket::bind(bind_addr)
-            .map_err(DaemonStartError::Bind)?
+    // Picks up hosting the content we've been paid to keep where we left off. Contracts whose
+    // content didn't make it to disk are no use to their clients, so we drop them.
+    fn load_hosted(&mut self) {
+        let now = Instant::now();
+        for root in self.contracts.hosted_roots() {
+            match self.content_store.load(&root) {
+                Some(tree) => {
+                    self.announcements.add(root, now);
+                    let _ = self.hosted.insert(root, tree);
+                },
+                None => self.contracts.drop_root(&root),
+            }
+        }
+    }
+
+    // Stops serving content we were only serving for `host` to fetch, once no other host is still
+    // waiting on it.
+    fn done_serving(&mut self, host: &XorAddr, root: &MerkleHash) {
+        let done = match self.awaiting_fetch.get_mut(root) {
+            Some(hosts) => hosts.remove(host) && hosts.is_empty(),
+            None => false,
         };
-        let socket = SharedUdpSocket::share(socket);
-        let peer_txs = HashMap::new();
-        let driver = Driver {
-            peer_txs,
-            user_command_rx,
-            socket,
+        if done {
+            let _ = self.awaiting_fetch.remove(root);
+            let _ = self.hosted.remove(root);
+        }
+    }
+
+    fn drop_contract(&mut self, client: XorAddr, contract_id: u64) {
+        if let Some(root) = self.contracts.drop_hosting(&client, contract_id) {
+            self.content_store.remove(&root);
+            self.stop_hosting(root);
+        }
+    }
+
+    // Pays the installments on our contracts which have fallen due, challenges hosts to show
+    // they've still got our content, and stops hosting content whose contracts are over.
+    fn poll_contracts(&mut self, now: Instant) {
+        let events = self.contracts.poll(now, unix_time());
+        for installment in events.installments {
+            self.ledger.debit(installment.host, installment.amount);
+            let msg = Msg::ContractPayment {
+                contract_id: installment.contract_id,
+                installment: installment.index,
+                total: installment.total,
+            };
+            let utility = installment.amount;
+            self.send_msg(&installment.host, msg, utility, CONTRACT_PAYMENT_UTILITY_DECAY);
+        }
+        for challenge in events.challenges {
+            let msg = Msg::ContractChallenge {
+                contract_id: challenge.contract_id,
+                index: challenge.index,
+            };
+            let offer = Offer::fixed(CONTRACT_REPLY_UTILITY);
+            let reply = {
+                self.request(challenge.host, msg, offer, CONTRACT_REPLY_UTILITY_DECAY)
+                .then(move |res| Ok((challenge, now, res)))
+                .into_send_boxed()
+            };
+            self.challenges.push(reply);
+        }
+        for root in events.expired {
+            self.content_store.remove(&root);
+            self.stop_hosting(root);
+        }
+    }
+
+    // Checks hosts' answers to our challenges. A host which doesn't show us the leaf in time
+    // loses reputation and stops getting paid until it does.
+    fn poll_challenges(&mut self) {
+        loop {
+            let (challenge, sent, res) = match self.challenges.poll().void_unwrap() {
+                Async::Ready(Some(reply)) => reply,
+                Async::Ready(None) | Async::NotReady => break,
+            };
+            let passed = match res {
+                Ok(Msg::ContractProof { contract_id, index, proof, data }) => {
+                    contract_id == challenge.contract_id
+                    && sent.elapsed() <= CHALLENGE_DEADLINE
+                    && challenge.check(index, &proof, &data)
+                },
+                _ => false,
+            };
+            let host = challenge.host;
+            self.reputations.record_accuracy(host, passed);
+            self.update_priority(host);
+            let gave_up = {
+                self.contracts
+                .challenged(&host, challenge.contract_id, passed, Instant::now())
+            };
+            if gave_up {
+                debug!(
+                    "giving up on contract {} with {:?}: failed challenges",
+                    challenge.contract_id,
+                    host,
+                );
+            }
+            // Either the host's shown us it's got the content, so we needn't serve it any more, or
+            // we've given up on it ever fetching it.
+            if passed || gave_up {
+                self.done_serving(&host, &challenge.root);
+            }
+        }
+    }
+
+    fn stop_hosting(&mut self, root: MerkleHash) {
+        let _ = self.hosted.remove(&root);
+        if self.announcements.remove(&root) {
+            self.announce(root, Vec::new());
+        }
+    }
+
+    // Lets the peer's driver know how much its packets are worth now its reputation's changed.
+    fn update_priority(&self, peer: XorAddr) {
+        if let Some(peer_tx) = self.peer_txs.get(&peer) {
+            peer_tx.update_priority(self.reputations.get(&peer).priority());
+        }
+    }
+
+    // A timeout waiting on `peer` is a sign that we might be sending to them too fast.
+    fn report_loss(&mut self, peer: &XorAddr, now: Instant) {
+        let dest = match self.peer_infos.get(peer) {
+            Some(peer_info) => peer_info.best_addr(now),
+            None => None,
+        };
+        if let Some(dest) = dest {
+            self.sockets.report_loss(&dest);
+        }
+    }
+
+    // Resends the requests, fragments and stream leaves which have timed out, throws away stale
+    // partly reassembled messages, reopens stalled streams, refreshes our provider records, sends
+    // hole punching probes, tells our peers when our download fee moves and sets a timer for
+    // whichever of these is due next.
+    fn poll_retransmits(&mut self) {
+        loop {
+            let now = Instant::now();
+            self.resource_monitor.update(now);
+            self.update_download_fee();
+            let upload_cost = self.costs.upload();
+            for timeout in self.transactions.poll_timeouts(now, upload_cost) {
+                self.report_loss(&timeout.peer, now);
+                match timeout.retransmit {
+                    Some(outgoing_msg) => self.send_request(timeout.peer, outgoing_msg),
+                    // We've given up, so maybe there's a NAT in the way.
+                    None => {
+                        self.reputations.record_answer(timeout.peer, false);
+                        self.update_priority(timeout.peer);
+                        self.request_introduction(timeout.peer, now);
+                    },
+                }
+            }
+
+            let timeouts = {
+                let transactions = &self.transactions;
+                self.fragmenter.poll_timeouts(now, upload_cost, |peer| transactions.rto(peer))
+            };
+            for peer in timeouts.lost {
+                self.report_loss(&peer, now);
+            }
+            for (peer, outgoing_msg) in timeouts.resend {
+                if let Some(peer_tx) = self.peer_txs.get(&peer) {
+                    let _ = peer_tx.send_message(outgoing_msg);
+                }
+            }
+
+            self.reassembly.expire(now);
+            for (peer, charge) in self.reassembly.take_charges(self.costs.memory()) {
+                self.ledger.credit(peer, charge);
+            }
+
+            let sends = {
+                let (transactions, ledger, reputations) = {
+                    (&self.transactions, &self.ledger, &self.reputations)
+                };
+                let credit = |peer: &XorAddr| {
+                    reputations.get(peer).debt_limit() - ledger.balance(peer)
+                };
+                self.stream_sender.poll_sends(now, |peer| transactions.rto(peer), credit)
+            };
+            for peer in sends.lost {
+                self.report_loss(&peer, now);
+            }
+            for (peer, outgoing_msg) in sends.send {
+                if let Err(e) = self.send_outgoing(peer, outgoing_msg) {
+                    debug!("not sending stream data to {:?}: {}", peer, e);
+                }
+            }
+            for request in self.stream_receiver.poll_stalls(now) {
+                self.open_stream(request);
+            }
+
+            self.refresh_announcements(now);
+            self.provider_store.expire(now, unix_time());
+            self.mutable_store.expire(now, unix_time());
+            self.pow_verifier.expire(unix_time());
+            self.reputations.save_if_due(now);
+            self.poll_contracts(now);
+            self.introductions.expire(now);
+            self.poll_hole_punches(now);
+            self.dns_cache.refresh(now);
+
+            let deadlines = [
+                self.transactions.next_deadline(),
+                self.fragmenter.next_deadline(),
+                self.reassembly.next_deadline(),
+                self.stream_sender.next_deadline(),
+                self.stream_receiver.next_deadline(),
+                self.announcements.next_deadline(),
+                self.provider_store.next_deadline(),
+                self.mutable_store.next_deadline(),
+                self.resource_monitor.next_deadline(),
+                self.reputations.next_deadline(),
+                self.contracts.next_deadline(),
+                self.swarms.values().filter_map(Swarm::next_deadline).min(),
+                self.introductions.next_deadline(),
+                self.dns_cache.next_deadline(),
+                self.hole_punches.values().filter_map(HolePunch::next_deadline).min(),
+            ];
+            let deadline = match deadlines.iter().filter_map(|deadline| *deadline).min() {
+                Some(deadline) => deadline,
+                None => {
+                    self.retransmit_timer = None;
+                    return;
+                },
+            };
+            let timer = self.retransmit_timer.get_or_insert_with(|| Delay::new(deadline));
+            if timer.deadline() != deadline {
+                timer.reset(deadline);
+            }
+            match timer.poll() {
+                Ok(Async::Ready(())) => continue,
+                Ok(Async::NotReady) => return,
+                // The timer's gone away, so the runtime is shutting down.
+                Err(..) => {
+                    self.retransmit_timer = None;
+                    return;
+                },
+            }
+        }
+    }
+
+    fn handle_msg(&mut self, msg: Msg, addr: SocketAddr) {
+        // Hole punching probes come from addresses we don't know about yet, that's the point.
+        let msg = match msg {
+            Msg::PunchProbe { nonce } => {
+                let ours = match self.hole_punches.get_mut(&nonce) {
+                    Some(hole_punch) => hole_punch.got_response(nonce, addr),
+                    None => false,
+                };
+                if ours {
+                    let ack = Msg::PunchAck { nonce };
+                    self.send_to(ack, addr, PUNCH_PROBE_UTILITY, PUNCH_PROBE_UTILITY_DECAY);
+                }
+                return;
+            },
+            Msg::PunchAck { nonce } => {
+                if let Some(hole_punch) = self.hole_punches.get_mut(&nonce) {
+                    let _ = hole_punch.got_response(nonce, addr);
+                }
+                return;
+            },
+            msg => msg,
+        };
+
+        // We only provide services to peers we can bill.
+        let peer = match self.peer_addrs.get(&addr) {
+            Some(peer) => *peer,
+            None => return,
+        };
+
+        self.handle_peer_msg(peer, msg, Some(addr));
+    }
+
+    // Handles a message from a known peer. `addr` is where the message came from, or `None` if it
+    // reached us through a relay.
+    fn handle_peer_msg(&mut self, peer: XorAddr, msg: Msg, addr: Option<SocketAddr>) {
+        match msg {
+            Msg::Request { request_id, offer, payload } => {
+                let msg = match Msg::read(&mut Cursor::new(payload)) {
+                    Ok(msg) => msg,
+                    Err(..) => return,
+                };
+                // We stop serving peers who've run up more debt than we trust them with, other
+                // than to let them pay it off.
+                let paying = match msg {
+                    Msg::PowPayment { .. } => true,
+                    _ => false,
+                };
+                let debt_limit = self.reputations.get(&peer).debt_limit();
+                if !paying && self.ledger.balance(&peer) > debt_limit {
+                    debug!("ignoring request from {:?}: over their debt limit", peer);
+                    return;
+                }
+                let received = Instant::now();
+                let request = IncomingRequest { peer, request_id, offer, addr, received };
+                self.handle_request(request, msg);
+            },
+            Msg::Response { request_id, delay, payload } => {
+                let reply = match Msg::read(&mut Cursor::new(payload)) {
+                    Ok(reply) => reply,
+                    Err(..) => {
+                        self.reputations.record_accuracy(peer, false);
+                        self.update_priority(peer);
+                        return;
+                    },
+                };
+                // Anyone can make up addresses close to a key, so we only pay for and follow
+                // contacts we already know.
+                let reply = match reply {
+                    Msg::Contacts { key, peers } => {
+                        let peers = {
+                            peers
+                            .into_iter()
+                            .filter(|contact| self.peer_txs.contains_key(contact))
+                            .collect()
+                        };
+                        Msg::Contacts { key, peers }
+                    },
+                    reply => reply,
+                };
+                let now = Instant::now();
+                let completed = {
+                    match self.transactions.complete(peer, request_id, reply, delay, now) {
+                        Some(completed) => completed,
+                        None => return,
+                    }
+                };
+                self.ledger.debit(peer, completed.payout);
+                if completed.understated {
+                    debug!("{:?} understated how long they held request {}", peer, request_id);
+                    self.reputations.record_accuracy(peer, false);
+                }
+                self.reputations.record_answer(peer, true);
+                self.update_priority(peer);
+                if let Some(addr) = addr {
+                    self.sockets.report_ack(&addr, completed.request_len);
+                }
+            },
+            Msg::Fragment { msg_id, index, count, data } => {
+                let now = Instant::now();
+                let reassembled = self.reassembly.add(peer, msg_id, index, count, data, now);
+                if let Some(ack) = reassembled.ack {
+                    self.reply(peer, ack, addr, FRAGMENT_ACK_UTILITY, FRAGMENT_ACK_UTILITY_DECAY);
+                }
+                if let Some(message) = reassembled.complete {
+                    match Msg::read(&mut Cursor::new(message)) {
+                        Ok(msg) => self.handle_peer_msg(peer, msg, addr),
+                        Err(..) => (),
+                    }
+                }
+            },
+            Msg::FragmentAck { msg_id, received } => {
+                let acked = self.fragmenter.on_ack(peer, msg_id, &received[..]);
+                if let Some(addr) = addr {
+                    if acked > Byte(0.0) {
+                        self.sockets.report_ack(&addr, acked);
+                    }
+                }
+            },
+            Msg::MtuProbe { nonce, .. } => {
+                let ack = Msg::MtuProbeAck { nonce };
+                self.reply(peer, ack, addr, FRAGMENT_ACK_UTILITY, FRAGMENT_ACK_UTILITY_DECAY);
+            },
+            Msg::MtuProbeAck { nonce } => {
+                self.fragmenter.probe_acked(peer, nonce, Instant::now());
+            },
+            Msg::StreamLeaf { stream_id, index, proof, data } => {
+                let received = {
+                    let now = Instant::now();
+                    self.stream_receiver.on_leaf(peer, stream_id, index, proof, data, now)
+                };
+                self.ledger.debit(peer, received.payment);
+                if received.bad_leaf || received.payment > Btc(0.0) {
+                    self.reputations.record_accuracy(peer, !received.bad_leaf);
+                    self.update_priority(peer);
+                }
+                if let Some(ack) = received.ack {
+                    let (utility, decay) = (STREAM_CONTROL_UTILITY, STREAM_CONTROL_UTILITY_DECAY);
+                    self.reply(peer, ack, addr, utility, decay);
+                }
+            },
+            Msg::StreamAck { stream_id, next } => {
+                let now = Instant::now();
+                let acked = match self.stream_sender.on_ack(peer, stream_id, next, now) {
+                    Some(acked) => acked,
+                    None => return,
+                };
+                self.ledger.credit(peer, acked.payment);
+                if let Some(addr) = addr {
+                    self.sockets.report_ack(&addr, acked.acked);
+                }
+                if let Some(root) = acked.finished {
+                    self.done_serving(&peer, &root);
+                }
+            },
+            // These only mean anything as part of a request and its response.
+            Msg::SenderGetAddress | Msg::SenderAddress { .. } => (),
+            Msg::StreamOpen { .. } | Msg::StreamOpened { .. } => (),
+            Msg::StreamQuery { .. } | Msg::StreamHave { .. } => (),
+            Msg::ProviderAdd { .. } | Msg::ProviderAdded { .. } => (),
+            Msg::ProviderQuery { .. } | Msg::Providers { .. } | Msg::Contacts { .. } => (),
+            Msg::ContractPropose { .. } | Msg::ContractAccepted { .. } => (),
+            Msg::ContractChallenge { .. } | Msg::ContractProof { .. } => (),
+            Msg::PowPayment { .. } | Msg::PowAccepted { .. } => (),
+            Msg::ContractPayment { contract_id, installment, total } => {
+                // An installment is what the client owes us for hosting, not money in hand, so it
+                // goes on its tab rather than towards its payment record.
+                let paid = self.contracts.on_payment(&peer, contract_id, installment, total);
+                if paid > Btc(0.0) {
+                    self.ledger.credit(peer, paid);
+                }
+            },
+            Msg::SenderIntroduce { target, nonce } => {
+                let target_addr = match self.peer_infos.get(&target) {
+                    Some(peer_info) => match peer_info.best_addr(Instant::now()) {
+                        Some(target_addr) => target_addr,
+                        None => return,
+                    },
+                    None => return,
+                };
+                let addr = match addr {
+                    Some(addr) => addr,
+                    None => return,
+                };
+                // Introductions cost us two messages, so the peer asking pays for them, and we
+                // stop introducing it once it owes more than we trust it with.
+                let debt_limit = self.reputations.get(&peer).debt_limit();
+                if self.ledger.balance(&peer) > debt_limit {
+                    debug!("not introducing {:?}: over their debt limit", peer);
+                    return;
+                }
+                let to_target = Msg::Introduction { peer, addr, nonce };
+                let to_peer = Msg::Introduction { peer: target, addr: target_addr, nonce };
+                let len = to_target.to_bytes().len() + to_peer.to_bytes().len();
+                self.ledger.credit(peer, self.costs.upload() * Byte::from(len));
+                self.send_msg(&target, to_target, PUNCH_PROBE_UTILITY, PUNCH_PROBE_UTILITY_DECAY);
+                self.send_msg(&peer, to_peer, PUNCH_PROBE_UTILITY, PUNCH_PROBE_UTILITY_DECAY);
+            },
+            Msg::Introduction { peer: other, addr: other_addr, nonce } => {
+                // A rendezvous has to be able to see where we are.
+                if addr.is_none() {
+                    return;
+                }
+                let now = Instant::now();
+                match self.introductions.on_introduction(peer, other, nonce, now) {
+                    IntroductionAction::Punch => {
+                        let hole_punch = HolePunch::new(other, peer, nonce, vec![other_addr], now);
+                        let _ = self.hole_punches.insert(nonce, hole_punch);
+                    },
+                    IntroductionAction::AskBack => {
+                        let msg = Msg::SenderIntroduce { target: other, nonce };
+                        self.send_msg(&peer, msg, PUNCH_PROBE_UTILITY, PUNCH_PROBE_UTILITY_DECAY);
+                    },
+                    IntroductionAction::Ignore => (),
+                }
+            },
+            Msg::SenderRelayFee { btc_per_byte } => {
+                if let Some(peer_info) = self.peer_infos.get(&peer) {
+                    let mut peer_info = (**peer_info).clone();
+                    peer_info.relay_fee = Some(btc_per_byte);
+                    self.add_peer(peer, Arc::new(peer_info));
+                }
+            },
+            Msg::Relay { dest, signature, payload } => {
+                if addr.is_none() {
+                    return;
+                }
+                let relay_fee = match self.our_info.relay_fee {
+                    Some(relay_fee) => relay_fee,
+                    None => return,
+                };
+                if !self.peer_txs.contains_key(&dest) {
+                    return;
+                }
+                let debt_limit = self.reputations.get(&peer).debt_limit();
+                if self.ledger.balance(&peer) > debt_limit {
+                    debug!("not relaying for {:?}: over their debt limit", peer);
+                    return;
+                }
+                let fee = relay_fee * Byte::from(payload.len());
+                self.ledger.credit(peer, fee);
+                let relayed = Msg::Relayed { src: peer, signature, payload };
+                self.send_msg(&dest, relayed, fee, RELAY_UTILITY_DECAY);
+            },
+            Msg::Relayed { src, signature, payload } => {
+                let relay_addr = match addr {
+                    Some(relay_addr) => relay_addr,
+                    None => return,
+                };
+                // The relay could claim it came from anyone, so the sender signs it.
+                let our_addr = self.node_keypair.public.to_xor_addr();
+                if !verify_relayed(&src, &our_addr, &payload, &signature) {
+                    debug!("dropping relayed message from {:?}: bad signature", peer);
+                    self.reputations.record_accuracy(peer, false);
+                    self.update_priority(peer);
+                    return;
+                }
+                let route = AddressKind::Relayed { relay: peer, relay_addr };
+                let known_route = match self.peer_infos.get(&src) {
+                    Some(peer_info) => peer_info.has_addr(&route),
+                    None => false,
+                };
+                if !known_route {
+                    let route = Address::new(route, RELAYED_ADDR_PROBABILITY, RELAYED_ADDR_LIFETIME);
+                    self.add_peer_addr(src, route);
+                }
+
+                let mut bytes = Cursor::new(payload);
+                while bytes.remaining() > 0 {
+                    match Msg::read(&mut bytes) {
+                        Ok(msg) => self.handle_peer_msg(src, msg, None),
+                        Err(..) => break,
+                    }
+                }
+            },
+            Msg::SenderDownloadFee { btc_per_byte } => {
+                if let Some(peer_info) = self.peer_infos.get(&peer) {
+                    let mut peer_info = (**peer_info).clone();
+                    observe_download_fee(&mut peer_info, btc_per_byte);
+                    self.add_peer(peer, Arc::new(peer_info));
+                }
+            },
+            // Hole punching messages only make sense coming straight from the sender, not relayed
+            // or wrapped up in something else.
+            Msg::PunchProbe { .. } | Msg::PunchAck { .. } => (),
+        }
+    }
+
+    // Starts offering to relay for our peers once we know we're publicly reachable, or stops if
+    // it turns out we aren't.
+    fn update_relay_fee(&mut self) {
+        let relay_fee = our_relay_fee(self.reflexive_addrs.nat_type(), &self.costs.get());
+        if relay_fee == self.our_info.relay_fee {
+            return;
+        }
+
+        self.our_info.relay_fee = relay_fee;
+        if let Some(btc_per_byte) = relay_fee {
+            let peers: Vec<XorAddr> = self.peer_txs.keys().cloned().collect();
+            for peer in peers {
+                let msg = Msg::SenderRelayFee { btc_per_byte };
+                self.send_msg(&peer, msg, PUNCH_PROBE_UTILITY, PUNCH_PROBE_UTILITY_DECAY);
+            }
+        }
+    }
+
+    // Tells our peers what we charge for downloads whenever it's moved far enough from what we
+    // last told them.
+    fn update_download_fee(&mut self) {
+        let fee = self.costs.upload();
+        if (fee.log() - self.our_info.exp_download_fee).abs() < DOWNLOAD_FEE_CHANGE.ln() {
+            return;
+        }
+
+        self.our_info.exp_download_fee = fee.log();
+        let peers: Vec<XorAddr> = self.peer_txs.keys().cloned().collect();
+        for peer in peers {
+            let msg = Msg::SenderDownloadFee { btc_per_byte: fee };
+            self.send_msg(&peer, msg, PUNCH_PROBE_UTILITY, PUNCH_PROBE_UTILITY_DECAY);
+        }
+    }
+
+    // Asks a peer we can already reach to put us in touch with `target`, so that we can punch a
+    // hole through to it. Called when sending straight to `target` fails.
+    fn request_introduction(&mut self, target: XorAddr, now: Instant) {
+        let rendezvous = match pick_rendezvous(&self.peer_infos, &target, now) {
+            Some(rendezvous) => rendezvous,
+            None => return,
         };
-        Ok((driver, user_command_tx))
+        if let Some(nonce) = self.introductions.ask(rendezvous, target, now) {
+            let msg = Msg::SenderIntroduce { target, nonce };
+            self.send_msg(&rendezvous, msg, PUNCH_PROBE_UTILITY, PUNCH_PROBE_UTILITY_DECAY);
+        }
+    }
+
+    fn poll_hole_punches(&mut self, now: Instant) {
+        let mut probes = Vec::new();
+        let mut finished = Vec::new();
+        for (nonce, hole_punch) in self.hole_punches.iter_mut() {
+            for dest in hole_punch.poll_probes(now) {
+                probes.push((*nonce, dest));
+            }
+            match hole_punch.state() {
+                HolePunchState::Probing => (),
+                HolePunchState::Succeeded(..) | HolePunchState::Failed => finished.push(*nonce),
+            }
+        }
+
+        for (nonce, dest) in probes {
+            let probe = Msg::PunchProbe { nonce };
+            self.send_to(probe, dest, PUNCH_PROBE_UTILITY, PUNCH_PROBE_UTILITY_DECAY);
+        }
+
+        for nonce in finished {
+            let hole_punch = unwrap!(self.hole_punches.remove(&nonce));
+            match hole_punch.state() {
+                HolePunchState::Succeeded(addr) => {
+                    let addr = Address::new(AddressKind::Resolved(addr), 1.0, PUNCHED_MAPPING_LIFETIME);
+                    self.add_peer_addr(hole_punch.peer(), addr);
+                },
+                // We can't get through the NATs in the way so fall back to paying someone to
+                // forward our packets.
+                HolePunchState::Failed => {
+                    // The rendezvous has shown it can reach them.
+                    let (target, via) = (hole_punch.peer(), [hole_punch.rendezvous()]);
+                    let relay = pick_relay(&self.peer_infos, &target, &via, now);
+                    if let Some((relay, relay_addr, _)) = relay {
+                        let route = AddressKind::Relayed { relay, relay_addr };
+                        let route = Address::new(route, RELAYED_ADDR_PROBABILITY, RELAYED_ADDR_LIFETIME);
+                        self.add_peer_addr(target, route);
+                    }
+                },
+                HolePunchState::Probing => unreachable!(),
+            }
+        }
+    }
+
+    fn poll_address_queries(&mut self) {
+        loop {
+            match self.address_queries.poll().void_unwrap() {
+                Async::Ready(Some((peer, res))) => {
+                    self.handle_address_reply(peer, res);
+                },
+                Async::Ready(None) | Async::NotReady => break,
+            }
+        }
+    }
+
+    fn query_addresses(&mut self, now: Instant) {
+        if !self.address_queries.is_empty() || !self.reflexive_addrs.needs_refresh(now) {
+            return;
+        }
+
+        let peers: Vec<XorAddr> = self.peer_txs.keys().take(ADDRESS_QUERY_PEERS).cloned().collect();
+        for peer in peers {
+            let offer = Offer::decaying(ADDRESS_QUERY_REWARD, ADDRESS_QUERY_REWARD_DECAY);
+            let query = {
+                self.request(peer, Msg::SenderGetAddress, offer, ADDRESS_QUERY_REWARD_DECAY)
+                .then(move |res| Ok((peer, res)))
+                .into_send_boxed()
+            };
+            self.address_queries.push(query);
+        }
+    }
+
+    pub fn nat_type(&self) -> NatType {
+        self.reflexive_addrs.nat_type()
     }
 
     /*
@@ -107,6 +2450,7 @@ impl Driver {
             addr: Address::Domain(String::from("canndrew.org:45666")),
             exp_download_fee: LogBtcPerByte(0.0),
             var_download_fee: 0.0,
+            relay_fee: None,
         };
         let mut ret = BTreeMap::new();
         ret.insert(id, node_info);
@@ -120,56 +2464,100 @@ impl Future for Driver {
     type Error = Void;
 
     fn poll(&mut self) -> Result<Async<()>, Void> {
-        /*
-        if let Some(mut bootstrapping) = self.bootstrapping.take() {
-            let bootstrapping = loop {
-                match bootstrapping.poll().void_unwrap() {
-                    Async::Ready(Some((pub_key, node_info))) => {
-                        let key = self.sign_keypair.public.to_xor_addr() ^ pub_key.to_xor_addr();
-                        let socket = self.socket.clone();
-                        self.peers.insert(key, Peer::from_node_info(socket, node_info));
-                    },
-                    Async::Ready(None) => break None,
-                    Async::NotReady => break Some(bootstrapping),
-                }
-            };
-            self.bootstrapping = bootstrapping;
-        }
         loop {
             let command = match self.user_command_rx.poll().void_unwrap() {
                 Async::Ready(Some(command)) => command,
-                Async::Ready(None) => return Ok(Async::Ready(())),
-                Async::NotReady => break,
+                // The daemon's been dropped, but there may still be streams for our peers to
+                // finish.
+                Async::Ready(None) | Async::NotReady => break,
             };
             match command {
-                UserCommand::GetMutable { id, params, result_tx } => {
-                    let mut pending = self.pending_mutables.entry(id).or_default();
-                    pending.add_client(params, result_tx);
+                UserCommand::AddPeer { peer, addrs } => {
+                    for addr in addrs {
+                        let kind = AddressKind::Resolved(normalize_addr(addr));
+                        self.add_peer_addr(peer, Address::new(kind, 1.0, KNOWN_ADDR_LIFETIME));
+                    }
+                },
+                UserCommand::Host { tree } => {
+                    // It's ours to host now, whether or not the hosts we've proposed contracts to
+                    // have fetched it.
+                    let _ = self.awaiting_fetch.remove(&tree.root());
+                    self.announcements.add(tree.root(), Instant::now());
+                    let _ = self.hosted.insert(tree.root(), tree);
+                },
+                UserCommand::Unhost { root } => {
+                    self.stop_hosting(root);
+                },
+                UserCommand::FindProviders { root, result_tx } => {
+                    self.find_providers(root, result_tx);
+                },
+                UserCommand::GetMutable { id, price, result_tx } => {
+                    self.get_mutable(id, price, result_tx);
+                },
+                UserCommand::PutMutable { record, result_tx } => {
+                    self.put_mutable(record, result_tx);
+                },
+                UserCommand::Download { peer, root, size, start, end, price, data_tx } => {
+                    let now = Instant::now();
+                    let request = {
+                        self.stream_receiver
+                        .open(peer, root, size, start, end, price, data_tx, now)
+                    };
+                    self.open_stream(request);
+                },
+                UserCommand::SwarmDownload { root, size, max_price, data_tx } => {
+                    self.start_swarm(Swarm::new(root, size, max_price, data_tx));
+                },
+                UserCommand::PayWithWork { peer, amount, result_tx } => {
+                    self.pay_with_work(peer, amount, result_tx);
+                },
+                UserCommand::StoreWith { host, tree, duration, price, installments, result_tx } => {
+                    self.propose_contract(host, tree, duration, price, installments, result_tx);
+                },
+                UserCommand::Upload { tree, availability, period, result_tx } => {
+                    self.upload(tree, availability, period, result_tx);
                 },
             }
         }
-        /*
-        loop {
-            match unwrap!(self.incoming_endpoints.poll()) {
-                Async::Ready(Some(udp_endpoint)) => {
-                    let peer = Peer::from_endpoint(udp_endpoint);
-                    self.peers.
-                },
+
+        while let Async::Ready(Some(event)) = self.peer_event_rx.poll().void_unwrap() {
+            match event {
+                PeerEvent::Unreachable { peer, route } => self.handle_unreachable(peer, route),
+                PeerEvent::Relayed { relay, len } => self.pay_relay(relay, len),
             }
         }
-        */
-        let node_key = self.sign_keypair.public;
-        let peers = &mut self.peers;
-        self.pending_mutables.retain(|key, pending_mutable| {
-            match pending_mutable.poll(node_key, *key, peers) {
-                Async::Ready(()) => false,
-                Async::NotReady => true,
+
+        for i in 0..self.msg_rxs.len() {
+            loop {
+                match self.msg_rxs[i].poll().never_err() {
+                    Async::Ready(Some((msg, addr))) => self.handle_msg(msg, addr),
+                    Async::Ready(None) => return Ok(Async::Ready(())),
+                    Async::NotReady => break,
+                }
             }
-        });
-        for (_, peer) in peers {
-            peer.poll(&self.socket);
         }
-        */
+
+        let now = Instant::now();
+        self.query_addresses(now);
+        self.poll_swarm_lookups();
+        self.poll_swarm_queries();
+        self.poll_swarms(now);
+        self.poll_retransmits();
+        self.poll_address_queries();
+        self.poll_stream_opens();
+        self.poll_provider_adds();
+        self.poll_provider_replies();
+        self.poll_mutable_replies();
+        self.poll_mutable_puts();
+        self.poll_forwards();
+        self.poll_mintings();
+        self.poll_pow_payments();
+        self.poll_contract_proposals();
+        self.poll_contract_fetches();
+        self.poll_challenges();
+        self.poll_shard_encodings();
+        self.poll_uploads();
+        self.poll_dns_lookups();
 
         Ok(Async::NotReady)
     }
@@ -179,5 +2567,127 @@ impl Future for Driver {
 pub enum DaemonStartError {
     #[fail(display = "error binding to udp socket: {}", _0)]
     Bind(io::Error),
+    #[fail(display = "error reading config: {}", _0)]
+    Config(ConfigError),
+    #[fail(display = "error loading node key: {}", _0)]
+    NodeKey(NodeKeyError),
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+    use tokio::runtime::Runtime;
+
+    // Starts a daemon which keeps its things in `data_dir`, returning the loopback address it can
+    // be reached at.
+    fn start(data_dir: PathBuf) -> (Daemon, SocketAddr) {
+        let config = Config {
+            data_dir: Some(data_dir),
+            ..Config::default()
+        };
+        let (daemon, local_addrs) = unwrap!(Daemon::start_with_config(&config));
+        let local_addr = unwrap!(local_addrs.into_iter().find(SocketAddr::is_ipv4));
+        (daemon, SocketAddr::new(ip!("127.0.0.1"), local_addr.port()))
+    }
+
+    // Sets up a driver without spawning it, so that we can look at what it knows. Returns the
+    // loopback address it can be reached at.
+    fn driver(data_dir: PathBuf) -> (Driver, SocketAddr) {
+        let config = Config {
+            data_dir: Some(data_dir),
+            ..Config::default()
+        };
+        let (driver, local_addrs, _) = unwrap!(Driver::new(&config));
+        let local_addr = unwrap!(local_addrs.into_iter().find(SocketAddr::is_ipv4));
+        (driver, SocketAddr::new(ip!("127.0.0.1"), local_addr.port()))
+    }
+
+    #[test]
+    fn peers_hear_what_we_charge() {
+        let client_dir = unwrap!(TempDir::new("lightstore-client"));
+        let host_dir = unwrap!(TempDir::new("lightstore-host"));
+        let (client_path, host_path) = (client_dir.path().to_owned(), host_dir.path().to_owned());
+        let mut runtime = unwrap!(Runtime::new());
+        let heard = future::lazy(move || {
+            let (mut client, client_addr) = driver(client_path);
+            let (mut host, host_addr) = driver(host_path);
+            let client_key = client.node_keypair.public.to_xor_addr();
+            let host_key = host.node_keypair.public.to_xor_addr();
+            let kind = AddressKind::Resolved(normalize_addr(host_addr));
+            client.add_peer_addr(host_key, Address::new(kind, 1.0, KNOWN_ADDR_LIFETIME));
+            let kind = AddressKind::Resolved(normalize_addr(client_addr));
+            host.add_peer_addr(client_key, Address::new(kind, 1.0, KNOWN_ADDR_LIFETIME));
+
+            // Until it's heard from the host, the client only has a rough idea what it charges.
+            let unheard = unwrap!(client.peer_infos.get(&host_key)).var_download_fee;
+            future::poll_fn(move || {
+                let _ = client.poll();
+                let _ = host.poll();
+                let info = unwrap!(client.peer_infos.get(&host_key));
+                if info.var_download_fee < unheard {
+                    Ok::<_, Void>(Async::Ready((**info).clone()))
+                } else {
+                    Ok::<_, Void>(Async::NotReady)
+                }
+            })
+        });
+        let info = runtime.block_on(heard).void_unwrap();
+
+        // The host told it what it charges for downloads, which is what uploading costs it.
+        assert!((info.exp_download_fee - Costs::base().upload.log()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn store_with_a_peer() {
+        let client_dir = unwrap!(TempDir::new("lightstore-client"));
+        let host_dir = unwrap!(TempDir::new("lightstore-host"));
+        let (client_path, host_path) = (client_dir.path().to_owned(), host_dir.path().to_owned());
+        let mut runtime = unwrap!(Runtime::new());
+        let data = Bytes::from(vec![7u8; 3 * LEAF_SIZE]);
+        let stored = future::lazy(move || {
+            let (client, client_addr) = start(client_path);
+            let (host, host_addr) = start(host_path);
+            client.add_peer(host.node_key(), vec![host_addr]);
+            host.add_peer(client.node_key(), vec![client_addr]);
+            let (client_key, host_key) = (client.node_key(), host.node_key());
+            let duration = Duration::from_secs(24 * 60 * 60);
+            client
+            .store_with(host_key.to_xor_addr(), data, duration, Btc(1e-3), 4)
+            .map(move |receipt| {
+                drop((client, host));
+                (receipt, client_key, host_key)
+            })
+        });
+        let (receipt, client_key, host_key) = unwrap!(runtime.block_on(stored));
+
+        // The receipt names both of us by our node keys, and the host signed it with its own.
+        assert_eq!(receipt.client, client_key.to_xor_addr());
+        assert_eq!(receipt.host, host_key.to_xor_addr());
+        unwrap!(receipt.verify());
+
+        // Both of us have the contract saved.
+        let config = Config {
+            data_dir: Some(host_dir.path().to_owned()),
+            ..Config::default()
+        };
+        let hosting = Contracts::open(config.contracts_path(), Instant::now());
+        let contract_id = receipt.terms.contract_id;
+        assert_eq!(hosting.hosting_receipt(&receipt.client, contract_id), Some(&receipt));
+        let config = Config {
+            data_dir: Some(client_dir.path().to_owned()),
+            ..Config::default()
+        };
+        let taken = Contracts::open(config.contracts_path(), Instant::now());
+        assert!(taken.is_host_of(&receipt.host, &receipt.terms.root));
+
+        // And the host comes back as the same peer.
+        let host_path = host_dir.path().to_owned();
+        let restarted = runtime.block_on(future::lazy(move || {
+            let (host, _) = start(host_path);
+            Ok::<_, Void>(host.node_key())
+        }));
+        assert_eq!(restarted.void_unwrap(), host_key);
+    }
+}
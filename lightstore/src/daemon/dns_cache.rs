@@ -0,0 +1,264 @@
+use super::*;
+use futures::stream::FuturesUnordered;
+use trust_dns_resolver::ResolverFuture;
+
+// The port we assume if a domain address doesn't specify one.
+const DEFAULT_PORT: u16 = 45666;
+
+// How long we remember that a lookup failed before trying again.
+const NEGATIVE_TTL: Duration = Duration::from_secs(5);
+
+// Don't let very short TTLs make resolved addresses decay to nothing before we can use them.
+const MIN_TTL: Sec = Sec(1.0);
+
+// Caches A/AAAA lookups for the domain addresses peers advertise, for as long as the records'
+// TTLs say we can, and looks them up again when they expire. Failed lookups are cached too, so we
+// don't hammer the resolver for a domain that's gone away, and retried once that runs out.
+pub struct DnsCache {
+    entries: HashMap<String, CacheEntry>,
+    // The peers with addresses at each domain, so that we know which records to keep fresh.
+    peers: HashMap<String, HashSet<XorAddr>>,
+    looking_up: HashSet<String>,
+    // Lookups waiting on the resolver to start.
+    queued: Vec<String>,
+    resolver: Resolver,
+    lookups: FuturesUnordered<BoxSendFuture<(String, Result<CacheEntry, String>), Void>>,
+}
+
+// Reading the system's resolver config and setting up its connections isn't free, so we do it
+// once and share the resolver between lookups.
+enum Resolver {
+    Idle,
+    Starting(BoxSendFuture<ResolverFuture, String>),
+    Ready(ResolverFuture),
+}
+
+#[derive(Clone)]
+struct CacheEntry {
+    addrs: Vec<SocketAddr>,
+    valid_until: Instant,
+}
+
+pub struct DnsLookupResult {
+    pub domain: String,
+    pub peers: HashSet<XorAddr>,
+    pub succeeded: bool,
+}
+
+impl DnsCache {
+    pub fn new() -> DnsCache {
+        DnsCache {
+            entries: HashMap::new(),
+            peers: HashMap::new(),
+            looking_up: HashSet::new(),
+            queued: Vec::new(),
+            resolver: Resolver::Idle,
+            lookups: FuturesUnordered::new(),
+        }
+    }
+
+    // The cached addresses for `domain` along with how long they're good for, or `None` if we
+    // need to look it up.
+    pub fn get(&self, domain: &str, now: Instant) -> Option<(&[SocketAddr], Sec)> {
+        let entry = self.entries.get(domain)?;
+        if entry.valid_until <= now {
+            return None;
+        }
+        let ttl = Sec::from(entry.valid_until.duration_since(now));
+        let ttl = if ttl < MIN_TTL { MIN_TTL } else { ttl };
+        Some((&entry.addrs[..], ttl))
+    }
+
+    // Starts looking up `domain` on behalf of `peer`, unless a lookup is already in flight. We
+    // keep the records fresh until `peer` is removed.
+    pub fn start_lookup(&mut self, domain: String, peer: XorAddr) {
+        let _ = self.peers.entry(domain.clone()).or_insert_with(HashSet::new).insert(peer);
+        self.lookup(domain);
+    }
+
+    // `peer` has given up on its address at `domain`.
+    pub fn remove_peer(&mut self, domain: &str, peer: &XorAddr) {
+        let unused = match self.peers.get_mut(domain) {
+            Some(peers) => {
+                let _ = peers.remove(peer);
+                peers.is_empty()
+            },
+            None => false,
+        };
+        if unused {
+            let _ = self.peers.remove(domain);
+            let _ = self.entries.remove(domain);
+        }
+    }
+
+    // Looks up the domains peers still need whose records have expired, and forgets the ones
+    // nobody does.
+    pub fn refresh(&mut self, now: Instant) {
+        let peers = &self.peers;
+        self.entries.retain(|domain, entry| entry.valid_until > now || peers.contains_key(domain));
+        let looking_up = &self.looking_up;
+        let expired: Vec<String> = {
+            self.entries
+            .iter()
+            .filter(|(domain, entry)| entry.valid_until <= now && !looking_up.contains(*domain))
+            .map(|(domain, _)| domain.clone())
+            .collect()
+        };
+        for domain in expired {
+            self.lookup(domain);
+        }
+    }
+
+    // When the next record a peer needs runs out.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.entries
+        .iter()
+        .filter(|(domain, _)| {
+            self.peers.contains_key(*domain) && !self.looking_up.contains(*domain)
+        })
+        .map(|(_, entry)| entry.valid_until)
+        .min()
+    }
+
+    fn lookup(&mut self, domain: String) {
+        if self.looking_up.insert(domain.clone()) {
+            self.queued.push(domain);
+            self.start_queued();
+        }
+    }
+
+    fn start_queued(&mut self) {
+        let resolver = match self.resolver {
+            Resolver::Ready(ref resolver) => resolver,
+            Resolver::Starting(..) => return,
+            Resolver::Idle => {
+                let starting = {
+                    ResolverFuture::from_system_conf()
+                    .into_future()
+                    .flatten()
+                    .map_err(|e| e.to_string())
+                    .into_send_boxed()
+                };
+                self.resolver = Resolver::Starting(starting);
+                return;
+            },
+        };
+        for domain in self.queued.drain(..) {
+            let (host, port) = split_domain(&domain);
+            let lookup = {
+                resolver
+                .lookup_ip(host.as_str())
+                .map(move |lookup_ip| {
+                    let addrs = lookup_ip.iter().map(|ip| SocketAddr::new(ip, port)).collect();
+                    CacheEntry {
+                        addrs,
+                        valid_until: lookup_ip.valid_until(),
+                    }
+                })
+                .map_err(|e| e.to_string())
+                .then(move |res| Ok((domain, res)))
+                .into_send_boxed()
+            };
+            self.lookups.push(lookup);
+        }
+    }
+
+    // Returns the lookups which have finished since we last checked.
+    pub fn poll_lookups(&mut self) -> Vec<DnsLookupResult> {
+        let mut ret = Vec::new();
+        let started = match self.resolver {
+            Resolver::Starting(ref mut starting) => match starting.poll() {
+                Ok(Async::Ready(resolver)) => Some(Ok(resolver)),
+                Ok(Async::NotReady) => None,
+                Err(e) => Some(Err(e)),
+            },
+            Resolver::Idle | Resolver::Ready(..) => None,
+        };
+        match started {
+            Some(Ok(resolver)) => {
+                self.resolver = Resolver::Ready(resolver);
+                self.start_queued();
+            },
+            // Everything waiting on it fails, and we try setting it up again next time.
+            Some(Err(e)) => {
+                debug!("error starting dns resolver: {}", e);
+                self.resolver = Resolver::Idle;
+                let queued: Vec<String> = self.queued.drain(..).collect();
+                for domain in queued {
+                    ret.push(self.finish_lookup(domain, Err(e.clone())));
+                }
+            },
+            None => (),
+        }
+
+        while let Async::Ready(Some((domain, res))) = self.lookups.poll().void_unwrap() {
+            ret.push(self.finish_lookup(domain, res));
+        }
+        ret
+    }
+
+    fn finish_lookup(
+        &mut self,
+        domain: String,
+        res: Result<CacheEntry, String>,
+    ) -> DnsLookupResult {
+        let _ = self.looking_up.remove(&domain);
+        let succeeded = res.is_ok();
+        let entry = match res {
+            Ok(entry) => entry,
+            Err(..) => CacheEntry {
+                addrs: Vec::new(),
+                valid_until: Instant::now() + NEGATIVE_TTL,
+            },
+        };
+        let _ = self.entries.insert(domain.clone(), entry);
+        let peers = self.peers.get(&domain).cloned().unwrap_or_default();
+        DnsLookupResult { domain, peers, succeeded }
+    }
+}
+
+fn split_domain(domain: &str) -> (String, u16) {
+    let mut split = domain.rsplitn(2, ':');
+    let last = unwrap!(split.next());
+    match (split.next(), u16::from_str(last)) {
+        (Some(host), Ok(port)) => (host.to_owned(), port),
+        _ => (domain.to_owned(), DEFAULT_PORT),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expired_records_are_looked_up_again() {
+        let peer = XorAddr::from_bytes([1u8; 32]);
+        let domain = String::from("example.com:1234");
+        let mut cache = DnsCache::new();
+        let now = Instant::now();
+        let valid_until = now + Duration::from_secs(30);
+        let _ = cache.peers.entry(domain.clone()).or_insert_with(HashSet::new).insert(peer);
+        let entry = CacheEntry { addrs: vec![addr!("10.0.0.1:1234")], valid_until };
+        let _ = cache.entries.insert(domain.clone(), entry);
+
+        let (addrs, ttl) = unwrap!(cache.get(&domain, now));
+        assert_eq!(addrs, &[addr!("10.0.0.1:1234")]);
+        assert_eq!(ttl, Sec(30.0));
+        assert_eq!(cache.next_deadline(), Some(valid_until));
+
+        // Nothing to do until it runs out. The resolver never starts, so the lookup stays queued.
+        cache.resolver = Resolver::Starting(future::empty().into_send_boxed());
+        cache.refresh(now);
+        assert!(cache.looking_up.is_empty());
+        assert!(cache.get(&domain, valid_until).is_none());
+        cache.refresh(valid_until);
+        assert!(cache.looking_up.contains(&domain));
+        assert_eq!(cache.queued, vec![domain.clone()]);
+        assert_eq!(cache.next_deadline(), None);
+
+        // Once nobody needs it, it's forgotten.
+        cache.remove_peer(&domain, &peer);
+        assert!(cache.entries.is_empty());
+        assert!(cache.peers.is_empty());
+    }
+}
@@ -0,0 +1,813 @@
+use super::*;
+
+// The bytes a `Msg::Fragment` adds on top of its data: tag, message id, index, count and data
+// length.
+const FRAGMENT_HEADER_LEN: usize = 2 + 8 + 2 + 2 + 2;
+
+// Bytes a `Msg::MtuProbe` adds on top of its padding.
+const PROBE_HEADER_LEN: usize = 2 + 8 + 2;
+
+// We won't split a message into more pieces than this. It keeps acks down to a single small
+// bitmap.
+const MAX_FRAGMENTS: usize = 1024;
+
+// The biggest message we can always send, even over a path stuck at the minimum MTU.
+pub const MAX_FRAGMENTED_LEN: usize = MAX_FRAGMENTS * (MAX_MSG_LEN - FRAGMENT_HEADER_LEN);
+
+// The message sizes we try to raise a path's MTU to, in order. The last one fills a 1500 byte
+// ethernet frame after the IPv6 and UDP headers.
+const PROBE_SIZES: [usize; 3] = [1200, 1400, 1452];
+
+// How long we give a probe to be acknowledged, and how many times a size can fail before we
+// decide the path can't take it.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_PROBE_FAILURES: u32 = 3;
+
+// Paths change, so every so often we go looking for a bigger MTU again.
+const PROBE_RAISE_INTERVAL: Duration = Duration::from_secs(600);
+
+// How long we remember a path's MTU after we stop sending big messages over it.
+const MTU_LIFETIME: Duration = Duration::from_secs(600);
+
+// The sender gives up on a message after sending its fragments this many times.
+const MAX_FRAGMENT_ATTEMPTS: u32 = 5;
+
+// The receiver acknowledges every this many fragments, as well as when a message completes or a
+// fragment arrives twice.
+const ACK_INTERVAL: usize = 16;
+
+// Limits on how much memory partly reassembled messages can take up, in total and for any one
+// peer. The slots for fragments which haven't arrived yet count too.
+const MAX_REASSEMBLY_BYTES: usize = 64 * 1024 * 1024;
+const MAX_PEER_REASSEMBLY_BYTES: usize = 4 * 1024 * 1024;
+
+// How many messages a peer can have partly reassembled at once.
+const MAX_PEER_PARTIALS: usize = 64;
+
+// Acks are tiny, and resends start within a round trip or two.
+pub const FRAGMENT_ACK_UTILITY: Btc = Btc(1e-10);
+pub const FRAGMENT_ACK_UTILITY_DECAY: Sec = Sec(1.0);
+
+// A message which hasn't been completed in this long is thrown away.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long we remember messages we've reassembled so we can keep acknowledging fragments which
+// the sender resends because our ack got lost.
+const COMPLETED_LIFETIME: Duration = Duration::from_secs(60);
+
+// Packetization layer path MTU discovery (RFC 8899) for one peer. We start at a size that gets
+// through pretty much anything and probe upwards with padded messages, moving up a size whenever
+// a probe gets acknowledged.
+struct PathMtu {
+    mtu: usize,
+    next_size: usize,
+    failures: u32,
+    probe: Option<(u64, Instant)>,
+    next_probe: Instant,
+    // When we last split a message for this path.
+    last_used: Instant,
+}
+
+impl PathMtu {
+    fn new(now: Instant) -> PathMtu {
+        PathMtu {
+            mtu: MAX_MSG_LEN,
+            next_size: 0,
+            failures: 0,
+            probe: None,
+            next_probe: now,
+            last_used: now,
+        }
+    }
+
+    // Returns the nonce and size of a probe to send, if it's time for one.
+    fn poll_probe(&mut self, now: Instant) -> Option<(u64, usize)> {
+        if let Some((_, deadline)) = self.probe {
+            if now < deadline {
+                return None;
+            }
+            self.probe = None;
+            self.failures += 1;
+            if self.failures >= MAX_PROBE_FAILURES {
+                // This is as big as the path goes, for now.
+                self.failures = 0;
+                self.next_size = PROBE_SIZES.len();
+                self.next_probe = now + PROBE_RAISE_INTERVAL;
+            }
+        }
+        if self.next_size >= PROBE_SIZES.len() {
+            if now < self.next_probe {
+                return None;
+            }
+            match PROBE_SIZES.iter().position(|size| *size > self.mtu) {
+                Some(next_size) => self.next_size = next_size,
+                None => {
+                    self.next_probe = now + PROBE_RAISE_INTERVAL;
+                    return None;
+                },
+            }
+        }
+        if now < self.next_probe {
+            return None;
+        }
+
+        let nonce = rand::random();
+        self.probe = Some((nonce, now + PROBE_TIMEOUT));
+        Some((nonce, PROBE_SIZES[self.next_size]))
+    }
+
+    fn probe_acked(&mut self, nonce: u64, now: Instant) {
+        match self.probe {
+            Some((probe_nonce, _)) if probe_nonce == nonce => (),
+            _ => return,
+        }
+        self.probe = None;
+        self.failures = 0;
+        self.mtu = PROBE_SIZES[self.next_size];
+        self.next_size += 1;
+        self.next_probe = now;
+    }
+
+    // Messages at the current size keep getting lost, so the path may have changed under us.
+    fn black_hole(&mut self, now: Instant) {
+        if self.mtu > MAX_MSG_LEN {
+            let last_used = self.last_used;
+            *self = PathMtu::new(now);
+            self.next_probe = now + PROBE_RAISE_INTERVAL;
+            self.last_used = last_used;
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        match self.probe {
+            Some((_, deadline)) => Some(deadline),
+            None => Some(self.next_probe),
+        }
+    }
+}
+
+struct OutgoingFragments {
+    // `None` once the receiver has acknowledged the fragment.
+    fragments: Vec<Option<Bytes>>,
+    utility: Btc,
+    utility_time: Instant,
+    utility_decay: Sec,
+    attempts: u32,
+    deadline: Instant,
+}
+
+impl OutgoingFragments {
+    fn outgoing_msg(&self, msg_id: u64, index: usize, data: Bytes) -> OutgoingMsg {
+        // Each fragment is worth its share of the message.
+        let share = data.len() as f64 / self.len() as f64;
+        OutgoingMsg {
+            msg: Msg::Fragment {
+                msg_id,
+                index: index as u16,
+                count: self.fragments.len() as u16,
+                data,
+            },
+            utility: self.utility * share,
+            utility_time: self.utility_time,
+            utility_decay: self.utility_decay,
+        }
+    }
+
+    fn unacked(&self, msg_id: u64) -> Vec<OutgoingMsg> {
+        self.fragments
+        .iter()
+        .enumerate()
+        .filter_map(|(index, data)| {
+            data.clone().map(|data| self.outgoing_msg(msg_id, index, data))
+        })
+        .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.fragments.iter().filter_map(|data| data.as_ref()).map(|data| data.len()).sum()
+    }
+
+    fn utility_at(&self, at: Instant) -> Btc {
+        let time = Sec::from(at.duration_since(self.utility_time));
+        self.utility * (- time / self.utility_decay).exp()
+    }
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "a {} byte message is too big to send", len)]
+pub struct MsgTooBig {
+    pub len: usize,
+}
+
+// What the driver needs to send after checking the fragmenter's timers.
+pub struct FragmentTimeouts {
+    pub resend: Vec<(XorAddr, OutgoingMsg)>,
+    // Peers which failed to acknowledge fragments in time.
+    pub lost: Vec<XorAddr>,
+}
+
+// The sending side. Splits messages that are too big for one datagram, resends the fragments
+// that don't get acknowledged and keeps track of each peer's path MTU.
+pub struct Fragmenter {
+    next_msg_id: u64,
+    outgoing: HashMap<(XorAddr, u64), OutgoingFragments>,
+    mtus: HashMap<XorAddr, PathMtu>,
+}
+
+impl Fragmenter {
+    pub fn new() -> Fragmenter {
+        Fragmenter {
+            next_msg_id: rand::random(),
+            outgoing: HashMap::new(),
+            mtus: HashMap::new(),
+        }
+    }
+
+    // The biggest message we can currently send to `peer` in one datagram.
+    pub fn mtu(&self, peer: &XorAddr) -> usize {
+        match self.mtus.get(peer) {
+            Some(path_mtu) => path_mtu.mtu,
+            None => MAX_MSG_LEN,
+        }
+    }
+
+    // Splits an encoded message into fragments of up to `mtu` bytes for `peer`. Fails if it takes
+    // more than `MAX_FRAGMENTS` of them, which never happens for messages of up to
+    // `MAX_FRAGMENTED_LEN` bytes sent straight to the peer.
+    pub fn split(
+        &mut self,
+        peer: XorAddr,
+        data: Bytes,
+        mtu: usize,
+        utility: Btc,
+        utility_decay: Sec,
+        rto: Duration,
+        now: Instant,
+    ) -> Result<Vec<OutgoingMsg>, MsgTooBig> {
+        let fragment_len = mtu - FRAGMENT_HEADER_LEN;
+        let count = (data.len() + fragment_len - 1) / fragment_len;
+        if count > MAX_FRAGMENTS {
+            return Err(MsgTooBig { len: data.len() });
+        }
+        self.mtus.entry(peer).or_insert_with(|| PathMtu::new(now)).last_used = now;
+
+        let msg_id = self.next_msg_id;
+        self.next_msg_id = self.next_msg_id.wrapping_add(1);
+        let fragments = {
+            (0..count)
+            .map(|i| {
+                let end = cmp::min((i + 1) * fragment_len, data.len());
+                Some(data.slice(i * fragment_len, end))
+            })
+            .collect()
+        };
+        let outgoing = OutgoingFragments {
+            fragments,
+            utility,
+            utility_time: now,
+            utility_decay,
+            attempts: 1,
+            deadline: now + rto,
+        };
+        let msgs = outgoing.unacked(msg_id);
+        let _ = self.outgoing.insert((peer, msg_id), outgoing);
+        Ok(msgs)
+    }
+
+    // Handles a selective ack from `peer`. `received` has a bit set for every fragment they've
+    // got. Returns how many bytes this newly acknowledges.
+    pub fn on_ack(&mut self, peer: XorAddr, msg_id: u64, received: &[u8]) -> Byte {
+        let mut acked = 0;
+        let done = match self.outgoing.get_mut(&(peer, msg_id)) {
+            Some(outgoing) => {
+                for (index, fragment) in outgoing.fragments.iter_mut().enumerate() {
+                    if bit_is_set(received, index) {
+                        if let Some(data) = fragment.take() {
+                            acked += data.len();
+                        }
+                    }
+                }
+                outgoing.fragments.iter().all(|fragment| fragment.is_none())
+            },
+            None => false,
+        };
+        if done {
+            let _ = self.outgoing.remove(&(peer, msg_id));
+        }
+        Byte::from(acked)
+    }
+
+    pub fn probe_acked(&mut self, peer: XorAddr, nonce: u64, now: Instant) {
+        if let Some(path_mtu) = self.mtus.get_mut(&peer) {
+            path_mtu.probe_acked(nonce, now);
+        }
+    }
+
+    // Resends unacknowledged fragments, backing off each time, and sends any MTU probes that are
+    // due. `rto` gives the retransmission timeout for a peer.
+    pub fn poll_timeouts<F>(
+        &mut self,
+        now: Instant,
+        upload_cost: BtcPerByte,
+        rto: F,
+    ) -> FragmentTimeouts
+    where
+        F: Fn(&XorAddr) -> Duration,
+    {
+        let mut timeouts = FragmentTimeouts {
+            resend: Vec::new(),
+            lost: Vec::new(),
+        };
+
+        let expired: Vec<(XorAddr, u64)> = {
+            self.outgoing
+            .iter()
+            .filter(|(_, outgoing)| outgoing.deadline <= now)
+            .map(|(key, _)| *key)
+            .collect()
+        };
+        for (peer, msg_id) in expired {
+            let mut outgoing = unwrap!(self.outgoing.remove(&(peer, msg_id)));
+            timeouts.lost.push(peer);
+            let cost = upload_cost * Byte::from(outgoing.len());
+            if outgoing.attempts >= MAX_FRAGMENT_ATTEMPTS || outgoing.utility_at(now) < cost {
+                if outgoing.attempts >= MAX_FRAGMENT_ATTEMPTS {
+                    if let Some(path_mtu) = self.mtus.get_mut(&peer) {
+                        path_mtu.black_hole(now);
+                    }
+                }
+                continue;
+            }
+
+            outgoing.deadline = now + rto(&peer) * (1 << outgoing.attempts);
+            outgoing.attempts += 1;
+            for msg in outgoing.unacked(msg_id) {
+                timeouts.resend.push((peer, msg));
+            }
+            let _ = self.outgoing.insert((peer, msg_id), outgoing);
+        }
+
+        // Only keep probing the paths we're still sending big messages over. The others keep what
+        // we've learned about them for a while, in case we start again.
+        let sending: HashSet<XorAddr> = self.outgoing.keys().map(|(peer, _)| *peer).collect();
+        self.mtus.retain(|peer, path_mtu| {
+            sending.contains(peer) || now < path_mtu.last_used + MTU_LIFETIME
+        });
+        for (peer, path_mtu) in self.mtus.iter_mut() {
+            if !sending.contains(peer) {
+                path_mtu.probe = None;
+                continue;
+            }
+            if let Some((nonce, size)) = path_mtu.poll_probe(now) {
+                let msg = Msg::MtuProbe {
+                    nonce,
+                    padding: Bytes::from(vec![0u8; size - PROBE_HEADER_LEN]),
+                };
+                // Probes are sent with the same value as a fragment of the same size would have.
+                let utility = upload_cost * Byte::from(size) * 2.0;
+                let outgoing_msg = OutgoingMsg {
+                    msg,
+                    utility,
+                    utility_time: now,
+                    utility_decay: Sec::from(PROBE_TIMEOUT),
+                };
+                timeouts.resend.push((*peer, outgoing_msg));
+            }
+        }
+        timeouts
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let resends = self.outgoing.values().map(|outgoing| outgoing.deadline);
+        let probes = {
+            self.mtus
+            .iter()
+            .filter(|(peer, _)| self.outgoing.keys().any(|(other, _)| other == *peer))
+            .filter_map(|(_, path_mtu)| path_mtu.deadline())
+        };
+        resends.chain(probes).min()
+    }
+}
+
+struct Partial {
+    fragments: Vec<Option<Bytes>>,
+    received: usize,
+    bytes: usize,
+    since_ack: usize,
+    started: Instant,
+    // Memory use so far, up until `charged_until`.
+    byte_secs: ByteSec,
+    charged_until: Instant,
+}
+
+impl Partial {
+    // The memory we're using for this message, including the empty slots.
+    fn footprint(&self) -> usize {
+        self.fragments.len() * mem::size_of::<Option<Bytes>>() + self.bytes
+    }
+
+    fn account(&mut self, now: Instant) {
+        if now > self.charged_until {
+            let held = Sec::from(now.duration_since(self.charged_until));
+            self.byte_secs += Byte::from(self.footprint()) * held;
+            self.charged_until = now;
+        }
+    }
+
+    fn bitmap(&self) -> Bytes {
+        bitmap(self.fragments.len(), |index| self.fragments[index].is_some())
+    }
+}
+
+// What happened to a fragment we received.
+pub struct Reassembled {
+    // An ack to send back to the sender, if one's due.
+    pub ack: Option<Msg>,
+    // The whole message, if this was the last fragment of it.
+    pub complete: Option<Bytes>,
+}
+
+// The receiving side. Holds onto fragments until their message is complete, keeping within our
+// memory limits. Peers get billed for the memory their fragments tie up, whether or not the
+// message ever gets completed.
+pub struct Reassembly {
+    partials: HashMap<(XorAddr, u64), Partial>,
+    completed: HashMap<(XorAddr, u64), (usize, Instant)>,
+    held: usize,
+    held_by_peer: HashMap<XorAddr, usize>,
+    partials_by_peer: HashMap<XorAddr, usize>,
+    // The memory each finished message tied up, waiting to be billed.
+    charges: Vec<(XorAddr, ByteSec)>,
+}
+
+impl Reassembly {
+    pub fn new() -> Reassembly {
+        Reassembly {
+            partials: HashMap::new(),
+            completed: HashMap::new(),
+            held: 0,
+            held_by_peer: HashMap::new(),
+            partials_by_peer: HashMap::new(),
+            charges: Vec::new(),
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        peer: XorAddr,
+        msg_id: u64,
+        index: u16,
+        count: u16,
+        data: Bytes,
+        now: Instant,
+    ) -> Reassembled {
+        let (index, count) = (index as usize, count as usize);
+        let mut reassembled = Reassembled {
+            ack: None,
+            complete: None,
+        };
+        if count == 0 || count > MAX_FRAGMENTS || index >= count || data.is_empty() {
+            return reassembled;
+        }
+
+        if let Some(&(completed_count, _)) = self.completed.get(&(peer, msg_id)) {
+            // They didn't get our last ack.
+            let received = bitmap(completed_count, |_| true);
+            reassembled.ack = Some(Msg::FragmentAck { msg_id, received });
+            return reassembled;
+        }
+
+        let new = !self.partials.contains_key(&(peer, msg_id));
+        let needed = if new {
+            if self.partials_by_peer.get(&peer).cloned().unwrap_or(0) >= MAX_PEER_PARTIALS {
+                return reassembled;
+            }
+            count * mem::size_of::<Option<Bytes>>() + data.len()
+        } else {
+            data.len()
+        };
+        let peer_held = self.held_by_peer.get(&peer).cloned().unwrap_or(0);
+        if self.held + needed > MAX_REASSEMBLY_BYTES ||
+           peer_held + needed > MAX_PEER_REASSEMBLY_BYTES
+        {
+            return reassembled;
+        }
+
+        let finished = {
+            let partial = self.partials.entry((peer, msg_id)).or_insert_with(|| Partial {
+                fragments: vec![None; count],
+                received: 0,
+                bytes: 0,
+                since_ack: 0,
+                started: now,
+                byte_secs: ByteSec(0.0),
+                charged_until: now,
+            });
+            if partial.fragments.len() != count {
+                return reassembled;
+            }
+            if partial.fragments[index].is_some() {
+                // The sender's resending things, so tell them what we've already got.
+                partial.since_ack = 0;
+                reassembled.ack = Some(Msg::FragmentAck { msg_id, received: partial.bitmap() });
+                return reassembled;
+            }
+
+            partial.account(now);
+            partial.bytes += data.len();
+            partial.received += 1;
+            partial.since_ack += 1;
+            // Fragments can wait a while for the rest, so don't let them pin the socket's receive
+            // buffers.
+            partial.fragments[index] = Some(Bytes::from(&data[..]));
+            if partial.received < count && partial.since_ack >= ACK_INTERVAL {
+                partial.since_ack = 0;
+                reassembled.ack = Some(Msg::FragmentAck { msg_id, received: partial.bitmap() });
+            }
+            partial.received == count
+        };
+        self.held += needed;
+        *self.held_by_peer.entry(peer).or_insert(0) += needed;
+        if new {
+            *self.partials_by_peer.entry(peer).or_insert(0) += 1;
+        }
+
+        if finished {
+            let partial = self.remove(peer, msg_id, now);
+            let mut message = BytesMut::with_capacity(partial.bytes);
+            for fragment in partial.fragments {
+                message.extend_from_slice(&unwrap!(fragment)[..]);
+            }
+            let _ = self.completed.insert((peer, msg_id), (count, now));
+            reassembled.ack = Some(Msg::FragmentAck { msg_id, received: bitmap(count, |_| true) });
+            reassembled.complete = Some(message.freeze());
+        }
+        reassembled
+    }
+
+    // Stops holding onto a message, charging its sender for the memory it used.
+    fn remove(&mut self, peer: XorAddr, msg_id: u64, now: Instant) -> Partial {
+        let mut partial = unwrap!(self.partials.remove(&(peer, msg_id)));
+        partial.account(now);
+        let footprint = partial.footprint();
+        self.held -= footprint;
+        let remove_peer = {
+            let peer_held = unwrap!(self.held_by_peer.get_mut(&peer));
+            *peer_held -= footprint;
+            let peer_partials = unwrap!(self.partials_by_peer.get_mut(&peer));
+            *peer_partials -= 1;
+            *peer_partials == 0
+        };
+        if remove_peer {
+            let _ = self.held_by_peer.remove(&peer);
+            let _ = self.partials_by_peer.remove(&peer);
+        }
+        self.charges.push((peer, partial.byte_secs));
+        partial
+    }
+
+    // Throws away the messages which have been waiting too long for their missing fragments.
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<(XorAddr, u64)> = {
+            self.partials
+            .iter()
+            .filter(|(_, partial)| now >= partial.started + REASSEMBLY_TIMEOUT)
+            .map(|(key, _)| *key)
+            .collect()
+        };
+        for (peer, msg_id) in expired {
+            let _ = self.remove(peer, msg_id, now);
+        }
+        self.completed.retain(|_, (_, completed_at)| now < *completed_at + COMPLETED_LIFETIME);
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.partials.values().map(|partial| partial.started + REASSEMBLY_TIMEOUT).min()
+    }
+
+    // What each peer owes us for the memory they've used since we last checked, at `memory` a
+    // byte-second.
+    pub fn take_charges(&mut self, memory: BtcPerByteSec) -> Vec<(XorAddr, Btc)> {
+        {
+            mem::replace(&mut self.charges, Vec::new())
+            .into_iter()
+            .map(|(peer, byte_secs)| (peer, memory * byte_secs))
+            .collect()
+        }
+    }
+
+    pub fn held(&self) -> usize {
+        self.held
+    }
+}
+
+fn bitmap<F: Fn(usize) -> bool>(len: usize, is_set: F) -> Bytes {
+    let mut bitmap = vec![0u8; (len + 7) / 8];
+    for index in 0..len {
+        if is_set(index) {
+            bitmap[index / 8] |= 1 << (index % 8);
+        }
+    }
+    Bytes::from(bitmap)
+}
+
+fn bit_is_set(bitmap: &[u8], index: usize) -> bool {
+    match bitmap.get(index / 8) {
+        Some(byte) => byte & (1 << (index % 8)) != 0,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fragment(outgoing_msg: &OutgoingMsg) -> (u64, u16, u16, Bytes) {
+        match outgoing_msg.msg {
+            Msg::Fragment { msg_id, index, count, ref data } => {
+                (msg_id, index, count, data.clone())
+            },
+            _ => panic!("not a fragment"),
+        }
+    }
+
+    fn received(ack: &Option<Msg>) -> Bytes {
+        match ack {
+            Some(Msg::FragmentAck { received, .. }) => received.clone(),
+            _ => panic!("expected an ack"),
+        }
+    }
+
+    #[test]
+    fn split_and_reassemble() {
+        let peer = XorAddr::from_bytes([1u8; 32]);
+        let now = Instant::now();
+        let message = Bytes::from((0..5000).map(|i| i as u8).collect::<Vec<u8>>());
+        let mut fragmenter = Fragmenter::new();
+        let rto = Duration::from_secs(1);
+        let mtu = fragmenter.mtu(&peer);
+        let fragments = {
+            unwrap!(fragmenter.split(peer, message.clone(), mtu, Btc(1.0), Sec(10.0), rto, now))
+        };
+        // 484 bytes of data fit in each 500 byte fragment.
+        assert_eq!(fragments.len(), 11);
+
+        let mut reassembly = Reassembly::new();
+        let mut last_ack = None;
+        // Deliver them out of order, and skip one the first time around.
+        for outgoing_msg in fragments.iter().rev().filter(|msg| fragment(msg).1 != 3) {
+            let (msg_id, index, count, data) = fragment(outgoing_msg);
+            let reassembled = reassembly.add(peer, msg_id, index, count, data, now);
+            assert!(reassembled.complete.is_none());
+            last_ack = reassembled.ack.or(last_ack);
+        }
+        assert!(last_ack.is_none());
+
+        // Our timer runs out and we resend. The duplicates get the receiver to tell us what's
+        // missing.
+        let later = now + rto;
+        let timeouts = fragmenter.poll_timeouts(later, BtcPerByte(0.0), |_| rto);
+        assert_eq!(timeouts.lost, vec![peer]);
+        let resent: Vec<OutgoingMsg> = {
+            timeouts.resend.into_iter()
+            .filter(|(_, msg)| match msg.msg { Msg::Fragment { .. } => true, _ => false })
+            .map(|(_, msg)| msg)
+            .collect()
+        };
+        assert_eq!(resent.len(), 11);
+        let (msg_id, index, count, data) = fragment(&resent[0]);
+        let reassembled = reassembly.add(peer, msg_id, index, count, data, later);
+        let acked = fragmenter.on_ack(peer, msg_id, &received(&reassembled.ack));
+        assert_eq!(acked, Byte(5000.0 - 484.0));
+
+        let timeouts = fragmenter.poll_timeouts(later + rto * 2, BtcPerByte(0.0), |_| rto);
+        let missing: Vec<&OutgoingMsg> = {
+            timeouts.resend.iter()
+            .map(|(_, msg)| msg)
+            .filter(|msg| match msg.msg { Msg::Fragment { .. } => true, _ => false })
+            .collect()
+        };
+        assert_eq!(missing.len(), 1);
+        let (msg_id, index, count, data) = fragment(missing[0]);
+        assert_eq!(index, 3);
+        let reassembled = reassembly.add(peer, msg_id, index, count, data, later + rto * 2);
+        assert_eq!(unwrap!(reassembled.complete), message);
+
+        let acked = fragmenter.on_ack(peer, msg_id, &received(&reassembled.ack));
+        assert_eq!(acked, Byte(484.0));
+        assert_eq!(reassembly.held(), 0);
+
+        // The sender gets billed for the memory.
+        let charges = reassembly.take_charges(Costs::base().memory);
+        assert_eq!(charges.len(), 1);
+        assert_eq!(charges[0].0, peer);
+        assert!(charges[0].1 > Btc(0.0));
+    }
+
+    #[test]
+    fn reassembly_memory_is_limited_per_peer() {
+        let greedy = XorAddr::from_bytes([1u8; 32]);
+        let other = XorAddr::from_bytes([2u8; 32]);
+        let now = Instant::now();
+        let mut reassembly = Reassembly::new();
+        let data = Bytes::from(vec![0u8; 64 * 1024]);
+        let count = MAX_FRAGMENTS as u16;
+
+        // Room for every fragment gets set aside when the first one arrives.
+        let _ = reassembly.add(greedy, 0, 0, count, data.clone(), now);
+        let footprint = MAX_FRAGMENTS * mem::size_of::<Option<Bytes>>() + data.len();
+        assert_eq!(reassembly.held(), footprint);
+        for msg_id in 1..MAX_PEER_PARTIALS {
+            let _ = reassembly.add(greedy, msg_id as u64, 0, count, data.clone(), now);
+        }
+        assert!(reassembly.held() <= MAX_PEER_REASSEMBLY_BYTES);
+        assert!(reassembly.held() + footprint > MAX_PEER_REASSEMBLY_BYTES);
+        let accepted = reassembly.partials.len();
+
+        let reassembled = reassembly.add(other, 0, 0, 1, data.clone(), now);
+        assert!(reassembled.complete.is_some());
+
+        reassembly.expire(now + REASSEMBLY_TIMEOUT);
+        assert_eq!(reassembly.held(), 0);
+        let charges = reassembly.take_charges(Costs::base().memory);
+        assert_eq!(charges.iter().filter(|(peer, _)| *peer == greedy).count(), accepted);
+    }
+
+    #[test]
+    fn partial_messages_are_limited_per_peer() {
+        let peer = XorAddr::from_bytes([1u8; 32]);
+        let now = Instant::now();
+        let mut reassembly = Reassembly::new();
+        let data = Bytes::from(&b"hello"[..]);
+        for msg_id in 0..(2 * MAX_PEER_PARTIALS) {
+            let _ = reassembly.add(peer, msg_id as u64, 0, 2, data.clone(), now);
+        }
+        assert_eq!(reassembly.partials.len(), MAX_PEER_PARTIALS);
+
+        // The ones we kept can still be finished, which makes room for another.
+        let reassembled = reassembly.add(peer, 0, 1, 2, data.clone(), now);
+        assert!(reassembled.complete.is_some());
+        let _ = reassembly.add(peer, 1000, 0, 2, data.clone(), now);
+        assert!(reassembly.partials.contains_key(&(peer, 1000)));
+    }
+
+    #[test]
+    fn learned_mtus_outlive_the_messages_which_found_them() {
+        let peer = XorAddr::from_bytes([1u8; 32]);
+        let now = Instant::now();
+        let rto = Duration::from_secs(1);
+        let mut fragmenter = Fragmenter::new();
+        let mtu = fragmenter.mtu(&peer);
+        let too_big = Bytes::from(vec![0u8; MAX_FRAGMENTED_LEN + 1]);
+        assert!(fragmenter.split(peer, too_big, mtu, Btc(1.0), Sec(10.0), rto, now).is_err());
+
+        let message = Bytes::from(vec![0u8; 5000]);
+        let fragments = {
+            unwrap!(fragmenter.split(peer, message, mtu, Btc(1.0), Sec(10.0), rto, now))
+        };
+        let timeouts = fragmenter.poll_timeouts(now, BtcPerByte(0.0), |_| rto);
+        let nonce = match timeouts.resend[..] {
+            [(_, OutgoingMsg { msg: Msg::MtuProbe { nonce, .. }, .. })] => nonce,
+            _ => panic!("expected a probe"),
+        };
+        fragmenter.probe_acked(peer, nonce, now);
+        let (msg_id, ..) = fragment(&fragments[0]);
+        let _ = fragmenter.on_ack(peer, msg_id, &bitmap(fragments.len(), |_| true));
+        assert_eq!(fragmenter.mtu(&peer), PROBE_SIZES[0]);
+
+        // We're not sending anything big, so there's no more probing but the MTU sticks around.
+        let later = now + Duration::from_secs(1);
+        let timeouts = fragmenter.poll_timeouts(later, BtcPerByte(0.0), |_| rto);
+        assert!(timeouts.resend.is_empty());
+        assert_eq!(fragmenter.next_deadline(), None);
+        assert_eq!(fragmenter.mtu(&peer), PROBE_SIZES[0]);
+
+        let _ = fragmenter.poll_timeouts(now + MTU_LIFETIME, BtcPerByte(0.0), |_| rto);
+        assert_eq!(fragmenter.mtu(&peer), MAX_MSG_LEN);
+    }
+
+    #[test]
+    fn path_mtu_rises_with_acked_probes() {
+        let now = Instant::now();
+        let mut path_mtu = PathMtu::new(now);
+        let (nonce, size) = unwrap!(path_mtu.poll_probe(now));
+        assert_eq!(size, PROBE_SIZES[0]);
+        assert!(path_mtu.poll_probe(now).is_none());
+        path_mtu.probe_acked(nonce, now);
+        assert_eq!(path_mtu.mtu, PROBE_SIZES[0]);
+
+        // The next size never gets through.
+        let mut at = now;
+        for _ in 0..MAX_PROBE_FAILURES {
+            let (_, size) = unwrap!(path_mtu.poll_probe(at));
+            assert_eq!(size, PROBE_SIZES[1]);
+            at += PROBE_TIMEOUT;
+        }
+        assert!(path_mtu.poll_probe(at).is_none());
+        assert_eq!(path_mtu.mtu, PROBE_SIZES[0]);
+
+        // Until we try again much later.
+        let (_, size) = unwrap!(path_mtu.poll_probe(at + PROBE_RAISE_INTERVAL));
+        assert_eq!(size, PROBE_SIZES[1]);
+    }
+}
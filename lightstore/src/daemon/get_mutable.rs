@@ -1,80 +1,428 @@
 use super::*;
-use crate::daemon::{Peer, Msg};
-use futures::sync::oneshot;
 
+// How long a mutable record lasts. Owners put a new version whenever the data changes, and need to
+// put the same one again before then if it hasn't.
+pub const MUTABLE_RECORD_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+// Plenty for a repository's refs, and small enough that a reply carrying it fits in a payload.
+pub const MAX_MUTABLE_LEN: usize = 32 * 1024;
+
+// Other people's data only gets so much of our memory.
+const MAX_STORED_BYTES: usize = 64 * 1024 * 1024;
+
+// Going through every record is too much work to do on every poll.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Keeps an owner's signature from being passed off as anything else they've signed.
+const SIGNATURE_CONTEXT: &[u8] = b"lightstore mutable record";
+
+#[derive(Debug, Fail)]
+pub enum MutableRecordError {
+    #[fail(display = "record's data is too big")]
+    TooBig,
+    #[fail(display = "record has expired")]
+    Expired,
+    #[fail(display = "record expires too far in the future")]
+    TooLong,
+    #[fail(display = "record has an invalid signature")]
+    BadSignature,
+    #[fail(display = "already have a newer version of this record")]
+    Stale,
+    #[fail(display = "not storing any more records")]
+    StoreFull,
+}
+
+// The latest version of some data that only the holder of `id`'s secret key can change.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MutableRecord {
+    pub id: PublicSignKey,
+    pub version: u64,
+    pub data: Bytes,
+    pub expires: u64,
+    pub signature: Signature,
+}
+
+impl MutableRecord {
+    pub fn new(keypair: &SignKeypair, version: u64, data: Bytes, expires: u64) -> MutableRecord {
+        let id = keypair.public;
+        let signature = keypair.sign(&signed_bytes(&id, version, &data, expires));
+        MutableRecord { id, version, data, expires, signature }
+    }
+
+    pub fn verify(&self, now: u64) -> Result<(), MutableRecordError> {
+        if self.data.len() > MAX_MUTABLE_LEN {
+            return Err(MutableRecordError::TooBig);
+        }
+        if self.expires <= now {
+            return Err(MutableRecordError::Expired);
+        }
+        if self.expires > now + MUTABLE_RECORD_LIFETIME.as_secs() + MAX_CLOCK_SKEW {
+            return Err(MutableRecordError::TooLong);
+        }
+        let signed = signed_bytes(&self.id, self.version, &self.data, self.expires);
+        if !self.id.verify(&signed, &self.signature) {
+            return Err(MutableRecordError::BadSignature);
+        }
+        Ok(())
+    }
+
+    // Whether this supersedes `other`. A record that's only been put again to keep it alive
+    // supersedes the one it renews.
+    pub fn is_newer_than(&self, other: &MutableRecord) -> bool {
+        (self.version, self.expires) > (other.version, other.expires)
+    }
+
+    // What it costs to hold onto this record until it expires, at `memory` a byte-second.
+    pub fn storage_cost(&self, now: u64, memory: BtcPerByteSec) -> Btc {
+        let len = Byte::from(32 + 8 + 8 + 64 + self.data.len());
+        let lifetime = Sec(self.expires.saturating_sub(now) as f64);
+        memory * (len * lifetime)
+    }
+}
+
+fn signed_bytes(id: &PublicSignKey, version: u64, data: &Bytes, expires: u64) -> Bytes {
+    let mut bytes = BytesMut::with_capacity(SIGNATURE_CONTEXT.len() + 32 + 8 + 8 + data.len());
+    bytes.put_slice(SIGNATURE_CONTEXT);
+    bytes.put_slice(&id.as_bytes());
+    bytes.put_u64_be(version);
+    bytes.put_u64_be(expires);
+    bytes.put_slice(&data[..]);
+    bytes.freeze()
+}
+
+// The records other peers have asked us to hold onto.
+pub struct MutableStore {
+    records: HashMap<PublicSignKey, MutableRecord>,
+    bytes: usize,
+    next_expire: Instant,
+}
+
+impl MutableStore {
+    pub fn new() -> MutableStore {
+        MutableStore {
+            records: HashMap::new(),
+            bytes: 0,
+            next_expire: Instant::now() + EXPIRE_INTERVAL,
+        }
+    }
+
+    // Stores a record, replacing any older version.
+    pub fn add(&mut self, record: MutableRecord, now: u64) -> Result<(), MutableRecordError> {
+        record.verify(now)?;
+        if let Some(existing) = self.records.get(&record.id) {
+            if !record.is_newer_than(existing) {
+                return Err(MutableRecordError::Stale);
+            }
+        }
+        if !self.has_room_for(&record) {
+            return Err(MutableRecordError::StoreFull);
+        }
+
+        self.bytes += record.data.len();
+        if let Some(replaced) = self.records.insert(record.id, record) {
+            self.bytes -= replaced.data.len();
+        }
+        Ok(())
+    }
+
+    // Whether `add` would find space for the record, counting the space freed by the version it
+    // replaces.
+    pub fn has_room_for(&self, record: &MutableRecord) -> bool {
+        let freed = match self.records.get(&record.id) {
+            Some(existing) => existing.data.len(),
+            None => 0,
+        };
+        self.bytes - freed + record.data.len() <= MAX_STORED_BYTES
+    }
+
+    pub fn get(&self, id: &PublicSignKey, now: u64) -> Option<MutableRecord> {
+        match self.records.get(id) {
+            Some(record) if record.expires > now => Some(record.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn expire(&mut self, now: Instant, unix_now: u64) {
+        if now < self.next_expire {
+            return;
+        }
+        self.next_expire = now + EXPIRE_INTERVAL;
+
+        let mut bytes = 0;
+        self.records.retain(|_, record| {
+            let keep = record.expires > unix_now;
+            if keep {
+                bytes += record.data.len();
+            }
+            keep
+        });
+        self.bytes = bytes;
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.records.is_empty() {
+            return None;
+        }
+        Some(self.next_expire)
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum GetMutableError {
+    #[fail(display = "nobody we could afford to ask has the data")]
+    NotFound,
+    #[fail(display = "the daemon shut down")]
+    Shutdown,
+}
+
+// Resolves to the newest version of some mutable data we could find.
 pub struct GetMutable {
-    crate result_rx: oneshot::Receiver<Vec<u8>>,
+    result_rx: oneshot::Receiver<Result<MutableRecord, GetMutableError>>,
+}
+
+impl GetMutable {
+    pub fn new(result_rx: oneshot::Receiver<Result<MutableRecord, GetMutableError>>) -> GetMutable {
+        GetMutable { result_rx }
+    }
 }
 
 impl Future for GetMutable {
-    type Item = Vec<u8>;
-    type Error = Void;
+    type Item = MutableRecord;
+    type Error = GetMutableError;
 
-    fn poll(&mut self) -> Result<Async<Vec<u8>>, Void> {
-        Ok(unwrap!(self.result_rx.poll()))
+    fn poll(&mut self) -> Result<Async<MutableRecord>, GetMutableError> {
+        match self.result_rx.poll() {
+            Ok(Async::Ready(res)) => res.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(oneshot::Canceled) => Err(GetMutableError::Shutdown),
+        }
     }
 }
 
-pub struct GetMutableParams {
-    pub price: Btc,
-    pub price_decay_over_time: Sec,
-    pub price_decay_over_versions: f64,
+#[derive(Debug, Fail)]
+pub enum PutMutableError {
+    #[fail(display = "invalid record: {}", _0)]
+    InvalidRecord(MutableRecordError),
+    #[fail(display = "none of the peers we asked stored the record")]
+    NotStored,
+    #[fail(display = "the daemon shut down")]
+    Shutdown,
 }
 
-#[derive(Default)]
-pub struct PendingGetMutable {
-    clients: Vec<PendingGetMutableClient>,
-    peers_messaged: BTreeMap<PublicEncryptKey, Instant>,
+// Resolves to how many peers stored a new version of some mutable data.
+pub struct PutMutable {
+    result_rx: oneshot::Receiver<Result<usize, PutMutableError>>,
 }
 
-pub struct PendingGetMutableClient {
-    result_tx: oneshot::Sender<Vec<u8>>,
-    params: GetMutableParams,
+impl PutMutable {
+    pub fn new(result_rx: oneshot::Receiver<Result<usize, PutMutableError>>) -> PutMutable {
+        PutMutable { result_rx }
+    }
+}
+
+impl Future for PutMutable {
+    type Item = usize;
+    type Error = PutMutableError;
+
+    fn poll(&mut self) -> Result<Async<usize>, PutMutableError> {
+        match self.result_rx.poll() {
+            Ok(Async::Ready(res)) => res.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(oneshot::Canceled) => Err(PutMutableError::Shutdown),
+        }
+    }
+}
+
+// A lookup of some mutable data. It asks the peers closest to it in rounds, each offering more
+// than the last, until one of them has it or we can't offer any more. Like `PendingLookup` this
+// doesn't do any IO, the driver sends the queries and feeds the replies in.
+pub struct PendingGetMutable {
+    id: PublicSignKey,
+    bid: Bid,
+    // Whether the caller picked the price, in which case we never raise it.
+    explicit: bool,
+    asked: HashSet<XorAddr>,
+    remaining: usize,
+    best: Option<MutableRecord>,
+    result_tx: oneshot::Sender<Result<MutableRecord, GetMutableError>>,
 }
 
 impl PendingGetMutable {
-    pub fn add_client(
-        &mut self,
-        params: GetMutableParams,
-        result_tx: oneshot::Sender<Vec<u8>>,
-    ) {
-        self.clients.push(PendingGetMutableClient {
-            params, result_tx,
-        })
-    }
-
-    pub fn poll(
-        &mut self,
-        node_id: PublicSignKey,
-        data_id: PublicSignKey,
-        known_peers: &mut BTreeMap<XorAddr, Peer>,
-    ) -> Async<()>
-    {
-        if !self.peers_messaged.is_empty() {
-            return Async::NotReady;
-        }
-
-        let key = node_id.to_xor_addr() ^ data_id.to_xor_addr();
-
-        // TODO: pick proper params
-        let params = GetMutableParams {
-            price: Btc(0.0),
-            price_decay_over_time: Sec(1.0),
-            price_decay_over_versions: 1.0,
+    pub fn new(
+        id: PublicSignKey,
+        local: Option<MutableRecord>,
+        bid: Bid,
+        explicit: bool,
+        result_tx: oneshot::Sender<Result<MutableRecord, GetMutableError>>,
+    ) -> PendingGetMutable {
+        PendingGetMutable {
+            id,
+            bid,
+            explicit,
+            asked: HashSet::new(),
+            remaining: 0,
+            best: local,
+            result_tx,
+        }
+    }
+
+    pub fn id(&self) -> PublicSignKey {
+        self.id
+    }
+
+    pub fn bid(&self) -> &Bid {
+        &self.bid
+    }
+
+    // Starts a round, asking `peers`. Returns whether there's anyone to wait on.
+    pub fn start_round(&mut self, peers: &[XorAddr]) -> bool {
+        self.asked = peers.iter().cloned().collect();
+        self.remaining = self.asked.len();
+        self.remaining > 0
+    }
+
+    // Takes the contacts a peer pointed us to instead of answering. Returns the ones we haven't
+    // asked this round, which we're now waiting on as well. Call this before `add`ing the reply
+    // they came in.
+    pub fn follow(&mut self, contacts: Vec<XorAddr>) -> Vec<XorAddr> {
+        let mut to_ask = Vec::new();
+        for contact in contacts {
+            if self.asked.len() >= MAX_LOOKUP_QUERIES {
+                break;
+            }
+            if self.asked.insert(contact) {
+                to_ask.push(contact);
+            }
+        }
+        self.remaining += to_ask.len();
+        to_ask
+    }
+
+    // Takes a peer's reply, keeping the newest version anyone's sent. Returns whether that was the
+    // last peer we were waiting on this round.
+    pub fn add(&mut self, record: Option<MutableRecord>, now: u64) -> bool {
+        self.remaining = self.remaining.saturating_sub(1);
+        if let Some(record) = record {
+            let newer = match self.best {
+                Some(ref best) => record.is_newer_than(best),
+                None => true,
+            };
+            if record.id == self.id && newer && record.verify(now).is_ok() {
+                self.best = Some(record);
+            }
+        }
+        self.remaining == 0
+    }
+
+    // Called once a round's over. If nobody had the data and there's room in the budget, hands
+    // the lookup back with a higher bid to go round again. Otherwise we're done.
+    pub fn next_round(mut self, bid_strategy: &BidStrategy) -> Option<PendingGetMutable> {
+        if self.best.is_none() {
+            if let Some(bid) = bid_strategy.raise(&self.bid, self.explicit) {
+                self.bid = bid;
+                return Some(self);
+            }
+        }
+        self.finish();
+        None
+    }
+
+    pub fn finish(self) {
+        let res = match self.best {
+            Some(record) => Ok(record),
+            None => Err(GetMutableError::NotFound),
         };
-        let msg = Msg::SenderGetMutable {
-            id: data_id,
-            params: params,
+        let _ = self.result_tx.send(res);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records_are_checked_and_replaced_by_newer_versions() {
+        let keypair = unwrap!(SignKeypair::new());
+        let now = unix_time();
+        let expires = now + 100;
+        let mut store = MutableStore::new();
+
+        let first = MutableRecord::new(&keypair, 1, Bytes::from(&b"first"[..]), expires);
+        unwrap!(first.verify(now));
+        let mut tampered = first.clone();
+        tampered.data = Bytes::from(&b"forged"[..]);
+        match tampered.verify(now) {
+            Err(MutableRecordError::BadSignature) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        assert!(first.storage_cost(now, Costs::base().memory) > Btc(0.0));
+        unwrap!(store.add(first.clone(), now));
+        let second = MutableRecord::new(&keypair, 2, Bytes::from(&b"second"[..]), expires);
+        unwrap!(store.add(second.clone(), now));
+        match store.add(first, now) {
+            Err(MutableRecordError::Stale) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(store.get(&keypair.public, now), Some(second));
+        assert_eq!(store.bytes, b"second".len());
+
+        let too_big = Bytes::from(vec![0u8; MAX_MUTABLE_LEN + 1]);
+        match MutableRecord::new(&keypair, 3, too_big, expires).verify(now) {
+            Err(MutableRecordError::TooBig) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn lookups_raise_their_bid_up_to_the_budget() {
+        let keypair = unwrap!(SignKeypair::new());
+        let now = unix_time();
+        let config = Config {
+            lookup_budget: Some(Btc(1e-6)),
+            ..Config::default()
         };
-        for (_, peer) in known_peers.range_mut(&key..) {
-            peer.send_message(msg);
-            return Async::NotReady;
+        let strategy = BidStrategy::new(&config);
+        let infos = vec![PeerInfo {
+            addrs: Vec::new(),
+            exp_download_fee: BtcPerByte(1e-11).log(),
+            var_download_fee: 0.0,
+            relay_fee: None,
+        }];
+        let peers: Vec<XorAddr> = (0..2).map(|i| XorAddr::from_bytes([i; 32])).collect();
+
+        // Nobody has it, so each round offers more than the last until we're offering the whole
+        // budget.
+        let (result_tx, result_rx) = oneshot::channel();
+        let bid = strategy.first_bid(None, &infos, Costs::base().upload);
+        let mut lookup = PendingGetMutable::new(keypair.public, None, bid, false, result_tx);
+        let mut prices = Vec::new();
+        loop {
+            prices.push(lookup.bid().offer().max_payout());
+            assert!(lookup.start_round(&peers));
+            assert!(!lookup.add(None, now));
+            assert!(lookup.add(None, now));
+            lookup = match lookup.next_round(&strategy) {
+                Some(lookup) => lookup,
+                None => break,
+            };
         }
-        for (_, peer) in known_peers.range_mut(..&key) {
-            peer.send_message(msg);
-            return Async::NotReady;
+        assert!(prices.windows(2).all(|w| w[0] < w[1]), "prices == {:?}", prices);
+        assert_eq!(prices.last(), Some(&Btc(1e-6)));
+        match result_rx.wait() {
+            Ok(Err(GetMutableError::NotFound)) => (),
+            _ => panic!("expected the lookup to fail"),
         }
-        Async::NotReady
+
+        // Once someone answers we stop.
+        let (result_tx, result_rx) = oneshot::channel();
+        let bid = strategy.first_bid(None, &infos, Costs::base().upload);
+        let mut lookup = PendingGetMutable::new(keypair.public, None, bid, false, result_tx);
+        let record = MutableRecord::new(&keypair, 1, Bytes::from(&b"refs"[..]), now + 100);
+        assert!(lookup.start_round(&peers));
+        assert!(!lookup.add(Some(record.clone()), now));
+        assert!(lookup.add(None, now));
+        assert!(lookup.next_round(&strategy).is_none());
+        assert_eq!(unwrap!(unwrap!(result_rx.wait())), record);
     }
 }
-
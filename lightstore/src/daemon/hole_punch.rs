@@ -0,0 +1,352 @@
+use super::*;
+
+// How often we fire a round of probes at the peer while punching.
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+
+// How many rounds of probes we send before giving up. Both sides start probing when the
+// rendezvous peer's introductions arrive, which can be a few hundred milliseconds apart.
+const MAX_PROBE_ROUNDS: u32 = 30;
+
+// How long an address we punched through is good for. NATs tend to forget idle UDP mappings
+// after about this long.
+pub const PUNCHED_MAPPING_LIFETIME: Sec = Sec(30.0);
+
+// How long we wait on a rendezvous peer to introduce us after we've asked. We hold onto the
+// nonce for a while after it's been used so that we don't act on the same introduction twice.
+const INTRODUCTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HolePunchState {
+    Probing,
+    Succeeded(SocketAddr),
+    Failed,
+}
+
+// One side of a hole punch. Once a rendezvous peer has told us the reflexive address of the peer
+// we want to reach (and told them ours) both sides fire probes at each other until one of them
+// makes it through. The first probe to arrive opens up the path in both directions since the
+// receiver's own probes have already created a mapping in its NAT.
+//
+// This doesn't do any IO itself. The driver asks it where to send probes and tells it when a
+// probe or ack arrives.
+pub struct HolePunch {
+    peer: XorAddr,
+    rendezvous: XorAddr,
+    nonce: u64,
+    candidates: Vec<SocketAddr>,
+    probe_rounds: u32,
+    next_probe: Instant,
+    state: HolePunchState,
+}
+
+impl HolePunch {
+    pub fn new(
+        peer: XorAddr,
+        rendezvous: XorAddr,
+        nonce: u64,
+        candidates: Vec<SocketAddr>,
+        now: Instant,
+    ) -> HolePunch {
+        HolePunch {
+            peer,
+            rendezvous,
+            nonce,
+            candidates,
+            probe_rounds: 0,
+            next_probe: now,
+            state: HolePunchState::Probing,
+        }
+    }
+
+    pub fn peer(&self) -> XorAddr {
+        self.peer
+    }
+
+    // Who introduced us, which goes to show they can reach the peer.
+    pub fn rendezvous(&self) -> XorAddr {
+        self.rendezvous
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn state(&self) -> HolePunchState {
+        self.state
+    }
+
+    // Returns the addresses we should send a probe to right now, if any.
+    pub fn poll_probes(&mut self, now: Instant) -> Vec<SocketAddr> {
+        if self.state != HolePunchState::Probing || now < self.next_probe {
+            return Vec::new();
+        }
+        if self.probe_rounds >= MAX_PROBE_ROUNDS {
+            self.state = HolePunchState::Failed;
+            return Vec::new();
+        }
+
+        self.probe_rounds += 1;
+        self.next_probe = now + PROBE_INTERVAL;
+        self.candidates.clone()
+    }
+
+    // When we next need to send a round of probes.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        match self.state {
+            HolePunchState::Probing => Some(self.next_probe),
+            HolePunchState::Succeeded(..) | HolePunchState::Failed => None,
+        }
+    }
+
+    // Called when a probe or ack arrives. The source address may not be one of our candidates if
+    // the peer's NAT picked a new port for us, in which case it's the address we want. Returns
+    // whether it carried our nonce.
+    pub fn got_response(&mut self, nonce: u64, from: SocketAddr) -> bool {
+        if nonce != self.nonce {
+            return false;
+        }
+        if self.state == HolePunchState::Probing {
+            self.state = HolePunchState::Succeeded(from);
+        }
+        true
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IntroductionAction {
+    // It's the introduction we asked for, so start probing.
+    Punch,
+    // Someone wants to reach us. We don't fire probes at addresses we haven't asked for, since
+    // anyone could have us spray them at a victim, so we ask the rendezvous to introduce us
+    // ourselves.
+    AskBack,
+    Ignore,
+}
+
+struct PendingIntroduction {
+    target: XorAddr,
+    rendezvous: XorAddr,
+    expires: Instant,
+    answered: bool,
+}
+
+// The introductions we've asked rendezvous peers for, by the nonce we picked for them.
+pub struct Introductions {
+    pending: HashMap<u64, PendingIntroduction>,
+}
+
+impl Introductions {
+    pub fn new() -> Introductions {
+        Introductions {
+            pending: HashMap::new(),
+        }
+    }
+
+    // Notes that we're asking `rendezvous` to introduce us to `target`, and returns the nonce to
+    // ask with, or `None` if we're already waiting on an introduction to `target`.
+    pub fn ask(&mut self, rendezvous: XorAddr, target: XorAddr, now: Instant) -> Option<u64> {
+        if self.pending.values().any(|pending| pending.target == target) {
+            return None;
+        }
+        let nonce = rand::random();
+        self.ask_with(rendezvous, target, nonce, now);
+        Some(nonce)
+    }
+
+    // Notes that we're asking `rendezvous` to introduce us to `target` using the nonce `target`
+    // picked when it asked for us.
+    pub fn ask_with(&mut self, rendezvous: XorAddr, target: XorAddr, nonce: u64, now: Instant) {
+        let pending = PendingIntroduction {
+            target,
+            rendezvous,
+            expires: now + INTRODUCTION_TIMEOUT,
+            answered: false,
+        };
+        let _ = self.pending.insert(nonce, pending);
+    }
+
+    // Works out what to do about `rendezvous` introducing us to `peer`.
+    pub fn on_introduction(
+        &mut self,
+        rendezvous: XorAddr,
+        peer: XorAddr,
+        nonce: u64,
+        now: Instant,
+    ) -> IntroductionAction {
+        match self.pending.get_mut(&nonce) {
+            Some(pending) => {
+                if pending.answered || pending.rendezvous != rendezvous || pending.target != peer {
+                    return IntroductionAction::Ignore;
+                }
+                pending.answered = true;
+                IntroductionAction::Punch
+            },
+            None => {
+                if self.pending.values().any(|pending| pending.target == peer) {
+                    return IntroductionAction::Ignore;
+                }
+                self.ask_with(rendezvous, peer, nonce, now);
+                IntroductionAction::AskBack
+            },
+        }
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        self.pending.retain(|_, pending| pending.expires > now);
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|pending| pending.expires).min()
+    }
+}
+
+// Picks a peer to ask for an introduction to `target`. It has to be able to reach `target` as well
+// as us, so we'd rather ask one that's already relaying for it, and otherwise one that's publicly
+// reachable, since `target` is likely to have spoken to it.
+pub fn pick_rendezvous(
+    peer_infos: &HashMap<XorAddr, Arc<PeerInfo>>,
+    target: &XorAddr,
+    now: Instant,
+) -> Option<XorAddr> {
+    let relaying = peer_infos.get(target).and_then(|peer_info| {
+        peer_info.addrs.iter().find_map(|addr| match addr.kind() {
+            AddressKind::Relayed { relay, .. } => Some(*relay),
+            _ => None,
+        })
+    });
+    if relaying.is_some() {
+        return relaying;
+    }
+    peer_infos
+    .iter()
+    .filter(|(xor_addr, peer_info)| {
+        *xor_addr != target && peer_info.relay_fee.is_some() && peer_info.best_addr(now).is_some()
+    })
+    .map(|(xor_addr, _)| *xor_addr)
+    .next()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::daemon::nat_sim::{NatSim, NatKind};
+
+    // Runs a hole punch between two hosts sitting behind simulated NATs, after each has spoken to
+    // a rendezvous peer at `rendezvous`.
+    fn punch(kind_a: NatKind, kind_b: NatKind) -> (HolePunchState, HolePunchState) {
+        let rendezvous = addr!("10.0.0.1:1000");
+        let inside_a = addr!("192.168.0.2:5000");
+        let inside_b = addr!("192.168.1.2:6000");
+        let mut nat_a = NatSim::new(ip!("10.0.1.1"), kind_a);
+        let mut nat_b = NatSim::new(ip!("10.0.2.1"), kind_b);
+
+        let public_a = nat_a.outbound(inside_a, rendezvous);
+        let public_b = nat_b.outbound(inside_b, rendezvous);
+
+        // A asks the rendezvous to introduce it to B, and B asks back when it hears of A.
+        let key_a = XorAddr::from_bytes([1u8; 32]);
+        let key_b = XorAddr::from_bytes([2u8; 32]);
+        let key_rendezvous = XorAddr::from_bytes([3u8; 32]);
+        let mut now = Instant::now();
+        let mut intros_a = Introductions::new();
+        let mut intros_b = Introductions::new();
+        let nonce = unwrap!(intros_a.ask(key_rendezvous, key_b, now));
+        let action_a = intros_a.on_introduction(key_rendezvous, key_b, nonce, now);
+        let action_b = intros_b.on_introduction(key_rendezvous, key_a, nonce, now);
+        assert_eq!((action_a, action_b), (IntroductionAction::Punch, IntroductionAction::AskBack));
+        let action_a = intros_a.on_introduction(key_rendezvous, key_b, nonce, now);
+        let action_b = intros_b.on_introduction(key_rendezvous, key_a, nonce, now);
+        assert_eq!((action_a, action_b), (IntroductionAction::Ignore, IntroductionAction::Punch));
+
+        let mut punch_a = HolePunch::new(key_b, key_rendezvous, nonce, vec![public_b], now);
+        let mut punch_b = HolePunch::new(key_a, key_rendezvous, nonce, vec![public_a], now);
+
+        // Sends a probe from the host behind `nat_src` and, if it makes it through, an ack back
+        // the other way.
+        fn deliver(
+            inside_src: SocketAddr,
+            dest: SocketAddr,
+            nat_src: &mut NatSim,
+            nat_dest: &mut NatSim,
+            punch_src: &mut HolePunch,
+            punch_dest: &mut HolePunch,
+        ) {
+            let public_src = nat_src.outbound(inside_src, dest);
+            if let Some(inside_dest) = nat_dest.inbound(public_src, dest) {
+                if !punch_dest.got_response(punch_src.nonce(), public_src) {
+                    return;
+                }
+                let public_dest = nat_dest.outbound(inside_dest, public_src);
+                if nat_src.inbound(public_dest, public_src).is_some() {
+                    let _ = punch_src.got_response(punch_dest.nonce(), public_dest);
+                }
+            }
+        }
+
+        for _ in 0..(MAX_PROBE_ROUNDS + 1) {
+            for dest in punch_a.poll_probes(now) {
+                deliver(inside_a, dest, &mut nat_a, &mut nat_b, &mut punch_a, &mut punch_b);
+            }
+            for dest in punch_b.poll_probes(now) {
+                deliver(inside_b, dest, &mut nat_b, &mut nat_a, &mut punch_b, &mut punch_a);
+            }
+            now += PROBE_INTERVAL;
+        }
+
+        (punch_a.state(), punch_b.state())
+    }
+
+    fn succeeded(state: HolePunchState) -> bool {
+        match state {
+            HolePunchState::Succeeded(..) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn punch_through_port_restricted_cones() {
+        let (a, b) = punch(NatKind::PortRestrictedCone, NatKind::PortRestrictedCone);
+        assert!(succeeded(a));
+        assert!(succeeded(b));
+    }
+
+    #[test]
+    fn punch_symmetric_to_full_cone() {
+        let (a, b) = punch(NatKind::Symmetric, NatKind::FullCone);
+        assert!(succeeded(a));
+        assert!(succeeded(b));
+    }
+
+    #[test]
+    fn only_introductions_we_asked_for_are_punched() {
+        let rendezvous = XorAddr::from_bytes([3u8; 32]);
+        let mallory = XorAddr::from_bytes([4u8; 32]);
+        let target = XorAddr::from_bytes([2u8; 32]);
+        let now = Instant::now();
+        let mut intros = Introductions::new();
+        let nonce = unwrap!(intros.ask(rendezvous, target, now));
+        assert_eq!(intros.ask(rendezvous, target, now), None);
+
+        // Nobody else gets to introduce us using our nonce, or to anyone else.
+        let action = intros.on_introduction(mallory, target, nonce, now);
+        assert_eq!(action, IntroductionAction::Ignore);
+        let action = intros.on_introduction(rendezvous, mallory, nonce, now);
+        assert_eq!(action, IntroductionAction::Ignore);
+        let action = intros.on_introduction(rendezvous, target, nonce, now);
+        assert_eq!(action, IntroductionAction::Punch);
+        let action = intros.on_introduction(rendezvous, target, nonce, now);
+        assert_eq!(action, IntroductionAction::Ignore);
+
+        // Once it's expired we can ask again.
+        intros.expire(now + INTRODUCTION_TIMEOUT);
+        assert_eq!(intros.next_deadline(), None);
+        assert!(intros.ask(rendezvous, target, now).is_some());
+    }
+
+    #[test]
+    fn punch_symmetric_to_port_restricted_cone_fails() {
+        let (a, b) = punch(NatKind::Symmetric, NatKind::PortRestrictedCone);
+        assert_eq!(a, HolePunchState::Failed);
+        assert_eq!(b, HolePunchState::Failed);
+    }
+}
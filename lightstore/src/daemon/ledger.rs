@@ -0,0 +1,43 @@
+use super::*;
+
+// The running balance we have with each peer. This is not cryptographically secure, it's simply
+// a counter that both sides keep and which gets settled every so often over lightning. A positive
+// balance means the peer owes us money, a negative balance means we owe them.
+pub struct Ledger {
+    balances: HashMap<XorAddr, Btc>,
+}
+
+impl Ledger {
+    pub fn new() -> Ledger {
+        Ledger {
+            balances: HashMap::new(),
+        }
+    }
+
+    pub fn balance(&self, peer: &XorAddr) -> Btc {
+        match self.balances.get(peer) {
+            Some(balance) => *balance,
+            None => Btc(0.0),
+        }
+    }
+
+    // Record that a peer owes us `amount` for a service we provided them.
+    pub fn credit(&mut self, peer: XorAddr, amount: Btc) {
+        *self.balances.entry(peer).or_insert(Btc(0.0)) += amount;
+    }
+
+    // Record that we owe a peer `amount` for a service they provided us.
+    pub fn debit(&mut self, peer: XorAddr, amount: Btc) {
+        *self.balances.entry(peer).or_insert(Btc(0.0)) -= amount;
+    }
+
+    // Writes off what a peer owes us if it's no more than `amount`. Returns whether we did.
+    pub fn forgive(&mut self, peer: XorAddr, amount: Btc) -> bool {
+        let balance = self.balance(&peer);
+        if balance <= Btc(0.0) || balance > amount {
+            return false;
+        }
+        let _ = self.balances.insert(peer, Btc(0.0));
+        true
+    }
+}
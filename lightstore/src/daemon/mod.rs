@@ -2,11 +2,48 @@ use super::*;
 
 mod daemon;
 mod msg;
-//mod get_mutable;
+mod get_mutable;
 mod peer;
+mod ledger;
+mod nat;
+mod hole_punch;
+mod relay;
+mod dns_cache;
+mod sockets;
+mod transaction;
+mod fragment;
+mod stream;
+mod swarm;
+mod provider;
+mod offer;
+mod responder;
+mod pricing;
+mod pow;
+mod contract;
+mod replication;
+mod node_key;
+#[cfg(test)]
+mod nat_sim;
 
 pub use self::daemon::*;
-//pub use self::get_mutable::*;
+pub use self::get_mutable::*;
 pub use self::peer::*;
 pub use self::msg::*;
-
+pub use self::ledger::*;
+pub use self::nat::*;
+pub use self::hole_punch::*;
+pub use self::relay::*;
+pub use self::dns_cache::*;
+pub use self::sockets::*;
+pub use self::transaction::*;
+pub use self::fragment::*;
+pub use self::stream::*;
+pub use self::swarm::*;
+pub use self::provider::*;
+pub use self::offer::*;
+pub use self::responder::*;
+pub use self::pricing::*;
+pub use self::pow::*;
+pub use self::contract::*;
+pub use self::replication::*;
+pub use self::node_key::*;
@@ -1,21 +1,185 @@
 use super::*;
-//use crate::daemon::GetMutableParams;
 
 pub enum Msg {
     SenderDownloadFee {
         btc_per_byte: BtcPerByte,
     },
-    /*
-    SenderGetMutable {
+    SenderGetAddress,
+    SenderAddress {
+        addr: SocketAddr,
+    },
+    // Asks the peer to introduce us to `target` for a hole punch, with a nonce we've picked.
+    SenderIntroduce {
+        target: XorAddr,
+        nonce: u64,
+    },
+    Introduction {
+        peer: XorAddr,
+        addr: SocketAddr,
+        nonce: u64,
+    },
+    PunchProbe {
+        nonce: u64,
+    },
+    PunchAck {
+        nonce: u64,
+    },
+    SenderRelayFee {
+        btc_per_byte: BtcPerByte,
+    },
+    // Asks a relay to pass `payload` on to `dest`. We sign it, so the relay can't forge or
+    // redirect it.
+    Relay {
+        dest: XorAddr,
+        signature: Signature,
+        payload: Bytes,
+    },
+    Relayed {
+        src: XorAddr,
+        signature: Signature,
+        payload: Bytes,
+    },
+    // Wraps a message which expects a reply. The reply comes back wrapped in a `Response` with the
+    // same id, and gets paid for according to the offer.
+    Request {
+        request_id: u64,
+        offer: Offer,
+        payload: Bytes,
+    },
+    // `delay` is how long the replier held the request before replying, which is what the offer
+    // gets valued at by both sides.
+    Response {
+        request_id: u64,
+        delay: Sec,
+        payload: Bytes,
+    },
+    // One piece of a message too big to fit in a datagram.
+    Fragment {
+        msg_id: u64,
+        index: u16,
+        count: u16,
+        data: Bytes,
+    },
+    // Has a bit set for each fragment of the message that's arrived.
+    FragmentAck {
+        msg_id: u64,
+        received: Bytes,
+    },
+    // Padded out to the size we want to know whether the path to the peer can carry.
+    MtuProbe {
+        nonce: u64,
+        padding: Bytes,
+    },
+    MtuProbeAck {
+        nonce: u64,
+    },
+    // Asks the peer to start streaming us leaves `start..end` of some content, at `price` per
+    // byte. Sent as a request.
+    StreamOpen {
+        stream_id: u64,
+        root: MerkleHash,
+        size: u64,
+        start: u64,
+        end: u64,
+        price: BtcPerByte,
+    },
+    StreamOpened {
+        stream_id: u64,
+    },
+    StreamLeaf {
+        stream_id: u64,
+        index: u64,
+        proof: MerkleProof,
+        data: Bytes,
+    },
+    // Acknowledges every leaf before `next`.
+    StreamAck {
+        stream_id: u64,
+        next: u64,
+    },
+    // Asks whether the peer hosts some content. Sent as a request.
+    StreamQuery {
+        root: MerkleHash,
+        size: u64,
+    },
+    // Whether the peer hosts the content, and what it currently asks per byte to stream it.
+    StreamHave {
+        root: MerkleHash,
+        hosted: bool,
+        price: BtcPerByte,
+    },
+    // Asks the peer to hold onto a provider record. Sent as a request.
+    ProviderAdd {
+        record: ProviderRecord,
+    },
+    ProviderAdded {
+        root: MerkleHash,
+    },
+    // Asks the peer who provides some content. Sent as a request.
+    ProviderQuery {
+        root: MerkleHash,
+    },
+    Providers {
+        root: MerkleHash,
+        records: Vec<ProviderRecord>,
+    },
+    // Peers closer to `key` than we are, for when we can't answer a request about it ourselves.
+    // We can introduce the requester to any of them.
+    Contacts {
+        key: XorAddr,
+        peers: Vec<XorAddr>,
+    },
+    // Pays off some of what we owe the peer with work instead of bitcoin. Sent as a request, so
+    // that we only count it as paid once the peer's taken it.
+    PowPayment {
+        token: PowToken,
+    },
+    PowAccepted {
+        nonce: u64,
+    },
+    // Asks the peer to keep some content for us on the given terms. Sent as a request.
+    ContractPropose {
+        terms: ContractTerms,
+    },
+    ContractAccepted {
+        receipt: Receipt,
+    },
+    // Pays for a contract's installments up to and including `installment`, bringing what's been
+    // paid on it to `total`.
+    ContractPayment {
+        contract_id: u64,
+        installment: u32,
+        total: Btc,
+    },
+    // Asks a host to show it still has a leaf of some content it's keeping for us. Sent as a
+    // request.
+    ContractChallenge {
+        contract_id: u64,
+        index: u64,
+    },
+    ContractProof {
+        contract_id: u64,
+        index: u64,
+        proof: MerkleProof,
+        data: Bytes,
+    },
+    // Asks the peer to hold onto a new version of some mutable data. Sent as a request.
+    MutablePut {
+        record: MutableRecord,
+    },
+    MutableStored {
         id: PublicSignKey,
-        params: GetMutableParams,
+        version: u64,
     },
-    */
-    /*
-    SenderGetAddress {
-        reward: Btc,
-        reward_decay_time: Sec,
+    // Asks the peer for the latest version of some mutable data. Sent as a request.
+    MutableQuery {
+        id: PublicSignKey,
     },
+    Mutable {
+        id: PublicSignKey,
+        record: Option<MutableRecord>,
+    },
+    /*
     SenderSignKey {
         sign_key: PublicSignKey,
     },
@@ -38,7 +202,48 @@ pub enum Msg {
 
 mod tag {
     pub const SENDER_DOWNLOAD_FEE: u16 = 0;
-    pub const SENDER_GET_MUTABLE: u16 = 1;
+    pub const MUTABLE_QUERY: u16 = 1;
+    pub const SENDER_GET_ADDRESS: u16 = 2;
+    pub const SENDER_ADDRESS: u16 = 3;
+    pub const SENDER_INTRODUCE: u16 = 4;
+    pub const INTRODUCTION: u16 = 5;
+    pub const PUNCH_PROBE: u16 = 6;
+    pub const PUNCH_ACK: u16 = 7;
+    pub const SENDER_RELAY_FEE: u16 = 8;
+    pub const RELAY: u16 = 9;
+    pub const RELAYED: u16 = 10;
+    pub const REQUEST: u16 = 11;
+    pub const RESPONSE: u16 = 12;
+    pub const FRAGMENT: u16 = 13;
+    pub const FRAGMENT_ACK: u16 = 14;
+    pub const MTU_PROBE: u16 = 15;
+    pub const MTU_PROBE_ACK: u16 = 16;
+    pub const STREAM_OPEN: u16 = 17;
+    pub const STREAM_OPENED: u16 = 18;
+    pub const STREAM_LEAF: u16 = 19;
+    pub const STREAM_ACK: u16 = 20;
+    pub const STREAM_QUERY: u16 = 21;
+    pub const STREAM_HAVE: u16 = 22;
+    pub const PROVIDER_ADD: u16 = 23;
+    pub const PROVIDER_ADDED: u16 = 24;
+    pub const PROVIDER_QUERY: u16 = 25;
+    pub const PROVIDERS: u16 = 26;
+    pub const CONTACTS: u16 = 27;
+    pub const POW_PAYMENT: u16 = 28;
+    pub const CONTRACT_PROPOSE: u16 = 29;
+    pub const CONTRACT_ACCEPTED: u16 = 30;
+    pub const CONTRACT_PAYMENT: u16 = 31;
+    pub const CONTRACT_CHALLENGE: u16 = 32;
+    pub const CONTRACT_PROOF: u16 = 33;
+    pub const MUTABLE_PUT: u16 = 34;
+    pub const MUTABLE_STORED: u16 = 35;
+    pub const MUTABLE: u16 = 36;
+    pub const POW_ACCEPTED: u16 = 37;
+}
+
+mod addr_tag {
+    pub const V4: u8 = 4;
+    pub const V6: u8 = 6;
 }
 
 pub struct OutgoingMsg {
@@ -59,18 +264,266 @@ pub enum ContentData {
 */
 
 impl Msg {
-    pub fn write(&self, _bytes: &mut BytesMut) {
+    pub fn to_bytes(&self) -> Bytes {
+        let mut bytes = BytesMut::new();
+        self.write(&mut bytes);
+        bytes.freeze()
+    }
+
+    pub fn write(&self, bytes: &mut BytesMut) {
         match self {
-            Msg::SenderDownloadFee { .. } => unimplemented!(),
-            /*
-            Msg::SenderGetMutable { id, params } => {
-                bytes.put_u16_be(tag::SENDER_GET_MUTABLE);
+            Msg::SenderDownloadFee { btc_per_byte } => {
+                bytes.reserve(2 + 8);
+                bytes.put_u16_be(tag::SENDER_DOWNLOAD_FEE);
+                bytes.put_f64_be(btc_per_byte.val());
+            },
+            Msg::SenderGetAddress => {
+                bytes.reserve(2);
+                bytes.put_u16_be(tag::SENDER_GET_ADDRESS);
+            },
+            Msg::SenderAddress { addr } => {
+                bytes.reserve(2);
+                bytes.put_u16_be(tag::SENDER_ADDRESS);
+                write_socket_addr(addr, bytes);
+            },
+            Msg::SenderIntroduce { target, nonce } => {
+                bytes.reserve(2 + 32 + 8);
+                bytes.put_u16_be(tag::SENDER_INTRODUCE);
+                bytes.put_slice(&target.as_bytes());
+                bytes.put_u64_be(*nonce);
+            },
+            Msg::Introduction { peer, addr, nonce } => {
+                bytes.reserve(2 + 32 + 8);
+                bytes.put_u16_be(tag::INTRODUCTION);
+                bytes.put_slice(&peer.as_bytes());
+                bytes.put_u64_be(*nonce);
+                write_socket_addr(addr, bytes);
+            },
+            Msg::PunchProbe { nonce } => {
+                bytes.reserve(2 + 8);
+                bytes.put_u16_be(tag::PUNCH_PROBE);
+                bytes.put_u64_be(*nonce);
+            },
+            Msg::PunchAck { nonce } => {
+                bytes.reserve(2 + 8);
+                bytes.put_u16_be(tag::PUNCH_ACK);
+                bytes.put_u64_be(*nonce);
+            },
+            Msg::SenderRelayFee { btc_per_byte } => {
+                bytes.reserve(2 + 8);
+                bytes.put_u16_be(tag::SENDER_RELAY_FEE);
+                bytes.put_f64_be(btc_per_byte.val());
+            },
+            Msg::Relay { dest, signature, payload } => {
+                bytes.reserve(2 + 32 + 64);
+                bytes.put_u16_be(tag::RELAY);
+                bytes.put_slice(&dest.as_bytes());
+                bytes.put_slice(&signature.as_bytes()[..]);
+                write_payload(payload, bytes);
+            },
+            Msg::Relayed { src, signature, payload } => {
+                bytes.reserve(2 + 32 + 64);
+                bytes.put_u16_be(tag::RELAYED);
+                bytes.put_slice(&src.as_bytes());
+                bytes.put_slice(&signature.as_bytes()[..]);
+                write_payload(payload, bytes);
+            },
+            Msg::Request { request_id, offer, payload } => {
+                bytes.reserve(2 + 8);
+                bytes.put_u16_be(tag::REQUEST);
+                bytes.put_u64_be(*request_id);
+                offer.write(bytes);
+                write_payload(payload, bytes);
+            },
+            Msg::Response { request_id, delay, payload } => {
+                bytes.reserve(2 + 8 + 8);
+                bytes.put_u16_be(tag::RESPONSE);
+                bytes.put_u64_be(*request_id);
+                bytes.put_f64_be(delay.val());
+                write_payload(payload, bytes);
+            },
+            Msg::Fragment { msg_id, index, count, data } => {
+                bytes.reserve(2 + 8 + 2 + 2);
+                bytes.put_u16_be(tag::FRAGMENT);
+                bytes.put_u64_be(*msg_id);
+                bytes.put_u16_be(*index);
+                bytes.put_u16_be(*count);
+                write_payload(data, bytes);
+            },
+            Msg::FragmentAck { msg_id, received } => {
+                bytes.reserve(2 + 8);
+                bytes.put_u16_be(tag::FRAGMENT_ACK);
+                bytes.put_u64_be(*msg_id);
+                write_payload(received, bytes);
+            },
+            Msg::MtuProbe { nonce, padding } => {
+                bytes.reserve(2 + 8);
+                bytes.put_u16_be(tag::MTU_PROBE);
+                bytes.put_u64_be(*nonce);
+                write_payload(padding, bytes);
+            },
+            Msg::MtuProbeAck { nonce } => {
+                bytes.reserve(2 + 8);
+                bytes.put_u16_be(tag::MTU_PROBE_ACK);
+                bytes.put_u64_be(*nonce);
+            },
+            Msg::StreamOpen { stream_id, root, size, start, end, price } => {
+                bytes.reserve(2 + 8 + 32 + 4 * 8);
+                bytes.put_u16_be(tag::STREAM_OPEN);
+                bytes.put_u64_be(*stream_id);
+                bytes.put_slice(&root.as_bytes());
+                bytes.put_u64_be(*size);
+                bytes.put_u64_be(*start);
+                bytes.put_u64_be(*end);
+                bytes.put_f64_be(price.val());
+            },
+            Msg::StreamOpened { stream_id } => {
+                bytes.reserve(2 + 8);
+                bytes.put_u16_be(tag::STREAM_OPENED);
+                bytes.put_u64_be(*stream_id);
+            },
+            Msg::StreamLeaf { stream_id, index, proof, data } => {
+                bytes.reserve(2 + 2 * 8 + 1 + proof.len() * 2 * 32);
+                bytes.put_u16_be(tag::STREAM_LEAF);
+                bytes.put_u64_be(*stream_id);
+                bytes.put_u64_be(*index);
+                write_count(proof.len(), bytes);
+                for (left, right) in proof {
+                    bytes.put_slice(&left.as_bytes());
+                    bytes.put_slice(&right.as_bytes());
+                }
+                write_payload(data, bytes);
+            },
+            Msg::StreamAck { stream_id, next } => {
+                bytes.reserve(2 + 2 * 8);
+                bytes.put_u16_be(tag::STREAM_ACK);
+                bytes.put_u64_be(*stream_id);
+                bytes.put_u64_be(*next);
+            },
+            Msg::StreamQuery { root, size } => {
+                bytes.reserve(2 + 32 + 8);
+                bytes.put_u16_be(tag::STREAM_QUERY);
+                bytes.put_slice(&root.as_bytes());
+                bytes.put_u64_be(*size);
+            },
+            Msg::StreamHave { root, hosted, price } => {
+                bytes.reserve(2 + 32 + 1 + 8);
+                bytes.put_u16_be(tag::STREAM_HAVE);
+                bytes.put_slice(&root.as_bytes());
+                bytes.put_u8(*hosted as u8);
+                bytes.put_f64_be(price.val());
+            },
+            Msg::ProviderAdd { record } => {
+                bytes.reserve(2);
+                bytes.put_u16_be(tag::PROVIDER_ADD);
+                write_provider_record(record, bytes);
+            },
+            Msg::ProviderAdded { root } => {
+                bytes.reserve(2 + 32);
+                bytes.put_u16_be(tag::PROVIDER_ADDED);
+                bytes.put_slice(&root.as_bytes());
+            },
+            Msg::ProviderQuery { root } => {
+                bytes.reserve(2 + 32);
+                bytes.put_u16_be(tag::PROVIDER_QUERY);
+                bytes.put_slice(&root.as_bytes());
+            },
+            Msg::Providers { root, records } => {
+                bytes.reserve(2 + 32 + 1);
+                bytes.put_u16_be(tag::PROVIDERS);
+                bytes.put_slice(&root.as_bytes());
+                write_count(records.len(), bytes);
+                for record in records {
+                    write_provider_record(record, bytes);
+                }
+            },
+            Msg::Contacts { key, peers } => {
+                bytes.reserve(2 + 32 + 1 + 32 * peers.len());
+                bytes.put_u16_be(tag::CONTACTS);
+                bytes.put_slice(&key.as_bytes());
+                write_count(peers.len(), bytes);
+                for peer in peers {
+                    bytes.put_slice(&peer.as_bytes());
+                }
+            },
+            Msg::PowPayment { token } => {
+                bytes.reserve(2 + 3 * 8 + 1);
+                bytes.put_u16_be(tag::POW_PAYMENT);
+                bytes.put_u64_be(token.expires);
+                bytes.put_u64_be(token.nonce);
+                bytes.put_u8(token.difficulty);
+                bytes.put_u64_be(token.solution);
+            },
+            Msg::PowAccepted { nonce } => {
+                bytes.reserve(2 + 8);
+                bytes.put_u16_be(tag::POW_ACCEPTED);
+                bytes.put_u64_be(*nonce);
+            },
+            Msg::ContractPropose { terms } => {
+                bytes.reserve(2);
+                bytes.put_u16_be(tag::CONTRACT_PROPOSE);
+                write_contract_terms(terms, bytes);
+            },
+            Msg::ContractAccepted { receipt } => {
+                bytes.reserve(2 + 2 * 32);
+                bytes.put_u16_be(tag::CONTRACT_ACCEPTED);
+                bytes.put_slice(&receipt.client.as_bytes());
+                bytes.put_slice(&receipt.host.as_bytes());
+                write_contract_terms(&receipt.terms, bytes);
+                bytes.reserve(8 + 64);
+                bytes.put_u64_be(receipt.starts);
+                bytes.put_slice(&receipt.signature.as_bytes()[..]);
+            },
+            Msg::ContractPayment { contract_id, installment, total } => {
+                bytes.reserve(2 + 8 + 4 + 8);
+                bytes.put_u16_be(tag::CONTRACT_PAYMENT);
+                bytes.put_u64_be(*contract_id);
+                bytes.put_u32_be(*installment);
+                bytes.put_f64_be(total.val());
+            },
+            Msg::ContractChallenge { contract_id, index } => {
+                bytes.reserve(2 + 2 * 8);
+                bytes.put_u16_be(tag::CONTRACT_CHALLENGE);
+                bytes.put_u64_be(*contract_id);
+                bytes.put_u64_be(*index);
+            },
+            Msg::ContractProof { contract_id, index, proof, data } => {
+                bytes.reserve(2 + 2 * 8 + 1 + proof.len() * 2 * 32);
+                bytes.put_u16_be(tag::CONTRACT_PROOF);
+                bytes.put_u64_be(*contract_id);
+                bytes.put_u64_be(*index);
+                write_count(proof.len(), bytes);
+                for (left, right) in proof {
+                    bytes.put_slice(&left.as_bytes());
+                    bytes.put_slice(&right.as_bytes());
+                }
+                write_payload(data, bytes);
+            },
+            Msg::MutablePut { record } => {
+                bytes.reserve(2);
+                bytes.put_u16_be(tag::MUTABLE_PUT);
+                write_mutable_record(record, bytes);
+            },
+            Msg::MutableStored { id, version } => {
+                bytes.reserve(2 + 32 + 8);
+                bytes.put_u16_be(tag::MUTABLE_STORED);
+                bytes.put_slice(&id.as_bytes());
+                bytes.put_u64_be(*version);
+            },
+            Msg::MutableQuery { id } => {
+                bytes.reserve(2 + 32);
+                bytes.put_u16_be(tag::MUTABLE_QUERY);
                 bytes.put_slice(&id.as_bytes());
-                bytes.put_f64_be(params.price.val());
-                bytes.put_f64_be(params.price_decay_over_time.val());
-                bytes.put_f64_be(params.price_decay_over_versions);
             },
-            */
+            Msg::Mutable { id, record } => {
+                bytes.reserve(2 + 32 + 1);
+                bytes.put_u16_be(tag::MUTABLE);
+                bytes.put_slice(&id.as_bytes());
+                bytes.put_u8(record.is_some() as u8);
+                if let Some(record) = record {
+                    write_mutable_record(record, bytes);
+                }
+            },
         }
     }
 
@@ -81,34 +534,583 @@ impl Msg {
 
         let tag = bytes.get_u16_be();
         match tag {
-            /*
-            tag::SENDER_GET_MUTABLE => {
-                if bytes.remaining() < 32 + 3 * 8 {
+            tag::SENDER_DOWNLOAD_FEE => {
+                if bytes.remaining() < 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let btc_per_byte = read_price(bytes)?;
+                Ok(Msg::SenderDownloadFee { btc_per_byte })
+            },
+            tag::SENDER_GET_ADDRESS => Ok(Msg::SenderGetAddress),
+            tag::SENDER_ADDRESS => {
+                let addr = read_socket_addr(bytes)?;
+                Ok(Msg::SenderAddress { addr })
+            },
+            tag::SENDER_INTRODUCE => {
+                let target = read_xor_addr(bytes)?;
+                if bytes.remaining() < 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let nonce = bytes.get_u64_be();
+                Ok(Msg::SenderIntroduce { target, nonce })
+            },
+            tag::INTRODUCTION => {
+                let peer = read_xor_addr(bytes)?;
+                if bytes.remaining() < 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let nonce = bytes.get_u64_be();
+                let addr = read_socket_addr(bytes)?;
+                Ok(Msg::Introduction { peer, addr, nonce })
+            },
+            tag::PUNCH_PROBE => {
+                if bytes.remaining() < 8 {
                     return Err(MsgReadError::Truncated);
                 }
 
-                let id = PublicSignKey::from_bytes(slice_to_array!(&bytes.get_ref()[..32], 32));
-                bytes.advance(32);
-                let price = Btc(bytes.get_f64_be());
-                let price_decay_over_time = Sec(bytes.get_f64_be());
-                let price_decay_over_versions = bytes.get_f64_be();
-                let params = GetMutableParams {
-                    price, price_decay_over_time, price_decay_over_versions,
+                let nonce = bytes.get_u64_be();
+                Ok(Msg::PunchProbe { nonce })
+            },
+            tag::PUNCH_ACK => {
+                if bytes.remaining() < 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let nonce = bytes.get_u64_be();
+                Ok(Msg::PunchAck { nonce })
+            },
+            tag::SENDER_RELAY_FEE => {
+                if bytes.remaining() < 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let btc_per_byte = read_price(bytes)?;
+                Ok(Msg::SenderRelayFee { btc_per_byte })
+            },
+            tag::RELAY => {
+                let dest = read_xor_addr(bytes)?;
+                let signature = read_signature(bytes)?;
+                let payload = read_payload(bytes)?;
+                Ok(Msg::Relay { dest, signature, payload })
+            },
+            tag::RELAYED => {
+                let src = read_xor_addr(bytes)?;
+                let signature = read_signature(bytes)?;
+                let payload = read_payload(bytes)?;
+                Ok(Msg::Relayed { src, signature, payload })
+            },
+            tag::REQUEST => {
+                if bytes.remaining() < 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let request_id = bytes.get_u64_be();
+                let offer = Offer::read(bytes)?;
+                let payload = read_payload(bytes)?;
+                Ok(Msg::Request { request_id, offer, payload })
+            },
+            tag::RESPONSE => {
+                if bytes.remaining() < 8 + 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let request_id = bytes.get_u64_be();
+                let delay = Sec(bytes.get_f64_be());
+                if !(delay.val().is_finite() && delay.val() >= 0.0) {
+                    return Err(MsgReadError::InvalidDelay);
+                }
+                let payload = read_payload(bytes)?;
+                Ok(Msg::Response { request_id, delay, payload })
+            },
+            tag::FRAGMENT => {
+                if bytes.remaining() < 8 + 2 + 2 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let msg_id = bytes.get_u64_be();
+                let index = bytes.get_u16_be();
+                let count = bytes.get_u16_be();
+                let data = read_payload(bytes)?;
+                Ok(Msg::Fragment { msg_id, index, count, data })
+            },
+            tag::FRAGMENT_ACK => {
+                if bytes.remaining() < 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let msg_id = bytes.get_u64_be();
+                let received = read_payload(bytes)?;
+                Ok(Msg::FragmentAck { msg_id, received })
+            },
+            tag::MTU_PROBE => {
+                if bytes.remaining() < 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let nonce = bytes.get_u64_be();
+                let padding = read_payload(bytes)?;
+                Ok(Msg::MtuProbe { nonce, padding })
+            },
+            tag::MTU_PROBE_ACK => {
+                if bytes.remaining() < 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let nonce = bytes.get_u64_be();
+                Ok(Msg::MtuProbeAck { nonce })
+            },
+            tag::STREAM_OPEN => {
+                if bytes.remaining() < 8 + 32 + 4 * 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let stream_id = bytes.get_u64_be();
+                let root = read_merkle_hash(bytes)?;
+                let size = bytes.get_u64_be();
+                let start = bytes.get_u64_be();
+                let end = bytes.get_u64_be();
+                let price = read_price(bytes)?;
+                Ok(Msg::StreamOpen { stream_id, root, size, start, end, price })
+            },
+            tag::STREAM_OPENED => {
+                if bytes.remaining() < 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let stream_id = bytes.get_u64_be();
+                Ok(Msg::StreamOpened { stream_id })
+            },
+            tag::STREAM_LEAF => {
+                if bytes.remaining() < 2 * 8 + 1 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let stream_id = bytes.get_u64_be();
+                let index = bytes.get_u64_be();
+                let proof_len = bytes.get_u8() as usize;
+                let mut proof = Vec::with_capacity(proof_len);
+                for _ in 0..proof_len {
+                    let left = read_merkle_hash(bytes)?;
+                    let right = read_merkle_hash(bytes)?;
+                    proof.push((left, right));
+                }
+                let data = read_payload(bytes)?;
+                Ok(Msg::StreamLeaf { stream_id, index, proof, data })
+            },
+            tag::STREAM_ACK => {
+                if bytes.remaining() < 2 * 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let stream_id = bytes.get_u64_be();
+                let next = bytes.get_u64_be();
+                Ok(Msg::StreamAck { stream_id, next })
+            },
+            tag::STREAM_QUERY => {
+                if bytes.remaining() < 32 + 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let root = read_merkle_hash(bytes)?;
+                let size = bytes.get_u64_be();
+                Ok(Msg::StreamQuery { root, size })
+            },
+            tag::STREAM_HAVE => {
+                if bytes.remaining() < 32 + 1 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let root = read_merkle_hash(bytes)?;
+                let hosted = bytes.get_u8() != 0;
+                let price = read_price(bytes)?;
+                Ok(Msg::StreamHave { root, hosted, price })
+            },
+            tag::PROVIDER_ADD => {
+                let record = read_provider_record(bytes)?;
+                Ok(Msg::ProviderAdd { record })
+            },
+            tag::PROVIDER_ADDED => {
+                let root = read_merkle_hash(bytes)?;
+                Ok(Msg::ProviderAdded { root })
+            },
+            tag::PROVIDER_QUERY => {
+                let root = read_merkle_hash(bytes)?;
+                Ok(Msg::ProviderQuery { root })
+            },
+            tag::PROVIDERS => {
+                let root = read_merkle_hash(bytes)?;
+                if bytes.remaining() < 1 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let count = bytes.get_u8() as usize;
+                let mut records = Vec::with_capacity(count);
+                for _ in 0..count {
+                    records.push(read_provider_record(bytes)?);
+                }
+                Ok(Msg::Providers { root, records })
+            },
+            tag::CONTACTS => {
+                let key = read_xor_addr(bytes)?;
+                if bytes.remaining() < 1 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let count = bytes.get_u8() as usize;
+                let mut peers = Vec::with_capacity(count);
+                for _ in 0..count {
+                    peers.push(read_xor_addr(bytes)?);
+                }
+                Ok(Msg::Contacts { key, peers })
+            },
+            tag::POW_PAYMENT => {
+                if bytes.remaining() < 3 * 8 + 1 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let expires = bytes.get_u64_be();
+                let nonce = bytes.get_u64_be();
+                let difficulty = bytes.get_u8();
+                let solution = bytes.get_u64_be();
+                let token = PowToken { expires, nonce, difficulty, solution };
+                Ok(Msg::PowPayment { token })
+            },
+            tag::POW_ACCEPTED => {
+                if bytes.remaining() < 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let nonce = bytes.get_u64_be();
+                Ok(Msg::PowAccepted { nonce })
+            },
+            tag::CONTRACT_PROPOSE => {
+                let terms = read_contract_terms(bytes)?;
+                Ok(Msg::ContractPropose { terms })
+            },
+            tag::CONTRACT_ACCEPTED => {
+                let client = read_xor_addr(bytes)?;
+                let host = read_xor_addr(bytes)?;
+                let terms = read_contract_terms(bytes)?;
+                if bytes.remaining() < 8 + 64 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let starts = bytes.get_u64_be();
+                let mut signature = [0u8; 64];
+                bytes.copy_to_slice(&mut signature[..]);
+                let signature = Signature::from_bytes(signature);
+                let receipt = Receipt { client, host, terms, starts, signature };
+                Ok(Msg::ContractAccepted { receipt })
+            },
+            tag::CONTRACT_PAYMENT => {
+                if bytes.remaining() < 8 + 4 + 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let contract_id = bytes.get_u64_be();
+                let installment = bytes.get_u32_be();
+                let total = Btc(bytes.get_f64_be());
+                Ok(Msg::ContractPayment { contract_id, installment, total })
+            },
+            tag::CONTRACT_CHALLENGE => {
+                if bytes.remaining() < 2 * 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let contract_id = bytes.get_u64_be();
+                let index = bytes.get_u64_be();
+                Ok(Msg::ContractChallenge { contract_id, index })
+            },
+            tag::CONTRACT_PROOF => {
+                if bytes.remaining() < 2 * 8 + 1 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let contract_id = bytes.get_u64_be();
+                let index = bytes.get_u64_be();
+                let proof_len = bytes.get_u8() as usize;
+                let mut proof = Vec::with_capacity(proof_len);
+                for _ in 0..proof_len {
+                    let left = read_merkle_hash(bytes)?;
+                    let right = read_merkle_hash(bytes)?;
+                    proof.push((left, right));
+                }
+                let data = read_payload(bytes)?;
+                Ok(Msg::ContractProof { contract_id, index, proof, data })
+            },
+            tag::MUTABLE_PUT => {
+                let record = read_mutable_record(bytes)?;
+                Ok(Msg::MutablePut { record })
+            },
+            tag::MUTABLE_STORED => {
+                let id = read_sign_key(bytes)?;
+                if bytes.remaining() < 8 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let version = bytes.get_u64_be();
+                Ok(Msg::MutableStored { id, version })
+            },
+            tag::MUTABLE_QUERY => {
+                let id = read_sign_key(bytes)?;
+                Ok(Msg::MutableQuery { id })
+            },
+            tag::MUTABLE => {
+                let id = read_sign_key(bytes)?;
+                if bytes.remaining() < 1 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let record = match bytes.get_u8() {
+                    0 => None,
+                    _ => Some(read_mutable_record(bytes)?),
                 };
-                Ok(Msg::SenderGetMutable { id, params })
+                Ok(Msg::Mutable { id, record })
             },
-            */
             _ => Err(MsgReadError::InvalidMsgKind),
         }
     }
 }
 
+// Payloads have their length written in front of them as a u16. Nothing we send comes close, since
+// messages get split up to fit in datagrams, but anything that did would be cut short.
+pub const MAX_PAYLOAD_LEN: usize = u16::MAX as usize;
+
+// Lists are prefixed with how long they are in a single byte. Whatever builds a message keeps its
+// lists shorter than that, so a longer one is a bug rather than something to truncate.
+fn write_count(count: usize, bytes: &mut BytesMut) {
+    assert!(count <= u8::MAX as usize, "list of {} items is too long", count);
+    bytes.reserve(1);
+    bytes.put_u8(count as u8);
+}
+
+fn write_payload(payload: &Bytes, bytes: &mut BytesMut) {
+    assert!(payload.len() <= MAX_PAYLOAD_LEN, "payload of {} bytes is too long", payload.len());
+    bytes.reserve(2 + payload.len());
+    bytes.put_u16_be(payload.len() as u16);
+    bytes.put_slice(&payload[..]);
+}
+
+// Reads a per-byte price. A NaN or negative one would poison whatever it's added to, so they're
+// refused here.
+fn read_price(bytes: &mut Cursor<Bytes>) -> Result<BtcPerByte, MsgReadError> {
+    if bytes.remaining() < 8 {
+        return Err(MsgReadError::Truncated);
+    }
+    let price = bytes.get_f64_be();
+    if !(price.is_finite() && price >= 0.0) {
+        return Err(MsgReadError::InvalidPrice);
+    }
+    Ok(BtcPerByte(price))
+}
+
+fn read_payload(bytes: &mut Cursor<Bytes>) -> Result<Bytes, MsgReadError> {
+    if bytes.remaining() < 2 {
+        return Err(MsgReadError::Truncated);
+    }
+
+    let len = bytes.get_u16_be() as usize;
+    if bytes.remaining() < len {
+        return Err(MsgReadError::Truncated);
+    }
+
+    let start = bytes.position() as usize;
+    let payload = bytes.get_ref().slice(start, start + len);
+    bytes.advance(len);
+    Ok(payload)
+}
+
+fn read_xor_addr(bytes: &mut Cursor<Bytes>) -> Result<XorAddr, MsgReadError> {
+    if bytes.remaining() < 32 {
+        return Err(MsgReadError::Truncated);
+    }
+
+    let mut addr = [0u8; 32];
+    bytes.copy_to_slice(&mut addr[..]);
+    Ok(XorAddr::from_bytes(addr))
+}
+
+fn read_sign_key(bytes: &mut Cursor<Bytes>) -> Result<PublicSignKey, MsgReadError> {
+    if bytes.remaining() < 32 {
+        return Err(MsgReadError::Truncated);
+    }
+
+    let mut key = [0u8; 32];
+    bytes.copy_to_slice(&mut key[..]);
+    Ok(PublicSignKey::from_bytes(key))
+}
+
+fn read_signature(bytes: &mut Cursor<Bytes>) -> Result<Signature, MsgReadError> {
+    if bytes.remaining() < 64 {
+        return Err(MsgReadError::Truncated);
+    }
+
+    let mut signature = [0u8; 64];
+    bytes.copy_to_slice(&mut signature[..]);
+    Ok(Signature::from_bytes(signature))
+}
+
+fn read_merkle_hash(bytes: &mut Cursor<Bytes>) -> Result<MerkleHash, MsgReadError> {
+    if bytes.remaining() < 32 {
+        return Err(MsgReadError::Truncated);
+    }
+
+    let mut hash = [0u8; 32];
+    bytes.copy_to_slice(&mut hash[..]);
+    Ok(MerkleHash::from_bytes(hash))
+}
+
+fn write_provider_record(record: &ProviderRecord, bytes: &mut BytesMut) {
+    bytes.reserve(32 + 32 + 8 + 1);
+    bytes.put_slice(&record.root.as_bytes());
+    bytes.put_slice(&record.provider.as_bytes());
+    bytes.put_u64_be(record.expires);
+    write_count(record.addrs.len(), bytes);
+    for addr in &record.addrs {
+        write_socket_addr(addr, bytes);
+    }
+    bytes.reserve(64);
+    bytes.put_slice(&record.signature.as_bytes()[..]);
+}
+
+fn read_provider_record(bytes: &mut Cursor<Bytes>) -> Result<ProviderRecord, MsgReadError> {
+    let root = read_merkle_hash(bytes)?;
+    let provider = read_xor_addr(bytes)?;
+    if bytes.remaining() < 8 + 1 {
+        return Err(MsgReadError::Truncated);
+    }
+
+    let expires = bytes.get_u64_be();
+    let addr_count = bytes.get_u8() as usize;
+    let mut addrs = Vec::with_capacity(addr_count);
+    for _ in 0..addr_count {
+        addrs.push(read_socket_addr(bytes)?);
+    }
+    if bytes.remaining() < 64 {
+        return Err(MsgReadError::Truncated);
+    }
+
+    let mut signature = [0u8; 64];
+    bytes.copy_to_slice(&mut signature[..]);
+    let signature = Signature::from_bytes(signature);
+    Ok(ProviderRecord { root, provider, addrs, expires, signature })
+}
+
+fn write_mutable_record(record: &MutableRecord, bytes: &mut BytesMut) {
+    bytes.reserve(32 + 8 + 8);
+    bytes.put_slice(&record.id.as_bytes());
+    bytes.put_u64_be(record.version);
+    bytes.put_u64_be(record.expires);
+    write_payload(&record.data, bytes);
+    bytes.reserve(64);
+    bytes.put_slice(&record.signature.as_bytes()[..]);
+}
+
+fn read_mutable_record(bytes: &mut Cursor<Bytes>) -> Result<MutableRecord, MsgReadError> {
+    let id = read_sign_key(bytes)?;
+    if bytes.remaining() < 8 + 8 {
+        return Err(MsgReadError::Truncated);
+    }
+
+    let version = bytes.get_u64_be();
+    let expires = bytes.get_u64_be();
+    let data = read_payload(bytes)?;
+    let signature = read_signature(bytes)?;
+    Ok(MutableRecord { id, version, data, expires, signature })
+}
+
+fn write_contract_terms(terms: &ContractTerms, bytes: &mut BytesMut) {
+    bytes.reserve(8 + 32 + 3 * 8 + 4);
+    bytes.put_u64_be(terms.contract_id);
+    bytes.put_slice(&terms.root.as_bytes());
+    bytes.put_u64_be(terms.size);
+    bytes.put_u64_be(terms.duration);
+    bytes.put_f64_be(terms.price.val());
+    bytes.put_u32_be(terms.installments);
+}
+
+fn read_contract_terms(bytes: &mut Cursor<Bytes>) -> Result<ContractTerms, MsgReadError> {
+    if bytes.remaining() < 8 {
+        return Err(MsgReadError::Truncated);
+    }
+
+    let contract_id = bytes.get_u64_be();
+    let root = read_merkle_hash(bytes)?;
+    if bytes.remaining() < 3 * 8 + 4 {
+        return Err(MsgReadError::Truncated);
+    }
+
+    let size = bytes.get_u64_be();
+    let duration = bytes.get_u64_be();
+    let price = Btc(bytes.get_f64_be());
+    let installments = bytes.get_u32_be();
+    Ok(ContractTerms { contract_id, root, size, duration, price, installments })
+}
+
+fn write_socket_addr(addr: &SocketAddr, bytes: &mut BytesMut) {
+    match addr {
+        SocketAddr::V4(addr) => {
+            bytes.reserve(1 + 4 + 2);
+            bytes.put_u8(addr_tag::V4);
+            bytes.put_slice(&addr.ip().octets());
+            bytes.put_u16_be(addr.port());
+        },
+        SocketAddr::V6(addr) => {
+            bytes.reserve(1 + 16 + 2);
+            bytes.put_u8(addr_tag::V6);
+            bytes.put_slice(&addr.ip().octets());
+            bytes.put_u16_be(addr.port());
+        },
+    }
+}
+
+fn read_socket_addr(bytes: &mut Cursor<Bytes>) -> Result<SocketAddr, MsgReadError> {
+    if bytes.remaining() < 1 {
+        return Err(MsgReadError::Truncated);
+    }
+
+    match bytes.get_u8() {
+        addr_tag::V4 => {
+            if bytes.remaining() < 4 + 2 {
+                return Err(MsgReadError::Truncated);
+            }
+
+            let mut octets = [0u8; 4];
+            bytes.copy_to_slice(&mut octets[..]);
+            let port = bytes.get_u16_be();
+            Ok(SocketAddr::from((octets, port)))
+        },
+        addr_tag::V6 => {
+            if bytes.remaining() < 16 + 2 {
+                return Err(MsgReadError::Truncated);
+            }
+
+            let mut octets = [0u8; 16];
+            bytes.copy_to_slice(&mut octets[..]);
+            let port = bytes.get_u16_be();
+            Ok(SocketAddr::from((octets, port)))
+        },
+        _ => Err(MsgReadError::InvalidAddrKind),
+    }
+}
+
 #[derive(Debug, Fail)]
 pub enum MsgReadError {
     #[fail(display = "message too short")]
     Truncated,
     #[fail(display = "invalid message kind")]
     InvalidMsgKind,
+    #[fail(display = "invalid address kind")]
+    InvalidAddrKind,
+    #[fail(display = "invalid offer: {}", _0)]
+    InvalidOffer(OfferError),
+    #[fail(display = "invalid reply delay")]
+    InvalidDelay,
+    #[fail(display = "invalid price")]
+    InvalidPrice,
 }
 
 impl OutgoingMsg {
@@ -118,3 +1120,110 @@ impl OutgoingMsg {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(msg: &Msg) -> Msg {
+        let bytes = msg.to_bytes();
+        let read = unwrap!(Msg::read(&mut Cursor::new(bytes.clone())));
+        assert_eq!(read.to_bytes(), bytes);
+        read
+    }
+
+    #[test]
+    fn address_msgs_round_trip() {
+        match round_trip(&Msg::SenderGetAddress) {
+            Msg::SenderGetAddress => (),
+            _ => panic!("wrong message kind"),
+        }
+        for addr in &[addr!("1.2.3.4:5678"), addr!("[2001:db8::1]:5678")] {
+            match round_trip(&Msg::SenderAddress { addr: *addr }) {
+                Msg::SenderAddress { addr: read } => assert_eq!(read, *addr),
+                _ => panic!("wrong message kind"),
+            }
+        }
+    }
+
+    #[test]
+    fn relayed_msgs_round_trip() {
+        let keypair = unwrap!(SignKeypair::new());
+        let src = keypair.public.to_xor_addr();
+        let payload = Msg::PunchAck { nonce: 3 }.to_bytes();
+        let signature = keypair.sign(&payload);
+        let relayed = Msg::Relayed { src, signature, payload: payload.clone() };
+        match round_trip(&relayed) {
+            Msg::Relayed { src: read_src, signature: read_signature, payload: read_payload } => {
+                assert_eq!(read_src, src);
+                assert_eq!(read_signature, signature);
+                assert_eq!(read_payload, payload);
+            },
+            _ => panic!("wrong message kind"),
+        }
+        let relay = Msg::Relay { dest: src, signature, payload: payload.clone() };
+        assert_eq!(relay.to_bytes().len(), payload.len() + RELAY_OVERHEAD);
+    }
+
+    #[test]
+    fn nonsense_prices_are_refused() {
+        let fee = Msg::SenderRelayFee { btc_per_byte: BtcPerByte(1e-9) };
+        match round_trip(&fee) {
+            Msg::SenderRelayFee { btc_per_byte } => assert_eq!(btc_per_byte, BtcPerByte(1e-9)),
+            _ => panic!("wrong message kind"),
+        }
+        for price in &[std::f64::NAN, std::f64::INFINITY, -1e-9] {
+            let bytes = Msg::SenderRelayFee { btc_per_byte: BtcPerByte(*price) }.to_bytes();
+            match Msg::read(&mut Cursor::new(bytes)) {
+                Err(MsgReadError::InvalidPrice) => (),
+                _ => panic!("accepted a relay fee of {}", price),
+            }
+            let bytes = Msg::SenderDownloadFee { btc_per_byte: BtcPerByte(*price) }.to_bytes();
+            match Msg::read(&mut Cursor::new(bytes)) {
+                Err(MsgReadError::InvalidPrice) => (),
+                _ => panic!("accepted a download fee of {}", price),
+            }
+        }
+    }
+
+    #[test]
+    fn nan_priced_streams_are_refused() {
+        // A stream at a NaN price would never run out of credit.
+        let open = Msg::StreamOpen {
+            stream_id: 1,
+            root: MerkleTree::new(Bytes::from(&b"hello"[..])).root(),
+            size: 5,
+            start: 0,
+            end: 1,
+            price: BtcPerByte(std::f64::NAN),
+        };
+        match Msg::read(&mut Cursor::new(open.to_bytes())) {
+            Err(MsgReadError::InvalidPrice) => (),
+            _ => panic!("accepted a stream at a NaN price"),
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn oversized_payloads_arent_truncated() {
+        let payload = Bytes::from(vec![0u8; MAX_PAYLOAD_LEN + 1]);
+        let _ = Msg::MtuProbe { nonce: 0, padding: payload }.to_bytes();
+    }
+
+    #[test]
+    #[should_panic]
+    fn oversized_lists_arent_truncated() {
+        let peers = (0..256).map(|i| XorAddr::from_bytes([i as u8; 32])).collect();
+        let _ = Msg::Contacts { key: XorAddr::from_bytes([0; 32]), peers }.to_bytes();
+    }
+
+    #[test]
+    fn truncated_msgs_are_refused() {
+        let bytes = Msg::SenderAddress { addr: addr!("[2001:db8::1]:5678") }.to_bytes();
+        for len in 0..bytes.len() {
+            assert!(Msg::read(&mut Cursor::new(bytes.slice(0, len))).is_err());
+        }
+        let mut bad_addr = BytesMut::from(&bytes[..]);
+        bad_addr[2] = 5;
+        assert!(Msg::read(&mut Cursor::new(bad_addr.freeze())).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use super::*;
+use std::net::IpAddr;
+
+// How long we trust a NAT mapping reported by a peer before asking again. Most NATs expire idle
+// UDP mappings after somewhere between 30 seconds and a few minutes.
+const MAPPING_LIFETIME: Sec = Sec(120.0);
+
+// We need at least this many independent reports before we'll guess at our NAT type.
+const MIN_REPORTS: usize = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NatType {
+    // We haven't heard back from enough peers to say.
+    Unknown,
+    // Peers see the same address that we're bound to. There's no NAT in the way.
+    Open,
+    // Peers all see the same public address. Anyone who learns it can (probably) reach us.
+    EndpointIndependent,
+    // Peers see different public addresses, so the mapping depends on who we're talking to. The
+    // addresses peers report to us are of little use to anyone else.
+    EndpointDependent,
+}
+
+// Collects the reflexive addresses that peers report back to us in reply to
+// `Msg::SenderGetAddress` and works out what sort of NAT (if any) we're sitting behind. IPv4 and
+// IPv6 traffic rarely goes through the same NAT, so each family is considered separately.
+pub struct ReflexiveAddrs {
+    local_addrs: Vec<SocketAddr>,
+    // The addresses of our interfaces that we've sent from. We bind to unspecified addresses, so
+    // `local_addrs` alone can't tell us whether a reported address is one of our own.
+    local_ips: HashSet<IpAddr>,
+    reports: HashMap<XorAddr, (SocketAddr, Instant)>,
+}
+
+impl ReflexiveAddrs {
+    pub fn new(local_addrs: Vec<SocketAddr>) -> ReflexiveAddrs {
+        ReflexiveAddrs {
+            local_addrs,
+            local_ips: HashSet::new(),
+            reports: HashMap::new(),
+        }
+    }
+
+    pub fn add_report(&mut self, peer: XorAddr, reported: SocketAddr) {
+        let _ = self.reports.insert(peer, (reported, Instant::now()));
+    }
+
+    // Notes an address of one of our interfaces, as picked by the OS to send to a peer.
+    pub fn add_local_ip(&mut self, ip: IpAddr) {
+        let _ = self.local_ips.insert(ip);
+    }
+
+    // Whether peers seeing `addr` means they're seeing us as we are, with no NAT in between.
+    fn is_local(&self, addr: &SocketAddr) -> bool {
+        self.local_addrs.iter().any(|local| {
+            local.port() == addr.port()
+            && local.is_ipv6() == addr.is_ipv6()
+            && (local.ip() == addr.ip() || self.local_ips.contains(&addr.ip()))
+        })
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.reports.retain(|_, (_, time)| {
+            Sec::from(now.duration_since(*time)) < MAPPING_LIFETIME
+        });
+    }
+
+    pub fn needs_refresh(&mut self, now: Instant) -> bool {
+        self.expire(now);
+        self.reports.len() < MIN_REPORTS
+    }
+
+    fn reports_for(&self, ipv6: bool) -> Vec<SocketAddr> {
+        self.reports
+        .values()
+        .map(|(addr, _)| *addr)
+        .filter(|addr| addr.is_ipv6() == ipv6)
+        .collect()
+    }
+
+    fn nat_type_for(&self, ipv6: bool) -> NatType {
+        let reported = self.reports_for(ipv6);
+        if reported.len() < MIN_REPORTS {
+            return NatType::Unknown;
+        }
+
+        let first = reported[0];
+        if !reported.iter().all(|addr| *addr == first) {
+            return NatType::EndpointDependent;
+        }
+
+        // Without knowing which interface addresses are ours we can't tell a port-preserving NAT
+        // apart from no NAT at all, so err on the side of assuming there's a NAT.
+        if self.is_local(&first) {
+            NatType::Open
+        } else {
+            NatType::EndpointIndependent
+        }
+    }
+
+    // Our NAT type as far as IPv4 peers are concerned, or as far as IPv6 peers are if we don't
+    // know about IPv4.
+    pub fn nat_type(&self) -> NatType {
+        match self.nat_type_for(false) {
+            NatType::Unknown => self.nat_type_for(true),
+            nat_type => nat_type,
+        }
+    }
+
+    // The public addresses we should advertise to other peers, weighted by how many of the
+    // reports for that address family agree on them.
+    pub fn public_addrs(&self) -> Vec<Address> {
+        let mut ret = Vec::new();
+        for &ipv6 in &[false, true] {
+            let nat_type = self.nat_type_for(ipv6);
+            let reported = self.reports_for(ipv6);
+            let total = reported.len() as f64;
+            let mut counts: HashMap<SocketAddr, usize> = HashMap::new();
+            for addr in reported {
+                *counts.entry(addr).or_insert(0) += 1;
+            }
+
+            for (addr, count) in counts {
+                let mut probability = count as f64 / total;
+                if nat_type == NatType::EndpointDependent {
+                    // A peer we haven't spoken to yet will almost certainly get a different
+                    // mapping.
+                    probability *= 0.1;
+                }
+                ret.push(Address::new(AddressKind::Resolved(addr), probability, MAPPING_LIFETIME));
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn peer(n: u8) -> XorAddr {
+        XorAddr::from_bytes([n; 32])
+    }
+
+    #[test]
+    fn nat_type_comes_from_what_peers_report() {
+        let mut addrs = ReflexiveAddrs::new(vec![addr!("0.0.0.0:4000")]);
+        addrs.add_report(peer(1), addr!("1.2.3.4:4000"));
+        assert_eq!(addrs.nat_type(), NatType::Unknown);
+
+        addrs.add_report(peer(2), addr!("1.2.3.4:4000"));
+        assert_eq!(addrs.nat_type(), NatType::EndpointIndependent);
+        assert_eq!(addrs.public_addrs().len(), 1);
+
+        // It turns out that's the address we're sending from.
+        addrs.add_local_ip(ip!("1.2.3.4"));
+        assert_eq!(addrs.nat_type(), NatType::Open);
+
+        addrs.add_report(peer(3), addr!("1.2.3.4:4001"));
+        assert_eq!(addrs.nat_type(), NatType::EndpointDependent);
+    }
+
+    #[test]
+    fn a_different_port_means_a_nat() {
+        let mut addrs = ReflexiveAddrs::new(vec![addr!("0.0.0.0:4000")]);
+        addrs.add_local_ip(ip!("1.2.3.4"));
+        addrs.add_report(peer(1), addr!("1.2.3.4:5000"));
+        addrs.add_report(peer(2), addr!("1.2.3.4:5000"));
+        assert_eq!(addrs.nat_type(), NatType::EndpointIndependent);
+    }
+}
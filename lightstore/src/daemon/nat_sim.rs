@@ -0,0 +1,75 @@
+use super::*;
+use std::net::IpAddr;
+
+// The usual taxonomy of NAT behaviours, from most to least permissive.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NatKind {
+    // One mapping per inside address, and anyone can send to it.
+    FullCone,
+    // One mapping per inside address, but only hosts we've sent to can send back.
+    RestrictedCone,
+    // One mapping per inside address, but only the exact addresses we've sent to can send back.
+    PortRestrictedCone,
+    // A fresh mapping for every destination, and only that destination can send back.
+    Symmetric,
+}
+
+// A user-space NAT for tests. It doesn't touch any sockets, it just rewrites addresses and drops
+// packets the same way a real NAT of the given kind would.
+pub struct NatSim {
+    public_ip: IpAddr,
+    kind: NatKind,
+    next_port: u16,
+    mappings: HashMap<(SocketAddr, Option<SocketAddr>), SocketAddr>,
+    bindings: HashMap<SocketAddr, (SocketAddr, HashSet<SocketAddr>)>,
+}
+
+impl NatSim {
+    pub fn new(public_ip: IpAddr, kind: NatKind) -> NatSim {
+        NatSim {
+            public_ip,
+            kind,
+            next_port: 40000,
+            mappings: HashMap::new(),
+            bindings: HashMap::new(),
+        }
+    }
+
+    // A packet from `inside` to `dest` passes out through the NAT. Returns the public source
+    // address the packet leaves with.
+    pub fn outbound(&mut self, inside: SocketAddr, dest: SocketAddr) -> SocketAddr {
+        let key = match self.kind {
+            NatKind::Symmetric => (inside, Some(dest)),
+            _ => (inside, None),
+        };
+        let public = match self.mappings.get(&key) {
+            Some(public) => *public,
+            None => {
+                let public = SocketAddr::new(self.public_ip, self.next_port);
+                self.next_port += 1;
+                let _ = self.mappings.insert(key, public);
+                let _ = self.bindings.insert(public, (inside, HashSet::new()));
+                public
+            },
+        };
+        let (_, sent_to) = unwrap!(self.bindings.get_mut(&public));
+        let _ = sent_to.insert(dest);
+        public
+    }
+
+    // A packet from `src` arrives at the NAT's public address `public`. Returns the inside address
+    // it gets forwarded to, or `None` if the NAT drops it.
+    pub fn inbound(&mut self, src: SocketAddr, public: SocketAddr) -> Option<SocketAddr> {
+        let (inside, sent_to) = self.bindings.get(&public)?;
+        let allowed = match self.kind {
+            NatKind::FullCone => true,
+            NatKind::RestrictedCone => sent_to.iter().any(|addr| addr.ip() == src.ip()),
+            NatKind::PortRestrictedCone | NatKind::Symmetric => sent_to.contains(&src),
+        };
+        if allowed {
+            Some(*inside)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use super::*;
+use std::fs::{self, File};
+use std::io;
+
+#[derive(Debug, Fail)]
+pub enum NodeKeyError {
+    #[fail(display = "error generating node key: {}", _0)]
+    Generate(rand::Error),
+    #[fail(display = "error reading node key: {}", _0)]
+    Read(io::Error),
+    #[fail(display = "error saving node key: {}", _0)]
+    Save(io::Error),
+    #[fail(display = "malformed node key file at {:?}", _0)]
+    Malformed(PathBuf),
+}
+
+// Loads the key peers know us by from `path`, or makes one up and saves it there if this is our
+// first run. Our address is the public half, so without somewhere to keep it we'd be a stranger
+// to everyone each time we started, and nobody could check our receipts or records afterwards.
+pub fn load_node_key(path: Option<&Path>) -> Result<SignKeypair, NodeKeyError> {
+    let path = match path {
+        Some(path) => path,
+        None => return SignKeypair::new().map_err(NodeKeyError::Generate),
+    };
+    match fs::read_to_string(path) {
+        Ok(contents) => match parse_node_key(&contents) {
+            Some(keypair) => Ok(keypair),
+            None => Err(NodeKeyError::Malformed(path.to_owned())),
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            let keypair = SignKeypair::new().map_err(NodeKeyError::Generate)?;
+            save_node_key(path, &keypair).map_err(NodeKeyError::Save)?;
+            Ok(keypair)
+        },
+        Err(e) => Err(NodeKeyError::Read(e)),
+    }
+}
+
+// The file holds the public key followed by the secret one. Only we get to read it.
+fn save_node_key(path: &Path, keypair: &SignKeypair) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut file = File::create(&tmp_path)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        writeln!(file, "{} {}", keypair.public, InspectSecret(&keypair.secret))?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)
+}
+
+fn parse_node_key(contents: &str) -> Option<SignKeypair> {
+    let mut fields = contents.split_whitespace();
+    let public = PublicSignKey::from_str(fields.next()?).ok()?;
+    let secret = SecretSignKey::from_str(fields.next()?).ok()?;
+    if fields.next().is_some() {
+        return None;
+    }
+    // Make sure the halves go together, or we'd sign everything with a key nobody can check.
+    ed25519_dalek::PublicKey::from_bytes(&public.as_bytes()[..]).ok()?;
+    let keypair = SignKeypair { public, secret };
+    if !public.verify(b"lightstore", &keypair.sign(b"lightstore")) {
+        return None;
+    }
+    Some(keypair)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn node_key_survives_restarts() {
+        let dir = unwrap!(TempDir::new("lightstore-node-key"));
+        let path = dir.path().join("data").join("node_key");
+        let first = unwrap!(load_node_key(Some(&path)));
+        let second = unwrap!(load_node_key(Some(&path)));
+        assert_eq!(first.public, second.public);
+        assert_eq!(first.secret, second.secret);
+
+        unwrap!(fs::write(&path, "not a key\n"));
+        match load_node_key(Some(&path)) {
+            Err(NodeKeyError::Malformed(..)) => (),
+            _ => panic!("expected a malformed key error"),
+        }
+    }
+}
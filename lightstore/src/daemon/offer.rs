@@ -0,0 +1,471 @@
+// Offers: what a requester will pay for a reply, as a function of what the reply is like.
+//
+// An offer is a small expression tree of `Reward`s. It's encoded depth-first, each node as a
+// one-byte tag followed by its fields:
+//
+//     FIXED           f64 amount
+//     TIME_DECAY      f64 decay (seconds), reward
+//     VERSION_DECAY   f64 factor, reward
+//     IF_DATA         then, otherwise
+//     PER_CLOSE_BIT   f64 amount
+//     SUM, MIN        u8 count, that many rewards
+//
+// Responders evaluate the offer against the reply they'd send to decide whether it's worth
+// sending, and both sides evaluate it once the reply's sent to settle up. The requester measures
+// the reply's delay as how much longer than the usual round trip it took, the responder counts it
+// as zero since it answers straight away, so the two can differ slightly for slow replies like any
+// other entry in the ledger.
+
+use super::*;
+
+// Offers get evaluated for every request we see, so they're kept small.
+const MAX_REWARD_NODES: usize = 32;
+const MAX_REWARD_DEPTH: usize = 8;
+
+// Closeness is a count of leading bits, so it can't be more than this.
+const MAX_CLOSENESS: u32 = 256;
+
+mod reward_tag {
+    pub const FIXED: u8 = 0;
+    pub const TIME_DECAY: u8 = 1;
+    pub const VERSION_DECAY: u8 = 2;
+    pub const IF_DATA: u8 = 3;
+    pub const PER_CLOSE_BIT: u8 = 4;
+    pub const SUM: u8 = 5;
+    pub const MIN: u8 = 6;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reward {
+    Fixed(Btc),
+    // `reward`, shrinking by a factor of e for every `decay` the reply is late by.
+    TimeDecay {
+        decay: Sec,
+        reward: Box<Reward>,
+    },
+    // `reward`, multiplied by `factor` for every version the data in the reply is out of date.
+    VersionDecay {
+        factor: f64,
+        reward: Box<Reward>,
+    },
+    // `then` if the reply has what was asked for, `otherwise` if it doesn't.
+    IfData {
+        then: Box<Reward>,
+        otherwise: Box<Reward>,
+    },
+    // This much for every leading bit the closest contact in the reply shares with the key we're
+    // looking for.
+    PerCloseBit(Btc),
+    Sum(Vec<Reward>),
+    Min(Vec<Reward>),
+}
+
+#[derive(Debug, Fail)]
+pub enum OfferError {
+    #[fail(display = "offer has too many terms")]
+    TooBig,
+    #[fail(display = "offer is nested too deeply")]
+    TooDeep,
+    #[fail(display = "offer has a negative or non-finite amount")]
+    InvalidAmount,
+    #[fail(display = "offer has a non-positive decay time")]
+    InvalidDecay,
+    #[fail(display = "offer has a decay factor outside of [0, 1]")]
+    InvalidFactor,
+    #[fail(display = "invalid reward kind")]
+    InvalidRewardKind,
+}
+
+// The things about a reply that an offer can depend on.
+#[derive(Clone, Copy, Debug)]
+pub struct ReplyTraits {
+    // How much longer than usual the reply took.
+    pub delay: Sec,
+    pub has_data: bool,
+    // How many leading bits the closest contact in the reply shares with the key.
+    pub closeness: u32,
+    pub versions_behind: u32,
+}
+
+impl ReplyTraits {
+    // Sizes up a reply which came `delay` later than expected.
+    pub fn of(reply: &Msg, delay: Sec) -> ReplyTraits {
+        let (has_data, closeness) = match reply {
+            Msg::SenderAddress { .. } => (true, 0),
+            Msg::StreamOpened { .. } => (true, 0),
+            Msg::StreamHave { hosted, .. } => (*hosted, 0),
+            Msg::ProviderAdded { .. } => (true, 0),
+            Msg::ContractAccepted { .. } => (true, 0),
+            Msg::ContractProof { .. } => (true, 0),
+            Msg::MutableStored { .. } => (true, 0),
+            Msg::PowAccepted { .. } => (true, 0),
+            Msg::Mutable { record, .. } => (record.is_some(), 0),
+            Msg::Providers { root, records } => {
+                let key = root.to_xor_addr();
+                let closeness = {
+                    records
+                    .iter()
+                    .map(|record| (record.provider ^ key).leading_zeros())
+                    .max()
+                    .unwrap_or(0)
+                };
+                (!records.is_empty(), closeness)
+            },
+            // The requester has to drop any contacts it can't vouch for before valuing these.
+            Msg::Contacts { key, peers } => {
+                let closeness = {
+                    peers
+                    .iter()
+                    .map(|peer| (*peer ^ *key).leading_zeros())
+                    .max()
+                    .unwrap_or(0)
+                };
+                (false, closeness)
+            },
+            _ => (false, 0),
+        };
+        ReplyTraits {
+            delay: if delay > Sec(0.0) { delay } else { Sec(0.0) },
+            has_data,
+            closeness,
+            versions_behind: 0,
+        }
+    }
+}
+
+impl Reward {
+    fn eval(&self, reply: &ReplyTraits) -> Btc {
+        match self {
+            Reward::Fixed(amount) => *amount,
+            Reward::TimeDecay { decay, reward } => {
+                reward.eval(reply) * (- reply.delay / *decay).exp()
+            },
+            Reward::VersionDecay { factor, reward } => {
+                reward.eval(reply) * factor.powi(reply.versions_behind as i32)
+            },
+            Reward::IfData { then, otherwise } => {
+                if reply.has_data {
+                    then.eval(reply)
+                } else {
+                    otherwise.eval(reply)
+                }
+            },
+            Reward::PerCloseBit(amount) => {
+                *amount * cmp::min(reply.closeness, MAX_CLOSENESS) as f64
+            },
+            Reward::Sum(rewards) => {
+                rewards.iter().fold(Btc(0.0), |sum, reward| sum + reward.eval(reply))
+            },
+            Reward::Min(rewards) => min_of(rewards.iter().map(|reward| reward.eval(reply))),
+        }
+    }
+
+    // The most this could ever pay out.
+    fn max(&self) -> Btc {
+        match self {
+            Reward::Fixed(amount) => *amount,
+            Reward::TimeDecay { reward, .. } => reward.max(),
+            Reward::VersionDecay { reward, .. } => reward.max(),
+            Reward::IfData { then, otherwise } => {
+                let (then, otherwise) = (then.max(), otherwise.max());
+                if then > otherwise { then } else { otherwise }
+            },
+            Reward::PerCloseBit(amount) => *amount * MAX_CLOSENESS as f64,
+            Reward::Sum(rewards) => {
+                rewards.iter().fold(Btc(0.0), |sum, reward| sum + reward.max())
+            },
+            Reward::Min(rewards) => min_of(rewards.iter().map(|reward| reward.max())),
+        }
+    }
+
+    fn check(&self, depth: usize, nodes: &mut usize) -> Result<(), OfferError> {
+        *nodes += 1;
+        if *nodes > MAX_REWARD_NODES {
+            return Err(OfferError::TooBig);
+        }
+        if depth > MAX_REWARD_DEPTH {
+            return Err(OfferError::TooDeep);
+        }
+        match self {
+            Reward::Fixed(amount) | Reward::PerCloseBit(amount) => check_amount(*amount),
+            Reward::TimeDecay { decay, reward } => {
+                if decay.val().is_nan() || decay.val() <= 0.0 {
+                    return Err(OfferError::InvalidDecay);
+                }
+                reward.check(depth + 1, nodes)
+            },
+            Reward::VersionDecay { factor, reward } => {
+                if factor.is_nan() || *factor < 0.0 || *factor > 1.0 {
+                    return Err(OfferError::InvalidFactor);
+                }
+                reward.check(depth + 1, nodes)
+            },
+            Reward::IfData { then, otherwise } => {
+                then.check(depth + 1, nodes)?;
+                otherwise.check(depth + 1, nodes)
+            },
+            Reward::Sum(rewards) | Reward::Min(rewards) => {
+                for reward in rewards {
+                    reward.check(depth + 1, nodes)?;
+                }
+                Ok(())
+            },
+        }
+    }
+
+    fn write(&self, bytes: &mut BytesMut) {
+        match self {
+            Reward::Fixed(amount) => {
+                bytes.reserve(1 + 8);
+                bytes.put_u8(reward_tag::FIXED);
+                bytes.put_f64_be(amount.val());
+            },
+            Reward::TimeDecay { decay, reward } => {
+                bytes.reserve(1 + 8);
+                bytes.put_u8(reward_tag::TIME_DECAY);
+                bytes.put_f64_be(decay.val());
+                reward.write(bytes);
+            },
+            Reward::VersionDecay { factor, reward } => {
+                bytes.reserve(1 + 8);
+                bytes.put_u8(reward_tag::VERSION_DECAY);
+                bytes.put_f64_be(*factor);
+                reward.write(bytes);
+            },
+            Reward::IfData { then, otherwise } => {
+                bytes.reserve(1);
+                bytes.put_u8(reward_tag::IF_DATA);
+                then.write(bytes);
+                otherwise.write(bytes);
+            },
+            Reward::PerCloseBit(amount) => {
+                bytes.reserve(1 + 8);
+                bytes.put_u8(reward_tag::PER_CLOSE_BIT);
+                bytes.put_f64_be(amount.val());
+            },
+            Reward::Sum(rewards) | Reward::Min(rewards) => {
+                let tag = match self {
+                    Reward::Sum(..) => reward_tag::SUM,
+                    _ => reward_tag::MIN,
+                };
+                bytes.reserve(1 + 1);
+                bytes.put_u8(tag);
+                bytes.put_u8(rewards.len() as u8);
+                for reward in rewards {
+                    reward.write(bytes);
+                }
+            },
+        }
+    }
+
+    // Reads a reward, giving up as soon as it's too big or too deep rather than after reading the
+    // whole thing.
+    fn read(
+        bytes: &mut Cursor<Bytes>,
+        depth: usize,
+        nodes: &mut usize,
+    ) -> Result<Reward, MsgReadError> {
+        *nodes += 1;
+        if *nodes > MAX_REWARD_NODES {
+            return Err(MsgReadError::InvalidOffer(OfferError::TooBig));
+        }
+        if depth > MAX_REWARD_DEPTH {
+            return Err(MsgReadError::InvalidOffer(OfferError::TooDeep));
+        }
+        if bytes.remaining() < 1 {
+            return Err(MsgReadError::Truncated);
+        }
+
+        match bytes.get_u8() {
+            reward_tag::FIXED => Ok(Reward::Fixed(Btc(read_f64(bytes)?))),
+            reward_tag::TIME_DECAY => {
+                let decay = Sec(read_f64(bytes)?);
+                let reward = Box::new(Reward::read(bytes, depth + 1, nodes)?);
+                Ok(Reward::TimeDecay { decay, reward })
+            },
+            reward_tag::VERSION_DECAY => {
+                let factor = read_f64(bytes)?;
+                let reward = Box::new(Reward::read(bytes, depth + 1, nodes)?);
+                Ok(Reward::VersionDecay { factor, reward })
+            },
+            reward_tag::IF_DATA => {
+                let then = Box::new(Reward::read(bytes, depth + 1, nodes)?);
+                let otherwise = Box::new(Reward::read(bytes, depth + 1, nodes)?);
+                Ok(Reward::IfData { then, otherwise })
+            },
+            reward_tag::PER_CLOSE_BIT => Ok(Reward::PerCloseBit(Btc(read_f64(bytes)?))),
+            tag @ reward_tag::SUM | tag @ reward_tag::MIN => {
+                if bytes.remaining() < 1 {
+                    return Err(MsgReadError::Truncated);
+                }
+
+                let count = bytes.get_u8() as usize;
+                let mut rewards = Vec::with_capacity(cmp::min(count, MAX_REWARD_NODES));
+                for _ in 0..count {
+                    rewards.push(Reward::read(bytes, depth + 1, nodes)?);
+                }
+                match tag {
+                    reward_tag::SUM => Ok(Reward::Sum(rewards)),
+                    _ => Ok(Reward::Min(rewards)),
+                }
+            },
+            _ => Err(MsgReadError::InvalidOffer(OfferError::InvalidRewardKind)),
+        }
+    }
+}
+
+fn min_of<I: Iterator<Item = Btc>>(amounts: I) -> Btc {
+    amounts.fold(None, |min, amount| match min {
+        Some(min) if min < amount => Some(min),
+        _ => Some(amount),
+    })
+    .unwrap_or(Btc(0.0))
+}
+
+fn check_amount(amount: Btc) -> Result<(), OfferError> {
+    if amount.val().is_finite() && amount.val() >= 0.0 {
+        Ok(())
+    } else {
+        Err(OfferError::InvalidAmount)
+    }
+}
+
+fn read_f64(bytes: &mut Cursor<Bytes>) -> Result<f64, MsgReadError> {
+    if bytes.remaining() < 8 {
+        return Err(MsgReadError::Truncated);
+    }
+    Ok(bytes.get_f64_be())
+}
+
+// A reward that's been checked to be small and sensible enough to evaluate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Offer {
+    reward: Reward,
+}
+
+impl Offer {
+    pub fn new(reward: Reward) -> Result<Offer, OfferError> {
+        reward.check(0, &mut 0)?;
+        Ok(Offer { reward })
+    }
+
+    pub fn fixed(amount: Btc) -> Offer {
+        unwrap!(Offer::new(Reward::Fixed(amount)))
+    }
+
+    // `amount`, shrinking by a factor of e for every `decay` the reply is late by.
+    pub fn decaying(amount: Btc, decay: Sec) -> Offer {
+        let reward = Reward::TimeDecay {
+            decay,
+            reward: Box::new(Reward::Fixed(amount)),
+        };
+        unwrap!(Offer::new(reward))
+    }
+
+    pub fn reward(&self) -> &Reward {
+        &self.reward
+    }
+
+    pub fn payout(&self, reply: &ReplyTraits) -> Btc {
+        self.reward.eval(reply)
+    }
+
+    pub fn max_payout(&self) -> Btc {
+        self.reward.max()
+    }
+
+    pub fn write(&self, bytes: &mut BytesMut) {
+        self.reward.write(bytes)
+    }
+
+    pub fn read(bytes: &mut Cursor<Bytes>) -> Result<Offer, MsgReadError> {
+        let reward = Reward::read(bytes, 0, &mut 0)?;
+        Offer::new(reward).map_err(MsgReadError::InvalidOffer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn traits(delay: f64, has_data: bool, closeness: u32, versions_behind: u32) -> ReplyTraits {
+        ReplyTraits { delay: Sec(delay), has_data, closeness, versions_behind }
+    }
+
+    fn close(a: Btc, b: Btc) -> bool {
+        (a - b).val().abs() < 1e-12
+    }
+
+    #[test]
+    fn evaluate_rewards() {
+        // Pay for the data, less the longer it takes and the older it is, or failing that pay a
+        // bit for each bit of progress towards whoever has it.
+        let reward = Reward::IfData {
+            then: Box::new(Reward::TimeDecay {
+                decay: Sec(2.0),
+                reward: Box::new(Reward::VersionDecay {
+                    factor: 0.5,
+                    reward: Box::new(Reward::Fixed(Btc(8.0))),
+                }),
+            }),
+            otherwise: Box::new(Reward::Min(vec![
+                Reward::PerCloseBit(Btc(0.1)),
+                Reward::Fixed(Btc(1.0)),
+            ])),
+        };
+        let offer = unwrap!(Offer::new(reward));
+
+        assert!(close(offer.payout(&traits(0.0, true, 0, 0)), Btc(8.0)));
+        assert!(close(offer.payout(&traits(0.0, true, 0, 2)), Btc(2.0)));
+        assert!(close(offer.payout(&traits(2.0, true, 0, 0)), Btc(8.0 / 1f64.exp())));
+        assert!(close(offer.payout(&traits(0.0, false, 4, 0)), Btc(0.4)));
+        assert!(close(offer.payout(&traits(0.0, false, 30, 0)), Btc(1.0)));
+        assert!(close(offer.max_payout(), Btc(8.0)));
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        let reward = Reward::Sum(vec![
+            Reward::Fixed(Btc(1e-9)),
+            Reward::TimeDecay {
+                decay: Sec(3.0),
+                reward: Box::new(Reward::IfData {
+                    then: Box::new(Reward::Fixed(Btc(2e-9))),
+                    otherwise: Box::new(Reward::PerCloseBit(Btc(1e-11))),
+                }),
+            },
+        ]);
+        let offer = unwrap!(Offer::new(reward));
+        let mut bytes = BytesMut::new();
+        offer.write(&mut bytes);
+        let read = unwrap!(Offer::read(&mut Cursor::new(bytes.freeze())));
+        assert_eq!(read, offer);
+    }
+
+    #[test]
+    fn reject_bad_offers() {
+        match Offer::new(Reward::Fixed(Btc(-1.0))) {
+            Err(OfferError::InvalidAmount) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        let reward = Reward::TimeDecay {
+            decay: Sec(0.0),
+            reward: Box::new(Reward::Fixed(Btc(1.0))),
+        };
+        match Offer::new(reward) {
+            Err(OfferError::InvalidDecay) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        // Deeply nested offers are rejected before they're fully read.
+        let mut bytes = BytesMut::new();
+        for _ in 0..1000 {
+            bytes.extend_from_slice(&[reward_tag::VERSION_DECAY, 0x3f, 0xf0, 0, 0, 0, 0, 0, 0]);
+        }
+        match Offer::read(&mut Cursor::new(bytes.freeze())) {
+            Err(MsgReadError::InvalidOffer(OfferError::TooBig)) => (),
+            Err(MsgReadError::InvalidOffer(OfferError::TooDeep)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
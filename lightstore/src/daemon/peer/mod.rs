@@ -3,8 +3,11 @@ use super::*;
 mod peer_tx;
 mod peer_info;
 mod peer_db;
+mod msg_rx;
+mod reputation;
 
 pub use self::peer_tx::*;
 pub use self::peer_info::*;
 pub use self::peer_db::*;
-
+pub use self::msg_rx::*;
+pub use self::reputation::*;
@@ -1,59 +1,52 @@
-pub struct MsgRx {
-    socket: SharedUdpSocket,
-    state: MsgRxState,
-    peer_db: Arc<PeerDb>,
-}
+use super::*;
+use lightstore_shared_udp_socket::{Subscription, Filter};
 
-enum MsgRxState {
-    Invalid,
-    Waiting,
-    Receiving(lightstore_shared_udp_socket::RecvDgram),
-    Unpacking(Cursor<Bytes>),
+pub struct MsgRx {
+    subscription: Subscription,
+    unpacking: Option<(Cursor<Bytes>, SocketAddr)>,
+    costs: ResourceCosts,
 }
 
 impl MsgRx {
-    pub fn new(socket: SharedUdpSocket, peer_db: Arc<PeerDb>) -> MsgRx {
+    pub fn new(mut socket: SharedUdpSocket, costs: ResourceCosts) -> MsgRx {
         MsgRx {
-            socket,
-            peer_db,
-            packet: None,
+            subscription: socket.subscribe(Filter::Any),
+            unpacking: None,
+            costs,
         }
     }
 }
 
 impl Stream for MsgRx {
-    type Item = Msg;
-    type Error = io::Error;
+    type Item = (Msg, SocketAddr);
+    type Error = !;
 
-    fn poll(&mut self) -> io::Result<Async<Option<Msg>>> {
+    fn poll(&mut self) -> Result<Async<Option<(Msg, SocketAddr)>>, !> {
         loop {
-            let state = mem::replace(&mut self.state, MsgRxState::Invalid);
-            match state {
-                MsgRxState::Invalid => unreachable!(),
-                MsgRxState::Receiving(mut recv_dgram) => {
-                    match recv_dgram.poll()? {
-                        Async::Ready((data, addr)) => {
-                            let bytes = Cursor::new(data.freeze());
-                            self.state = MsgRxState::Unpacking(bytes);
-                        }, 
-                        Async::NotReady => {
-                            self.state = MsgRxState::Receiving(recv_dgram);
-                            return Ok(Async::NotReady);
-                        },
-                    }
-                },
-                MsgRxState::Unpacking(mut bytes) => {
-                    if bytes.remaining() == 0 {
-                        self.state = MsgRxState::Receiving(self.socket.recv_dgram());
-                        continue;
-                    }
+            if let Some((mut bytes, addr)) = self.unpacking.take() {
+                if bytes.remaining() == 0 {
+                    continue;
+                }
+                match Msg::read(&mut bytes) {
+                    Ok(msg) => {
+                        self.unpacking = Some((bytes, addr));
+                        return Ok(Async::Ready(Some((msg, addr))));
+                    },
+                    // Once we hit something we can't parse there's no way to find where the
+                    // next message in the packet starts, so drop the rest of it.
+                    Err(..) => continue,
+                }
+            }
 
-                    let msg_res = Msg::read(&mut bytes);
-                    self.state = MsgRxState::Unpacking(bytes);
-                    Ok(Async::Ready(Some((msg_res, addr))))
+            match self.subscription.poll()? {
+                Async::Ready(Some((data, addr))) => {
+                    self.costs.record_download(Byte::from(data.len()));
+                    let bytes = Cursor::new(data.freeze());
+                    self.unpacking = Some((bytes, normalize_addr(addr)));
                 },
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
             }
         }
     }
 }
-
@@ -1,13 +1,14 @@
 use super::*;
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone)]
 pub struct PeerInfo {
     pub addrs: Vec<Address>,
     pub exp_download_fee: LogBtcPerByte,
     pub var_download_fee: f64,
+    pub relay_fee: Option<BtcPerByte>,
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone)]
 pub struct Address {
     kind: AddressKind,
     probability: f64,
@@ -15,10 +16,15 @@ pub struct Address {
     probability_decay: Sec,
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone)]
 pub enum AddressKind {
     Resolved(SocketAddr),
     Domain(String),
+    // We can't reach the peer directly, but `relay` will forward packets to them for a fee.
+    Relayed {
+        relay: XorAddr,
+        relay_addr: SocketAddr,
+    },
 }
 
 impl PeerInfo {
@@ -26,8 +32,9 @@ impl PeerInfo {
         // TODO: pick proper values here
         let peer_info = PeerInfo {
             addrs: Vec::new(),
-            exp_download_fee: resource_costs::download().log(),
+            exp_download_fee: Costs::base().download.log(),
             var_download_fee: 1.0,
+            relay_fee: None,
         };
         Arc::new(peer_info)
     }
@@ -41,5 +48,91 @@ impl PeerInfo {
     pub fn update(&self, _msg: &Msg) {
         unimplemented!()
     }
+
+    // The resolved address we're most likely to reach this peer at.
+    pub fn best_addr(&self, at: Instant) -> Option<SocketAddr> {
+        let mut best = None;
+        let mut best_probability = 0.0;
+        for addr in &self.addrs {
+            if let AddressKind::Resolved(socket_addr) = addr.kind {
+                let probability = addr.probability_at(at);
+                if best.is_none() || probability > best_probability {
+                    best = Some(socket_addr);
+                    best_probability = probability;
+                }
+            }
+        }
+        best
+    }
+
+    // The address we're most likely to reach this peer at, including relays. `can_send_to` says
+    // whether we have a socket for a given address's family.
+    pub fn best_route<F>(&self, at: Instant, can_send_to: F) -> Option<&AddressKind>
+    where
+        F: Fn(&SocketAddr) -> bool,
+    {
+        let mut best = None;
+        let mut best_probability = 0.0;
+        for addr in &self.addrs {
+            let socket_addr = match addr.kind {
+                AddressKind::Domain(..) => continue,
+                AddressKind::Resolved(ref socket_addr) => socket_addr,
+                AddressKind::Relayed { ref relay_addr, .. } => relay_addr,
+            };
+            if !can_send_to(socket_addr) {
+                continue;
+            }
+            let probability = addr.probability_at(at);
+            if best.is_none() || probability > best_probability {
+                best = Some(&addr.kind);
+                best_probability = probability;
+            }
+        }
+        best
+    }
+
+    pub fn has_addr(&self, kind: &AddressKind) -> bool {
+        self.addrs.iter().any(|addr| addr.kind == *kind)
+    }
+
+    pub fn has_domain(&self) -> bool {
+        self.addrs.iter().any(|addr| match addr.kind {
+            AddressKind::Domain(..) => true,
+            _ => false,
+        })
+    }
+
+    pub fn scale_addr_probability(&mut self, kind: &AddressKind, factor: f64, at: Instant) {
+        for addr in self.addrs.iter_mut() {
+            if addr.kind == *kind {
+                addr.scale_probability(factor, at);
+            }
+        }
+    }
 }
 
+impl Address {
+    pub fn new(kind: AddressKind, probability: f64, probability_decay: Sec) -> Address {
+        Address {
+            kind,
+            probability,
+            probability_time: Instant::now(),
+            probability_decay,
+        }
+    }
+
+    pub fn kind(&self) -> &AddressKind {
+        &self.kind
+    }
+
+    pub fn probability_at(&self, at: Instant) -> f64 {
+        let time = Sec::from(at.duration_since(self.probability_time));
+        self.probability * (- time / self.probability_decay).exp()
+    }
+
+    // Scales our confidence in this address by `factor`, eg. after failing to reach it.
+    pub fn scale_probability(&mut self, factor: f64, at: Instant) {
+        self.probability = self.probability_at(at) * factor;
+        self.probability_time = at;
+    }
+}
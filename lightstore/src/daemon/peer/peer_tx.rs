@@ -1,51 +1,110 @@
 use super::*;
 use std::io;
-use trust_dns_resolver::ResolverFuture;
-use lightstore_shared_udp_socket::{SendDgram, OutgoingPacket};
+use lightstore_shared_udp_socket::{SendDgram, SendOutcome, OutgoingPacket, ErrorScope, error_scope};
 
-const MAX_MSG_LEN: usize = 500;
+// The biggest message we send before we know anything about the path to a peer. Bigger ones get
+// split up by the `Fragmenter`.
+pub const MAX_MSG_LEN: usize = 500;
+
+// How much we lose faith in an address when sending to it fails with an error like EHOSTUNREACH.
+pub const UNREACHABLE_PENALTY: f64 = 0.25;
+
+// What peer drivers tell the daemon about.
+pub enum PeerEvent {
+    // Sending to `route` failed because the network told us the address is unreachable.
+    Unreachable {
+        peer: XorAddr,
+        route: AddressKind,
+    },
+    // We sent `len` bytes through `relay`, which we owe it its fee on.
+    Relayed {
+        relay: XorAddr,
+        len: usize,
+    },
+}
 
 pub struct PeerTx {
     message_tx: UnboundedSender<PendingSendMessage>,
+    info_tx: UnboundedSender<Arc<PeerInfo>>,
+    priority_tx: UnboundedSender<f64>,
 }
 
 struct PeerDriver {
     message_rx: UnboundedReceiver<PendingSendMessage>,
+    info_rx: UnboundedReceiver<Arc<PeerInfo>>,
+    priority_rx: UnboundedReceiver<f64>,
+    // How far to favour the peer's packets over others', going by its reputation. Packets to
+    // well-behaved peers go out first when we're short of bandwidth, but they're no more worth
+    // sending than their utility says.
+    priority: f64,
     send_messages: VecDeque<PendingSendMessage>,
-    socket: SharedUdpSocket,
-    sending: Option<(SendDgram, Vec<oneshot::Sender<Result<(), PeerSendError>>>)>,
+    sockets: Sockets,
+    sending: Option<Sending>,
     out_buffer: BytesMut,
+    xor_addr: XorAddr,
     peer_info: Arc<PeerInfo>,
+    node_keypair: SignKeypair,
+    event_tx: UnboundedSender<PeerEvent>,
 }
 
 pub enum PeerSendError {
     Socket(Arc<io::Error>),
     TooExpensive,
+    NoAddress,
+    // The peer's driver went away before sending the message, which only happens when the runtime
+    // is shutting down.
+    Dropped,
 }
 
 pub struct SendMessage {
     result_rx: oneshot::Receiver<Result<(), PeerSendError>>,
 }
 
+// A packet that's on its way out, what to tell the daemon once it's gone if it's going through a
+// relay, and who's waiting to hear about it.
+type Sending = (
+    SendDgram,
+    AddressKind,
+    Option<PeerEvent>,
+    Vec<oneshot::Sender<Result<(), PeerSendError>>>,
+);
+
 struct PendingSendMessage {
     outgoing_msg: OutgoingMsg,
     result_tx: oneshot::Sender<Result<(), PeerSendError>>,
 }
 
 impl PeerTx {
-    pub fn from_peer_info(socket: SharedUdpSocket, peer_info: Arc<PeerInfo>) -> PeerTx {
+    pub fn from_peer_info(
+        sockets: Sockets,
+        xor_addr: XorAddr,
+        peer_info: Arc<PeerInfo>,
+        priority: f64,
+        node_keypair: SignKeypair,
+        event_tx: UnboundedSender<PeerEvent>,
+    ) -> PeerTx {
         let (message_tx, message_rx) = mpsc::unbounded();
+        let (info_tx, info_rx) = mpsc::unbounded();
+        let (priority_tx, priority_rx) = mpsc::unbounded();
         let peer_driver = PeerDriver {
             message_rx,
+            info_rx,
+            priority_rx,
+            priority,
             send_messages: VecDeque::new(),
-            socket,
+            sockets,
             sending: None,
             out_buffer: BytesMut::new(),
+            xor_addr,
             peer_info,
+            node_keypair,
+            event_tx,
         };
         tokio::spawn(peer_driver.infer_err());
         let peer_tx = PeerTx {
             message_tx,
+            info_tx,
+            priority_tx,
         };
         peer_tx
     }
@@ -62,13 +121,33 @@ impl PeerTx {
         }
     }
 
-    pub fn update_info(&self, _info: Arc<PeerInfo>) {
-        unimplemented!()
+    pub fn update_info(&self, info: Arc<PeerInfo>) {
+        unwrap!(self.info_tx.unbounded_send(info));
+    }
+
+    pub fn update_priority(&self, priority: f64) {
+        unwrap!(self.priority_tx.unbounded_send(priority));
+    }
+}
+
+impl Future for SendMessage {
+    type Item = ();
+    type Error = PeerSendError;
+
+    fn poll(&mut self) -> Result<Async<()>, PeerSendError> {
+        match self.result_rx.poll() {
+            Ok(Async::Ready(res)) => res.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(oneshot::Canceled) => Err(PeerSendError::Dropped),
+        }
     }
 }
 
 impl PeerDriver {
-    fn create_packet(&mut self) -> (OutgoingPacket, Vec<oneshot::Sender<Result<(), PeerSendError>>>) {
+    fn create_packet(
+        &mut self,
+        route: AddressKind,
+    ) -> (OutgoingPacket, Option<PeerEvent>, Vec<oneshot::Sender<Result<(), PeerSendError>>>) {
         //let mut rng = rand::thread_rng();
         let mtu = MAX_MSG_LEN;
         self.out_buffer.reserve(mtu);
@@ -76,15 +155,32 @@ impl PeerDriver {
         let msg = unwrap!(self.send_messages.pop_front());
         msg.outgoing_msg.msg.write(&mut self.out_buffer);
         let bytes = self.out_buffer.take().freeze();
+        let (bytes, dest, relayed) = match route {
+            AddressKind::Resolved(dest) => (bytes, dest, None),
+            AddressKind::Relayed { relay, relay_addr } => {
+                // We only owe the relay once it's actually been sent.
+                let relayed = PeerEvent::Relayed { relay, len: bytes.len() };
+                let signature = sign_relayed(&self.node_keypair, &self.xor_addr, &bytes);
+                let relay_msg = Msg::Relay {
+                    dest: self.xor_addr,
+                    signature,
+                    payload: bytes,
+                };
+                relay_msg.write(&mut self.out_buffer);
+                (self.out_buffer.take().freeze(), relay_addr, Some(relayed))
+            },
+            AddressKind::Domain(..) => unreachable!(),
+        };
         let sending = vec![msg.result_tx];
         let packet = OutgoingPacket {
             data: bytes,
-            dest: addr!("0.0.0.0:0"),
+            dest,
             utility: msg.outgoing_msg.utility,
             utility_time: msg.outgoing_msg.utility_time,
             utility_decay: msg.outgoing_msg.utility_decay,
+            priority: self.priority,
         };
-        (packet, sending)
+        (packet, relayed, sending)
     }
 }
 
@@ -93,6 +189,13 @@ impl Future for PeerDriver {
     type Error = !;
 
     fn poll(&mut self) -> Result<Async<()>, !> {
+        while let Async::Ready(Some(peer_info)) = self.info_rx.poll().void_unwrap() {
+            self.peer_info = peer_info;
+        }
+        while let Async::Ready(Some(priority)) = self.priority_rx.poll().void_unwrap() {
+            self.priority = priority;
+        }
+
         let shutting_down = loop {
             match self.message_rx.poll().void_unwrap() {
                 Async::Ready(Some(send_message)) => self.send_messages.push_back(send_message),
@@ -103,18 +206,34 @@ impl Future for PeerDriver {
 
         let queue_empty = loop {
             let sending = self.sending.take();
-            if let Some((mut sending, result_txs)) = sending {
+            if let Some((mut sending, route, relayed, result_txs)) = sending {
                 match sending.poll() {
-                    Ok(Async::Ready(())) => {
+                    Ok(Async::Ready(SendOutcome::Sent)) => {
+                        if let Some(relayed) = relayed {
+                            let _ = self.event_tx.unbounded_send(relayed);
+                        }
                         for result_tx in result_txs {
                             let _ = result_tx.send(Ok(()));
                         }
                     },
+                    Ok(Async::Ready(SendOutcome::DroppedUnprofitable)) => {
+                        for result_tx in result_txs {
+                            let _ = result_tx.send(Err(PeerSendError::TooExpensive));
+                        }
+                    },
                     Ok(Async::NotReady) => {
-                        self.sending = Some((sending, result_txs));
+                        self.sending = Some((sending, route, relayed, result_txs));
                         break false;
                     },
                     Err(e) => {
+                        // Stop favouring this address straight away, and let the daemon know so
+                        // that it sticks.
+                        if error_scope(&e) == ErrorScope::Destination {
+                            let peer_info = Arc::make_mut(&mut self.peer_info);
+                            peer_info.scale_addr_probability(&route, UNREACHABLE_PENALTY, Instant::now());
+                            let event = PeerEvent::Unreachable { peer: self.xor_addr, route };
+                            let _ = self.event_tx.unbounded_send(event);
+                        }
                         for result_tx in result_txs {
                             let _ = result_tx.send(Err(PeerSendError::Socket(e.clone())));
                         }
@@ -126,15 +245,34 @@ impl Future for PeerDriver {
             self.send_messages.insertion_sort_by(|send_msg_0, send_msg_1| {
                 let decay_0 = send_msg_0.outgoing_msg.utility_decay_at(now);
                 let decay_1 = send_msg_1.outgoing_msg.utility_decay_at(now);
-                unwrap!(decay_0.partial_cmp(&decay_1))
+                // A NaN utility can't be ordered, the socket drops it when it gets there.
+                decay_0.partial_cmp(&decay_1).unwrap_or(cmp::Ordering::Equal)
             });
 
             if self.send_messages.is_empty() {
                 break true;
-            } {
-                let (packet, send_messages) = self.create_packet();
-                let sending = self.socket.send_dgram(packet);
-                self.sending = Some((sending, send_messages));
+            }
+
+            let route = {
+                let sockets = &self.sockets;
+                self.peer_info.best_route(now, |addr| sockets.can_send_to(addr)).cloned()
+            };
+            let route = match route {
+                Some(route) => route,
+                // The daemon is still resolving the peer's domain, it'll send us the results.
+                None if self.peer_info.has_domain() => break false,
+                None => {
+                    for send_message in self.send_messages.drain(..) {
+                        let _ = send_message.result_tx.send(Err(PeerSendError::NoAddress));
+                    }
+                    break true;
+                },
+            };
+
+            {
+                let (packet, relayed, send_messages) = self.create_packet(route.clone());
+                let sending = unwrap!(self.sockets.send_dgram(packet));
+                self.sending = Some((sending, route, relayed, send_messages));
             }
         };
 
@@ -0,0 +1,307 @@
+use super::*;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+
+// How far each thing a peer does moves its averages. Roughly its last twenty of each count.
+const REPUTATION_WEIGHT: f64 = 0.05;
+
+// Where a peer we know nothing about starts out on each average.
+const NEUTRAL: f64 = 0.5;
+
+// How much a peer has to have paid us in total for its payment history to count for half.
+const PAYMENT_HISTORY_SCALE: Btc = Btc(1e-6);
+
+// The debt we tolerate from a peer with the worst reputation, and from one with the best.
+const MIN_DEBT_LIMIT: Btc = Btc(1e-7);
+const MAX_DEBT_LIMIT: Btc = Btc(1e-5);
+
+// The most we'll write off of what a peer with a perfect reputation owes us once it's paid up.
+// Chasing payment for a few satoshis costs more than it's worth.
+const MAX_FORGIVENESS: Btc = Btc(1e-8);
+
+// How often we write scores out, if they've changed.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reputation {
+    // Everything the peer's ever paid us.
+    pub paid: Btc,
+    // Moving averages, between 0 and 1, of how often the peer paid before going over its debt
+    // limit, answered our requests, and sent us data that checked out.
+    pub on_time: f64,
+    pub uptime: f64,
+    pub accuracy: f64,
+}
+
+impl Reputation {
+    pub fn new() -> Reputation {
+        Reputation {
+            paid: Btc(0.0),
+            on_time: NEUTRAL,
+            uptime: NEUTRAL,
+            accuracy: NEUTRAL,
+        }
+    }
+
+    // How well-behaved the peer's been, between 0 and 1.
+    pub fn score(&self) -> f64 {
+        let history = self.paid / (self.paid + PAYMENT_HISTORY_SCALE);
+        (history + self.on_time + self.uptime + self.accuracy) / 4.0
+    }
+
+    // How much the peer can owe us before we stop serving it.
+    pub fn debt_limit(&self) -> Btc {
+        MIN_DEBT_LIMIT * (MAX_DEBT_LIMIT / MIN_DEBT_LIMIT).powf(self.score())
+    }
+
+    // How much of what the peer still owes us after paying we write off.
+    pub fn forgiveness(&self) -> Btc {
+        MAX_FORGIVENESS * self.score().powi(2)
+    }
+
+    // How far the peer's packets are favoured over everyone else's when they're queued to send.
+    pub fn priority(&self) -> f64 {
+        0.5 + self.score()
+    }
+}
+
+fn record(average: &mut f64, good: bool) {
+    let target = if good { 1.0 } else { 0.0 };
+    *average += REPUTATION_WEIGHT * (target - *average);
+}
+
+#[derive(Debug, Fail)]
+pub enum ReputationDbError {
+    #[fail(display = "io error: {}", _0)]
+    Io(io::Error),
+    #[fail(display = "malformed entry on line {}", _0)]
+    Malformed(usize),
+}
+
+// The reputations of every peer we've dealt with, saved alongside the rest of what we know about
+// peers so that they survive restarts.
+pub struct Reputations {
+    peers: HashMap<XorAddr, Reputation>,
+    path: Option<PathBuf>,
+    dirty: bool,
+    next_save: Instant,
+}
+
+impl Reputations {
+    // Loads the reputations saved at `path`. If there's nothing there we start afresh, and if
+    // there's no path we never save them.
+    pub fn open(path: Option<PathBuf>, now: Instant) -> Reputations {
+        let peers = match path {
+            Some(ref path) => match Reputations::load(path) {
+                Ok(peers) => peers,
+                Err(ReputationDbError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+                    HashMap::new()
+                },
+                Err(e) => {
+                    debug!("ignoring reputations at {:?}: {}", path, e);
+                    HashMap::new()
+                },
+            },
+            None => HashMap::new(),
+        };
+        Reputations {
+            peers,
+            path,
+            dirty: false,
+            next_save: now + SAVE_INTERVAL,
+        }
+    }
+
+    // Reads the reputations saved at `path`. Each line is a peer's address followed by its
+    // reputation.
+    pub fn load(path: &Path) -> Result<HashMap<XorAddr, Reputation>, ReputationDbError> {
+        let file = File::open(path).map_err(ReputationDbError::Io)?;
+        let mut peers = HashMap::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(ReputationDbError::Io)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (peer, reputation) = match parse_line(&line) {
+                Some(entry) => entry,
+                None => return Err(ReputationDbError::Malformed(i + 1)),
+            };
+            let _ = peers.insert(peer, reputation);
+        }
+        Ok(peers)
+    }
+
+    pub fn get(&self, peer: &XorAddr) -> Reputation {
+        match self.peers.get(peer) {
+            Some(reputation) => reputation.clone(),
+            None => Reputation::new(),
+        }
+    }
+
+    // Records a payment from `peer`, and whether it came before the peer went over its limit.
+    pub fn record_payment(&mut self, peer: XorAddr, amount: Btc, on_time: bool) {
+        let reputation = self.entry(peer);
+        reputation.paid += amount;
+        record(&mut reputation.on_time, on_time);
+    }
+
+    // Records whether `peer` answered a request before we gave up on it.
+    pub fn record_answer(&mut self, peer: XorAddr, answered: bool) {
+        record(&mut self.entry(peer).uptime, answered);
+    }
+
+    // Records whether something `peer` sent us checked out.
+    pub fn record_accuracy(&mut self, peer: XorAddr, accurate: bool) {
+        record(&mut self.entry(peer).accuracy, accurate);
+    }
+
+    // Writes the reputations out if they've changed and it's been a while.
+    pub fn save_if_due(&mut self, now: Instant) {
+        if !self.dirty || now < self.next_save {
+            return;
+        }
+        self.next_save = now + SAVE_INTERVAL;
+        if let Err(e) = self.save() {
+            debug!("error saving reputations: {}", e);
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.dirty && self.path.is_some() {
+            Some(self.next_save)
+        } else {
+            None
+        }
+    }
+
+    // Writes the reputations to a temporary file first, so that dying part way through doesn't
+    // lose the lot.
+    fn save(&mut self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            for (peer, reputation) in &self.peers {
+                writeln!(
+                    file,
+                    "{} {:e} {} {} {}",
+                    as_base32(&peer.as_bytes()),
+                    reputation.paid.val(),
+                    reputation.on_time,
+                    reputation.uptime,
+                    reputation.accuracy,
+                )?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn entry(&mut self, peer: XorAddr) -> &mut Reputation {
+        self.dirty = true;
+        self.peers.entry(peer).or_insert_with(Reputation::new)
+    }
+}
+
+// Whatever's changed since the last save is written out when the daemon shuts down, rather than
+// waiting for the next one.
+impl Drop for Reputations {
+    fn drop(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        if let Err(e) = self.save() {
+            debug!("error saving reputations: {}", e);
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Option<(XorAddr, Reputation)> {
+    let mut fields = line.split_whitespace();
+    let mut bytes = [0u8; 32];
+    from_base32(fields.next()?, &mut bytes).ok()?;
+    let mut average = || {
+        match fields.next()?.parse::<f64>() {
+            Ok(value) if value.is_finite() && value >= 0.0 => Some(value),
+            _ => None,
+        }
+    };
+    let paid = Btc(average()?);
+    let on_time = average()?;
+    let uptime = average()?;
+    let accuracy = average()?;
+    if on_time > 1.0 || uptime > 1.0 || accuracy > 1.0 {
+        return None;
+    }
+    let reputation = Reputation { paid, on_time, uptime, accuracy };
+    Some((XorAddr::from_bytes(bytes), reputation))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn good_behaviour_raises_the_debt_limit() {
+        let peer = XorAddr::from_bytes([1; 32]);
+        let mut reputations = Reputations::open(None, Instant::now());
+        let start = reputations.get(&peer);
+        assert!(start.debt_limit() > MIN_DEBT_LIMIT && start.debt_limit() < MAX_DEBT_LIMIT);
+
+        for _ in 0..20 {
+            reputations.record_payment(peer, Btc(1e-7), true);
+            reputations.record_answer(peer, true);
+            reputations.record_accuracy(peer, true);
+        }
+        let good = reputations.get(&peer);
+        assert!(good.score() > start.score());
+        assert!(good.debt_limit() > start.debt_limit());
+        assert!(good.forgiveness() > start.forgiveness());
+        assert!(good.priority() > start.priority());
+
+        for _ in 0..20 {
+            reputations.record_answer(peer, false);
+            reputations.record_accuracy(peer, false);
+        }
+        assert!(reputations.get(&peer).debt_limit() < good.debt_limit());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = unwrap!(TempDir::new("lightstore-reputation"));
+        let path = dir.path().join("peers").join("reputation");
+        let peer = XorAddr::from_bytes([2; 32]);
+        let now = Instant::now();
+
+        let mut reputations = Reputations::open(Some(path.clone()), now);
+        reputations.record_payment(peer, Btc(3e-9), false);
+        reputations.record_accuracy(peer, true);
+        assert_eq!(reputations.next_deadline(), Some(now + SAVE_INTERVAL));
+        reputations.save_if_due(now + SAVE_INTERVAL);
+        assert_eq!(reputations.next_deadline(), None);
+
+        let loaded = Reputations::open(Some(path.clone()), now);
+        assert_eq!(loaded.get(&peer), reputations.get(&peer));
+
+        // Changes since the last save aren't lost when we shut down.
+        reputations.record_answer(peer, false);
+        let answered = reputations.get(&peer);
+        drop(reputations);
+        assert_eq!(Reputations::open(Some(path.clone()), now).get(&peer), answered);
+
+        unwrap!(fs::write(&path, "not a peer\n"));
+        match Reputations::load(&path) {
+            Err(ReputationDbError::Malformed(1)) => (),
+            res => panic!("unexpected result: {:?}", res.map(|peers| peers.len())),
+        }
+    }
+}
@@ -0,0 +1,412 @@
+// Proof-of-work tokens, so that nodes with nothing in their wallets can still pay their way.
+//
+// A token is a solution to a hash puzzle bound to who it pays (the creditor), who pays with it
+// (the debtor), an expiry time and a nonce. The creditor checks it with a single hash, credits
+// the debtor with the work it's expected to have taken, and remembers it until it expires so it
+// can't be spent twice. Both sides are named by their node keys, which outlive restarts, so the
+// creditor writes down what's been spent to remember it across them too.
+
+use super::*;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread;
+
+const POW_PREFIX: &[u8] = b"lightstore-pow";
+
+// Tokens easier than this aren't worth the hash it takes to check them, and ones harder than this
+// take longer to find than they'd be valid for. A CPU core manages a few million hashes a second,
+// so 2^32 of them takes it around twenty minutes, inside the half of a token's lifetime we mint
+// tokens for.
+pub const MIN_POW_DIFFICULTY: u8 = 12;
+pub const MAX_POW_DIFFICULTY: u8 = 32;
+
+// How long a token's good for. We don't take tokens which expire any later than this, so that we
+// never have to remember a spent one for longer.
+pub const POW_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+// What a hash is worth if the user hasn't said: about what it costs a CPU to do one.
+pub const DEFAULT_BTC_PER_HASH: Btc = Btc(1e-16);
+
+// How many hashes we try between checking whether the token we're minting has expired.
+const EXPIRY_CHECK_INTERVAL: u64 = 1 << 16;
+
+// The most tokens we'll have waiting to be minted. Each can take minutes, so any more and the
+// ones at the back would expire before we got to them.
+const MAX_QUEUED_MINTINGS: usize = 4;
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum PowError {
+    #[fail(display = "token difficulty out of range")]
+    BadDifficulty,
+    #[fail(display = "token has expired")]
+    Expired,
+    #[fail(display = "token expires too far in the future")]
+    TooLong,
+    #[fail(display = "token doesn't solve its puzzle")]
+    InsufficientWork,
+    #[fail(display = "token has already been spent")]
+    Replayed,
+    #[fail(display = "couldn't write the token down as spent")]
+    Unsaved,
+}
+
+#[derive(Debug, Fail)]
+pub enum PayWithWorkError {
+    #[fail(display = "the amount's more than one token can pay")]
+    TooMuch,
+    #[fail(display = "too many tokens are waiting to be minted already")]
+    Busy,
+    #[fail(display = "the token expired before we could mint it")]
+    Expired,
+    #[fail(display = "the peer didn't acknowledge the payment")]
+    Refused,
+    #[fail(display = "the daemon shut down")]
+    Shutdown,
+}
+
+// Resolves to what we paid once the peer's acknowledged the token.
+pub struct PayWithWork {
+    result_rx: oneshot::Receiver<Result<Btc, PayWithWorkError>>,
+}
+
+impl PayWithWork {
+    pub fn new(result_rx: oneshot::Receiver<Result<Btc, PayWithWorkError>>) -> PayWithWork {
+        PayWithWork { result_rx }
+    }
+}
+
+impl Future for PayWithWork {
+    type Item = Btc;
+    type Error = PayWithWorkError;
+
+    fn poll(&mut self) -> Result<Async<Btc>, PayWithWorkError> {
+        match self.result_rx.poll() {
+            Ok(Async::Ready(res)) => res.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(oneshot::Canceled) => Err(PayWithWorkError::Shutdown),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PowToken {
+    pub expires: u64,
+    pub nonce: u64,
+    // How many leading zero bits the hash has.
+    pub difficulty: u8,
+    pub solution: u64,
+}
+
+impl PowToken {
+    // Does the work. This takes around 2^difficulty hashes, so call it somewhere it won't hold
+    // anything else up. Gives up if the token expires before we find it.
+    pub fn mint(
+        creditor: XorAddr,
+        debtor: XorAddr,
+        difficulty: u8,
+        expires: u64,
+    ) -> Option<PowToken> {
+        let nonce = rand::random();
+        let mut token = PowToken { expires, nonce, difficulty, solution: 0 };
+        while !token.solves(&creditor, &debtor) {
+            token.solution = token.solution.wrapping_add(1);
+            if token.solution % EXPIRY_CHECK_INTERVAL == 0 && unix_time() >= expires {
+                return None;
+            }
+        }
+        Some(token)
+    }
+
+    // How many hashes finding this is expected to have taken.
+    pub fn expected_hashes(&self) -> f64 {
+        2f64.powi(i32::from(self.difficulty))
+    }
+
+    fn solves(&self, creditor: &XorAddr, debtor: &XorAddr) -> bool {
+        let mut hasher = Sha256::default();
+        hasher.input(POW_PREFIX);
+        hasher.input(&creditor.as_bytes());
+        hasher.input(&debtor.as_bytes());
+        hasher.input(&u64_bytes(self.expires));
+        hasher.input(&u64_bytes(self.nonce));
+        hasher.input(&[self.difficulty]);
+        hasher.input(&u64_bytes(self.solution));
+        leading_zero_bits(&hasher.result()[..]) >= u32::from(self.difficulty)
+    }
+}
+
+fn u64_bytes(x: u64) -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (x >> (56 - 8 * i)) as u8;
+    }
+    bytes
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+// The difficulty to mint at to pay at least `amount`, if it's within range.
+pub fn pow_difficulty_for(amount: Btc, btc_per_hash: Btc) -> Option<u8> {
+    let hashes = amount / btc_per_hash;
+    let difficulty = cmp::max(hashes.log2().ceil() as i64, i64::from(MIN_POW_DIFFICULTY));
+    if difficulty > i64::from(MAX_POW_DIFFICULTY) {
+        return None;
+    }
+    Some(difficulty as u8)
+}
+
+struct MintJob {
+    creditor: XorAddr,
+    debtor: XorAddr,
+    difficulty: u8,
+    expires: u64,
+    token_tx: oneshot::Sender<Option<PowToken>>,
+}
+
+// Mints tokens one at a time on a thread of its own, so the work doesn't hold up everything else
+// and paying lots of peers at once doesn't start a thread for each. The thread finishes once the
+// minter's dropped.
+pub struct PowMinter {
+    job_tx: SyncSender<MintJob>,
+}
+
+impl PowMinter {
+    pub fn new() -> PowMinter {
+        let (job_tx, job_rx) = sync_channel::<MintJob>(MAX_QUEUED_MINTINGS);
+        let _ = thread::spawn(move || {
+            for job in job_rx {
+                let token = PowToken::mint(job.creditor, job.debtor, job.difficulty, job.expires);
+                let _ = job.token_tx.send(token);
+            }
+        });
+        PowMinter { job_tx }
+    }
+
+    // Queues up a token to be minted. The receiver gets the token, or `None` if it expired before
+    // we found it. Returns `None` if there's already as much queued as we'll take.
+    pub fn mint(
+        &self,
+        creditor: XorAddr,
+        debtor: XorAddr,
+        difficulty: u8,
+        expires: u64,
+    ) -> Option<oneshot::Receiver<Option<PowToken>>> {
+        let (token_tx, token_rx) = oneshot::channel();
+        let job = MintJob { creditor, debtor, difficulty, expires, token_tx };
+        match self.job_tx.try_send(job) {
+            Ok(()) => Some(token_rx),
+            Err(..) => None,
+        }
+    }
+}
+
+// Checks the tokens peers pay us with and keeps track of the ones they've spent.
+pub struct PowVerifier {
+    our_addr: XorAddr,
+    btc_per_hash: Btc,
+    spent: HashMap<(XorAddr, u64), u64>,
+    // Where the spent tokens are written down, if anywhere.
+    path: Option<PathBuf>,
+}
+
+impl PowVerifier {
+    // Picks up the tokens we'd already taken that are still good from `path`. Without a path we
+    // only remember them until we stop.
+    pub fn open(
+        our_addr: XorAddr,
+        btc_per_hash: Btc,
+        path: Option<PathBuf>,
+        now: u64,
+    ) -> PowVerifier {
+        let spent = match path {
+            Some(ref path) => match load_spent(path) {
+                Ok(spent) => spent,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+                Err(e) => {
+                    debug!("couldn't read spent tokens at {:?}: {}", path, e);
+                    HashMap::new()
+                },
+            },
+            None => HashMap::new(),
+        };
+        let mut verifier = PowVerifier { our_addr, btc_per_hash, spent, path };
+        verifier.expire(now);
+        verifier
+    }
+
+    // Checks a token `debtor` has paid us with, and returns what it's worth.
+    pub fn redeem(
+        &mut self,
+        debtor: XorAddr,
+        token: &PowToken,
+        now: u64,
+    ) -> Result<Btc, PowError> {
+        if token.difficulty < MIN_POW_DIFFICULTY || token.difficulty > MAX_POW_DIFFICULTY {
+            return Err(PowError::BadDifficulty);
+        }
+        if token.expires <= now {
+            return Err(PowError::Expired);
+        }
+        if token.expires > now + POW_TOKEN_LIFETIME.as_secs() {
+            return Err(PowError::TooLong);
+        }
+        if self.spent.contains_key(&(debtor, token.nonce)) {
+            return Err(PowError::Replayed);
+        }
+        if !token.solves(&self.our_addr, &debtor) {
+            return Err(PowError::InsufficientWork);
+        }
+
+        // If we can't be sure we'll remember it, we can't take it.
+        if let Err(e) = self.append(&debtor, token) {
+            debug!("couldn't write down spent token: {}", e);
+            return Err(PowError::Unsaved);
+        }
+        let _ = self.spent.insert((debtor, token.nonce), token.expires);
+        Ok(self.btc_per_hash * token.expected_hashes())
+    }
+
+    // Forgets spent tokens which have expired anyway.
+    pub fn expire(&mut self, now: u64) {
+        let before = self.spent.len();
+        self.spent.retain(|_, expires| *expires > now);
+        if self.spent.len() < before {
+            if let Err(e) = self.save() {
+                debug!("error saving spent tokens: {}", e);
+            }
+        }
+    }
+
+    fn append(&self, debtor: &XorAddr, token: &PowToken) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        write_spent(&mut file, debtor, token.nonce, token.expires)?;
+        file.sync_data()
+    }
+
+    // Writes out just the tokens that are still good, via a temporary file so that dying part way
+    // through doesn't lose the lot.
+    fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            for ((debtor, nonce), expires) in &self.spent {
+                write_spent(&mut file, debtor, *nonce, *expires)?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)
+    }
+}
+
+// Each line is the debtor's address, the token's nonce and when it expires.
+fn write_spent(file: &mut File, debtor: &XorAddr, nonce: u64, expires: u64) -> io::Result<()> {
+    writeln!(file, "{} {} {}", as_base32(&debtor.as_bytes()), nonce, expires)
+}
+
+// Skips lines it can't make sense of, such as one we were part way through writing when we died,
+// rather than forgetting everything else in the file.
+fn load_spent(path: &Path) -> io::Result<HashMap<(XorAddr, u64), u64>> {
+    let file = File::open(path)?;
+    let mut spent = HashMap::new();
+    for line in BufReader::new(file).lines() {
+        if let Some((debtor, nonce, expires)) = parse_spent(&line?) {
+            let _ = spent.insert((debtor, nonce), expires);
+        }
+    }
+    Ok(spent)
+}
+
+fn parse_spent(line: &str) -> Option<(XorAddr, u64, u64)> {
+    let mut fields = line.split_whitespace();
+    let mut bytes = [0u8; 32];
+    from_base32(fields.next()?, &mut bytes).ok()?;
+    let nonce = fields.next()?.parse().ok()?;
+    let expires = fields.next()?.parse().ok()?;
+    Some((XorAddr::from_bytes(bytes), nonce, expires))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn redeem_tokens_once() {
+        let creditor = XorAddr::from_bytes([1; 32]);
+        let debtor = XorAddr::from_bytes([2; 32]);
+        let now = 1_000_000;
+        let mut verifier = PowVerifier::open(creditor, Btc(1e-16), None, now);
+
+        let token = unwrap!(PowToken::mint(creditor, debtor, MIN_POW_DIFFICULTY, now + 60));
+        let worth = unwrap!(verifier.redeem(debtor, &token, now));
+        assert_eq!(worth, Btc(1e-16 * 4096.0));
+        assert_eq!(verifier.redeem(debtor, &token, now), Err(PowError::Replayed));
+
+        // Nobody else gets to spend it.
+        let other = XorAddr::from_bytes([3; 32]);
+        assert_eq!(verifier.redeem(other, &token, now), Err(PowError::InsufficientWork));
+        let mut other_verifier = PowVerifier::open(other, Btc(1e-16), None, now);
+        assert_eq!(other_verifier.redeem(debtor, &token, now), Err(PowError::InsufficientWork));
+
+        // Or spend it late, or forge it.
+        let token = unwrap!(PowToken::mint(creditor, debtor, MIN_POW_DIFFICULTY, now + 60));
+        assert_eq!(verifier.redeem(debtor, &token, now + 60), Err(PowError::Expired));
+        let forged = PowToken { difficulty: MIN_POW_DIFFICULTY + 1, ..token };
+        assert!(verifier.redeem(debtor, &forged, now).is_err());
+
+        verifier.expire(now + 61);
+        assert!(verifier.spent.is_empty());
+    }
+
+    #[test]
+    fn spent_tokens_stay_spent_across_restarts() {
+        let dir = unwrap!(TempDir::new("lightstore-pow"));
+        let path = dir.path().join("data").join("spent_pow");
+        let creditor = XorAddr::from_bytes([1; 32]);
+        let debtor = XorAddr::from_bytes([2; 32]);
+        let now = unix_time();
+
+        let token = unwrap!(PowToken::mint(creditor, debtor, MIN_POW_DIFFICULTY, now + 60));
+        let mut verifier = PowVerifier::open(creditor, Btc(1e-16), Some(path.clone()), now);
+        unwrap!(verifier.redeem(debtor, &token, now));
+
+        // We come back with the same key, so the token would still check out if we'd forgotten it.
+        let mut verifier = PowVerifier::open(creditor, Btc(1e-16), Some(path.clone()), now);
+        assert_eq!(verifier.redeem(debtor, &token, now), Err(PowError::Replayed));
+
+        let verifier = PowVerifier::open(creditor, Btc(1e-16), Some(path), now + 61);
+        assert!(verifier.spent.is_empty());
+    }
+
+    #[test]
+    fn difficulty_covers_amount() {
+        assert_eq!(pow_difficulty_for(Btc(1e-10), Btc(1e-16)), Some(20));
+        assert_eq!(pow_difficulty_for(Btc(1e-20), Btc(1e-16)), Some(MIN_POW_DIFFICULTY));
+        assert_eq!(pow_difficulty_for(Btc(1.0), Btc(1e-16)), None);
+    }
+}
@@ -0,0 +1,206 @@
+use super::*;
+
+// What we assume a lookup's reply weighs when pricing it. Most of what we look up is a list of
+// refs, which rarely runs to more than a few kilobytes.
+const EXPECTED_REPLY_SIZE: Byte = Byte(4096.0);
+
+// How many standard deviations above a peer's typical fee we bid, in log space. One puts us above
+// what most of them charge most of the time.
+const FEE_DEVIATIONS: f64 = 1.0;
+
+// How far each fee a peer tells us it charges moves our estimate of what it typically charges.
+const FEE_OBSERVATION_WEIGHT: f64 = 0.25;
+
+// How much we raise the bid by each time nobody answers.
+const RAISE_FACTOR: f64 = 2.0;
+
+// What we'll spend on one lookup and how long we're happy to wait for it, if the user hasn't said.
+pub const DEFAULT_LOOKUP_BUDGET: Btc = Btc(1e-6);
+pub const DEFAULT_TARGET_LATENCY: Sec = Sec(2.0);
+
+// What we're currently offering for a lookup.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bid {
+    pub price: Btc,
+    // Replies lose a factor of e of their worth for each `decay` they're late by.
+    pub decay: Sec,
+    // How many times we've raised it.
+    pub raises: u32,
+}
+
+impl Bid {
+    // What we'll pay for the data: `price` if it comes straight away, less the longer it takes,
+    // and nothing if they don't have it.
+    pub fn offer(&self) -> Offer {
+        let reward = Reward::TimeDecay {
+            decay: self.decay,
+            reward: Box::new(Reward::IfData {
+                then: Box::new(Reward::Fixed(self.price)),
+                otherwise: Box::new(Reward::Fixed(Btc(0.0))),
+            }),
+        };
+        unwrap!(Offer::new(reward))
+    }
+}
+
+// Chooses what to offer for lookups, starting from what the peers we know tend to charge and
+// raising it each time nobody answers, until it hits the user's budget.
+pub struct BidStrategy {
+    budget: Btc,
+    latency: Sec,
+}
+
+impl BidStrategy {
+    pub fn new(config: &Config) -> BidStrategy {
+        BidStrategy {
+            budget: config.lookup_budget.unwrap_or(DEFAULT_LOOKUP_BUDGET),
+            latency: config.target_latency.unwrap_or(DEFAULT_TARGET_LATENCY),
+        }
+    }
+
+    // The opening bid. If the caller named a price we stick to it, otherwise we bid what the
+    // median peer we know would likely take, and never less than uploading the reply costs us at
+    // `upload_cost` a byte.
+    pub fn first_bid<'a, I>(
+        &self,
+        price: Option<Btc>,
+        peer_infos: I,
+        upload_cost: BtcPerByte,
+    ) -> Bid
+    where
+        I: IntoIterator<Item = &'a PeerInfo>,
+    {
+        let price = match price {
+            Some(price) => price,
+            None => {
+                let mut fees: Vec<BtcPerByte> = {
+                    peer_infos
+                    .into_iter()
+                    .map(|info| {
+                        let deviation = info.var_download_fee.max(0.0).sqrt();
+                        (info.exp_download_fee + FEE_DEVIATIONS * deviation).exp()
+                    })
+                    .filter(|fee| fee.val().is_finite())
+                    .collect()
+                };
+                fees.sort_by(|a, b| unwrap!(a.partial_cmp(b)));
+                let fee = match fees.get(fees.len() / 2) {
+                    Some(fee) if *fee > upload_cost => *fee,
+                    _ => upload_cost,
+                };
+                self.cap(fee * EXPECTED_REPLY_SIZE)
+            },
+        };
+        Bid {
+            price,
+            decay: self.latency,
+            raises: 0,
+        }
+    }
+
+    // The next bid after nobody answered `bid`, or `None` if we're already bidding our budget.
+    // A bid with a price the caller named is never raised.
+    pub fn raise(&self, bid: &Bid, explicit: bool) -> Option<Bid> {
+        if explicit || bid.price >= self.budget {
+            return None;
+        }
+        Some(Bid {
+            price: self.cap(bid.price * RAISE_FACTOR),
+            raises: bid.raises + 1,
+            ..*bid
+        })
+    }
+
+    fn cap(&self, price: Btc) -> Btc {
+        if price > self.budget { self.budget } else { price }
+    }
+}
+
+// Folds a fee `peer_info`'s peer told us it charges into our running estimate of its fees, in log
+// space, so that a peer which keeps quoting the same fee ends up with a small variance.
+pub fn observe_download_fee(peer_info: &mut PeerInfo, fee: BtcPerByte) {
+    // A free peer's fee has no log, and we never bid below our own upload cost anyway.
+    if fee <= BtcPerByte(0.0) {
+        return;
+    }
+    let deviation = fee.log() - peer_info.exp_download_fee;
+    let var = peer_info.var_download_fee + FEE_OBSERVATION_WEIGHT * deviation * deviation;
+    peer_info.exp_download_fee = peer_info.exp_download_fee + FEE_OBSERVATION_WEIGHT * deviation;
+    peer_info.var_download_fee = (1.0 - FEE_OBSERVATION_WEIGHT) * var;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn peer_info(fee: f64) -> PeerInfo {
+        PeerInfo {
+            addrs: Vec::new(),
+            exp_download_fee: BtcPerByte(fee).log(),
+            var_download_fee: 0.0,
+            relay_fee: None,
+        }
+    }
+
+    #[test]
+    fn bids_start_at_the_median_fee_and_rise_to_the_budget() {
+        let config = Config {
+            lookup_budget: Some(Btc(1e-6)),
+            ..Config::default()
+        };
+        let strategy = BidStrategy::new(&config);
+        let infos = vec![peer_info(1e-12), peer_info(1e-11), peer_info(1e-9)];
+        let bid = strategy.first_bid(None, &infos, Costs::base().upload);
+        assert!((bid.price.val() - 1e-11 * 4096.0).abs() < 1e-15);
+        assert_eq!(bid.decay, DEFAULT_TARGET_LATENCY);
+
+        let mut raised = bid;
+        let mut raises = 0;
+        while let Some(next) = strategy.raise(&raised, false) {
+            assert!(next.price > raised.price);
+            assert!(next.price <= Btc(1e-6));
+            raised = next;
+            raises += 1;
+        }
+        assert_eq!(raised.price, Btc(1e-6));
+        assert_eq!(raises, 5);
+
+        // A price the caller picked is what they get.
+        let bid = strategy.first_bid(Some(Btc(3e-9)), &infos, Costs::base().upload);
+        assert_eq!(bid.price, Btc(3e-9));
+        assert!(strategy.raise(&bid, true).is_none());
+    }
+    #[test]
+    fn observed_fees_set_the_opening_bid() {
+        let strategy = BidStrategy::new(&Config::default());
+        let upload_cost = BtcPerByte(1e-14);
+
+        // A peer we've never heard from is assumed to charge the base fee, give or take a lot.
+        let mut info = (*PeerInfo::new()).clone();
+        let unheard = strategy.first_bid(None, vec![&info], upload_cost);
+
+        // Once it's quoted the same fee a few times, we bid about that.
+        for _ in 0..40 {
+            observe_download_fee(&mut info, BtcPerByte(1e-12));
+        }
+        assert!((info.exp_download_fee - BtcPerByte(1e-12).log()).abs() < 0.01);
+        assert!(info.var_download_fee < 0.001);
+        let bid = strategy.first_bid(None, vec![&info], upload_cost);
+        assert!(bid.price > unheard.price);
+        assert!((bid.price.val() / (1e-12 * 4096.0) - 1.0).abs() < 0.05);
+
+        // Free peers don't drag the estimate off to nowhere.
+        observe_download_fee(&mut info, BtcPerByte(0.0));
+        assert!(info.exp_download_fee.val().is_finite());
+    }
+
+    #[test]
+    fn nonsense_fee_estimates_are_skipped() {
+        let strategy = BidStrategy::new(&Config::default());
+        let mut broken = peer_info(1e-11);
+        broken.exp_download_fee = LogBtcPerByte(std::f64::NAN);
+        let infos = vec![peer_info(1e-12), broken, peer_info(1e-11)];
+        let bid = strategy.first_bid(None, &infos, Costs::base().upload);
+        assert!((bid.price.val() - 1e-11 * 4096.0).abs() < 1e-15);
+    }
+}
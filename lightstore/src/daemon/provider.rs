@@ -0,0 +1,470 @@
+use super::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// How long a provider record lasts. Providers renew theirs at half that, so one lost announcement
+// doesn't make their content disappear.
+pub const PROVIDER_RECORD_LIFETIME: Duration = Duration::from_secs(60 * 60);
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+// How many of the peers closest to some content we tell when we're hosting it, and ask when we're
+// looking for it.
+pub const PROVIDER_REPLICATION: usize = 4;
+
+// The most peers one lookup asks, counting the contacts it follows. Contacts always get closer to
+// the content, so a lookup that's asked this many without finding it isn't going to.
+pub const MAX_LOOKUP_QUERIES: usize = 4 * PROVIDER_REPLICATION;
+
+// A record expiring further out than its lifetime plus this is from someone whose clock is way
+// off, or who wants it held onto forever.
+pub const MAX_CLOCK_SKEW: u64 = 5 * 60;
+
+pub const MAX_PROVIDER_ADDRS: usize = 8;
+pub const MAX_PROVIDERS_PER_REPLY: usize = 16;
+const MAX_STORED_RECORDS: usize = 100_000;
+
+// Going through every record is too much work to do on every poll.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Keeps a provider's signature from being passed off as anything else they've signed.
+const SIGNATURE_CONTEXT: &[u8] = b"lightstore provider record";
+
+// Seconds since the unix epoch, which is what records expire by since they get passed between
+// machines.
+pub fn unix_time() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch.as_secs(),
+        Err(..) => 0,
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum ProviderRecordError {
+    #[fail(display = "record has too many addresses")]
+    TooManyAddrs,
+    #[fail(display = "record has expired")]
+    Expired,
+    #[fail(display = "record expires too far in the future")]
+    TooLong,
+    #[fail(display = "record has an invalid signature")]
+    BadSignature,
+    #[fail(display = "already have a newer record from this provider")]
+    Stale,
+    #[fail(display = "not storing any more records")]
+    StoreFull,
+}
+
+// A provider's signed statement that it's hosting some content and where to find it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProviderRecord {
+    pub root: MerkleHash,
+    pub provider: XorAddr,
+    // A record with no addresses withdraws any earlier one.
+    pub addrs: Vec<SocketAddr>,
+    pub expires: u64,
+    pub signature: Signature,
+}
+
+impl ProviderRecord {
+    pub fn new(
+        keypair: &SignKeypair,
+        root: MerkleHash,
+        addrs: Vec<SocketAddr>,
+        expires: u64,
+    ) -> ProviderRecord {
+        let provider = keypair.public.to_xor_addr();
+        let signature = keypair.sign(&signed_bytes(&root, &provider, &addrs, expires));
+        ProviderRecord { root, provider, addrs, expires, signature }
+    }
+
+    pub fn is_withdrawal(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    pub fn verify(&self, now: u64) -> Result<(), ProviderRecordError> {
+        if self.addrs.len() > MAX_PROVIDER_ADDRS {
+            return Err(ProviderRecordError::TooManyAddrs);
+        }
+        if self.expires <= now {
+            return Err(ProviderRecordError::Expired);
+        }
+        if self.expires > now + PROVIDER_RECORD_LIFETIME.as_secs() + MAX_CLOCK_SKEW {
+            return Err(ProviderRecordError::TooLong);
+        }
+        let key = PublicSignKey::from_bytes(self.provider.as_bytes());
+        let signed = signed_bytes(&self.root, &self.provider, &self.addrs, self.expires);
+        if !key.verify(&signed, &self.signature) {
+            return Err(ProviderRecordError::BadSignature);
+        }
+        Ok(())
+    }
+
+    // What it costs to hold onto this record until it expires, at `memory` a byte-second.
+    pub fn storage_cost(&self, now: u64, memory: BtcPerByteSec) -> Btc {
+        let len = Byte::from(32 + 32 + 8 + 64 + self.addrs.len() * 19);
+        let lifetime = Sec(self.expires.saturating_sub(now) as f64);
+        memory * (len * lifetime)
+    }
+}
+
+fn signed_bytes(
+    root: &MerkleHash,
+    provider: &XorAddr,
+    addrs: &[SocketAddr],
+    expires: u64,
+) -> Bytes {
+    let mut bytes = BytesMut::with_capacity(SIGNATURE_CONTEXT.len() + 32 + 32 + 8);
+    bytes.put_slice(SIGNATURE_CONTEXT);
+    bytes.put_slice(&root.as_bytes());
+    bytes.put_slice(&provider.as_bytes());
+    bytes.put_u64_be(expires);
+    for addr in addrs {
+        bytes.reserve(1 + 16 + 2);
+        match addr {
+            SocketAddr::V4(addr) => {
+                bytes.put_u8(4);
+                bytes.put_slice(&addr.ip().octets());
+            },
+            SocketAddr::V6(addr) => {
+                bytes.put_u8(6);
+                bytes.put_slice(&addr.ip().octets());
+            },
+        }
+        bytes.put_u16_be(addr.port());
+    }
+    bytes.freeze()
+}
+
+// The records other providers have asked us to hold onto.
+pub struct ProviderStore {
+    records: HashMap<MerkleHash, HashMap<XorAddr, ProviderRecord>>,
+    len: usize,
+    next_expire: Instant,
+}
+
+impl ProviderStore {
+    pub fn new() -> ProviderStore {
+        ProviderStore {
+            records: HashMap::new(),
+            len: 0,
+            next_expire: Instant::now() + EXPIRE_INTERVAL,
+        }
+    }
+
+    // Stores a record, replacing any older one from the same provider.
+    pub fn add(&mut self, record: ProviderRecord, now: u64) -> Result<(), ProviderRecordError> {
+        record.verify(now)?;
+        let existing = {
+            self.records
+            .get(&record.root)
+            .and_then(|providers| providers.get(&record.provider))
+            .map(|existing| existing.expires)
+        };
+        match existing {
+            Some(expires) if expires >= record.expires => {
+                return Err(ProviderRecordError::Stale);
+            },
+            Some(..) => (),
+            None => {
+                if self.len >= MAX_STORED_RECORDS {
+                    return Err(ProviderRecordError::StoreFull);
+                }
+                self.len += 1;
+            },
+        }
+
+        let providers = self.records.entry(record.root).or_insert_with(HashMap::new);
+        let _ = providers.insert(record.provider, record);
+        Ok(())
+    }
+
+    // Whether `add` would find space for the record, ie. we're not full or it replaces one
+    // we've already got.
+    pub fn has_room_for(&self, record: &ProviderRecord) -> bool {
+        self.len < MAX_STORED_RECORDS || {
+            self.records
+            .get(&record.root)
+            .map(|providers| providers.contains_key(&record.provider))
+            .unwrap_or(false)
+        }
+    }
+
+    pub fn lookup(&self, root: &MerkleHash, now: u64) -> Vec<ProviderRecord> {
+        let providers = match self.records.get(root) {
+            Some(providers) => providers,
+            None => return Vec::new(),
+        };
+        providers
+        .values()
+        .filter(|record| !record.is_withdrawal() && record.expires > now)
+        .take(MAX_PROVIDERS_PER_REPLY)
+        .cloned()
+        .collect()
+    }
+
+    pub fn expire(&mut self, now: Instant, unix_now: u64) {
+        if now < self.next_expire {
+            return;
+        }
+        self.next_expire = now + EXPIRE_INTERVAL;
+
+        let mut len = 0;
+        self.records.retain(|_, providers| {
+            providers.retain(|_, record| record.expires > unix_now);
+            len += providers.len();
+            !providers.is_empty()
+        });
+        self.len = len;
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.records.is_empty() {
+            return None;
+        }
+        Some(self.next_expire)
+    }
+}
+
+// The content we host, and when we next need to remind the network of it.
+pub struct Announcements {
+    next_refresh: HashMap<MerkleHash, Instant>,
+}
+
+impl Announcements {
+    pub fn new() -> Announcements {
+        Announcements {
+            next_refresh: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, root: MerkleHash, now: Instant) {
+        let _ = self.next_refresh.insert(root, now);
+    }
+
+    pub fn remove(&mut self, root: &MerkleHash) -> bool {
+        self.next_refresh.remove(root).is_some()
+    }
+
+    pub fn refresh_all(&mut self, now: Instant) {
+        for next_refresh in self.next_refresh.values_mut() {
+            *next_refresh = now;
+        }
+    }
+
+    // The content which is due to be announced again.
+    pub fn due(&mut self, now: Instant) -> Vec<MerkleHash> {
+        let mut due = Vec::new();
+        for (root, next_refresh) in self.next_refresh.iter_mut() {
+            if *next_refresh <= now {
+                *next_refresh = now + REFRESH_INTERVAL;
+                due.push(*root);
+            }
+        }
+        due
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.next_refresh.values().min().cloned()
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum FindProvidersError {
+    #[fail(display = "the daemon shut down")]
+    Shutdown,
+}
+
+// The providers of some content, as far as we and the peers closest to it know.
+pub struct FindProviders {
+    result_rx: oneshot::Receiver<Vec<ProviderRecord>>,
+}
+
+impl FindProviders {
+    pub fn new(result_rx: oneshot::Receiver<Vec<ProviderRecord>>) -> FindProviders {
+        FindProviders { result_rx }
+    }
+}
+
+impl Future for FindProviders {
+    type Item = Vec<ProviderRecord>;
+    type Error = FindProvidersError;
+
+    fn poll(&mut self) -> Result<Async<Vec<ProviderRecord>>, FindProvidersError> {
+        self.result_rx.poll().map_err(|oneshot::Canceled| FindProvidersError::Shutdown)
+    }
+}
+
+// A lookup waiting on the peers we asked.
+pub struct PendingLookup {
+    root: MerkleHash,
+    asked: HashSet<XorAddr>,
+    remaining: usize,
+    records: HashMap<XorAddr, ProviderRecord>,
+    result_tx: oneshot::Sender<Vec<ProviderRecord>>,
+}
+
+impl PendingLookup {
+    pub fn new(
+        root: MerkleHash,
+        local: Vec<ProviderRecord>,
+        asked: &[XorAddr],
+        result_tx: oneshot::Sender<Vec<ProviderRecord>>,
+    ) -> PendingLookup {
+        let records = local.into_iter().map(|record| (record.provider, record)).collect();
+        PendingLookup {
+            root,
+            asked: asked.iter().cloned().collect(),
+            remaining: asked.len(),
+            records,
+            result_tx,
+        }
+    }
+
+    pub fn root(&self) -> MerkleHash {
+        self.root
+    }
+
+    // Takes the contacts a peer pointed us to instead of answering. Returns the ones we haven't
+    // asked yet, which we're now waiting on as well. Call this before `add`ing the reply they came
+    // in, so that the lookup doesn't finish in between.
+    pub fn follow(&mut self, contacts: Vec<XorAddr>) -> Vec<XorAddr> {
+        let mut to_ask = Vec::new();
+        for contact in contacts {
+            if self.asked.len() >= MAX_LOOKUP_QUERIES {
+                break;
+            }
+            if self.asked.insert(contact) {
+                to_ask.push(contact);
+            }
+        }
+        self.remaining += to_ask.len();
+        to_ask
+    }
+
+    // Merges in what a peer told us, keeping the newest record from each provider. Returns whether
+    // that was the last peer we were waiting on.
+    pub fn add(&mut self, records: Vec<ProviderRecord>, now: u64) -> bool {
+        self.remaining = self.remaining.saturating_sub(1);
+        for record in records {
+            if record.root != self.root || record.verify(now).is_err() {
+                continue;
+            }
+            let newer = match self.records.get(&record.provider) {
+                Some(existing) => record.expires > existing.expires,
+                None => true,
+            };
+            if newer {
+                let _ = self.records.insert(record.provider, record);
+            }
+        }
+        self.remaining == 0
+    }
+
+    pub fn finish(self) {
+        let records = {
+            self.records
+            .into_iter()
+            .map(|(_, record)| record)
+            .filter(|record| !record.is_withdrawal())
+            .collect()
+        };
+        let _ = self.result_tx.send(records);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(keypair: &SignKeypair, addrs: Vec<SocketAddr>, expires: u64) -> ProviderRecord {
+        let root = MerkleHash::from_bytes([7u8; 32]);
+        ProviderRecord::new(keypair, root, addrs, expires)
+    }
+
+    #[test]
+    fn records_are_checked() {
+        let keypair = unwrap!(SignKeypair::new());
+        let now = unix_time();
+        let lifetime = PROVIDER_RECORD_LIFETIME.as_secs();
+        let addrs = vec![addr!("1.2.3.4:5678"), addr!("[::1]:45666")];
+
+        let good = record(&keypair, addrs.clone(), now + lifetime);
+        unwrap!(good.verify(now));
+
+        let mut tampered = good.clone();
+        tampered.addrs[0] = addr!("6.6.6.6:5678");
+        match tampered.verify(now) {
+            Err(ProviderRecordError::BadSignature) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        let forever = record(&keypair, addrs.clone(), now + 100 * lifetime);
+        match forever.verify(now) {
+            Err(ProviderRecordError::TooLong) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match good.verify(now + lifetime) {
+            Err(ProviderRecordError::Expired) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn newer_records_replace_older_ones() {
+        let keypair = unwrap!(SignKeypair::new());
+        let root = MerkleHash::from_bytes([7u8; 32]);
+        let now = unix_time();
+        let mut store = ProviderStore::new();
+
+        let first = record(&keypair, vec![addr!("1.2.3.4:5678")], now + 100);
+        assert!(first.storage_cost(now, Costs::base().memory) > Btc(0.0));
+        unwrap!(store.add(first.clone(), now));
+        assert_eq!(store.lookup(&root, now), vec![first.clone()]);
+
+        // A replay of what we've already got changes nothing.
+        match store.add(first.clone(), now) {
+            Err(ProviderRecordError::Stale) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        let moved = record(&keypair, vec![addr!("4.3.2.1:5678")], now + 200);
+        unwrap!(store.add(moved.clone(), now));
+        assert_eq!(store.lookup(&root, now), vec![moved]);
+
+        // Once the provider stops hosting, the old records can't be brought back.
+        let withdrawn = record(&keypair, Vec::new(), now + 300);
+        unwrap!(store.add(withdrawn, now));
+        assert!(store.lookup(&root, now).is_empty());
+        assert!(store.add(first, now).is_err());
+
+        store.expire(Instant::now() + EXPIRE_INTERVAL, now + 300);
+        assert!(store.records.is_empty());
+        assert_eq!(store.len, 0);
+    }
+
+    #[test]
+    fn lookups_follow_contacts_they_havent_asked() {
+        let keypair = unwrap!(SignKeypair::new());
+        let root = MerkleHash::from_bytes([7u8; 32]);
+        let now = unix_time();
+        let peers: Vec<XorAddr> = (0..2).map(|i| XorAddr::from_bytes([i; 32])).collect();
+        let (result_tx, result_rx) = oneshot::channel();
+        let mut lookup = PendingLookup::new(root, Vec::new(), &peers, result_tx);
+
+        // The first peer points us at the second, who we've asked already, and a new one.
+        let contact = XorAddr::from_bytes([9u8; 32]);
+        assert_eq!(lookup.follow(vec![peers[1], contact]), vec![contact]);
+        assert!(!lookup.add(Vec::new(), now));
+        assert!(!lookup.add(Vec::new(), now));
+
+        let found = record(&keypair, vec![addr!("1.2.3.4:5678")], now + 100);
+        assert!(lookup.add(vec![found.clone()], now));
+        lookup.finish();
+        assert_eq!(unwrap!(result_rx.wait()), vec![found]);
+
+        // However many contacts we're given, we only ask so many peers in all.
+        let (result_tx, _result_rx) = oneshot::channel();
+        let mut lookup = PendingLookup::new(root, Vec::new(), &peers, result_tx);
+        let contacts: Vec<XorAddr> = (10..100).map(|i| XorAddr::from_bytes([i; 32])).collect();
+        assert_eq!(lookup.follow(contacts).len(), MAX_LOOKUP_QUERIES - peers.len());
+    }
+}
@@ -0,0 +1,139 @@
+use super::*;
+
+// What we charge for relaying, as a multiple of what the bandwidth costs us. Relaying means both
+// downloading and re-uploading every byte.
+const RELAY_MARKUP: f64 = 2.0;
+
+// A relayed address works as long as both sides stay connected to the relay, which is less
+// certain than a direct address.
+pub const RELAYED_ADDR_PROBABILITY: f64 = 0.5;
+pub const RELAYED_ADDR_LIFETIME: Sec = Sec(300.0);
+
+// What wrapping a message up in a `Msg::Relay` adds to it: the tag, the destination, the
+// sender's signature and the payload's length.
+pub const RELAY_OVERHEAD: usize = 2 + 32 + 64 + 2;
+
+// Relayed datagrams are forwarded straight away or not at all.
+pub const RELAY_UTILITY_DECAY: Sec = Sec(1.0);
+
+// The per-byte fee we ask for relaying, or `None` if we can't act as a relay because we aren't
+// publicly reachable ourselves.
+pub fn our_relay_fee(nat_type: NatType, costs: &Costs) -> Option<BtcPerByte> {
+    match nat_type {
+        NatType::Open | NatType::EndpointIndependent => {
+            let cost = costs.upload + costs.download;
+            Some(cost * RELAY_MARKUP)
+        },
+        NatType::Unknown | NatType::EndpointDependent => None,
+    }
+}
+
+// Picks the cheapest peer that's offered to relay for us and that we can reach directly, out of
+// `via`, the peers we know can reach `target`. `target` obviously can't relay to itself.
+pub fn pick_relay<'a, I>(
+    peers: I,
+    target: &XorAddr,
+    via: &[XorAddr],
+    now: Instant,
+) -> Option<(XorAddr, SocketAddr, BtcPerByte)>
+where
+    I: IntoIterator<Item = (&'a XorAddr, &'a Arc<PeerInfo>)>,
+{
+    let mut best: Option<(XorAddr, SocketAddr, BtcPerByte)> = None;
+    for (xor_addr, peer_info) in peers {
+        if xor_addr == target || !via.contains(xor_addr) {
+            continue;
+        }
+        let relay_fee = match peer_info.relay_fee {
+            Some(relay_fee) => relay_fee,
+            None => continue,
+        };
+        let relay_addr = match peer_info.best_addr(now) {
+            Some(relay_addr) => relay_addr,
+            None => continue,
+        };
+        let cheaper = match best {
+            Some((_, _, best_fee)) => relay_fee < best_fee,
+            None => true,
+        };
+        if cheaper {
+            best = Some((*xor_addr, relay_addr, relay_fee));
+        }
+    }
+    best
+}
+
+// What the sender of a relayed message signs. The relay could otherwise pass off messages of its
+// own as anyone's, or send them on to someone they weren't meant for.
+fn relayed_bytes(dest: &XorAddr, payload: &Bytes) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(32 + payload.len());
+    bytes.extend_from_slice(&dest.as_bytes());
+    bytes.extend_from_slice(&payload[..]);
+    bytes
+}
+
+pub fn sign_relayed(keypair: &SignKeypair, dest: &XorAddr, payload: &Bytes) -> Signature {
+    keypair.sign(&relayed_bytes(dest, payload))
+}
+
+// Whether `payload` really came from `src`, whose address is its node key, and was meant for
+// `dest`.
+pub fn verify_relayed(
+    src: &XorAddr,
+    dest: &XorAddr,
+    payload: &Bytes,
+    signature: &Signature,
+) -> bool {
+    let src_key = PublicSignKey::from_bytes(src.as_bytes());
+    src_key.verify(&relayed_bytes(dest, payload), signature)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn relays_cant_impersonate_senders() {
+        let sender = unwrap!(SignKeypair::new());
+        let relay = unwrap!(SignKeypair::new());
+        let src = sender.public.to_xor_addr();
+        let dest = XorAddr::from_bytes([7u8; 32]);
+        let payload = Msg::PunchProbe { nonce: 1 }.to_bytes();
+        let signature = sign_relayed(&sender, &dest, &payload);
+        assert!(verify_relayed(&src, &dest, &payload, &signature));
+
+        // Not from someone else, not to someone else, and not anything else.
+        let relay_addr = relay.public.to_xor_addr();
+        assert!(!verify_relayed(&relay_addr, &dest, &payload, &signature));
+        assert!(!verify_relayed(&src, &relay_addr, &payload, &signature));
+        let forged = Msg::PunchProbe { nonce: 2 }.to_bytes();
+        assert!(!verify_relayed(&src, &dest, &forged, &signature));
+        let signature = sign_relayed(&relay, &dest, &forged);
+        assert!(!verify_relayed(&src, &dest, &forged, &signature));
+    }
+
+    #[test]
+    fn only_relays_which_reach_the_target_are_picked() {
+        let now = Instant::now();
+        let target = XorAddr::from_bytes([1u8; 32]);
+        let relay_info = |fee, port| {
+            let mut peer_info = (*PeerInfo::new()).clone();
+            peer_info.relay_fee = Some(BtcPerByte(fee));
+            let addr = SocketAddr::new(ip!("10.0.0.1"), port);
+            peer_info.addrs.push(Address::new(AddressKind::Resolved(addr), 1.0, Sec(60.0)));
+            Arc::new(peer_info)
+        };
+        let mut peers = HashMap::new();
+        let cheap = XorAddr::from_bytes([2u8; 32]);
+        let dear = XorAddr::from_bytes([3u8; 32]);
+        let _ = peers.insert(cheap, relay_info(1e-12, 1000));
+        let _ = peers.insert(dear, relay_info(1e-11, 2000));
+        let _ = peers.insert(target, relay_info(1e-13, 3000));
+
+        let picked = pick_relay(&peers, &target, &[dear], now);
+        assert_eq!(picked.map(|(relay, ..)| relay), Some(dear));
+        let picked = pick_relay(&peers, &target, &[cheap, dear, target], now);
+        assert_eq!(picked.map(|(relay, ..)| relay), Some(cheap));
+        assert!(pick_relay(&peers, &target, &[], now).is_none());
+    }
+}
@@ -0,0 +1,742 @@
+// Plans how to spread an upload across hosts so that it's still there when it's wanted, for as
+// little as we can.
+//
+// Each host is given some chance of still having its copy at the end of the upload period, going
+// by its reputation, and a price, going by what it charges for other things. The content is split
+// into `needed` shards and erasure coded into one more for each of the plan's hosts, so that the
+// content survives as long as any `needed` of them do. With `needed` at one every host keeps the
+// whole thing instead.
+
+use super::*;
+use futures::stream::FuturesUnordered;
+
+// What we pay to host each byte for, how long for, and how many times the cheapest price we'll
+// pay for extra availability, if the user hasn't said.
+pub const DEFAULT_MAX_UPLOAD_PRICE: BtcPerByte = BtcPerByte(1e-10);
+pub const DEFAULT_UPLOAD_PERIOD: Sec = Sec(30.0 * DAY.0);
+pub const DEFAULT_UPLOAD_PRICE_FACTOR: f64 = 1.0;
+
+// The availability we aim for if the caller hasn't said.
+pub const DEFAULT_AVAILABILITY: f64 = 0.9;
+
+// The most hosts we'll spread one upload across, and the most pieces we'll split it into.
+const MAX_HOSTS: usize = 32;
+pub const MAX_DATA_SHARDS: usize = 16;
+
+// How likely a host with the best reputation, and one with the worst, is to lose what it's
+// hosting over a day.
+const BEST_DAILY_LOSS: f64 = 0.0005;
+const WORST_DAILY_LOSS: f64 = 0.05;
+
+const DAY: Sec = Sec(24.0 * 60.0 * 60.0);
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum PlanError {
+    #[fail(display = "target availability must be between 0 and 1")]
+    BadAvailability,
+    #[fail(display = "upload period is empty or longer than hosts will sign up for")]
+    BadPeriod,
+    #[fail(display = "not enough hosts to reach the target, the best we can do is {}", _0)]
+    Unreachable(f64),
+    #[fail(display = "reaching the target costs at least {:?}, over the budget of {:?}", _0, _1)]
+    TooExpensive(Btc, Btc),
+}
+
+// A host we could upload to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostCandidate {
+    pub host: XorAddr,
+    // What we expect it to want per byte for the whole upload period.
+    pub price: BtcPerByte,
+    // The chance it still has what we give it at the end of the upload period.
+    pub reliability: f64,
+}
+
+// The price we expect `info`'s node to want to host each byte for `period`. We work out what it
+// would cost us and scale that by how the node's fees compare to ours, never going below our
+// own.
+pub fn hosting_price(info: &PeerInfo, period: Sec, costs: &Costs) -> BtcPerByte {
+    let ratio = info.exp_download_fee.exp() / costs.download;
+    let cost = costs.download + costs.memory * period;
+    cost * ratio.max(1.0)
+}
+
+// The chance a node with `reputation` still has what we give it after `period`.
+pub fn host_reliability(reputation: &Reputation, period: Sec) -> f64 {
+    let range = BEST_DAILY_LOSS / WORST_DAILY_LOSS;
+    let daily_loss = WORST_DAILY_LOSS * range.powf(reputation.score());
+    (1.0 - daily_loss).powf(period / DAY)
+}
+
+// The chance that at least `needed` of hosts with `reliabilities` still have their shards.
+pub fn survival_chance(needed: usize, reliabilities: &[f64]) -> f64 {
+    // survivors[i] is the chance that exactly i of the hosts so far still have theirs.
+    let mut survivors = vec![1.0];
+    for reliability in reliabilities {
+        let mut next = vec![0.0; survivors.len() + 1];
+        for (i, chance) in survivors.iter().enumerate() {
+            next[i] += chance * (1.0 - reliability);
+            next[i + 1] += chance * reliability;
+        }
+        survivors = next;
+    }
+    survivors.iter().skip(needed).sum()
+}
+
+// Splits content into the `count` shards an upload plan gives one to each host. Coding big
+// uploads takes a while, so the daemon does it on a thread of its own.
+pub fn encode_shards(tree: Arc<MerkleTree>, needed: usize, count: usize) -> Vec<Arc<MerkleTree>> {
+    if needed == 1 {
+        return vec![tree; count];
+    }
+    let coder = unwrap!(ErasureCoder::new(needed, count));
+    coder.encode(tree.data()).into_iter().map(|shard| Arc::new(MerkleTree::new(shard))).collect()
+}
+
+// Which hosts to upload to, and how many of them it takes to get the content back.
+#[derive(Clone, Debug)]
+pub struct UploadPlan {
+    pub hosts: Vec<HostCandidate>,
+    pub needed: usize,
+    pub shard_size: u64,
+    pub cost: Btc,
+    pub availability: f64,
+    // The most the user's config lets us spend on the upload, should some of the hosts refuse
+    // and we have to find others.
+    pub limit: Btc,
+}
+
+// The hosts worth asking to keep something, best value first: by what they charge for each factor
+// of e they cut the chance of losing it by.
+pub fn rank_hosts(candidates: &[HostCandidate]) -> Vec<HostCandidate> {
+    let mut ranked: Vec<(f64, &HostCandidate)> = {
+        candidates
+        .iter()
+        .filter(|candidate| {
+            candidate.reliability > 0.0
+            && candidate.price.val().is_finite()
+            && candidate.price >= BtcPerByte(0.0)
+        })
+        .map(|candidate| {
+            let nines = -(1.0 - candidate.reliability).ln();
+            (candidate.price.val() / nines, candidate)
+        })
+        .collect()
+    };
+    ranked.sort_by(|a, b| unwrap!(a.0.partial_cmp(&b.0)));
+    ranked.into_iter().map(|(_, candidate)| candidate.clone()).collect()
+}
+
+// Chooses hosts for uploads according to the user's config.
+pub struct UploadPlanner {
+    max_price: BtcPerByte,
+    period: Sec,
+    price_factor: f64,
+}
+
+impl UploadPlanner {
+    pub fn new(config: &Config) -> UploadPlanner {
+        UploadPlanner {
+            max_price: config.max_upload_price.unwrap_or(DEFAULT_MAX_UPLOAD_PRICE),
+            period: config.upload_period.unwrap_or(DEFAULT_UPLOAD_PERIOD),
+            price_factor: config.upload_price_factor.unwrap_or(DEFAULT_UPLOAD_PRICE_FACTOR),
+        }
+    }
+
+    // How long uploads are hosted for if the caller hasn't said.
+    pub fn default_period(&self) -> Sec {
+        self.period
+    }
+
+    // The most we'll pay to have `size` bytes hosted for `period`. The user's max price is for
+    // their upload period, so it's scaled to fit.
+    pub fn budget(&self, size: u64, period: Sec) -> Btc {
+        self.max_price * Byte(size as f64) * (period / self.period)
+    }
+
+    // Plans the cheapest upload of `size` bytes to `candidates` that's still there at the end of
+    // `period` with a chance of at least `target`, splitting the content into no more than
+    // `max_needed` shards. The candidates' prices and reliabilities should be for `period` too.
+    // If the user's price factor allows, we then pay up to that many times as much to make it
+    // more likely still.
+    //
+    // Finding the very cheapest set of hosts is too slow to do, so for each way of splitting the
+    // content we add hosts in the order `rank_hosts` puts them in, until we're there.
+    pub fn plan(
+        &self,
+        size: u64,
+        period: Sec,
+        candidates: &[HostCandidate],
+        target: f64,
+        max_needed: usize,
+    ) -> Result<UploadPlan, PlanError> {
+        if !(target > 0.0 && target < 1.0) {
+            return Err(PlanError::BadAvailability);
+        }
+        if !(period > Sec(0.0)) || period > Sec::from(MAX_CONTRACT_DURATION) {
+            return Err(PlanError::BadPeriod);
+        }
+
+        let mut candidates = rank_hosts(candidates);
+        candidates.truncate(MAX_HOSTS);
+
+        let mut plans = Vec::new();
+        let mut best = 0.0;
+        for needed in 1..=cmp::min(max_needed, candidates.len()) {
+            let shard = Byte(shard_size(size, needed) as f64);
+            let mut price = BtcPerByte(0.0);
+            let mut reliabilities = Vec::new();
+            for candidate in &candidates {
+                price += candidate.price;
+                reliabilities.push(candidate.reliability);
+                if reliabilities.len() < needed {
+                    continue;
+                }
+                let chance = survival_chance(needed, &reliabilities);
+                best = f64::max(best, chance);
+                if chance >= target {
+                    plans.push((needed, reliabilities.len(), price * shard));
+                }
+            }
+        }
+
+        let cheapest = {
+            plans
+            .iter()
+            .map(|&(_, _, cost)| cost)
+            .min_by(|a, b| unwrap!(a.partial_cmp(b)))
+        };
+        let cheapest = match cheapest {
+            Some(cheapest) => cheapest,
+            None => return Err(PlanError::Unreachable(best)),
+        };
+        let budget = self.budget(size, period);
+        if cheapest > budget {
+            return Err(PlanError::TooExpensive(cheapest, budget));
+        }
+        let limit = {
+            let limit = cheapest * self.price_factor.max(1.0);
+            if limit > budget { budget } else { limit }
+        };
+
+        let mut chosen: Option<UploadPlan> = None;
+        for (needed, count, cost) in plans {
+            if cost > limit {
+                continue;
+            }
+            let hosts = candidates[..count].to_vec();
+            let reliabilities: Vec<f64> = hosts.iter().map(|host| host.reliability).collect();
+            let chance = survival_chance(needed, &reliabilities);
+            let better = match chosen {
+                Some(ref chosen) => {
+                    chance > chosen.availability
+                    || (chance == chosen.availability && cost < chosen.cost)
+                },
+                None => true,
+            };
+            if better {
+                chosen = Some(UploadPlan {
+                    hosts,
+                    needed,
+                    shard_size: shard_size(size, needed),
+                    cost,
+                    availability: chance,
+                    limit,
+                });
+            }
+        }
+        Ok(unwrap!(chosen))
+    }
+}
+
+// Where the shards of an upload went, which is everything it takes to get it back.
+#[derive(Clone, Debug)]
+pub struct ShardManifest {
+    pub root: MerkleHash,
+    pub size: u64,
+    // How many shards it takes to rebuild the content, and how many it was coded into. When
+    // `needed` is one every shard is a whole copy.
+    pub needed: usize,
+    pub shard_count: usize,
+    pub shards: Vec<StoredShard>,
+}
+
+#[derive(Clone, Debug)]
+pub struct StoredShard {
+    pub index: usize,
+    pub host: XorAddr,
+    pub root: MerkleHash,
+}
+
+impl ShardManifest {
+    pub fn shard_size(&self) -> u64 {
+        shard_size(self.size, self.needed)
+    }
+}
+
+// Written on one line, so that it can go in a git remote's mutable data: the root, size, how many
+// shards are needed out of how many, and then the index, host and root of each stored shard.
+impl fmt::Display for ShardManifest {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{} {} {} {}",
+            as_base32(&self.root.as_bytes()),
+            self.size,
+            self.needed,
+            self.shard_count,
+        )?;
+        for shard in &self.shards {
+            write!(
+                fmt,
+                " {}:{}:{}",
+                shard.index,
+                as_base32(&shard.host.as_bytes()),
+                as_base32(&shard.root.as_bytes()),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ShardManifest {
+    type Err = ParseManifestError;
+
+    fn from_str(s: &str) -> Result<ShardManifest, ParseManifestError> {
+        parse_manifest(s).ok_or(ParseManifestError)
+    }
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "malformed shard manifest")]
+pub struct ParseManifestError;
+
+fn parse_manifest(s: &str) -> Option<ShardManifest> {
+    let mut fields = s.split_whitespace();
+    let mut root = [0u8; 32];
+    from_base32(fields.next()?, &mut root).ok()?;
+    let size = fields.next()?.parse().ok()?;
+    let needed = fields.next()?.parse().ok()?;
+    let shard_count = fields.next()?.parse().ok()?;
+    let mut shards = Vec::new();
+    for field in fields {
+        let mut parts = field.split(':');
+        let index = parts.next()?.parse().ok()?;
+        let mut host = [0u8; 32];
+        from_base32(parts.next()?, &mut host).ok()?;
+        let mut shard_root = [0u8; 32];
+        from_base32(parts.next()?, &mut shard_root).ok()?;
+        shards.push(StoredShard {
+            index,
+            host: XorAddr::from_bytes(host),
+            root: MerkleHash::from_bytes(shard_root),
+        });
+    }
+    Some(ShardManifest {
+        root: MerkleHash::from_bytes(root),
+        size,
+        needed,
+        shard_count,
+        shards,
+    })
+}
+
+// What came of an upload: where its shards went, the receipts from the hosts which agreed to keep
+// them, and the chance that enough of them still have theirs at the end of the period.
+#[derive(Debug)]
+pub struct Uploaded {
+    pub manifest: ShardManifest,
+    pub receipts: Vec<Receipt>,
+    pub availability: f64,
+}
+
+// A shard a host's agreed to keep, and how likely it is to keep it.
+pub struct ShardStored {
+    pub index: usize,
+    pub receipt: Receipt,
+    pub reliability: f64,
+}
+
+// An upload we're still finding hosts for.
+pub struct UploadJob {
+    pub root: MerkleHash,
+    pub size: u64,
+    pub needed: usize,
+    pub period: Sec,
+    pub target: f64,
+    pub limit: Btc,
+    // One for each host in the plan.
+    pub shards: Vec<Arc<MerkleTree>>,
+    pub stored: Vec<ShardStored>,
+    // Everyone we've asked, so that we don't ask a host which has refused again.
+    pub asked: HashSet<XorAddr>,
+    pub result_tx: oneshot::Sender<Result<Uploaded, UploadError>>,
+}
+
+impl UploadJob {
+    pub fn availability(&self) -> f64 {
+        let reliabilities: Vec<f64> = self.stored.iter().map(|stored| stored.reliability).collect();
+        survival_chance(self.needed, &reliabilities)
+    }
+
+    // The shards nobody's agreed to keep yet.
+    pub fn missing(&self) -> Vec<usize> {
+        (0..self.shards.len())
+        .filter(|index| !self.stored.iter().any(|stored| stored.index == *index))
+        .collect()
+    }
+
+    // What the hosts which have agreed are charging, all told.
+    pub fn spent(&self) -> Btc {
+        self.stored.iter().fold(Btc(0.0), |spent, stored| spent + stored.receipt.terms.price)
+    }
+
+    // Lets the caller know how the upload went, once it's met its target or there's nobody left
+    // to ask. Falling short is an error, but the caller still gets the receipts of the hosts
+    // which did agree.
+    pub fn finish(self) {
+        let availability = self.availability();
+        let shards = {
+            self.stored
+            .iter()
+            .map(|stored| StoredShard {
+                index: stored.index,
+                host: stored.receipt.host,
+                root: stored.receipt.terms.root,
+            })
+            .collect()
+        };
+        let manifest = ShardManifest {
+            root: self.root,
+            size: self.size,
+            needed: self.needed,
+            shard_count: self.shards.len(),
+            shards,
+        };
+        let receipts = self.stored.into_iter().map(|stored| stored.receipt).collect();
+        let uploaded = Uploaded { manifest, receipts, availability };
+        let res = if availability >= self.target {
+            Ok(uploaded)
+        } else {
+            Err(UploadError::Unavailable(uploaded))
+        };
+        let _ = self.result_tx.send(res);
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum UploadError {
+    #[fail(display = "couldn't plan the upload: {}", _0)]
+    Plan(PlanError),
+    #[fail(
+        display = "not enough hosts agreed, the upload's only available with a chance of {}",
+        _0.availability,
+    )]
+    Unavailable(Uploaded),
+    #[fail(display = "the daemon shut down")]
+    Shutdown,
+}
+
+// Resolves once enough hosts have agreed to keep an upload to meet its target, or we've run out of
+// hosts to ask.
+pub struct Upload {
+    result_rx: oneshot::Receiver<Result<Uploaded, UploadError>>,
+}
+
+impl Upload {
+    pub fn new(result_rx: oneshot::Receiver<Result<Uploaded, UploadError>>) -> Upload {
+        Upload { result_rx }
+    }
+}
+
+impl Future for Upload {
+    type Item = Uploaded;
+    type Error = UploadError;
+
+    fn poll(&mut self) -> Result<Async<Uploaded>, UploadError> {
+        match self.result_rx.poll() {
+            Ok(Async::Ready(res)) => res.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(oneshot::Canceled) => Err(UploadError::Shutdown),
+        }
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum RebuildError {
+    #[fail(display = "not enough shards could be downloaded")]
+    NotEnoughShards,
+    #[fail(display = "error decoding shards: {}", _0)]
+    Erasure(ErasureError),
+    #[fail(display = "rebuilt content doesn't match its root")]
+    BadRoot,
+}
+
+type ShardDownload = BoxSendFuture<(usize, Result<BytesMut, DownloadError>), Void>;
+
+// Downloads the shards of an upload all at once and rebuilds the content from whichever are
+// first to arrive. The rest are dropped, which cancels their streams.
+pub struct Rebuild {
+    manifest: ShardManifest,
+    downloads: FuturesUnordered<ShardDownload>,
+    shards: Vec<(usize, Bytes)>,
+}
+
+impl Rebuild {
+    pub fn new(manifest: ShardManifest, downloads: Vec<(usize, Download)>) -> Rebuild {
+        let downloads = {
+            downloads
+            .into_iter()
+            .map(|(index, download)| {
+                download
+                .fold(BytesMut::new(), |mut shard, data| {
+                    shard.extend_from_slice(&data);
+                    Ok::<_, DownloadError>(shard)
+                })
+                .then(move |res| Ok((index, res)))
+                .into_send_boxed()
+            })
+            .collect()
+        };
+        Rebuild {
+            manifest,
+            downloads,
+            shards: Vec::new(),
+        }
+    }
+
+    fn rebuild(&self) -> Result<Bytes, RebuildError> {
+        let data = if self.manifest.needed == 1 {
+            self.shards[0].1.clone()
+        } else {
+            let manifest = &self.manifest;
+            let coder = {
+                ErasureCoder::new(manifest.needed, manifest.shard_count)
+                .map_err(RebuildError::Erasure)?
+            };
+            coder.decode(manifest.size, &self.shards).map_err(RebuildError::Erasure)?
+        };
+        // Each shard was checked against its own root on the way in, so this only fails if the
+        // manifest's wrong.
+        if MerkleTree::new(data.clone()).root() != self.manifest.root {
+            return Err(RebuildError::BadRoot);
+        }
+        Ok(data)
+    }
+}
+
+impl Future for Rebuild {
+    type Item = Bytes;
+    type Error = RebuildError;
+
+    fn poll(&mut self) -> Result<Async<Bytes>, RebuildError> {
+        loop {
+            match self.downloads.poll().void_unwrap() {
+                Async::Ready(Some((index, Ok(shard)))) => self.shards.push((index, shard.freeze())),
+                Async::Ready(Some((_, Err(..)))) => (),
+                Async::Ready(None) => return Err(RebuildError::NotEnoughShards),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+            if self.shards.len() >= self.manifest.needed {
+                return self.rebuild().map(Async::Ready);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn candidate(i: u8, price: f64, reliability: f64) -> HostCandidate {
+        HostCandidate {
+            host: XorAddr::from_bytes([i; 32]),
+            price: BtcPerByte(price),
+            reliability,
+        }
+    }
+
+    #[test]
+    fn availability_of_shards() {
+        assert!((survival_chance(1, &[0.5, 0.5]) - 0.75).abs() < 1e-12);
+        assert!((survival_chance(2, &[0.5, 0.5]) - 0.25).abs() < 1e-12);
+        assert!((survival_chance(2, &[0.9, 0.9, 0.9]) - 0.972).abs() < 1e-12);
+        assert_eq!(survival_chance(3, &[0.9, 0.9]), 0.0);
+        assert_eq!(shard_size(10 * LEAF_SIZE as u64, 1), 10 * LEAF_SIZE as u64);
+        assert_eq!(shard_size(10 * LEAF_SIZE as u64, 3), 4 * LEAF_SIZE as u64);
+        assert_eq!(shard_size(100, 3), 100);
+    }
+
+    #[test]
+    fn plans_meet_the_target_for_the_least_we_can() {
+        let config = Config {
+            max_upload_price: Some(BtcPerByte(1e-9)),
+            ..Config::default()
+        };
+        let planner = UploadPlanner::new(&config);
+        let period = planner.default_period();
+        let candidates = vec![
+            candidate(1, 1e-12, 0.6),
+            candidate(2, 1e-12, 0.6),
+            candidate(3, 4e-12, 0.99),
+            candidate(4, 1e-12, 0.6),
+            candidate(5, 1e-12, 0.0),
+        ];
+
+        // The reliable host is dear, but not as dear as the three flaky ones it'd take instead.
+        let plan = unwrap!(planner.plan(1000, period, &candidates, 0.95, 1));
+        assert_eq!(plan.needed, 1);
+        assert_eq!(plan.hosts.len(), 1);
+        assert_eq!(plan.hosts[0].host, XorAddr::from_bytes([3; 32]));
+        assert!((plan.cost.val() - 4e-9).abs() < 1e-20);
+
+        assert_eq!(
+            planner.plan(1000, period, &candidates, 0.9999999, 1).map(|plan| plan.hosts.len()),
+            Err(PlanError::Unreachable(survival_chance(1, &[0.99, 0.6, 0.6, 0.6]))),
+        );
+        let stingy = Config {
+            max_upload_price: Some(BtcPerByte(2e-12)),
+            ..Config::default()
+        };
+        match UploadPlanner::new(&stingy).plan(1000, period, &candidates, 0.95, 1) {
+            Err(PlanError::TooExpensive(..)) => (),
+            res => panic!("unexpected result: {:?}", res.map(|plan| plan.cost)),
+        }
+        // Hosting for half as long, the budget's half as big too.
+        assert_eq!(planner.budget(1000, period * 0.5), Btc(1e-9 * 1000.0 * 0.5));
+        let too_long = Sec::from(MAX_CONTRACT_DURATION) * 2.0;
+        assert_eq!(
+            planner.plan(1000, too_long, &candidates, 0.95, 1).map(|plan| plan.cost),
+            Err(PlanError::BadPeriod),
+        );
+
+        // Paying up to 30% more buys another flaky host.
+        let config = Config {
+            upload_price_factor: Some(1.3),
+            ..config
+        };
+        let plan = unwrap!(UploadPlanner::new(&config).plan(1000, period, &candidates, 0.95, 1));
+        assert_eq!(plan.hosts.len(), 2);
+        assert!(plan.availability > 0.99);
+        assert!((plan.cost.val() - 5e-9).abs() < 1e-20);
+    }
+
+    #[test]
+    fn erasure_coding_is_chosen_when_cheaper() {
+        let planner = UploadPlanner::new(&Config::default());
+        let period = planner.default_period();
+        let candidates: Vec<HostCandidate> = (0..8).map(|i| candidate(i, 1e-12, 0.8)).collect();
+        let size = 9 * LEAF_SIZE as u64;
+
+        // Three whole copies are enough, but seven hosts with a third each cost less.
+        let plan = unwrap!(planner.plan(size, period, &candidates, 0.99, 1));
+        assert_eq!((plan.needed, plan.hosts.len()), (1, 3));
+        let plan = unwrap!(planner.plan(size, period, &candidates, 0.99, MAX_DATA_SHARDS));
+        assert_eq!((plan.needed, plan.hosts.len()), (3, 7));
+        assert_eq!(plan.shard_size, 3 * LEAF_SIZE as u64);
+        assert!(plan.availability >= 0.99);
+    }
+
+    #[test]
+    fn rebuild_from_the_shards_which_arrive() {
+        let data: Vec<u8> = (0..9 * LEAF_SIZE).map(|i| (i * 7) as u8).collect();
+        let tree = Arc::new(MerkleTree::new(Bytes::from(data)));
+        let shards = encode_shards(tree.clone(), 3, 7);
+        let manifest = ShardManifest {
+            root: tree.root(),
+            size: tree.size(),
+            needed: 3,
+            shard_count: 7,
+            shards: Vec::new(),
+        };
+
+        // Shards 0 and 2 never come, 3 stalls halfway and 4's host goes away, which leaves just
+        // enough.
+        let mut downloads = Vec::new();
+        for (index, shard) in shards.iter().enumerate() {
+            let (data_tx, download) = download_channel(0, shard.leaf_count());
+            let leaves: Vec<&[u8]> = shard.data().chunks(LEAF_SIZE).collect();
+            match index {
+                0 | 2 => {
+                    let _ = data_tx.unbounded_send(Err(DownloadError::NoResponse));
+                },
+                3 => {
+                    let _ = data_tx.unbounded_send(Ok(Bytes::from(leaves[0])));
+                    let _ = data_tx.unbounded_send(Err(DownloadError::Stalled));
+                },
+                4 => (),
+                _ => {
+                    for leaf in leaves {
+                        let _ = data_tx.unbounded_send(Ok(Bytes::from(leaf)));
+                    }
+                },
+            }
+            downloads.push((index, download));
+        }
+        let rebuilt = unwrap!(Rebuild::new(manifest.clone(), downloads).wait());
+        assert_eq!(rebuilt, *tree.data());
+
+        // With only two of them coming, there's not enough to go on.
+        let mut downloads = Vec::new();
+        for (index, shard) in shards.iter().enumerate() {
+            let (data_tx, download) = download_channel(0, shard.leaf_count());
+            if index < 2 {
+                for leaf in shard.data().chunks(LEAF_SIZE) {
+                    let _ = data_tx.unbounded_send(Ok(Bytes::from(leaf)));
+                }
+            }
+            downloads.push((index, download));
+        }
+        match Rebuild::new(manifest, downloads).wait() {
+            Err(RebuildError::NotEnoughShards) => (),
+            res => panic!("unexpected result: {:?}", res.map(|data| data.len())),
+        }
+    }
+
+    #[test]
+    fn uploads_which_fall_short_are_errors() {
+        let keypair = unwrap!(SignKeypair::new());
+        let tree = Arc::new(MerkleTree::new(Bytes::from(vec![1u8; 1000])));
+        let terms = ContractTerms {
+            contract_id: 1,
+            root: tree.root(),
+            size: tree.size(),
+            duration: 1000,
+            price: Btc(1e-9),
+            installments: 1,
+        };
+        let client = XorAddr::from_bytes([1; 32]);
+        let receipt = Receipt::new(&keypair, client, terms, 1_000_000);
+        let (result_tx, result_rx) = oneshot::channel();
+        let mut job = UploadJob {
+            root: tree.root(),
+            size: tree.size(),
+            needed: 1,
+            period: Sec(1000.0),
+            target: 0.9,
+            limit: Btc(1e-8),
+            shards: vec![tree.clone(), tree],
+            stored: Vec::new(),
+            asked: HashSet::new(),
+            result_tx,
+        };
+        job.stored.push(ShardStored { index: 1, receipt, reliability: 0.5 });
+        assert_eq!(job.missing(), vec![0]);
+        assert_eq!(job.spent(), Btc(1e-9));
+
+        // The host which refused left us short, but we still hear about the one which agreed.
+        job.finish();
+        match unwrap!(result_rx.wait()) {
+            Err(UploadError::Unavailable(uploaded)) => {
+                assert_eq!(uploaded.availability, 0.5);
+                assert_eq!(uploaded.receipts.len(), 1);
+                assert_eq!(uploaded.manifest.shards[0].index, 1);
+                let written = uploaded.manifest.to_string();
+                let read: ShardManifest = unwrap!(written.parse());
+                assert_eq!(read.to_string(), written);
+                assert_eq!(read.shards[0].host, keypair.public.to_xor_addr());
+            },
+            res => panic!("unexpected result: {:?}", res.map(|uploaded| uploaded.availability)),
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use super::*;
+
+// Any peer we know of that's closer to the key than us was probably asked too, and each one halves
+// the odds that it's our reply the requester ends up using.
+const CLOSER_PEER_ODDS: f64 = 0.5;
+
+// The share of what we expect to be paid that we offer the peer we forward a request to.
+const FORWARD_SHARE: f64 = 0.5;
+
+// How often a forwarded request comes back with something worth passing on.
+const FORWARD_SUCCESS_ODDS: f64 = 0.75;
+
+// What we hold in memory for each request we're forwarding, and how big we guess the reply to it
+// will be when we don't have one of our own to go by.
+const FORWARD_STATE_SIZE: Byte = Byte(1024.0);
+const FORWARDED_REPLY_SIZE: Byte = Byte(512.0);
+
+// The most contacts we hand out in one reply.
+const MAX_CONTACTS: usize = 8;
+
+// Wrapping a reply in a `Msg::Response`: tag, request id, delay and payload length.
+const RESPONSE_OVERHEAD: usize = 2 + 8 + 8 + 2;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    // Reply with what we've got.
+    Answer,
+    // Ask a closer peer, offering them `offer`, and pass on what they say.
+    Forward {
+        peer: XorAddr,
+        offer: Offer,
+    },
+    // Reply with peers closer to the key than us.
+    Contacts(Vec<XorAddr>),
+    Ignore,
+}
+
+// What we know about a request and what we could do about it.
+pub struct RequestContext<'a> {
+    pub offer: &'a Offer,
+    pub our_addr: XorAddr,
+    // What the request's about, if it's about a key. We can only answer requests that aren't.
+    pub key: Option<XorAddr>,
+    // What we could reply with from what we've got, and what that costs us beyond uploading it.
+    pub answer: Option<(&'a Msg, Btc)>,
+    // The request to send on if it can be forwarded.
+    pub forward: Option<&'a Msg>,
+    // The peers we know of other than the requester, and how long they take to reply.
+    pub peers: Vec<(XorAddr, Sec)>,
+    // What our own resources cost.
+    pub costs: Costs,
+}
+
+pub struct Decision {
+    pub action: Action,
+    pub expected_profit: Btc,
+    // Why we chose what we did, for the logs.
+    pub reason: String,
+}
+
+// Works out what each thing we could do with a request is expected to make us, and picks the best.
+pub fn decide(ctx: &RequestContext) -> Decision {
+    let closer: Vec<(XorAddr, Sec)> = match ctx.key {
+        Some(key) => {
+            let mut closer: Vec<(XorAddr, Sec)> = {
+                ctx.peers
+                .iter()
+                .filter(|(peer, _)| (*peer ^ key) < (ctx.our_addr ^ key))
+                .cloned()
+                .collect()
+            };
+            closer.sort_by_key(|(peer, _)| *peer ^ key);
+            closer
+        },
+        None => Vec::new(),
+    };
+    let first_odds = CLOSER_PEER_ODDS.powi(closer.len() as i32);
+
+    let mut candidates = vec![(Action::Ignore, Btc(0.0))];
+
+    let mut reply_size = FORWARDED_REPLY_SIZE;
+    if let Some((reply, cost)) = ctx.answer {
+        let payout = ctx.offer.payout(&ReplyTraits::of(reply, Sec(0.0)));
+        let size = Byte::from(reply.to_bytes().len() + RESPONSE_OVERHEAD);
+        let profit = payout * first_odds - cost - ctx.costs.upload * size;
+        candidates.push((Action::Answer, profit));
+        reply_size = size;
+    }
+
+    if let (Some(request), Some((peer, rtt))) = (ctx.forward, closer.first()) {
+        // They'll only take so long if they answer straight away, so this is the best case.
+        let traits = ReplyTraits {
+            delay: *rtt,
+            has_data: true,
+            closeness: 0,
+            versions_behind: 0,
+        };
+        let payout = ctx.offer.payout(&traits) * first_odds;
+        let passed_on = payout * FORWARD_SHARE;
+        let traffic = Byte::from(request.to_bytes().len()) + reply_size;
+        let costs = {
+            ctx.costs.upload * traffic
+            + ctx.costs.download * reply_size
+            + ctx.costs.memory * (FORWARD_STATE_SIZE * *rtt)
+        };
+        let profit = (payout - passed_on) * FORWARD_SUCCESS_ODDS - costs;
+        let offer = Offer::new(Reward::IfData {
+            then: Box::new(Reward::Fixed(passed_on)),
+            otherwise: Box::new(Reward::Fixed(Btc(0.0))),
+        });
+        if let Ok(offer) = offer {
+            candidates.push((Action::Forward { peer: *peer, offer }, profit));
+        }
+    }
+
+    if let (Some(key), false) = (ctx.key, closer.is_empty()) {
+        let peers: Vec<XorAddr> = {
+            closer
+            .iter()
+            .take(MAX_CONTACTS)
+            .map(|(peer, _)| *peer)
+            .collect()
+        };
+        let reply = Msg::Contacts { key, peers: peers.clone() };
+        // Contacts are a step towards the answer whoever else the requester asked, so they're not
+        // discounted for the competition.
+        let payout = ctx.offer.payout(&ReplyTraits::of(&reply, Sec(0.0)));
+        let size = Byte::from(reply.to_bytes().len() + RESPONSE_OVERHEAD);
+        let profit = payout - ctx.costs.upload * size;
+        candidates.push((Action::Contacts(peers), profit));
+    }
+
+    let reason = {
+        candidates
+        .iter()
+        .map(|(action, profit)| format!("{} {:e}", action.name(), profit.val()))
+        .collect::<Vec<_>>()
+        .join(", ")
+    };
+    let reason = format!("expected profits: {} with {} closer peers", reason, closer.len());
+    let (action, expected_profit) = unwrap!({
+        candidates
+        .into_iter()
+        .fold(None, |best: Option<(Action, Btc)>, (action, profit)| match best {
+            Some(best) if best.1 >= profit => Some(best),
+            _ => Some((action, profit)),
+        })
+    });
+    Decision { action, expected_profit, reason }
+}
+
+impl Action {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Answer => "answer",
+            Action::Forward { .. } => "forward",
+            Action::Contacts(..) => "contacts",
+            Action::Ignore => "ignore",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(byte: u8) -> XorAddr {
+        XorAddr::from_bytes([byte; 32])
+    }
+
+    #[test]
+    fn answer_when_it_pays() {
+        let offer = Offer::fixed(Btc(1e-9));
+        let reply = Msg::SenderAddress { addr: addr!("1.2.3.4:5") };
+        let mut ctx = RequestContext {
+            offer: &offer,
+            our_addr: addr(1),
+            key: None,
+            answer: Some((&reply, Btc(0.0))),
+            forward: None,
+            peers: Vec::new(),
+            costs: Costs::base(),
+        };
+        assert_eq!(decide(&ctx).action, Action::Answer);
+
+        // Not if it costs more than we're offered.
+        ctx.answer = Some((&reply, Btc(2e-9)));
+        assert_eq!(decide(&ctx).action, Action::Ignore);
+    }
+
+    #[test]
+    fn pass_on_requests_we_cant_answer() {
+        let key = addr(0);
+        let reward = Reward::IfData {
+            then: Box::new(Reward::Fixed(Btc(1e-8))),
+            otherwise: Box::new(Reward::PerCloseBit(Btc(1e-11))),
+        };
+        let offer = unwrap!(Offer::new(reward));
+        let empty = Msg::Providers { root: MerkleHash::from_bytes([0; 32]), records: Vec::new() };
+        let forward = Msg::ProviderQuery { root: MerkleHash::from_bytes([0; 32]) };
+        let mut ctx = RequestContext {
+            offer: &offer,
+            our_addr: addr(0xff),
+            key: Some(key),
+            answer: Some((&empty, Btc(0.0))),
+            forward: Some(&forward),
+            peers: vec![(addr(0x0f), Sec(0.1)), (addr(0xf0), Sec(0.1)), (addr(0xfe), Sec(0.1))],
+            costs: Costs::base(),
+        };
+        match decide(&ctx).action {
+            Action::Forward { peer, .. } => assert!(peer == addr(0x0f)),
+            action => panic!("unexpected action: {:?}", action),
+        }
+
+        // Without forwarding, the closer peers are worth more than an empty answer.
+        ctx.forward = None;
+        match decide(&ctx).action {
+            Action::Contacts(peers) => {
+                assert!(peers == vec![addr(0x0f), addr(0xf0), addr(0xfe)]);
+            },
+            action => panic!("unexpected action: {:?}", action),
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use super::*;
+use std::io;
+use std::net::IpAddr;
+use lightstore_shared_udp_socket::{SendDgram, SendOutcome, SocketConfig, UploadBudget};
+
+// One socket per address family. We bind the two separately rather than relying on a dual-stack
+// socket since whether `[::]` accepts IPv4 traffic depends on how the system is configured.
+#[derive(Clone)]
+pub struct Sockets {
+    v4: Option<SharedUdpSocket>,
+    v6: Option<SharedUdpSocket>,
+}
+
+impl Sockets {
+    // Binds an IPv4 and an IPv6 socket, returning the addresses they're bound to. Plenty of
+    // machines don't have IPv6 so we carry on without it, but we always want IPv4. The two
+    // sockets share the upload budget, and count what they send towards `costs`.
+    pub fn bind(config: &Config, costs: ResourceCosts) -> io::Result<(Sockets, Vec<SocketAddr>)> {
+        let mut local_addrs = Vec::new();
+        let upload_cost = {
+            let costs = costs.clone();
+            Arc::new(move || costs.upload())
+        };
+        let on_sent = Arc::new(move |size| costs.record_upload(size));
+        let socket_config = SocketConfig {
+            upload_cost,
+            upload_budget: config.upload_budget.map(UploadBudget::new),
+            on_sent,
+        };
+
+        let v4 = UdpSocket::bind(&addr!("0.0.0.0:0"))?;
+        local_addrs.push(v4.local_addr()?);
+        let v4 = Some(SharedUdpSocket::share_with_config(v4, socket_config.clone()));
+
+        let v6 = match UdpSocket::bind(&addr!("[::]:0")) {
+            Ok(v6) => {
+                local_addrs.push(v6.local_addr()?);
+                Some(SharedUdpSocket::share_with_config(v6, socket_config))
+            },
+            Err(..) => None,
+        };
+
+        Ok((Sockets { v4, v6 }, local_addrs))
+    }
+
+    pub fn can_send_to(&self, dest: &SocketAddr) -> bool {
+        match dest {
+            SocketAddr::V4(..) => self.v4.is_some(),
+            SocketAddr::V6(..) => self.v6.is_some(),
+        }
+    }
+
+    pub fn for_dest(&mut self, dest: &SocketAddr) -> Option<&mut SharedUdpSocket> {
+        match dest {
+            SocketAddr::V4(..) => self.v4.as_mut(),
+            SocketAddr::V6(..) => self.v6.as_mut(),
+        }
+    }
+
+    // Sends a packet over the socket for the destination's address family. Returns `None` if we
+    // don't have one.
+    pub fn send_dgram(&mut self, packet: OutgoingPacket) -> Option<SendDgram> {
+        let socket = self.for_dest(&packet.dest)?;
+        Some(socket.send_dgram(packet))
+    }
+
+    // Feeds the congestion control for `dest`. See `SharedUdpSocket::report_ack`.
+    pub fn report_ack(&mut self, dest: &SocketAddr, acked: Byte) {
+        if let Some(socket) = self.for_dest(dest) {
+            socket.report_ack(*dest, acked);
+        }
+    }
+
+    pub fn report_loss(&mut self, dest: &SocketAddr) {
+        if let Some(socket) = self.for_dest(dest) {
+            socket.report_loss(*dest);
+        }
+    }
+
+    pub fn all(&self) -> Vec<SharedUdpSocket> {
+        self.v4.iter().chain(self.v6.iter()).cloned().collect()
+    }
+}
+
+// Turns an IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) back into a plain IPv4 address, so that a
+// peer looks the same whichever socket we hear from them on.
+pub fn normalize_addr(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(addr_v6) = addr {
+        let segments = addr_v6.ip().segments();
+        if segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff {
+            let octets = addr_v6.ip().octets();
+            let ipv4 = [octets[12], octets[13], octets[14], octets[15]];
+            return SocketAddr::from((ipv4, addr_v6.port()));
+        }
+    }
+    addr
+}
+
+// The address of the interface the OS would send from to reach `dest`. Connecting a UDP socket
+// doesn't send anything, it just picks a route.
+pub fn local_ip_for(dest: &SocketAddr) -> Option<IpAddr> {
+    let unspecified: SocketAddr = match dest {
+        SocketAddr::V4(..) => addr!("0.0.0.0:0"),
+        SocketAddr::V6(..) => addr!("[::]:0"),
+    };
+    let socket = std::net::UdpSocket::bind(unspecified).ok()?;
+    socket.connect(dest).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use canndrews_misc_ext_traits::ResultNeverErrExt;
+    use tokio::runtime::Runtime;
+
+    fn loopback(addr: &SocketAddr) -> SocketAddr {
+        match addr {
+            SocketAddr::V4(..) => SocketAddr::new(ip!("127.0.0.1"), addr.port()),
+            SocketAddr::V6(..) => SocketAddr::new(ip!("::1"), addr.port()),
+        }
+    }
+
+    // Sends a packet between two daemons' sockets over the loopback address of the given family.
+    fn send_and_recv(ipv6: bool) -> impl Future<Item = (), Error = !> {
+        future::lazy(move || {
+            let config = Config::default();
+            let costs = ResourceCosts::new();
+            let (mut sender, _) = unwrap!(Sockets::bind(&config, costs.clone()));
+            let (mut receiver, local_addrs) = unwrap!(Sockets::bind(&config, costs));
+            let local_addr = unwrap!(
+                local_addrs.iter().find(|addr| addr.is_ipv6() == ipv6),
+                "no {} socket bound",
+                if ipv6 { "IPv6" } else { "IPv4" },
+            );
+            let dest = loopback(local_addr);
+            assert!(sender.can_send_to(&dest));
+
+            let recv = unwrap!(receiver.for_dest(&dest)).recv_dgram();
+            let packet = OutgoingPacket::new(Bytes::from(&b"hello"[..]), dest, Btc(1.0), Sec(1.0));
+            unwrap!(sender.send_dgram(packet))
+            .map_err(|e| panic!("error sending: {}", e))
+            .and_then(move |outcome| {
+                assert_eq!(outcome, SendOutcome::Sent);
+                recv
+                .map_err(|e| panic!("error receiving: {:?}", e))
+                .map(move |(data, from)| {
+                    assert_eq!(&data[..], b"hello");
+                    assert_eq!(from.is_ipv6(), ipv6);
+                })
+            })
+        })
+    }
+
+    #[test]
+    fn send_over_ipv4_loopback() {
+        let mut runtime = unwrap!(Runtime::new());
+        runtime.block_on(send_and_recv(false)).never_err()
+    }
+
+    #[test]
+    fn send_over_ipv6_loopback() {
+        // Not every machine we're tested on has IPv6.
+        if std::net::UdpSocket::bind(addr!("[::1]:0")).is_err() {
+            return;
+        }
+        let mut runtime = unwrap!(Runtime::new());
+        runtime.block_on(send_and_recv(true)).never_err()
+    }
+
+    #[test]
+    fn normalize_mapped_addrs() {
+        assert_eq!(normalize_addr(addr!("[::ffff:127.0.0.1]:1234")), addr!("127.0.0.1:1234"));
+        assert_eq!(normalize_addr(addr!("[::1]:1234")), addr!("[::1]:1234"));
+        assert_eq!(normalize_addr(addr!("10.0.0.1:1234")), addr!("10.0.0.1:1234"));
+    }
+}
@@ -0,0 +1,700 @@
+use super::*;
+
+// How many leaves a sender can have unacknowledged at once. Pacing within the window is left to
+// the socket's congestion control. The window also shrinks to whatever the receiver's credit
+// with us covers, since they only pay for leaves once they've got them.
+const WINDOW_LEAVES: u64 = 32;
+
+// How many streams a peer can have open from us at once.
+const MAX_PEER_STREAMS: usize = 8;
+
+// A leaf's resend timer doubles each time it runs out, up to this many times.
+const MAX_RESEND_BACKOFF: u32 = 6;
+
+// How far past the leaf it's waiting on a receiver will hold onto leaves which arrive early.
+const REORDER_LEAVES: u64 = 2 * WINDOW_LEAVES;
+
+// The receiver acknowledges every this many leaves, and straight away when it sees a gap.
+const ACK_EVERY: u64 = 4;
+
+// Acks which don't move the stream on mean a leaf went missing. After this many we resend it
+// rather than waiting for its timer to run out.
+const DUP_ACK_THRESHOLD: u32 = 3;
+
+// A sender gives up on a stream that hasn't been acknowledged in this long.
+const SENDER_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// A receiver which hasn't verified a leaf in this long asks for the stream again from the leaf
+// it's waiting on. This is how we pick up after the sender loses track of us.
+const STALL_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REOPENS: u32 = 5;
+
+// Leaves are only worth sending while they can still be acknowledged before the sender's timer
+// runs out.
+const LEAF_UTILITY_DECAY: Sec = Sec(2.0);
+
+#[derive(Debug, Fail)]
+pub enum DownloadError {
+    #[fail(display = "the peer didn't respond")]
+    NoResponse,
+    #[fail(display = "the peer stopped sending")]
+    Stalled,
+    #[fail(display = "no peers could provide the content")]
+    NoSources,
+    #[fail(display = "the daemon shut down")]
+    Shutdown,
+}
+
+// The verified leaves of some content, in order.
+pub struct Download {
+    data_rx: UnboundedReceiver<Result<Bytes, DownloadError>>,
+    remaining: u64,
+}
+
+// Creates a download of leaves `start..end`. The driver sends it leaves through the returned
+// sender.
+pub fn download_channel(
+    start: u64,
+    end: u64,
+) -> (UnboundedSender<Result<Bytes, DownloadError>>, Download) {
+    let (data_tx, data_rx) = mpsc::unbounded();
+    let remaining = end.saturating_sub(start);
+    (data_tx, Download { data_rx, remaining })
+}
+
+impl Stream for Download {
+    type Item = Bytes;
+    type Error = DownloadError;
+
+    fn poll(&mut self) -> Result<Async<Option<Bytes>>, DownloadError> {
+        if self.remaining == 0 {
+            return Ok(Async::Ready(None));
+        }
+        match self.data_rx.poll().void_unwrap() {
+            Async::Ready(Some(Ok(data))) => {
+                self.remaining -= 1;
+                Ok(Async::Ready(Some(data)))
+            },
+            Async::Ready(Some(Err(e))) => Err(e),
+            Async::Ready(None) => Err(DownloadError::Shutdown),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+// A request to (re)open a stream, for the driver to send.
+pub struct StreamRequest {
+    pub peer: XorAddr,
+    pub stream_id: u64,
+    pub msg: Msg,
+}
+
+struct OutgoingStream {
+    tree: Arc<MerkleTree>,
+    start: u64,
+    end: u64,
+    acked: u64,
+    next: u64,
+    price: BtcPerByte,
+    // When each unacknowledged leaf should be resent, and how many times it's been sent.
+    in_flight: BTreeMap<u64, (Instant, u32)>,
+    dup_acks: u32,
+    last_heard: Instant,
+}
+
+impl OutgoingStream {
+    // What the receiver will owe us once it's got the leaves we've sent so far.
+    fn unpaid(&self) -> Btc {
+        let size = self.tree.size();
+        let unacked: usize = (self.acked..self.next).map(|index| leaf_len(size, index)).sum();
+        self.price * Byte::from(unacked)
+    }
+
+    fn leaf_msg(&self, stream_id: u64, index: u64, now: Instant) -> OutgoingMsg {
+        let data = self.tree.leaf(index);
+        let utility = self.price * Byte::from(data.len());
+        OutgoingMsg {
+            msg: Msg::StreamLeaf {
+                stream_id,
+                index,
+                proof: self.tree.proof(index, self.start),
+                data,
+            },
+            utility,
+            utility_time: now,
+            utility_decay: LEAF_UTILITY_DECAY,
+        }
+    }
+}
+
+// What came of the receiver acknowledging some leaves.
+pub struct StreamAcked {
+    // What the receiver owes us for them, and how many bytes they were.
+    pub payment: Btc,
+    pub acked: Byte,
+    // The root of the content, if they've now got to the end of it.
+    pub finished: Option<MerkleHash>,
+}
+
+pub struct StreamSends {
+    pub send: Vec<(XorAddr, OutgoingMsg)>,
+    // Peers we had to resend leaves to.
+    pub lost: Vec<XorAddr>,
+}
+
+// Streams the content we host to the peers who've asked for it.
+pub struct StreamSender {
+    streams: HashMap<(XorAddr, u64), OutgoingStream>,
+}
+
+impl StreamSender {
+    pub fn new() -> StreamSender {
+        StreamSender {
+            streams: HashMap::new(),
+        }
+    }
+
+    // Whether `peer` can open another stream. Reopening one they've already got is fine.
+    pub fn has_room_for(&self, peer: &XorAddr, stream_id: u64) -> bool {
+        if self.streams.contains_key(&(*peer, stream_id)) {
+            return true;
+        }
+        self.streams.keys().filter(|(other, _)| other == peer).count() < MAX_PEER_STREAMS
+    }
+
+    // Starts streaming leaves `start..end` of `tree` to `peer`. If we're already streaming to
+    // them under this id then they've lost track of things and we start again from `start`.
+    pub fn open(
+        &mut self,
+        peer: XorAddr,
+        stream_id: u64,
+        tree: Arc<MerkleTree>,
+        start: u64,
+        end: u64,
+        price: BtcPerByte,
+        now: Instant,
+    ) {
+        let stream = OutgoingStream {
+            tree,
+            start,
+            end,
+            acked: start,
+            next: start,
+            price,
+            in_flight: BTreeMap::new(),
+            dup_acks: 0,
+            last_heard: now,
+        };
+        let _ = self.streams.insert((peer, stream_id), stream);
+    }
+
+    pub fn on_ack(
+        &mut self,
+        peer: XorAddr,
+        stream_id: u64,
+        next: u64,
+        now: Instant,
+    ) -> Option<StreamAcked> {
+        let (payment, acked, done, finished) = {
+            let stream = self.streams.get_mut(&(peer, stream_id))?;
+            stream.last_heard = now;
+            // They can't have got leaves we haven't sent.
+            let next = cmp::min(next, stream.next);
+            if next <= stream.acked {
+                stream.dup_acks += 1;
+                if stream.dup_acks == DUP_ACK_THRESHOLD {
+                    if let Some((resend_at, _)) = stream.in_flight.get_mut(&stream.acked) {
+                        *resend_at = now;
+                    }
+                }
+                return None;
+            }
+
+            let size = stream.tree.size();
+            let acked: usize = (stream.acked..next).map(|index| leaf_len(size, index)).sum();
+            stream.in_flight = stream.in_flight.split_off(&next);
+            stream.acked = next;
+            stream.dup_acks = 0;
+            let acked = Byte::from(acked);
+            let done = next >= stream.end;
+            let finished = {
+                if done && stream.end == stream.tree.leaf_count() {
+                    Some(stream.tree.root())
+                } else {
+                    None
+                }
+            };
+            (stream.price * acked, acked, done, finished)
+        };
+        if done {
+            let _ = self.streams.remove(&(peer, stream_id));
+        }
+        Some(StreamAcked { payment, acked, finished })
+    }
+
+    // Resends the leaves whose timers have run out and tops up each stream's window. `rto` gives
+    // the retransmission timeout for a peer, and `credit` how much more we'll let them owe us.
+    pub fn poll_sends<F, C>(&mut self, now: Instant, rto: F, credit: C) -> StreamSends
+    where
+        F: Fn(&XorAddr) -> Duration,
+        C: Fn(&XorAddr) -> Btc,
+    {
+        self.streams.retain(|_, stream| now < stream.last_heard + SENDER_IDLE_TIMEOUT);
+
+        // What each peer has left to spend on top of the leaves they've yet to pay for.
+        let mut credits: HashMap<XorAddr, Btc> = HashMap::new();
+        for (&(peer, _), stream) in self.streams.iter() {
+            let remaining = credits.entry(peer).or_insert_with(|| credit(&peer));
+            *remaining = *remaining - stream.unpaid();
+        }
+
+        let mut sends = StreamSends {
+            send: Vec::new(),
+            lost: Vec::new(),
+        };
+        for (&(peer, stream_id), stream) in self.streams.iter_mut() {
+            let rto = rto(&peer);
+            let expired: Vec<(u64, u32)> = {
+                stream.in_flight
+                .iter()
+                .filter(|(_, (resend_at, _))| *resend_at <= now)
+                .map(|(index, (_, sends))| (*index, *sends))
+                .collect()
+            };
+            if !expired.is_empty() {
+                sends.lost.push(peer);
+            }
+            for (index, sent) in expired {
+                let backoff = rto * (1 << cmp::min(sent, MAX_RESEND_BACKOFF));
+                let _ = stream.in_flight.insert(index, (now + backoff, sent + 1));
+                sends.send.push((peer, stream.leaf_msg(stream_id, index, now)));
+            }
+
+            let remaining = unwrap!(credits.get_mut(&peer));
+            let end = cmp::min(stream.acked + WINDOW_LEAVES, stream.end);
+            while stream.next < end {
+                let index = stream.next;
+                let cost = stream.price * Byte::from(leaf_len(stream.tree.size(), index));
+                if cost > Btc(0.0) && cost > *remaining {
+                    break;
+                }
+                *remaining = *remaining - cost;
+                stream.next += 1;
+                let _ = stream.in_flight.insert(index, (now + rto, 1));
+                sends.send.push((peer, stream.leaf_msg(stream_id, index, now)));
+            }
+        }
+        sends
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.streams
+        .values()
+        .flat_map(|stream| {
+            let idle = stream.last_heard + SENDER_IDLE_TIMEOUT;
+            stream.in_flight.values().map(|(resend_at, _)| *resend_at).chain(Some(idle))
+        })
+        .min()
+    }
+}
+
+struct IncomingStream {
+    root: MerkleHash,
+    size: u64,
+    end: u64,
+    price: BtcPerByte,
+    verifier: LeafVerifier,
+    // Leaves which arrived before the one we're waiting on.
+    buffer: BTreeMap<u64, (MerkleProof, Bytes)>,
+    data_tx: UnboundedSender<Result<Bytes, DownloadError>>,
+    unacked: u64,
+    last_progress: Instant,
+    // Where we (re)opened the stream from.
+    opened_at: u64,
+    reopens: u32,
+}
+
+impl IncomingStream {
+    fn ack(&mut self, stream_id: u64) -> Msg {
+        self.unacked = 0;
+        Msg::StreamAck { stream_id, next: self.verifier.next() }
+    }
+
+    fn request(&self, peer: XorAddr, stream_id: u64) -> StreamRequest {
+        let start = self.verifier.next();
+        StreamRequest {
+            peer,
+            stream_id,
+            msg: Msg::StreamOpen {
+                stream_id,
+                root: self.root,
+                size: self.size,
+                start,
+                end: self.end,
+                price: self.price,
+            },
+        }
+    }
+}
+
+// What came of receiving a leaf.
+pub struct LeafReceived {
+    pub ack: Option<Msg>,
+    // What we owe the sender for the leaves this let us verify.
+    pub payment: Btc,
+    // Whether they sent us a leaf which didn't check out.
+    pub bad_leaf: bool,
+}
+
+// Receives the streams we've asked for, checking each leaf against the content's root before
+// handing it on and paying for it.
+pub struct StreamReceiver {
+    streams: HashMap<(XorAddr, u64), IncomingStream>,
+}
+
+impl StreamReceiver {
+    pub fn new() -> StreamReceiver {
+        StreamReceiver {
+            streams: HashMap::new(),
+        }
+    }
+
+    // Starts downloading leaves `start..end` of the content with root `root` from `peer`.
+    // Returns the request to send them.
+    pub fn open(
+        &mut self,
+        peer: XorAddr,
+        root: MerkleHash,
+        size: u64,
+        start: u64,
+        end: u64,
+        price: BtcPerByte,
+        data_tx: UnboundedSender<Result<Bytes, DownloadError>>,
+        now: Instant,
+    ) -> StreamRequest {
+        let stream_id = rand::random();
+        let stream = IncomingStream {
+            root,
+            size,
+            end,
+            price,
+            verifier: LeafVerifier::new(root, size, start),
+            buffer: BTreeMap::new(),
+            data_tx,
+            unacked: 0,
+            last_progress: now,
+            opened_at: start,
+            reopens: 0,
+        };
+        let request = stream.request(peer, stream_id);
+        let _ = self.streams.insert((peer, stream_id), stream);
+        request
+    }
+
+    pub fn on_leaf(
+        &mut self,
+        peer: XorAddr,
+        stream_id: u64,
+        index: u64,
+        proof: MerkleProof,
+        data: Bytes,
+        now: Instant,
+    ) -> LeafReceived {
+        let mut received = LeafReceived {
+            ack: None,
+            payment: Btc(0.0),
+            bad_leaf: false,
+        };
+        let finished = {
+            let stream = match self.streams.get_mut(&(peer, stream_id)) {
+                Some(stream) => stream,
+                None => return received,
+            };
+            let next = stream.verifier.next();
+            if index < next {
+                // They must have missed our ack.
+                received.ack = Some(stream.ack(stream_id));
+                return received;
+            }
+            if index >= cmp::min(next + REORDER_LEAVES, stream.end) {
+                return received;
+            }
+            // Copied so that leaves we hang onto don't pin the socket's receive buffers.
+            let _ = stream.buffer.insert(index, (proof, Bytes::from(&data[..])));
+            if index > next {
+                // Let them know there's a gap.
+                received.ack = Some(stream.ack(stream_id));
+                return received;
+            }
+
+            let mut cancelled = false;
+            while let Some((proof, data)) = stream.buffer.remove(&stream.verifier.next()) {
+                // A leaf that doesn't check out gets dropped, we'll wait for them to resend it.
+                if stream.verifier.verify(stream.verifier.next(), &data, &proof).is_err() {
+                    received.bad_leaf = true;
+                    break;
+                }
+                received.payment += stream.price * Byte::from(data.len());
+                stream.unacked += 1;
+                stream.last_progress = now;
+                stream.reopens = 0;
+                cancelled |= stream.data_tx.unbounded_send(Ok(data)).is_err();
+            }
+
+            let done = stream.verifier.next() >= stream.end;
+            if done || stream.unacked >= ACK_EVERY {
+                received.ack = Some(stream.ack(stream_id));
+            }
+            done || cancelled
+        };
+        if finished {
+            let _ = self.streams.remove(&(peer, stream_id));
+        }
+        received
+    }
+
+    // Asks again for the streams which have stopped making progress, and gives up on the ones
+    // we've asked for too many times.
+    pub fn poll_stalls(&mut self, now: Instant) -> Vec<StreamRequest> {
+        let mut requests = Vec::new();
+        let mut failed = Vec::new();
+        for (&(peer, stream_id), stream) in self.streams.iter_mut() {
+            if now < stream.last_progress + STALL_TIMEOUT {
+                continue;
+            }
+            if stream.reopens >= MAX_REOPENS {
+                failed.push((peer, stream_id));
+                continue;
+            }
+
+            let next = stream.verifier.next();
+            stream.reopens += 1;
+            stream.last_progress = now;
+            stream.verifier = LeafVerifier::new(stream.root, stream.size, next);
+            stream.buffer.clear();
+            stream.opened_at = next;
+            requests.push(stream.request(peer, stream_id));
+        }
+        for key in failed {
+            let stream = unwrap!(self.streams.remove(&key));
+            let _ = stream.data_tx.unbounded_send(Err(DownloadError::Stalled));
+        }
+        requests
+    }
+
+    // Our request to open a stream went unanswered. If nothing's arrived since then we give up.
+    pub fn open_failed(&mut self, peer: XorAddr, stream_id: u64) {
+        let no_progress = match self.streams.get(&(peer, stream_id)) {
+            Some(stream) => stream.verifier.next() == stream.opened_at,
+            None => false,
+        };
+        if no_progress {
+            let stream = unwrap!(self.streams.remove(&(peer, stream_id)));
+            let _ = stream.data_tx.unbounded_send(Err(DownloadError::NoResponse));
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.streams.values().map(|stream| stream.last_progress + STALL_TIMEOUT).min()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Passes messages between a sender and a receiver, dropping every `drop_every`th leaf the
+    // first time it's sent. Returns the data that came out of the download.
+    fn transfer(tree: Arc<MerkleTree>, start: u64, drop_every: usize) -> (Vec<u8>, Btc, Btc) {
+        let sender_key = XorAddr::from_bytes([1u8; 32]);
+        let receiver_key = XorAddr::from_bytes([2u8; 32]);
+        let rto = Duration::from_millis(100);
+        let price = BtcPerByte(1e-9);
+        let mut now = Instant::now();
+
+        let mut sender = StreamSender::new();
+        let mut receiver = StreamReceiver::new();
+        let end = tree.leaf_count();
+        let (data_tx, download) = download_channel(start, end);
+        let request = {
+            receiver.open(sender_key, tree.root(), tree.size(), start, end, price, data_tx, now)
+        };
+        match request.msg {
+            Msg::StreamOpen { stream_id, start, end, price, .. } => {
+                sender.open(receiver_key, stream_id, tree.clone(), start, end, price, now);
+            },
+            _ => panic!("expected a StreamOpen"),
+        }
+
+        let mut paid = Btc(0.0);
+        let mut earned = Btc(0.0);
+        let mut sent = 0;
+        let mut dropped = HashSet::new();
+        for _ in 0..1000 {
+            let sends = sender.poll_sends(now, |_| rto, |_| Btc(1.0));
+            for (_, outgoing_msg) in sends.send {
+                let (stream_id, index, proof, data) = match outgoing_msg.msg {
+                    Msg::StreamLeaf { stream_id, index, proof, data } => {
+                        (stream_id, index, proof, data)
+                    },
+                    _ => panic!("expected a StreamLeaf"),
+                };
+                sent += 1;
+                if sent % drop_every == 0 && dropped.insert(index) {
+                    continue;
+                }
+                let received = receiver.on_leaf(sender_key, stream_id, index, proof, data, now);
+                paid += received.payment;
+                if let Some(Msg::StreamAck { stream_id, next }) = received.ack {
+                    if let Some(acked) = sender.on_ack(receiver_key, stream_id, next, now) {
+                        earned += acked.payment;
+                    }
+                }
+            }
+            now += rto / 4;
+        }
+
+        let data = unwrap!(download.collect().wait());
+        (data.concat(), paid, earned)
+    }
+
+    fn content(size: usize) -> Bytes {
+        Bytes::from((0..size).map(|i| (i * 13) as u8).collect::<Vec<u8>>())
+    }
+
+    #[test]
+    fn stream_with_losses() {
+        let data = content(200 * LEAF_SIZE + 300);
+        let tree = Arc::new(MerkleTree::new(data.clone()));
+        let (received, paid, earned) = transfer(tree, 0, 7);
+        assert_eq!(&received[..], &data[..]);
+
+        // Both sides agree on what was paid, to within rounding.
+        let expected = BtcPerByte(1e-9) * Byte::from(data.len());
+        assert!((paid - expected).val().abs() < 1e-12);
+        assert!((earned - expected).val().abs() < 1e-12);
+    }
+
+    #[test]
+    fn resume_from_a_leaf() {
+        let data = content(100 * LEAF_SIZE);
+        let tree = Arc::new(MerkleTree::new(data.clone()));
+        let (received, _, _) = transfer(tree, 37, 5);
+        assert_eq!(&received[..], &data[37 * LEAF_SIZE..]);
+    }
+
+    #[test]
+    fn stalled_streams_are_reopened_where_they_left_off() {
+        let tree = Arc::new(MerkleTree::new(content(50 * LEAF_SIZE)));
+        let sender_key = XorAddr::from_bytes([1u8; 32]);
+        let now = Instant::now();
+        let mut receiver = StreamReceiver::new();
+        let (data_tx, _download) = download_channel(0, 50);
+        let price = BtcPerByte(1e-9);
+        let request = {
+            receiver.open(sender_key, tree.root(), tree.size(), 0, 50, price, data_tx, now)
+        };
+        let stream_id = request.stream_id;
+        let mut paid = Btc(0.0);
+        for index in 0..10 {
+            let proof = tree.proof(index, 0);
+            let leaf = tree.leaf(index);
+            paid += receiver.on_leaf(sender_key, stream_id, index, proof, leaf, now).payment;
+        }
+        assert!((paid - price * Byte::from(10 * LEAF_SIZE)).val().abs() < 1e-15);
+
+        assert!(receiver.poll_stalls(now).is_empty());
+        let requests = receiver.poll_stalls(now + STALL_TIMEOUT);
+        assert_eq!(requests.len(), 1);
+        match requests[0].msg {
+            Msg::StreamOpen { stream_id: id, start, .. } => {
+                assert_eq!(id, stream_id);
+                assert_eq!(start, 10);
+            },
+            _ => panic!("expected a StreamOpen"),
+        }
+
+        // The resent leaf carries the full proof for the new starting point.
+        let proof = tree.proof(10, 10);
+        let later = now + STALL_TIMEOUT;
+        let received = receiver.on_leaf(sender_key, stream_id, 10, proof, tree.leaf(10), later);
+        assert!((received.payment - price * Byte::from(LEAF_SIZE)).val().abs() < 1e-15);
+        assert!(receiver.poll_stalls(later).is_empty());
+    }
+
+    #[test]
+    fn most_leaves_fit_in_a_datagram() {
+        let tree = MerkleTree::new(content(1000 * LEAF_SIZE));
+        for index in (1..tree.leaf_count()).filter(|index| index % 16 != 0) {
+            let msg = Msg::StreamLeaf {
+                stream_id: 0,
+                index,
+                proof: tree.proof(index, 0),
+                data: tree.leaf(index),
+            };
+            assert!(msg.to_bytes().len() <= MAX_MSG_LEN);
+        }
+    }
+
+    fn sent_leaves(sends: &StreamSends) -> Vec<u64> {
+        sends.send.iter().map(|(_, outgoing_msg)| match outgoing_msg.msg {
+            Msg::StreamLeaf { index, .. } => index,
+            _ => panic!("expected a StreamLeaf"),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn senders_only_trust_receivers_with_what_they_have_credit_for() {
+        let tree = Arc::new(MerkleTree::new(content(50 * LEAF_SIZE)));
+        let receiver_key = XorAddr::from_bytes([2u8; 32]);
+        let rto = Duration::from_secs(1);
+        let price = BtcPerByte(1e-9);
+        let leaf_price = price * Byte::from(LEAF_SIZE);
+        let now = Instant::now();
+        let mut sender = StreamSender::new();
+        sender.open(receiver_key, 1, tree.clone(), 0, 50, price, now);
+
+        // Nothing goes out until they've got credit with us, and then only what it covers.
+        assert!(sender.poll_sends(now, |_| rto, |_| Btc(0.0)).send.is_empty());
+        let sends = sender.poll_sends(now, |_| rto, |_| leaf_price * 3.5);
+        assert_eq!(sent_leaves(&sends), vec![0, 1, 2]);
+        let _ = sender.on_ack(receiver_key, 1, 2, now);
+        let sends = sender.poll_sends(now, |_| rto, |_| leaf_price * 3.5);
+        assert_eq!(sent_leaves(&sends), vec![3, 4]);
+
+        // Content we're giving away goes out a window at a time regardless.
+        sender.open(receiver_key, 2, tree, 0, 50, BtcPerByte(0.0), now);
+        let sends = sender.poll_sends(now, |_| rto, |_| Btc(0.0));
+        assert_eq!(sent_leaves(&sends).len(), WINDOW_LEAVES as usize);
+
+        // Peers can only have so many streams at once.
+        for stream_id in 3..(MAX_PEER_STREAMS as u64 + 1) {
+            assert!(sender.has_room_for(&receiver_key, stream_id));
+            let tree = Arc::new(MerkleTree::new(content(LEAF_SIZE)));
+            sender.open(receiver_key, stream_id, tree, 0, 1, price, now);
+        }
+        assert!(!sender.has_room_for(&receiver_key, 100));
+        assert!(sender.has_room_for(&receiver_key, 1));
+    }
+
+    #[test]
+    fn resends_back_off() {
+        let tree = Arc::new(MerkleTree::new(content(LEAF_SIZE)));
+        let receiver_key = XorAddr::from_bytes([2u8; 32]);
+        let rto = Duration::from_secs(1);
+        let now = Instant::now();
+        let mut sender = StreamSender::new();
+        sender.open(receiver_key, 1, tree, 0, 1, BtcPerByte(0.0), now);
+        assert_eq!(sent_leaves(&sender.poll_sends(now, |_| rto, |_| Btc(0.0))), vec![0]);
+
+        let mut at = now;
+        for backoff in &[1, 2, 4, 8] {
+            at += rto * *backoff;
+            assert_eq!(sender.next_deadline(), Some(at));
+            let sends = sender.poll_sends(at, |_| rto, |_| Btc(0.0));
+            assert_eq!(sent_leaves(&sends), vec![0]);
+            assert_eq!(sends.lost, vec![receiver_key]);
+        }
+    }
+}
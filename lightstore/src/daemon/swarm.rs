@@ -0,0 +1,493 @@
+use super::*;
+
+// Ranges are sized between these, in proportion to how a peer compares with the best one in the
+// swarm. Big ranges mean fewer streams to open, small ones mean less work stuck with a peer that
+// turns out to be slow.
+const MIN_RANGE_LEAVES: u64 = 16;
+const MAX_RANGE_LEAVES: u64 = 256;
+
+// The throughput we assume of a peer until we've downloaded a range from it.
+const DEFAULT_RATE: BytePerSec = BytePerSec(100e3);
+
+// How much weight a new throughput measurement gets against what we'd seen before.
+const RATE_SMOOTHING: f64 = 0.5;
+
+// We don't ask for leaves more than this far past the next one the caller's waiting on, so that
+// the ones which arrive early can't pile up without limit.
+const MAX_LOOKAHEAD_LEAVES: u64 = 4 * MAX_RANGE_LEAVES;
+
+// A range which hasn't given us a leaf in this long is given up on and handed to someone else.
+// The stream underneath gets a chance to reopen itself first.
+const RANGE_STALL_TIMEOUT: Duration = Duration::from_secs(12);
+
+struct SwarmPeer {
+    price: BtcPerByte,
+    rate: BytePerSec,
+    failed: bool,
+}
+
+impl SwarmPeer {
+    // How fast this peer gets us data for what it costs. Our own download costs are counted too
+    // so that peers who don't charge anything don't drown out everyone else.
+    fn score(&self, download_cost: BtcPerByte) -> f64 {
+        self.rate.val() / (self.price + download_cost).val()
+    }
+}
+
+struct Range {
+    peer: XorAddr,
+    next: u64,
+    end: u64,
+    received: Byte,
+    opened: Instant,
+    last_progress: Instant,
+    download: Download,
+    // Whether another peer's been asked for the rest of this range as well.
+    duplicated: bool,
+}
+
+// A range of leaves for the driver to ask a peer for.
+pub struct RangeAssignment {
+    pub peer: XorAddr,
+    pub start: u64,
+    pub end: u64,
+    pub price: BtcPerByte,
+}
+
+// Downloads one piece of content from every peer that has it at once, handing the leaves on to
+// the caller in order.
+pub struct Swarm {
+    root: MerkleHash,
+    size: u64,
+    max_price: BtcPerByte,
+    data_tx: UnboundedSender<Result<Bytes, DownloadError>>,
+    peers: HashMap<XorAddr, SwarmPeer>,
+    // Whether we're still looking up who provides the content.
+    looking_up: bool,
+    // How many peers we've asked whether they have the content and haven't heard back from.
+    querying: usize,
+    // The leaves no one's been asked for yet, as start -> end.
+    unassigned: BTreeMap<u64, u64>,
+    ranges: Vec<Range>,
+    // Leaves which arrived before ones earlier in the content.
+    early: BTreeMap<u64, Bytes>,
+    // The next leaf to hand to the caller.
+    next: u64,
+    cancelled: bool,
+}
+
+impl Swarm {
+    pub fn new(
+        root: MerkleHash,
+        size: u64,
+        max_price: BtcPerByte,
+        data_tx: UnboundedSender<Result<Bytes, DownloadError>>,
+    ) -> Swarm {
+        let mut unassigned = BTreeMap::new();
+        let _ = unassigned.insert(0, leaf_count(size));
+        Swarm {
+            root,
+            size,
+            max_price,
+            data_tx,
+            peers: HashMap::new(),
+            looking_up: true,
+            querying: 0,
+            unassigned,
+            ranges: Vec::new(),
+            early: BTreeMap::new(),
+            next: 0,
+            cancelled: false,
+        }
+    }
+
+    pub fn root(&self) -> MerkleHash {
+        self.root
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // We've found the providers of the content and are asking `peers` of them whether they still
+    // have it.
+    pub fn querying(&mut self, peers: usize) {
+        self.looking_up = false;
+        self.querying += peers;
+    }
+
+    // A peer's told us whether they have the content. `price` is what they've asked per byte, or
+    // `None` if they don't have it.
+    pub fn query_answered(&mut self, peer: XorAddr, price: Option<BtcPerByte>) {
+        self.querying = self.querying.saturating_sub(1);
+        let price = match price {
+            Some(price) if price <= self.max_price => price,
+            _ => return,
+        };
+        let swarm_peer = SwarmPeer {
+            price,
+            rate: DEFAULT_RATE,
+            failed: false,
+        };
+        let _ = self.peers.insert(peer, swarm_peer);
+    }
+
+    fn have(&self, index: u64) -> bool {
+        index < self.next || self.early.contains_key(&index)
+    }
+
+    // Hands out work to the peers sitting idle, the ones which give us the most for our money
+    // first. `download_cost` is what each byte costs us to receive.
+    pub fn assign(&mut self, download_cost: BtcPerByte) -> Vec<RangeAssignment> {
+        let best_score = {
+            self.peers
+            .values()
+            .filter(|swarm_peer| !swarm_peer.failed)
+            .map(|swarm_peer| swarm_peer.score(download_cost))
+            .fold(0.0, f64::max)
+        };
+        let mut idle: Vec<(XorAddr, f64)> = {
+            self.peers
+            .iter()
+            .filter(|(peer, swarm_peer)| {
+                !swarm_peer.failed && !self.ranges.iter().any(|range| range.peer == **peer)
+            })
+            .map(|(peer, swarm_peer)| (*peer, swarm_peer.score(download_cost)))
+            .collect()
+        };
+        idle.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(cmp::Ordering::Equal));
+
+        let mut assignments = Vec::new();
+        for (peer, score) in idle {
+            let len = (MAX_RANGE_LEAVES as f64 * score / best_score) as u64;
+            let len = cmp::max(MIN_RANGE_LEAVES, cmp::min(MAX_RANGE_LEAVES, len));
+            let range = match self.take_unassigned(len) {
+                Some(range) => Some(range),
+                None => self.steal(peer),
+            };
+            if let Some((start, end)) = range {
+                let price = self.peers[&peer].price;
+                assignments.push(RangeAssignment { peer, start, end, price });
+            }
+        }
+        assignments
+    }
+
+    fn take_unassigned(&mut self, len: u64) -> Option<(u64, u64)> {
+        let (start, end) = match self.unassigned.iter().next() {
+            Some((start, end)) => (*start, *end),
+            None => return None,
+        };
+        let lookahead_end = self.next + MAX_LOOKAHEAD_LEAVES;
+        if start >= lookahead_end {
+            return None;
+        }
+        let _ = self.unassigned.remove(&start);
+        let split = cmp::min(cmp::min(end, start + len), lookahead_end);
+        if split < end {
+            let _ = self.unassigned.insert(split, end);
+        }
+        Some((start, split))
+    }
+
+    // Once everything's been handed out, a peer with nothing to do races whichever range looks
+    // like it'll take the longest to finish, if it could do it in well under the time. Whoever
+    // finishes first wins and the other stream gets dropped.
+    fn steal(&mut self, peer: XorAddr) -> Option<(u64, u64)> {
+        let mut slowest: Option<(usize, Sec)> = None;
+        for (i, range) in self.ranges.iter().enumerate() {
+            if range.duplicated {
+                continue;
+            }
+            let remaining = Byte::from((range.end - range.next) as usize * LEAF_SIZE);
+            let eta = remaining / self.peers[&range.peer].rate;
+            if slowest.map_or(true, |(_, slowest_eta)| eta > slowest_eta) {
+                slowest = Some((i, eta));
+            }
+        }
+
+        let (i, eta) = slowest?;
+        let range = &mut self.ranges[i];
+        let remaining = Byte::from((range.end - range.next) as usize * LEAF_SIZE);
+        if remaining / self.peers[&peer].rate >= eta * 0.5 {
+            return None;
+        }
+        range.duplicated = true;
+        Some((range.next, range.end))
+    }
+
+    // Starts tracking a range the driver has asked a peer for.
+    pub fn range_opened(&mut self, assignment: RangeAssignment, download: Download, now: Instant) {
+        let range = Range {
+            peer: assignment.peer,
+            next: assignment.start,
+            end: assignment.end,
+            received: Byte(0.0),
+            opened: now,
+            last_progress: now,
+            download,
+            duplicated: false,
+        };
+        self.ranges.push(range);
+    }
+
+    fn received(&mut self, index: u64, data: Bytes) {
+        if self.have(index) {
+            return;
+        }
+        if index != self.next {
+            let _ = self.early.insert(index, data);
+            return;
+        }
+
+        self.cancelled |= self.data_tx.unbounded_send(Ok(data)).is_err();
+        self.next += 1;
+        while let Some(data) = self.early.remove(&self.next) {
+            self.cancelled |= self.data_tx.unbounded_send(Ok(data)).is_err();
+            self.next += 1;
+        }
+    }
+
+    fn completed(&mut self, range: Range, now: Instant) {
+        let elapsed = Sec::from(now.duration_since(range.opened));
+        if let Some(swarm_peer) = self.peers.get_mut(&range.peer) {
+            if elapsed > Sec(0.0) {
+                let rate = range.received / elapsed;
+                swarm_peer.rate = swarm_peer.rate * (1.0 - RATE_SMOOTHING) + rate * RATE_SMOOTHING;
+            }
+        }
+    }
+
+    // Gives up on a peer and puts the leaves it owed us back up for grabs, unless another peer's
+    // already been asked for them.
+    fn failed(&mut self, range: Range) {
+        if let Some(swarm_peer) = self.peers.get_mut(&range.peer) {
+            swarm_peer.failed = true;
+        }
+
+        let mut run_start = None;
+        for index in range.next..(range.end + 1) {
+            let wanted = {
+                index < range.end
+                && !self.have(index)
+                && !self.ranges.iter().any(|other| other.next <= index && index < other.end)
+            };
+            match (wanted, run_start) {
+                (true, None) => run_start = Some(index),
+                (false, Some(start)) => {
+                    let _ = self.unassigned.insert(start, index);
+                    run_start = None;
+                },
+                _ => (),
+            }
+        }
+    }
+
+    // Collects whatever leaves have arrived. Ready once the whole download's been handed on, or
+    // the caller's stopped listening.
+    pub fn poll(&mut self, now: Instant) -> Async<()> {
+        let mut i = 0;
+        while i < self.ranges.len() {
+            let finished = loop {
+                match self.ranges[i].download.poll() {
+                    Ok(Async::Ready(Some(data))) => {
+                        let index = self.ranges[i].next;
+                        self.ranges[i].next += 1;
+                        self.ranges[i].received += Byte::from(data.len());
+                        self.ranges[i].last_progress = now;
+                        self.received(index, data);
+                    },
+                    Ok(Async::Ready(None)) => break Some(Ok(())),
+                    Ok(Async::NotReady) => break None,
+                    Err(e) => break Some(Err(e)),
+                }
+            };
+            match finished {
+                Some(Ok(())) => {
+                    let range = self.ranges.swap_remove(i);
+                    self.completed(range, now);
+                },
+                Some(Err(..)) => {
+                    let range = self.ranges.swap_remove(i);
+                    self.failed(range);
+                },
+                None => {
+                    let range = &self.ranges[i];
+                    if (range.next..range.end).all(|index| self.have(index)) {
+                        // Someone else beat them to it.
+                        let _ = self.ranges.swap_remove(i);
+                    } else if now >= range.last_progress + RANGE_STALL_TIMEOUT {
+                        // Dropping the download closes the stream.
+                        let range = self.ranges.swap_remove(i);
+                        self.failed(range);
+                    } else {
+                        i += 1;
+                    }
+                },
+            }
+        }
+
+        if self.cancelled || self.next >= leaf_count(self.size) {
+            return Async::Ready(());
+        }
+        Async::NotReady
+    }
+
+    // When we'll give up on the slowest range, if it doesn't give us anything before then.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.ranges.iter().map(|range| range.last_progress + RANGE_STALL_TIMEOUT).min()
+    }
+
+    // Whether there's no one left to get the rest of the content from.
+    pub fn is_stuck(&self) -> bool {
+        self.ranges.is_empty() &&
+        !self.looking_up &&
+        self.querying == 0 &&
+        self.next < leaf_count(self.size)
+    }
+
+    pub fn fail(self) {
+        let _ = self.data_tx.unbounded_send(Err(DownloadError::NoSources));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn swarm(leaves: u64) -> (Swarm, Download) {
+        let root = MerkleHash::from_bytes([0u8; 32]);
+        let (data_tx, download) = download_channel(0, leaves);
+        let swarm = Swarm::new(root, leaves * LEAF_SIZE as u64, BtcPerByte(1e-9), data_tx);
+        (swarm, download)
+    }
+
+    fn leaf(index: u64) -> Bytes {
+        Bytes::from(vec![index as u8; LEAF_SIZE])
+    }
+
+    #[test]
+    fn cheaper_peers_get_bigger_ranges() {
+        let cheap = XorAddr::from_bytes([1u8; 32]);
+        let pricey = XorAddr::from_bytes([2u8; 32]);
+        let greedy = XorAddr::from_bytes([3u8; 32]);
+        let (mut swarm, _download) = swarm(10_000);
+        swarm.querying(3);
+        swarm.query_answered(cheap, Some(BtcPerByte(1e-13)));
+        swarm.query_answered(pricey, Some(BtcPerByte(1e-11)));
+        swarm.query_answered(greedy, Some(BtcPerByte(1e-6)));
+
+        let assignments = swarm.assign(Costs::base().download);
+        assert_eq!(assignments.len(), 2);
+        assert!(assignments[0].peer == cheap);
+        assert_eq!((assignments[0].start, assignments[0].end), (0, MAX_RANGE_LEAVES));
+        assert!(assignments[1].peer == pricey);
+        let pricey_len = assignments[1].end - assignments[1].start;
+        assert_eq!(assignments[1].start, MAX_RANGE_LEAVES);
+        assert!(pricey_len >= MIN_RANGE_LEAVES && pricey_len < MAX_RANGE_LEAVES);
+    }
+
+    #[test]
+    fn leaves_come_out_in_order_and_failures_are_reassigned() {
+        let a = XorAddr::from_bytes([1u8; 32]);
+        let b = XorAddr::from_bytes([2u8; 32]);
+        let (mut swarm, download) = swarm(300);
+        swarm.querying(2);
+        swarm.query_answered(a, Some(BtcPerByte(1e-13)));
+        swarm.query_answered(b, Some(BtcPerByte(2e-13)));
+
+        let now = Instant::now();
+        let mut data_txs = HashMap::new();
+        for assignment in swarm.assign(Costs::base().download) {
+            let (data_tx, range_download) = download_channel(assignment.start, assignment.end);
+            let _ = data_txs.insert(assignment.peer, (assignment.start, assignment.end, data_tx));
+            swarm.range_opened(assignment, range_download, now);
+        }
+        let (a_start, a_end, a_tx) = unwrap!(data_txs.remove(&a));
+        let (b_start, b_end, b_tx) = unwrap!(data_txs.remove(&b));
+        assert_eq!((a_start, a_end, b_start), (0, 256, 256));
+        assert_eq!(b_end, 300);
+
+        let res = future::lazy(move || {
+            // The later range finishes first and has to wait.
+            for index in b_start..b_end {
+                unwrap!(b_tx.unbounded_send(Ok(leaf(index))));
+            }
+            drop(b_tx);
+            assert!(swarm.poll(now).is_not_ready());
+            assert_eq!(swarm.next, 0);
+
+            // `a` gets partway then gives up, and `b` picks up where it left off.
+            for index in a_start..100 {
+                unwrap!(a_tx.unbounded_send(Ok(leaf(index))));
+            }
+            unwrap!(a_tx.unbounded_send(Err(DownloadError::Stalled)));
+            assert!(swarm.poll(now).is_not_ready());
+            assert_eq!(swarm.next, 100);
+
+            let assignments = swarm.assign(Costs::base().download);
+            assert_eq!(assignments.len(), 1);
+            assert!(assignments[0].peer == b);
+            assert_eq!((assignments[0].start, assignments[0].end), (100, 256));
+            let (b_tx, range_download) = download_channel(100, 256);
+            swarm.range_opened(unwrap!(assignments.into_iter().next()), range_download, now);
+            for index in 100..256 {
+                unwrap!(b_tx.unbounded_send(Ok(leaf(index))));
+            }
+            assert!(swarm.poll(now).is_ready());
+            Ok::<_, ()>(())
+        }).wait();
+        unwrap!(res);
+
+        let data = unwrap!(download.collect().wait());
+        assert_eq!(data.len(), 300);
+        for (index, leaf_data) in data.iter().enumerate() {
+            assert_eq!(*leaf_data, leaf(index as u64));
+        }
+    }
+
+    #[test]
+    fn stalled_ranges_are_reassigned_and_lookahead_is_limited() {
+        let peers: Vec<XorAddr> = (1..10).map(|i| XorAddr::from_bytes([i; 32])).collect();
+        let (mut swarm, _download) = swarm(10_000);
+        swarm.querying(peers.len());
+        for peer in &peers {
+            swarm.query_answered(*peer, Some(BtcPerByte(1e-13)));
+        }
+
+        // There's more content and more peers, but we only ask for so far ahead.
+        let now = Instant::now();
+        let mut data_txs = HashMap::new();
+        for assignment in swarm.assign(Costs::base().download) {
+            let (data_tx, range_download) = download_channel(assignment.start, assignment.end);
+            let _ = data_txs.insert(assignment.start, (assignment.peer, data_tx));
+            swarm.range_opened(assignment, range_download, now);
+        }
+        let assigned: u64 = swarm.ranges.iter().map(|range| range.end - range.next).sum();
+        assert_eq!(assigned, MAX_LOOKAHEAD_LEAVES);
+
+        let res = future::lazy(move || {
+            // Everyone but the first peer delivers.
+            for (start, (_, data_tx)) in data_txs.iter().filter(|(start, _)| **start > 0) {
+                for index in *start..(*start + MAX_RANGE_LEAVES) {
+                    unwrap!(data_tx.unbounded_send(Ok(leaf(index))));
+                }
+            }
+            let later = now + Duration::from_secs(1);
+            assert!(swarm.poll(later).is_not_ready());
+            assert_eq!(swarm.next, 0);
+            assert_eq!(swarm.next_deadline(), Some(now + RANGE_STALL_TIMEOUT));
+
+            // The first range is handed to someone else, and nothing past the lookahead is.
+            let stalled_at = now + RANGE_STALL_TIMEOUT;
+            assert!(swarm.poll(stalled_at).is_not_ready());
+            let (slow, _) = data_txs[&0];
+            assert!(swarm.peers[&slow].failed);
+            let assignments = swarm.assign(Costs::base().download);
+            assert_eq!(assignments.len(), 1);
+            assert_eq!((assignments[0].start, assignments[0].end), (0, MAX_RANGE_LEAVES));
+            Ok::<_, ()>(())
+        }).wait();
+        unwrap!(res);
+    }
+}
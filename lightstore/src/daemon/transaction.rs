@@ -0,0 +1,434 @@
+use super::*;
+
+// Retransmission timer parameters, as in RFC 6298.
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+// Even a request that's still worth plenty gets abandoned after this many sends. By then the
+// back-off has us waiting minutes between attempts and the peer is most likely gone.
+const MAX_ATTEMPTS: u32 = 8;
+
+// How long we remember the requests we've answered. Retransmissions of the same request get
+// answered again, in case our reply was the thing that got lost, but we only get paid once.
+const ANSWERED_LIFETIME: Duration = Duration::from_secs(120);
+
+// Smoothed round-trip time for one peer.
+pub struct RttEstimator {
+    srtt: Option<Sec>,
+    rttvar: Sec,
+}
+
+impl RttEstimator {
+    pub fn new() -> RttEstimator {
+        RttEstimator {
+            srtt: None,
+            rttvar: Sec(0.0),
+        }
+    }
+
+    pub fn sample(&mut self, rtt: Sec) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt * 0.5;
+            },
+            Some(srtt) => {
+                let deviation = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = self.rttvar * 0.75 + deviation * 0.25;
+                self.srtt = Some(srtt * 0.875 + rtt * 0.125);
+            },
+        }
+    }
+
+    // How long to wait for a reply before sending again.
+    pub fn rto(&self) -> Duration {
+        let rto = match self.srtt {
+            Some(srtt) => Duration::from(srtt + self.rttvar * 4.0),
+            None => INITIAL_RTO,
+        };
+        if rto < MIN_RTO {
+            MIN_RTO
+        } else if rto > MAX_RTO {
+            MAX_RTO
+        } else {
+            rto
+        }
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum RequestError {
+    #[fail(display = "no reply after {} attempts", attempts)]
+    TimedOut {
+        attempts: u32,
+    },
+    #[fail(display = "the daemon shut down")]
+    Shutdown,
+    #[fail(display = "{}", _0)]
+    TooBig(MsgTooBig),
+}
+
+// Resolves to the peer's reply to a request.
+pub struct Response {
+    result_rx: oneshot::Receiver<Result<Msg, RequestError>>,
+}
+
+impl Future for Response {
+    type Item = Msg;
+    type Error = RequestError;
+
+    fn poll(&mut self) -> Result<Async<Msg>, RequestError> {
+        match self.result_rx.poll() {
+            Ok(Async::Ready(res)) => res.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(oneshot::Canceled) => Err(RequestError::Shutdown),
+        }
+    }
+}
+
+struct PendingRequest {
+    peer: XorAddr,
+    offer: Offer,
+    payload: Bytes,
+    utility: Btc,
+    utility_time: Instant,
+    utility_decay: Sec,
+    attempts: u32,
+    sent_at: Instant,
+    deadline: Instant,
+    result_tx: oneshot::Sender<Result<Msg, RequestError>>,
+}
+
+// A request which was answered, and what we owe for the answer.
+pub struct Completed {
+    pub request_len: Byte,
+    pub payout: Btc,
+    // Whether the reply took longer to reach us than the replier could have held it for, even
+    // allowing for a slow round trip. They've claimed a shorter delay to get paid more.
+    pub understated: bool,
+}
+
+// A request which wasn't answered in time.
+pub struct Timeout {
+    pub peer: XorAddr,
+    // The request to send again, or `None` if we've given up on it.
+    pub retransmit: Option<OutgoingMsg>,
+}
+
+// Matches replies to the requests we've sent and decides when to send them again. Like
+// `HolePunch` this doesn't do any IO, the driver sends what it's told to and feeds replies in.
+pub struct Transactions {
+    next_id: u64,
+    pending: HashMap<u64, PendingRequest>,
+    rtts: HashMap<XorAddr, RttEstimator>,
+    answered: HashMap<(XorAddr, u64), (Instant, Sec)>,
+}
+
+impl Transactions {
+    pub fn new() -> Transactions {
+        Transactions {
+            // Start somewhere random so that replies to a previous run's requests don't match.
+            next_id: rand::random(),
+            pending: HashMap::new(),
+            rtts: HashMap::new(),
+            answered: HashMap::new(),
+        }
+    }
+
+    pub fn rto(&self, peer: &XorAddr) -> Duration {
+        match self.rtts.get(peer) {
+            Some(rtt) => rtt.rto(),
+            None => INITIAL_RTO,
+        }
+    }
+
+    // Starts a request to `peer`, offering to pay `offer` for the reply. Returns the message to
+    // send and a future for the reply. The request is worth the most the offer could pay out and
+    // is retransmitted for as long as that's more than sending it costs.
+    pub fn start(
+        &mut self,
+        peer: XorAddr,
+        msg: &Msg,
+        offer: Offer,
+        utility_decay: Sec,
+        now: Instant,
+    ) -> (OutgoingMsg, Response) {
+        let request_id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let (result_tx, result_rx) = oneshot::channel();
+        let utility = offer.max_payout();
+        let pending = PendingRequest {
+            peer,
+            offer,
+            payload: msg.to_bytes(),
+            utility,
+            utility_time: now,
+            utility_decay,
+            attempts: 1,
+            sent_at: now,
+            deadline: now + self.rto(&peer),
+            result_tx,
+        };
+        let outgoing_msg = pending.outgoing_msg(request_id);
+        let _ = self.pending.insert(request_id, pending);
+        (outgoing_msg, Response { result_rx })
+    }
+
+    // Called when `peer` replies to one of our requests, saying they held it for `delay`. Returns
+    // the size of the request and what the reply's worth under our offer, if it was one we were
+    // waiting on. We pay for the delay they've said so that we both charge the same amount, but
+    // check it against how long the reply actually took.
+    pub fn complete(
+        &mut self,
+        peer: XorAddr,
+        request_id: u64,
+        reply: Msg,
+        delay: Sec,
+        now: Instant,
+    ) -> Option<Completed> {
+        match self.pending.get(&request_id) {
+            Some(pending) if pending.peer == peer => (),
+            _ => return None,
+        }
+        let pending = unwrap!(self.pending.remove(&request_id));
+
+        // Whichever copy of the request reached them first, the last one we sent got there no
+        // later than a round trip before the reply arrived.
+        let min_delay = {
+            Sec::from(now.duration_since(pending.sent_at))
+            - Sec::from(self.rto(&peer))
+        };
+        let understated = delay < min_delay;
+        let payout = pending.offer.payout(&ReplyTraits::of(&reply, delay));
+
+        // Karn's algorithm: if we sent more than once we can't tell which send this is a reply
+        // to, so the time it took tells us nothing.
+        if pending.attempts == 1 && now > pending.sent_at {
+            let rtt = Sec::from(now.duration_since(pending.sent_at));
+            self.rtts.entry(peer).or_insert_with(RttEstimator::new).sample(rtt);
+        }
+
+        let request_len = Byte::from(pending.payload.len());
+        let _ = pending.result_tx.send(Ok(reply));
+        Some(Completed { request_len, payout, understated })
+    }
+
+    // Finds the requests whose timers have run out, backing off and resending the ones still
+    // worth resending and failing the rest.
+    pub fn poll_timeouts(&mut self, now: Instant, upload_cost: BtcPerByte) -> Vec<Timeout> {
+        self.pending.retain(|_, pending| !pending.result_tx.is_canceled());
+        self.answered.retain(|_, (answered_at, _)| now < *answered_at + ANSWERED_LIFETIME);
+
+        let expired: Vec<u64> = {
+            self.pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(request_id, _)| *request_id)
+            .collect()
+        };
+
+        let mut timeouts = Vec::with_capacity(expired.len());
+        for request_id in expired {
+            let mut pending = unwrap!(self.pending.remove(&request_id));
+            let peer = pending.peer;
+            let cost = upload_cost * Byte::from(pending.payload.len());
+            if pending.attempts >= MAX_ATTEMPTS || pending.utility_at(now) < cost {
+                let attempts = pending.attempts;
+                let _ = pending.result_tx.send(Err(RequestError::TimedOut { attempts }));
+                timeouts.push(Timeout { peer, retransmit: None });
+                continue;
+            }
+
+            let backoff = self.rto(&peer) * (1 << pending.attempts);
+            let backoff = if backoff > MAX_RTO { MAX_RTO } else { backoff };
+            pending.attempts += 1;
+            pending.sent_at = now;
+            pending.deadline = now + backoff;
+            let retransmit = Some(pending.outgoing_msg(request_id));
+            timeouts.push(Timeout { peer, retransmit });
+            let _ = self.pending.insert(request_id, pending);
+        }
+        timeouts
+    }
+
+    // Gives up on a request we couldn't send.
+    pub fn fail(&mut self, request_id: u64, error: RequestError) {
+        if let Some(pending) = self.pending.remove(&request_id) {
+            let _ = pending.result_tx.send(Err(error));
+        }
+    }
+
+    // When the next request will time out, if we're waiting on any.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|pending| pending.deadline).min()
+    }
+
+    // Records that we've answered `peer`'s request after holding it for `delay`. Returns the delay
+    // to tell them, and `false` if we'd already answered it, ie. this is a retransmission. They
+    // might only get the later reply, so it has to say what we first charged for.
+    pub fn answer(
+        &mut self,
+        peer: XorAddr,
+        request_id: u64,
+        delay: Sec,
+        now: Instant,
+    ) -> (Sec, bool) {
+        if let Some((_, charged)) = self.answered.get(&(peer, request_id)) {
+            return (*charged, false);
+        }
+        let _ = self.answered.insert((peer, request_id), (now, delay));
+        (delay, true)
+    }
+}
+
+impl PendingRequest {
+    fn utility_at(&self, at: Instant) -> Btc {
+        let time = Sec::from(at.duration_since(self.utility_time));
+        self.utility * (- time / self.utility_decay).exp()
+    }
+
+    fn outgoing_msg(&self, request_id: u64) -> OutgoingMsg {
+        OutgoingMsg {
+            msg: Msg::Request {
+                request_id,
+                offer: self.offer.clone(),
+                payload: self.payload.clone(),
+            },
+            utility: self.utility,
+            utility_time: self.utility_time,
+            utility_decay: self.utility_decay,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request_id(outgoing_msg: &OutgoingMsg) -> u64 {
+        match outgoing_msg.msg {
+            Msg::Request { request_id, .. } => request_id,
+            _ => panic!("not a request"),
+        }
+    }
+
+    #[test]
+    fn rtt_estimate_converges() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!(rtt.rto(), INITIAL_RTO);
+        for _ in 0..100 {
+            rtt.sample(Sec(0.3));
+        }
+        let rto = Sec::from(rtt.rto());
+        assert!(rto > Sec(0.29) && rto < Sec(0.31), "rto == {:?}", rto);
+
+        for _ in 0..100 {
+            rtt.sample(Sec(0.001));
+        }
+        assert_eq!(rtt.rto(), MIN_RTO);
+    }
+
+    #[test]
+    fn reply_resolves_request() {
+        let mut transactions = Transactions::new();
+        let peer = XorAddr::from_bytes([1u8; 32]);
+        let other = XorAddr::from_bytes([2u8; 32]);
+        let now = Instant::now();
+        let msg = Msg::SenderGetAddress;
+        let offer = Offer::fixed(Btc(1.0));
+        let (outgoing_msg, response) = transactions.start(peer, &msg, offer, Sec(10.0), now);
+        let request_id = request_id(&outgoing_msg);
+
+        let reply = Msg::SenderAddress { addr: addr!("1.2.3.4:5") };
+        let later = now + Duration::from_millis(300);
+        assert!(transactions.complete(other, request_id, reply, Sec(0.0), later).is_none());
+
+        let reply = Msg::SenderAddress { addr: addr!("1.2.3.4:5") };
+        let completed = unwrap!(transactions.complete(peer, request_id, reply, Sec(0.0), later));
+        assert_eq!(completed.payout, Btc(1.0));
+        assert!(!completed.understated);
+        match unwrap!(response.wait()) {
+            Msg::SenderAddress { addr } => assert_eq!(addr, addr!("1.2.3.4:5")),
+            _ => panic!("wrong reply"),
+        }
+        let rto = Sec::from(transactions.rto(&peer));
+        assert!(rto > Sec(0.89) && rto < Sec(0.91), "rto == {:?}", rto);
+        assert!(transactions.next_deadline().is_none());
+    }
+
+    #[test]
+    fn retransmits_back_off_until_unprofitable() {
+        let mut transactions = Transactions::new();
+        let peer = XorAddr::from_bytes([1u8; 32]);
+        let mut now = Instant::now();
+        let msg = Msg::SenderGetAddress;
+        let (_, response) = transactions.start(peer, &msg, Offer::fixed(Btc(1.0)), Sec(10.0), now);
+
+        // Sending costs a tenth of the request's initial worth, so we should give up once it's
+        // decayed to 10% which takes about 23 seconds.
+        let upload_cost = BtcPerByte(0.1 / msg.to_bytes().len() as f64);
+        let mut sends = vec![now];
+        let timed_out = loop {
+            now = unwrap!(transactions.next_deadline());
+            let timeouts = transactions.poll_timeouts(now, upload_cost);
+            assert_eq!(timeouts.len(), 1);
+            match timeouts[0].retransmit {
+                Some(..) => sends.push(now),
+                None => break now,
+            }
+        };
+
+        let gaps: Vec<Duration> = sends.windows(2).map(|w| w[1].duration_since(w[0])).collect();
+        assert_eq!(gaps[0], INITIAL_RTO);
+        for w in gaps.windows(2) {
+            assert_eq!(w[1], w[0] * 2);
+        }
+        let elapsed = Sec::from(timed_out.duration_since(sends[0]));
+        assert!(elapsed > Sec(10.0 * 10.0f64.ln()));
+        match response.wait() {
+            Err(RequestError::TimedOut { attempts }) => assert_eq!(attempts as usize, sends.len()),
+            _ => panic!("expected a timeout"),
+        }
+    }
+
+    #[test]
+    fn replies_are_paid_for_the_delay_they_state() {
+        let mut transactions = Transactions::new();
+        let peer = XorAddr::from_bytes([1u8; 32]);
+        let now = Instant::now();
+        let msg = Msg::SenderGetAddress;
+        let offer = Offer::decaying(Btc(1.0), Sec(1.0));
+        let later = now + Duration::from_secs(3);
+
+        let (outgoing_msg, _rx) = transactions.start(peer, &msg, offer.clone(), Sec(10.0), now);
+        let reply = Msg::SenderAddress { addr: addr!("1.2.3.4:5") };
+        let completed = {
+            unwrap!(transactions.complete(peer, request_id(&outgoing_msg), reply, Sec(2.5), later))
+        };
+        assert_eq!(completed.payout, offer.payout(&ReplyTraits::of(&msg, Sec(2.5))));
+        assert!(!completed.understated);
+
+        // Three seconds is more than a second longer than any round trip we'd expect, so they
+        // can't have answered straight away.
+        let (outgoing_msg, _rx) = transactions.start(peer, &msg, offer.clone(), Sec(10.0), now);
+        let reply = Msg::SenderAddress { addr: addr!("1.2.3.4:5") };
+        let completed = {
+            unwrap!(transactions.complete(peer, request_id(&outgoing_msg), reply, Sec(0.0), later))
+        };
+        assert_eq!(completed.payout, Btc(1.0));
+        assert!(completed.understated);
+    }
+
+    #[test]
+    fn retransmissions_are_only_paid_once() {
+        let mut transactions = Transactions::new();
+        let peer = XorAddr::from_bytes([1u8; 32]);
+        let now = Instant::now();
+        assert_eq!(transactions.answer(peer, 7, Sec(0.5), now), (Sec(0.5), true));
+        // The second reply says what we charged for the first.
+        assert_eq!(transactions.answer(peer, 7, Sec(3.0), now), (Sec(0.5), false));
+        assert_eq!(transactions.answer(peer, 8, Sec(0.0), now), (Sec(0.0), true));
+    }
+}
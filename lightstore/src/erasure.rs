@@ -0,0 +1,262 @@
+// Reed-Solomon erasure coding over GF(256), so that content can be split into `shards` pieces any
+// `needed` of which are enough to get it back.
+//
+// The first `needed` shards are the content itself, cut into equal runs of leaves and padded out
+// with zeros, and the rest are parity. Each parity byte is a sum of the bytes at the same place in
+// the data shards, weighted by a row of a Cauchy matrix. Any `needed` rows of an identity matrix
+// stacked on a Cauchy matrix can be inverted, which is what lets any `needed` shards be solved
+// for the data.
+
+use super::*;
+
+// The most shards content can be coded into. Every shard needs its own element of the field.
+pub const MAX_SHARDS: usize = 256;
+
+// x^8 + x^4 + x^3 + x^2 + 1, under which 2 generates the field.
+const POLYNOMIAL: u16 = 0x11d;
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum ErasureError {
+    #[fail(display = "invalid number of shards")]
+    BadParameters,
+    #[fail(display = "not enough shards to rebuild the content")]
+    NotEnoughShards,
+    #[fail(display = "shard {} is malformed", _0)]
+    BadShard(usize),
+}
+
+// How many bytes go in each of `needed` shards of `size` bytes of content. Shards are split on
+// leaf boundaries.
+pub fn shard_size(size: u64, needed: usize) -> u64 {
+    let shard_leaves = (leaf_count(size) + needed as u64 - 1) / needed as u64;
+    cmp::min(size, shard_leaves * LEAF_SIZE as u64)
+}
+
+// Log and antilog tables for multiplying in GF(256). Adding is just xor.
+struct Field {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+impl Field {
+    fn new() -> Field {
+        let mut exp = [0u8; 512];
+        let mut log = [0u8; 256];
+        // 2 has order 255, so `exp` repeats itself after that, which saves reducing the sum of two
+        // logs.
+        let mut x: u16 = 1;
+        for (i, power) in exp.iter_mut().enumerate() {
+            *power = x as u8;
+            if i < 255 {
+                log[x as usize] = i as u8;
+            }
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= POLYNOMIAL;
+            }
+        }
+        Field { exp, log }
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            return 0;
+        }
+        self.exp[self.log[a as usize] as usize + self.log[b as usize] as usize]
+    }
+
+    fn inv(&self, a: u8) -> u8 {
+        self.exp[255 - self.log[a as usize] as usize]
+    }
+
+    // Adds `coefficient` times `src` to `dst`.
+    fn mul_add(&self, dst: &mut [u8], coefficient: u8, src: &[u8]) {
+        for (d, s) in dst.iter_mut().zip(src) {
+            *d ^= self.mul(coefficient, *s);
+        }
+    }
+
+    // Inverts a square matrix by Gauss-Jordan elimination, or returns `None` if it's singular.
+    fn invert(&self, mut matrix: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+        let n = matrix.len();
+        let mut inverse: Vec<Vec<u8>> = {
+            (0..n)
+            .map(|i| (0..n).map(|j| if i == j { 1 } else { 0 }).collect())
+            .collect()
+        };
+        for col in 0..n {
+            let pivot = (col..n).find(|&row| matrix[row][col] != 0)?;
+            matrix.swap(col, pivot);
+            inverse.swap(col, pivot);
+            let scale = self.inv(matrix[col][col]);
+            for x in matrix[col].iter_mut().chain(inverse[col].iter_mut()) {
+                *x = self.mul(scale, *x);
+            }
+            let (pivot_row, pivot_inverse) = (matrix[col].clone(), inverse[col].clone());
+            for row in 0..n {
+                let factor = matrix[row][col];
+                if row == col || factor == 0 {
+                    continue;
+                }
+                self.mul_add(&mut matrix[row], factor, &pivot_row);
+                self.mul_add(&mut inverse[row], factor, &pivot_inverse);
+            }
+        }
+        Some(inverse)
+    }
+}
+
+pub struct ErasureCoder {
+    needed: usize,
+    shards: usize,
+    field: Field,
+    // The weights of the data shards in each parity shard.
+    parity: Vec<Vec<u8>>,
+}
+
+impl ErasureCoder {
+    pub fn new(needed: usize, shards: usize) -> Result<ErasureCoder, ErasureError> {
+        if needed == 0 || needed > shards || shards > MAX_SHARDS {
+            return Err(ErasureError::BadParameters);
+        }
+        let field = Field::new();
+        // Parity row i and data column j get the distinct elements i and parities + j, and the
+        // weight where they meet is 1 / (i + parities + j).
+        let parities = shards - needed;
+        let parity: Vec<Vec<u8>> = {
+            (0..parities)
+            .map(|i| {
+                (0..needed)
+                .map(|j| field.inv(i as u8 ^ (parities + j) as u8))
+                .collect()
+            })
+            .collect()
+        };
+        Ok(ErasureCoder { needed, shards, field, parity })
+    }
+
+    // How long each shard of `size` bytes of content is.
+    pub fn shard_len(&self, size: u64) -> usize {
+        shard_size(size, self.needed) as usize
+    }
+
+    // Splits `data` into shards, the first `needed` of which are the data itself.
+    pub fn encode(&self, data: &[u8]) -> Vec<Bytes> {
+        let len = self.shard_len(data.len() as u64);
+        let mut shards: Vec<Vec<u8>> = {
+            (0..self.needed)
+            .map(|i| {
+                let start = cmp::min(i * len, data.len());
+                let end = cmp::min(start + len, data.len());
+                let mut shard = data[start..end].to_vec();
+                shard.resize(len, 0);
+                shard
+            })
+            .collect()
+        };
+        let parity: Vec<Vec<u8>> = {
+            self.parity
+            .iter()
+            .map(|row| {
+                let mut shard = vec![0u8; len];
+                for (coefficient, data_shard) in row.iter().zip(&shards) {
+                    self.field.mul_add(&mut shard, *coefficient, data_shard);
+                }
+                shard
+            })
+            .collect()
+        };
+        shards.extend(parity);
+        shards.into_iter().map(Bytes::from).collect()
+    }
+
+    // Rebuilds `size` bytes of content from some of its shards, given with their indices.
+    pub fn decode(&self, size: u64, shards: &[(usize, Bytes)]) -> Result<Bytes, ErasureError> {
+        let len = self.shard_len(size);
+        let mut chosen: Vec<(usize, &Bytes)> = Vec::with_capacity(self.needed);
+        for (index, shard) in shards {
+            if *index >= self.shards || shard.len() != len {
+                return Err(ErasureError::BadShard(*index));
+            }
+            if chosen.len() < self.needed && chosen.iter().all(|(i, _)| i != index) {
+                chosen.push((*index, shard));
+            }
+        }
+        if chosen.len() < self.needed {
+            return Err(ErasureError::NotEnoughShards);
+        }
+
+        // The rows of the code that made the shards we've got. Multiplying the shards by the
+        // inverse of that gives back the data.
+        let rows: Vec<Vec<u8>> = {
+            chosen
+            .iter()
+            .map(|&(index, _)| match index.checked_sub(self.needed) {
+                Some(i) => self.parity[i].clone(),
+                None => (0..self.needed).map(|j| if j == index { 1 } else { 0 }).collect(),
+            })
+            .collect()
+        };
+        let inverse = match self.field.invert(rows) {
+            Some(inverse) => inverse,
+            None => return Err(ErasureError::NotEnoughShards),
+        };
+        let mut data = BytesMut::with_capacity(len * self.needed);
+        for row in inverse {
+            let mut shard = vec![0u8; len];
+            for (coefficient, (_, chosen_shard)) in row.iter().zip(&chosen) {
+                self.field.mul_add(&mut shard, *coefficient, chosen_shard);
+            }
+            data.extend_from_slice(&shard);
+        }
+        data.truncate(size as usize);
+        Ok(data.freeze())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn any_needed_shards_rebuild_the_data() {
+        let data: Vec<u8> = (0..7 * LEAF_SIZE + 100).map(|i| (i * 13 + i / 251) as u8).collect();
+        let coder = unwrap!(ErasureCoder::new(3, 5));
+        let shards = coder.encode(&data);
+        assert_eq!(shards.len(), 5);
+        assert_eq!(coder.shard_len(data.len() as u64), 3 * LEAF_SIZE);
+        assert_eq!(&shards[0][..], &data[..3 * LEAF_SIZE]);
+
+        for a in 0..5 {
+            for b in (a + 1)..5 {
+                for c in (b + 1)..5 {
+                    let some = vec![
+                        (c, shards[c].clone()),
+                        (a, shards[a].clone()),
+                        (b, shards[b].clone()),
+                    ];
+                    let rebuilt = unwrap!(coder.decode(data.len() as u64, &some));
+                    assert_eq!(&rebuilt[..], &data[..]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bad_shards_are_refused() {
+        assert!(ErasureCoder::new(0, 4).is_err());
+        assert!(ErasureCoder::new(5, 4).is_err());
+        assert!(ErasureCoder::new(2, MAX_SHARDS + 1).is_err());
+
+        let data = vec![7u8; 4 * LEAF_SIZE];
+        let coder = unwrap!(ErasureCoder::new(2, 4));
+        let shards = coder.encode(&data);
+        let size = data.len() as u64;
+        let twice = vec![(1, shards[1].clone()), (1, shards[1].clone())];
+        assert_eq!(coder.decode(size, &twice), Err(ErasureError::NotEnoughShards));
+        let unknown = vec![(4, shards[1].clone()), (0, shards[0].clone())];
+        assert_eq!(coder.decode(size, &unknown), Err(ErasureError::BadShard(4)));
+        let short = vec![(0, shards[0].slice(0, 10)), (1, shards[1].clone())];
+        assert_eq!(coder.decode(size, &short), Err(ErasureError::BadShard(0)));
+    }
+}
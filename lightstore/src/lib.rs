@@ -1,5 +1,6 @@
 #![feature(never_type)]
 #![feature(underscore_imports)]
+#![feature(integer_atomics)]
 #![allow(unused_imports)]
 
 #[macro_export]
@@ -23,14 +24,19 @@ pub mod daemon;
 //pub mod priv_prelude;
 pub mod crypto;
 pub mod resource_costs;
+pub mod config;
+pub mod merkle;
+pub mod erasure;
 
 pub use crate::daemon::Daemon;
+pub use crate::config::{Config, ConfigError};
 use std::path::{Path, PathBuf};
-use std::{str, fmt, mem, ptr};
+use std::{str, fmt, mem, ptr, cmp};
 use std::io::{Read, Write, Cursor};
 use std::ops::Deref;
 use unwrap::*;
 use failure::Fail;
+use log::debug;
 use url::Url;
 use futures::{future, Future, Stream, Sink, Async, IntoFuture};
 use futures::sync::oneshot;
@@ -44,15 +50,18 @@ use std::hash::{Hash, Hasher};
 use std::sync::{atomic, Arc};
 use std::str::FromStr;
 use self::crypto::*;
+use self::merkle::*;
+use self::erasure::*;
+use self::resource_costs::{Costs, ResourceCosts};
 use tokio::net::UdpSocket;
 use tokio::timer::Delay;
 use net_literals::*;
 use std::time::{Duration, Instant};
 use bytes::{Bytes, BytesMut, Buf, BufMut, IntoBuf};
 use std::marker::PhantomData;
-use lightstore_shared_udp_socket::SharedUdpSocket;
+use lightstore_shared_udp_socket::{SharedUdpSocket, OutgoingPacket};
 use atomic_arc::AtomicArc;
 use lightstore_units::*;
-use canndrews_misc_ext_traits::{FutureExt as _, VecDequeExt};
+use canndrews_misc_ext_traits::{FutureExt as _, VecDequeExt, ResultNeverErrExt};
 use rand::OsRng;
 
@@ -0,0 +1,324 @@
+// Merkle trees over content, so that it can be downloaded a leaf at a time from untrusted peers
+// and every leaf checked as it arrives.
+//
+// The tree is left-balanced: a node covering `n` leaves has a left child covering the largest
+// power of two less than `n` and a right child covering the rest. Leaves and interior nodes are
+// hashed with different prefixes so one can't be passed off as the other.
+
+use super::*;
+use sha2::{Digest, Sha256};
+
+// Small enough that a leaf, along with the few proof hashes most leaves need, goes in a single
+// datagram at the minimum MTU. Only every 16th leaf has a proof long enough to need splitting.
+pub const LEAF_SIZE: usize = 256;
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MerkleHash {
+    bytes: [u8; 32],
+}
+
+impl MerkleHash {
+    pub fn from_bytes(bytes: [u8; 32]) -> MerkleHash {
+        MerkleHash { bytes }
+    }
+
+    pub fn as_bytes(&self) -> [u8; 32] {
+        self.bytes
+    }
+
+    // Where in the network records about this content live.
+    pub fn to_xor_addr(&self) -> XorAddr {
+        XorAddr::from_bytes(self.bytes)
+    }
+
+    fn digest(prefix: u8, parts: &[&[u8]]) -> MerkleHash {
+        let mut hasher = Sha256::default();
+        hasher.input(&[prefix]);
+        for part in parts {
+            hasher.input(part);
+        }
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&hasher.result()[..]);
+        MerkleHash { bytes }
+    }
+
+    pub fn leaf(data: &[u8]) -> MerkleHash {
+        MerkleHash::digest(LEAF_PREFIX, &[data])
+    }
+
+    pub fn node(left: &MerkleHash, right: &MerkleHash) -> MerkleHash {
+        MerkleHash::digest(NODE_PREFIX, &[&left.bytes[..], &right.bytes[..]])
+    }
+}
+
+impl fmt::Debug for MerkleHash {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let s = as_base32(&self.as_bytes());
+        fmt.debug_tuple("MerkleHash").field(&s).finish()
+    }
+}
+
+// How many leaves `size` bytes of content is split into. Empty content still has one, empty,
+// leaf so that it has a root.
+pub fn leaf_count(size: u64) -> u64 {
+    cmp::max(1, (size + LEAF_SIZE as u64 - 1) / LEAF_SIZE as u64)
+}
+
+// The length of leaf `index` of content `size` bytes long.
+pub fn leaf_len(size: u64, index: u64) -> usize {
+    let start = index * LEAF_SIZE as u64;
+    cmp::min(LEAF_SIZE as u64, size.saturating_sub(start)) as usize
+}
+
+// How many of `count` leaves go under the left child of the node covering them.
+fn left_count(count: u64) -> u64 {
+    let mut left = 1;
+    while left * 2 < count {
+        left *= 2;
+    }
+    left
+}
+
+// The hashes of the two children of each node we pass through on the way from some node down to
+// a leaf.
+pub type MerkleProof = Vec<(MerkleHash, MerkleHash)>;
+
+#[derive(Debug, Fail)]
+pub enum MerkleError {
+    #[fail(display = "expected leaf {}, got leaf {}", expected, got)]
+    OutOfOrder {
+        expected: u64,
+        got: u64,
+    },
+    #[fail(display = "leaf has the wrong length")]
+    WrongLength,
+    #[fail(display = "proof doesn't match the tree")]
+    InvalidProof,
+}
+
+// Some content along with every node of its tree.
+pub struct MerkleTree {
+    data: Bytes,
+    // `levels[0]` holds the leaf hashes, and each level above holds the hashes of pairs of nodes
+    // from the one below. A node without a partner is carried up as-is, which is what makes the
+    // tree left-balanced.
+    levels: Vec<Vec<MerkleHash>>,
+}
+
+impl MerkleTree {
+    pub fn new(data: Bytes) -> MerkleTree {
+        let count = leaf_count(data.len() as u64);
+        let leaves: Vec<MerkleHash> = {
+            (0..count)
+            .map(|index| {
+                let start = index as usize * LEAF_SIZE;
+                let len = leaf_len(data.len() as u64, index);
+                MerkleHash::leaf(&data[start..(start + len)])
+            })
+            .collect()
+        };
+
+        let mut levels = vec![leaves];
+        while unwrap!(levels.last()).len() > 1 {
+            let level = {
+                unwrap!(levels.last())
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => MerkleHash::node(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect()
+            };
+            levels.push(level);
+        }
+        MerkleTree { data, levels }
+    }
+
+    pub fn root(&self) -> MerkleHash {
+        unwrap!(self.levels.last())[0]
+    }
+
+    pub fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+
+    pub fn leaf_count(&self) -> u64 {
+        self.levels[0].len() as u64
+    }
+
+    pub fn leaf(&self, index: u64) -> Bytes {
+        let start = index as usize * LEAF_SIZE;
+        let len = leaf_len(self.size(), index);
+        self.data.slice(start, start + len)
+    }
+
+    // The hash of the node covering leaves `lo..hi`. These are always aligned to a power of two,
+    // except at the right-hand edge of the tree.
+    fn hash(&self, lo: u64, hi: u64) -> MerkleHash {
+        let mut level = 0;
+        while (1 << level) < hi - lo {
+            level += 1;
+        }
+        self.levels[level][(lo >> level) as usize]
+    }
+
+    // The proof to send along with leaf `index` to a `LeafVerifier` which started at leaf
+    // `start`. The first leaf gets the children of every node between it and the root, after
+    // that each leaf only needs the nodes that it's the leftmost leaf of, since the verifier has
+    // already checked the rest.
+    pub fn proof(&self, index: u64, start: u64) -> MerkleProof {
+        let mut proof = Vec::new();
+        let (mut lo, mut hi) = (0, self.leaf_count());
+        while hi - lo > 1 {
+            let mid = lo + left_count(hi - lo);
+            if lo == index || index == start {
+                proof.push((self.hash(lo, mid), self.hash(mid, hi)));
+            }
+            if index < mid {
+                hi = mid;
+            } else {
+                lo = mid;
+            }
+        }
+        proof
+    }
+}
+
+// Checks the leaves of some content, in order, against its root.
+#[derive(Clone)]
+pub struct LeafVerifier {
+    size: u64,
+    next: u64,
+    // The nodes to the right of where we are that we know the hash of but haven't descended into
+    // yet, as (hash, first leaf, last leaf + 1). The top of the stack is the next one along.
+    stack: Vec<(MerkleHash, u64, u64)>,
+}
+
+impl LeafVerifier {
+    // Starts checking the content with root `root` and length `size` from leaf `start`.
+    pub fn new(root: MerkleHash, size: u64, start: u64) -> LeafVerifier {
+        LeafVerifier {
+            size,
+            next: start,
+            stack: vec![(root, 0, leaf_count(size))],
+        }
+    }
+
+    // The next leaf we're expecting.
+    pub fn next(&self) -> u64 {
+        self.next
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.next >= leaf_count(self.size)
+    }
+
+    pub fn verify(
+        &mut self,
+        index: u64,
+        data: &[u8],
+        proof: &[(MerkleHash, MerkleHash)],
+    ) -> Result<(), MerkleError> {
+        if index != self.next || self.is_done() {
+            return Err(MerkleError::OutOfOrder { expected: self.next, got: index });
+        }
+        if data.len() != leaf_len(self.size, index) {
+            return Err(MerkleError::WrongLength);
+        }
+
+        let mut stack = self.stack.clone();
+        let (mut expected, mut lo, mut hi) = match stack.pop() {
+            Some(top) => top,
+            None => return Err(MerkleError::InvalidProof),
+        };
+        let mut proof = proof.iter();
+        while hi - lo > 1 {
+            let (left, right) = match proof.next() {
+                Some(pair) => pair,
+                None => return Err(MerkleError::InvalidProof),
+            };
+            if MerkleHash::node(left, right) != expected {
+                return Err(MerkleError::InvalidProof);
+            }
+            let mid = lo + left_count(hi - lo);
+            if index < mid {
+                stack.push((*right, mid, hi));
+                expected = *left;
+                hi = mid;
+            } else {
+                expected = *right;
+                lo = mid;
+            }
+        }
+        if proof.next().is_some() || lo != index || MerkleHash::leaf(data) != expected {
+            return Err(MerkleError::InvalidProof);
+        }
+
+        self.stack = stack;
+        self.next += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn content(size: usize) -> Bytes {
+        Bytes::from((0..size).map(|i| (i * 7) as u8).collect::<Vec<u8>>())
+    }
+
+    fn verify_from(tree: &MerkleTree, start: u64) {
+        let mut verifier = LeafVerifier::new(tree.root(), tree.size(), start);
+        for index in start..tree.leaf_count() {
+            let proof = tree.proof(index, start);
+            unwrap!(verifier.verify(index, &tree.leaf(index), &proof));
+        }
+        assert!(verifier.is_done());
+    }
+
+    #[test]
+    fn verify_every_leaf() {
+        for &size in &[0, 1, LEAF_SIZE, LEAF_SIZE + 1, 5 * LEAF_SIZE + 17, 64 * LEAF_SIZE] {
+            let tree = MerkleTree::new(content(size));
+            verify_from(&tree, 0);
+        }
+    }
+
+    #[test]
+    fn resume_partway_through() {
+        let tree = MerkleTree::new(content(37 * LEAF_SIZE + 100));
+        for start in &[1, 16, 31, 37] {
+            verify_from(&tree, *start);
+        }
+    }
+
+    #[test]
+    fn reject_bad_leaves() {
+        let tree = MerkleTree::new(content(10 * LEAF_SIZE));
+        let mut verifier = LeafVerifier::new(tree.root(), tree.size(), 0);
+        let proof = tree.proof(0, 0);
+
+        let mut tampered = tree.leaf(0).to_vec();
+        tampered[3] ^= 1;
+        match verifier.verify(0, &tampered, &proof) {
+            Err(MerkleError::InvalidProof) => (),
+            _ => panic!("accepted a tampered leaf"),
+        }
+        match verifier.verify(1, &tree.leaf(1), &tree.proof(1, 0)) {
+            Err(MerkleError::OutOfOrder { expected: 0, got: 1 }) => (),
+            _ => panic!("accepted a leaf out of order"),
+        }
+        // Failures leave the verifier where it was.
+        unwrap!(verifier.verify(0, &tree.leaf(0), &proof));
+        assert_eq!(verifier.next(), 1);
+    }
+}
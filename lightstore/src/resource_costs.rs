@@ -1,26 +1,411 @@
 use super::*;
+use std::fs;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
-// This function is supposed to give us a measure of the cost of RAM usage. This is used when
-// when deciding how to optimize/balance certain parts of the implementation.
-//
-// Ideally we should have some way of measuring the system RAM usage and the daemon's RAM usage.
-// This, combined with some user configuration options should determine how willing we are to
-// consume more RAM.
-pub fn memory() -> BtcPerByteSec {
-    // Fermi estimate of a good value picked by looking at RAM prices
-    BtcPerByteSec(3e-19)
+// Fermi estimates of good values, picked by looking at RAM prices and local internet plan prices.
+// These are what we charge until we've measured anything, and what costs rise from as the machine
+// gets busy.
+const BASE_MEMORY: BtcPerByteSec = BtcPerByteSec(3e-19);
+const BASE_UPLOAD: BtcPerByte = BtcPerByte(1e-13);
+const BASE_DOWNLOAD: BtcPerByte = BtcPerByte(1e-13);
+
+// However busy we get, we don't charge more than this many times the base costs.
+const MAX_PRESSURE: f64 = 20.0;
+
+// What we assume our connection can carry until we've seen it carry more.
+const ASSUMED_CAPACITY: BytePerSec = BytePerSec(1e6);
+
+// Roughly how long a month is, for spreading a monthly plan over.
+const MONTH: Sec = Sec(30.0 * 24.0 * 60.0 * 60.0);
+
+// How often we re-measure, and how much weight each new throughput measurement gets.
+const UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+const RATE_SMOOTHING: f64 = 0.3;
+
+// What one daemon's resources currently cost, along with the traffic it's sent and received.
+// Clones share the same state, so the sockets can count traffic towards the costs the driver
+// works out.
+#[derive(Clone)]
+pub struct ResourceCosts {
+    inner: Arc<ResourceCostsInner>,
+}
+
+struct ResourceCostsInner {
+    // The costs, as the bits of an `f64`.
+    memory: AtomicU64,
+    upload: AtomicU64,
+    download: AtomicU64,
+    // Running totals, for measuring throughput.
+    uploaded: AtomicUsize,
+    downloaded: AtomicUsize,
+}
+
+impl ResourceCosts {
+    pub fn new() -> ResourceCosts {
+        let base = Costs::base();
+        ResourceCosts {
+            inner: Arc::new(ResourceCostsInner {
+                memory: AtomicU64::new(base.memory.val().to_bits()),
+                upload: AtomicU64::new(base.upload.val().to_bits()),
+                download: AtomicU64::new(base.download.val().to_bits()),
+                uploaded: AtomicUsize::new(0),
+                downloaded: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    pub fn get(&self) -> Costs {
+        Costs {
+            memory: self.memory(),
+            upload: self.upload(),
+            download: self.download(),
+        }
+    }
+
+    // The cost of holding onto data in RAM. This is used when deciding how to optimize/balance
+    // certain parts of the implementation. It goes up as the machine runs short of memory and as
+    // we take up more of it.
+    pub fn memory(&self) -> BtcPerByteSec {
+        BtcPerByteSec(f64::from_bits(self.inner.memory.load(Ordering::Relaxed)))
+    }
+
+    // The cost of upload bandwidth. This takes into account the user's internet plan, how much of
+    // the connection we're already using, and how close we are to the plan's data cap.
+    pub fn upload(&self) -> BtcPerByte {
+        BtcPerByte(f64::from_bits(self.inner.upload.load(Ordering::Relaxed)))
+    }
+
+    pub fn download(&self) -> BtcPerByte {
+        BtcPerByte(f64::from_bits(self.inner.download.load(Ordering::Relaxed)))
+    }
+
+    // Counts traffic towards the throughput measurements.
+    pub fn record_upload(&self, size: Byte) {
+        let _ = self.inner.uploaded.fetch_add(size.val() as usize, Ordering::Relaxed);
+    }
+
+    pub fn record_download(&self, size: Byte) {
+        let _ = self.inner.downloaded.fetch_add(size.val() as usize, Ordering::Relaxed);
+    }
+
+    fn set(&self, costs: &Costs) {
+        self.inner.memory.store(costs.memory.val().to_bits(), Ordering::Relaxed);
+        self.inner.upload.store(costs.upload.val().to_bits(), Ordering::Relaxed);
+        self.inner.download.store(costs.download.val().to_bits(), Ordering::Relaxed);
+    }
+
+    fn traffic(&self) -> (usize, usize) {
+        let uploaded = self.inner.uploaded.load(Ordering::Relaxed);
+        let downloaded = self.inner.downloaded.load(Ordering::Relaxed);
+        (uploaded, downloaded)
+    }
+}
+
+// The state of the machine at some point in time.
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    pub mem_total: Byte,
+    pub mem_available: Byte,
+    // How much of it we're using ourselves.
+    pub rss: Byte,
+    pub upload_rate: BytePerSec,
+    pub download_rate: BytePerSec,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Costs {
+    pub memory: BtcPerByteSec,
+    pub upload: BtcPerByte,
+    pub download: BtcPerByte,
+}
+
+impl Costs {
+    // What we charge until we've measured anything.
+    pub fn base() -> Costs {
+        Costs {
+            memory: BASE_MEMORY,
+            upload: BASE_UPLOAD,
+            download: BASE_DOWNLOAD,
+        }
+    }
 }
 
-// This function is supposed to give us a measure of the cost of upload bandwidth. This should take
-// into account the user's internet expenses, the total bandwidth available, as well as other
-// consumers of the system's bandwidth which may be important to the user.
-pub fn upload() -> BtcPerByte {
-    // Fermi estimate of a good value picked by looking at local internet plan prices
-    BtcPerByte(1e-13)
+// What the user's told us about their internet plan and connection.
+#[derive(Clone, Copy, Debug)]
+pub struct Plan {
+    pub monthly_price: Option<Btc>,
+    pub upload_cap: Option<Byte>,
+    pub download_cap: Option<Byte>,
+    pub upload_capacity: Option<BytePerSec>,
+}
+
+impl Plan {
+    pub fn from_config(config: &Config) -> Plan {
+        Plan {
+            monthly_price: config.plan_price,
+            upload_cap: config.upload_cap,
+            download_cap: config.download_cap,
+            upload_capacity: config.upload_budget,
+        }
+    }
+
+    // What each byte of the plan costs, if it has a price and a cap. The cap's shared between
+    // both directions when the plan has one for each.
+    fn price_per_byte(&self) -> Option<BtcPerByte> {
+        let price = self.monthly_price?;
+        let cap = match (self.upload_cap, self.download_cap) {
+            (Some(up), Some(down)) => up + down,
+            (Some(cap), None) | (None, Some(cap)) => cap,
+            (None, None) => return None,
+        };
+        Some(price / cap)
+    }
+}
+
+// Works out what our resources cost given the state of the machine. `peak_upload` and
+// `peak_download` are the most we've seen the connection carry.
+pub fn estimate(
+    plan: &Plan,
+    sample: &Sample,
+    peak_upload: BytePerSec,
+    peak_download: BytePerSec,
+) -> Costs {
+    let memory_pressure = {
+        let available = sample.mem_available / sample.mem_total;
+        let ours = sample.rss / sample.mem_total;
+        (1.0 + ours) / available.max(1.0 / MAX_PRESSURE)
+    };
+
+    let upload_capacity = match plan.upload_capacity {
+        Some(capacity) => capacity,
+        None => if peak_upload > ASSUMED_CAPACITY { peak_upload } else { ASSUMED_CAPACITY },
+    };
+    let download_capacity = {
+        if peak_download > ASSUMED_CAPACITY { peak_download } else { ASSUMED_CAPACITY }
+    };
+    let upload_pressure = {
+        busyness(sample.upload_rate / upload_capacity)
+        * cap_pressure(sample.upload_rate, plan.upload_cap)
+    };
+    let download_pressure = {
+        busyness(sample.download_rate / download_capacity)
+        * cap_pressure(sample.download_rate, plan.download_cap)
+    };
+
+    let per_byte = plan.price_per_byte();
+    Costs {
+        memory: BASE_MEMORY * clamp_pressure(memory_pressure),
+        upload: per_byte.unwrap_or(BASE_UPLOAD) * clamp_pressure(upload_pressure),
+        download: per_byte.unwrap_or(BASE_DOWNLOAD) * clamp_pressure(download_pressure),
+    }
 }
 
-pub fn download() -> BtcPerByte {
-    // Fermi estimate of a good value picked by looking at local internet plan prices
-    BtcPerByte(1e-13)
+// How much dearer a link gets when it's `utilization` full. Like the queueing delay on it, this
+// blows up as it fills.
+fn busyness(utilization: f64) -> f64 {
+    1.0 / (1.0 - utilization.max(0.0).min(1.0))
 }
 
+// How much dearer traffic gets when keeping up `rate` would take us over the month's cap.
+fn cap_pressure(rate: BytePerSec, cap: Option<Byte>) -> f64 {
+    match cap {
+        Some(cap) => (rate * MONTH / cap).max(1.0),
+        None => 1.0,
+    }
+}
+
+fn clamp_pressure(pressure: f64) -> f64 {
+    if pressure.is_nan() {
+        return MAX_PRESSURE;
+    }
+    pressure.max(1.0).min(MAX_PRESSURE)
+}
+
+// Keeps the costs up to date by re-measuring the machine every so often.
+pub struct ResourceMonitor {
+    plan: Plan,
+    costs: ResourceCosts,
+    next_update: Instant,
+    last_update: Instant,
+    uploaded: usize,
+    downloaded: usize,
+    upload_rate: BytePerSec,
+    download_rate: BytePerSec,
+    peak_upload: BytePerSec,
+    peak_download: BytePerSec,
+}
+
+impl ResourceMonitor {
+    pub fn new(config: &Config, costs: ResourceCosts, now: Instant) -> ResourceMonitor {
+        let (uploaded, downloaded) = costs.traffic();
+        ResourceMonitor {
+            plan: Plan::from_config(config),
+            costs,
+            next_update: now,
+            last_update: now,
+            uploaded,
+            downloaded,
+            upload_rate: BytePerSec(0.0),
+            download_rate: BytePerSec(0.0),
+            peak_upload: BytePerSec(0.0),
+            peak_download: BytePerSec(0.0),
+        }
+    }
+
+    pub fn update(&mut self, now: Instant) {
+        if now < self.next_update {
+            return;
+        }
+        self.next_update = now + UPDATE_INTERVAL;
+
+        let elapsed = Sec::from(now.duration_since(self.last_update));
+        self.last_update = now;
+        let (uploaded, downloaded) = self.costs.traffic();
+        if elapsed > Sec(0.0) {
+            let upload_rate = Byte::from(uploaded.wrapping_sub(self.uploaded)) / elapsed;
+            let download_rate = Byte::from(downloaded.wrapping_sub(self.downloaded)) / elapsed;
+            self.upload_rate = self.upload_rate * (1.0 - RATE_SMOOTHING)
+                + upload_rate * RATE_SMOOTHING;
+            self.download_rate = self.download_rate * (1.0 - RATE_SMOOTHING)
+                + download_rate * RATE_SMOOTHING;
+            if upload_rate > self.peak_upload {
+                self.peak_upload = upload_rate;
+            }
+            if download_rate > self.peak_download {
+                self.peak_download = download_rate;
+            }
+        }
+        self.uploaded = uploaded;
+        self.downloaded = downloaded;
+
+        // Without /proc (ie. off Linux) we can't tell how memory's holding up, so we assume it's
+        // fine.
+        let (mem_total, mem_available) = match read_meminfo() {
+            Some(meminfo) => meminfo,
+            None => (Byte(1.0), Byte(1.0)),
+        };
+        let sample = Sample {
+            mem_total,
+            mem_available,
+            rss: read_proc_kb("/proc/self/status", "VmRSS").unwrap_or(Byte(0.0)),
+            upload_rate: self.upload_rate,
+            download_rate: self.download_rate,
+        };
+        let costs = estimate(&self.plan, &sample, self.peak_upload, self.peak_download);
+        self.costs.set(&costs);
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        Some(self.next_update)
+    }
+}
+
+fn read_meminfo() -> Option<(Byte, Byte)> {
+    let total = read_proc_kb("/proc/meminfo", "MemTotal")?;
+    let available = read_proc_kb("/proc/meminfo", "MemAvailable")?;
+    Some((total, available))
+}
+
+fn read_proc_kb(path: &str, field: &str) -> Option<Byte> {
+    let contents = fs::read_to_string(path).ok()?;
+    parse_kb(&contents, field)
+}
+
+// Finds a line like `MemTotal:       16318268 kB`.
+fn parse_kb(contents: &str, field: &str) -> Option<Byte> {
+    for line in contents.lines() {
+        let mut words = line.split_whitespace();
+        let name = match words.next() {
+            Some(name) => name,
+            None => continue,
+        };
+        if name.len() == field.len() + 1 && name.starts_with(field) && name.ends_with(':') {
+            let kb: f64 = words.next()?.parse().ok()?;
+            return Some(Byte(kb * 1024.0));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn idle() -> Sample {
+        Sample {
+            mem_total: Byte(8e9),
+            mem_available: Byte(8e9),
+            rss: Byte(0.0),
+            upload_rate: BytePerSec(0.0),
+            download_rate: BytePerSec(0.0),
+        }
+    }
+
+    fn no_plan() -> Plan {
+        Plan {
+            monthly_price: None,
+            upload_cap: None,
+            download_cap: None,
+            upload_capacity: None,
+        }
+    }
+
+    #[test]
+    fn costs_rise_under_pressure() {
+        let plan = no_plan();
+        let peak = BytePerSec(0.0);
+        let base = estimate(&plan, &idle(), peak, peak);
+        assert_eq!(base, Costs::base());
+
+        let sample = Sample { mem_available: Byte(2e9), rss: Byte(2e9), ..idle() };
+        let costs = estimate(&plan, &sample, peak, peak);
+        assert!(costs.memory > BASE_MEMORY * 4.0);
+
+        let sample = Sample { upload_rate: BytePerSec(9e5), ..idle() };
+        let costs = estimate(&plan, &sample, peak, peak);
+        assert!(costs.upload > BASE_UPLOAD * 9.0);
+        assert_eq!(costs.download, BASE_DOWNLOAD);
+
+        // However bad it gets, it only gets so bad.
+        let sample = Sample {
+            mem_available: Byte(0.0),
+            rss: Byte(8e9),
+            upload_rate: BytePerSec(1e9),
+            ..idle()
+        };
+        let costs = estimate(&plan, &sample, peak, peak);
+        assert_eq!(costs.memory, BASE_MEMORY * MAX_PRESSURE);
+        assert_eq!(costs.upload, BASE_UPLOAD * MAX_PRESSURE);
+    }
+
+    #[test]
+    fn costs_follow_the_plan() {
+        let plan = Plan {
+            monthly_price: Some(Btc(0.01)),
+            upload_cap: Some(Byte(1e11)),
+            download_cap: None,
+            upload_capacity: None,
+        };
+        let peak = BytePerSec(0.0);
+        let costs = estimate(&plan, &idle(), peak, peak);
+        assert!((costs.upload.val() - 1e-13).abs() < 1e-20);
+
+        // Keeping this up would use twice the month's cap.
+        let rate = BytePerSec(2e11 / MONTH.val());
+        let sample = Sample { upload_rate: rate, ..idle() };
+        let costs = estimate(&plan, &sample, peak, peak);
+        assert!(costs.upload > BtcPerByte(2e-13));
+    }
+
+    #[test]
+    fn parse_meminfo() {
+        let meminfo = "MemTotal:       16318268 kB\nMemFree:  100 kB\nMemAvailable:    8000 kB\n";
+        assert_eq!(parse_kb(meminfo, "MemTotal"), Some(Byte(16318268.0 * 1024.0)));
+        assert_eq!(parse_kb(meminfo, "MemAvailable"), Some(Byte(8000.0 * 1024.0)));
+        assert_eq!(parse_kb(meminfo, "Mem"), None);
+
+        // /proc/self/status and friends can have blank lines in them.
+        let status = "Name:\tlightstore\n\nVmRSS:\t  2048 kB\n";
+        assert_eq!(parse_kb(status, "VmRSS"), Some(Byte(2048.0 * 1024.0)));
+    }
+}